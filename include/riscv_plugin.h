@@ -0,0 +1,42 @@
+/*
+ * Analysis plugin interface of the riscv interpreter.
+ *
+ * A plugin is a shared library loaded with `--plugin path[,key=value...]`. It must export
+ * riscv_plugin_init; the other entry points are optional and only cost anything when they
+ * are exported. All calls come from the thread that runs the hart.
+ */
+#ifndef RISCV_PLUGIN_H
+#define RISCV_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+/* Bumped on incompatible changes to this interface. */
+#define RISCV_PLUGIN_ABI_VERSION 1
+
+/*
+ * Called once after loading with the interface version of the interpreter and the
+ * key=value arguments given on the command line, which stay valid until the library is
+ * unloaded. Whatever is stored in *user_data is passed to the other callbacks. A non-zero
+ * return value aborts the start.
+ */
+int32_t riscv_plugin_init(uint32_t abi_version, size_t argc, const char *const *keys,
+                          const char *const *values, void **user_data);
+
+/* Called before each instruction executes, with its address and encoding. */
+void riscv_plugin_insn(void *user_data, uint64_t pc, uint32_t insn);
+
+/*
+ * Called after each guest load (is_store = 0) or store (is_store = 1) of size bits at addr
+ * by the instruction at pc. value is what was read or written.
+ */
+void riscv_plugin_mem(void *user_data, uint64_t pc, uint64_t addr, uint32_t size,
+                      uint64_t value, uint8_t is_store);
+
+/*
+ * Called once when the guest stops, with its exit code, or with 0xffffffff if the run
+ * ended at an exception the interpreter does not handle.
+ */
+void riscv_plugin_exit(void *user_data, uint32_t exit_code);
+
+#endif
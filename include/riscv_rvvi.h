@@ -0,0 +1,97 @@
+/*
+ * Verification interface of the riscv interpreter for RTL testbenches, after the concepts
+ * of the RISC-V Verification Interface (RVVI).
+ *
+ * Link against the cdylib of the crate (libriscv.so). The testbench creates a machine as the
+ * reference model, steps it whenever the design retires an instruction and compares the
+ * retire events. Values the emulator cannot know are fed back into it: interrupt lines and
+ * what the design read from its own devices. A machine must only be used from one thread
+ * at a time.
+ */
+#ifndef RISCV_RVVI_H
+#define RISCV_RVVI_H
+
+#include <stdint.h>
+
+/* Bumped on incompatible changes to this interface. */
+#define RISCV_RVVI_ABI_VERSION 1
+
+/* CSR writes a retire event has room for. Instructions write at most one. */
+#define RISCV_RVVI_MAX_CSR_WRITES 4
+
+typedef struct riscv_rvvi riscv_rvvi_t;
+
+typedef struct {
+    /* Number of instructions retired before this one. */
+    uint64_t order;
+    uint64_t pc;
+    /* mcause and mtval of the exception or interrupt when trap is set. */
+    uint64_t cause;
+    uint64_t tval;
+    /* All integer registers after the step. */
+    uint64_t x[32];
+    uint64_t csr_value[RISCV_RVVI_MAX_CSR_WRITES];
+    uint32_t insn;
+    /* Exit code when halt is set. */
+    uint32_t exit_code;
+    /* Bit n is set when xn was written. */
+    uint32_t x_written;
+    /* There are no floating point registers, this is always 0. */
+    uint32_t f_written;
+    /* Number of valid entries in csr_addr and csr_value. */
+    uint32_t csr_count;
+    uint32_t csr_addr[RISCV_RVVI_MAX_CSR_WRITES];
+    /*
+     * The instruction raised an exception instead of retiring, or the hart took an interrupt
+     * before it.
+     */
+    uint8_t trap;
+    /* The guest powered the machine off. */
+    uint8_t halt;
+} riscv_rvvi_retire_t;
+
+/* RISCV_RVVI_ABI_VERSION of the library. */
+uint32_t riscv_rvvi_abi_version(void);
+
+/*
+ * Create a machine running the ELF executable or raw binary at path. Returns NULL when it
+ * cannot be loaded.
+ */
+riscv_rvvi_t *riscv_rvvi_open(const char *path);
+void riscv_rvvi_close(riscv_rvvi_t *rvvi);
+
+/*
+ * Execute one instruction and describe it in *event. An instruction that raises an exception
+ * enters the handler at mtvec: the event has trap set with the pc and encoding of the
+ * instruction, and the hart is at the handler. A step that takes an interrupt executes
+ * nothing: the event has trap set and the pc of the interrupted instruction, and the hart is
+ * at the handler.
+ */
+void riscv_rvvi_step(riscv_rvvi_t *rvvi, riscv_rvvi_retire_t *event);
+
+uint64_t riscv_rvvi_pc(const riscv_rvvi_t *rvvi);
+uint64_t riscv_rvvi_gpr(const riscv_rvvi_t *rvvi, uint32_t index);
+uint64_t riscv_rvvi_csr(const riscv_rvvi_t *rvvi, uint32_t csr);
+
+/* Overwrite a register, for values only the design can produce. Writes to x0 are ignored. */
+void riscv_rvvi_set_gpr(riscv_rvvi_t *rvvi, uint32_t index, uint64_t value);
+
+/*
+ * Drive interrupt line irq, bit irq of mip. The hart takes the interrupt at the next step once
+ * the guest enabled it in mie and mstatus.MIE.
+ */
+void riscv_rvvi_set_interrupt(riscv_rvvi_t *rvvi, uint32_t irq, uint8_t level);
+
+/*
+ * Make lo..hi (inclusive) volatile: the emulator no longer models it, loads from it return
+ * what the testbench stored with riscv_rvvi_mem_write or the guest stored last.
+ */
+void riscv_rvvi_set_volatile(riscv_rvvi_t *rvvi, uint64_t lo, uint64_t hi);
+
+/*
+ * Store value of size bits at addr, as the design saw it there. Returns 0, or -1 if nothing
+ * is at addr or size is not 8, 16, 32 or 64.
+ */
+int32_t riscv_rvvi_mem_write(riscv_rvvi_t *rvvi, uint64_t addr, uint64_t value, uint32_t size);
+
+#endif
@@ -1,27 +1,743 @@
-use riscv::interpreter::cpu::Cpu;
-use std::io::Read;
+use riscv::interpreter::{
+    arch_test,
+    chardev::{CharBackend, FileBackend, Stdio, UnixSocketBackend},
+    cosim::{Lockstep, ProcessModel, ReferenceModel, SpikeLog},
+    cpu::{
+        control::StopReason,
+        trace::{TraceFormat, Tracer},
+        Cpu,
+    },
+    elf::{is_elf, Elf},
+    exception::Exception,
+    framebuffer::Framebuffer,
+    gdbstub::{self, SessionEnd},
+    htif::Htif,
+    linux::{check_extensions, load_interpreter, LinuxProcess},
+    plugin::Plugin,
+    replay::{Journal, JournaledBackend},
+    riscv_tests,
+    semihosting::Semihosting,
+    virtio::{
+        console::VirtioConsole,
+        input::{parse_script, InputKind, VirtioInput},
+        p9::Virtio9p,
+        rng::{EntropySource, VirtioRng},
+        vsock::VirtioVsock,
+        VirtioDevice,
+    },
+};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &str = "Usage:\n\
+    - cargo run [options] <filename> [guest arguments]\n\
+    - cargo run --riscv-tests <dir>[,only=<prefix>][,timeout=<seconds>]\n\
+    \x20 run the prebuilt riscv-tests ISA tests in dir, or those whose name starts with prefix\n\
+    - cargo run --riscof-config <dir>\n\
+    \x20 write the ISA and platform descriptions and model_test.h for a RISCOF plugin\n\
+    The file is a raw binary loaded at the start of DRAM, or an ELF executable. ELF files\n\
+    with `tohost`/`fromhost` symbols get an HTIF console and syscall proxy.\n\
+    Options:\n\
+    --virtio-9p tag=<tag>,path=<dir>[,readonly]  share a host directory with the guest\n\
+    --virtio-console <backend>[,name=<name>]     add a console port, the first one is the console\n\
+    \x20                                           backend: stdio | file=<out>[,input=<in>] | socket=<path>\n\
+    --virtio-rng seed=<n> | host                 add an entropy device\n\
+    --virtio-vsock cid=<n>,path=<socket>         bridge vsock to host UNIX sockets\n\
+    --virtio-input keyboard|mouse[,script=<file>][,stdio]\n\
+    \x20                                           add an input device fed from an event script or\n\
+    \x20                                           from characters typed on stdin\n\
+    --linux-user                                 run an RV64I Linux ELF executable in user mode,\n\
+    \x20                                           translating its system calls to the host\n\
+    --sysroot <dir>                              look up the dynamic loader, libraries and other\n\
+    \x20                                           absolute paths of --linux-user guests here first\n\
+    --semihosting                                handle semihosting calls (ebreak sequence)\n\
+    --plugin <path>[,<key>=<value>...]           load an analysis plugin (see include/riscv_plugin.h)\n\
+    --framebuffer <width>x<height>               add a simple-framebuffer\n\
+    --screenshot <path>[,at=<n>]                 dump the framebuffer as PPM or PNG after n\n\
+    \x20                                           instructions, or on exit\n\
+    --save-snapshot <path>[,at=<n>]              save the machine after n instructions, or on exit\n\
+    --restore-snapshot <path>                    resume from a snapshot of the same machine\n\
+    --record <path>                              log console input, host entropy and clocks\n\
+    --replay <path>                              feed a recorded log back for an identical run\n\
+    --signature <path>[,granularity=<n>]         run a riscv-arch-test ELF and write its signature\n\
+    \x20                                           in words of n bytes, 4 by default\n\
+    --trace <format>[,file=<path>][,pc=<start>..<end>][,from=<n>][,count=<n>]\n\
+    \x20                                           trace retired instructions to stdout or a file\n\
+    \x20                                           format: spike | qemu | json\n\
+    --lockstep log=<path>[,start=<pc>] | exec=<command>\n\
+    \x20                                           compare every instruction with a Spike commit log\n\
+    \x20                                           or a reference process and stop at the first\n\
+    \x20                                           difference\n\
+    --gdb <host>:<port>                          wait for GDB to connect and run under its control,\n\
+    \x20                                           with reverse execution if the machine allows it";
+
+/// Reverse execution under GDB: a checkpoint every this many instructions, and how many are kept.
+const REVERSE_INTERVAL: u64 = 100_000;
+const REVERSE_CHECKPOINTS: usize = 100;
+
+/// Split `key=value,flag` style option values.
+fn parse_option_list(value: &str) -> Vec<(&str, &str)> {
+    value
+        .split(',')
+        .map(|item| item.split_once('=').unwrap_or((item, "")))
+        .collect()
+}
+
+fn fail(message: &str) -> ! {
+    println!("{}", message);
+    std::process::exit(1);
+}
+
+fn add_virtio_device(cpu: &mut Cpu, device: Box<dyn VirtioDevice>) {
+    if cpu.bus.add_virtio_device(device).is_none() {
+        fail("too many virtio devices");
+    }
+}
+
+/// Parse a `--virtio-console` value into the port name and its host backend.
+fn parse_console(value: &str) -> (String, Box<dyn CharBackend>) {
+    let mut name = String::new();
+    let mut output = None;
+    let mut input = None;
+    let mut socket = None;
+    let mut stdio = false;
+    for (key, value) in parse_option_list(value) {
+        match key {
+            "name" => name = value.to_string(),
+            "stdio" => stdio = true,
+            "file" => output = Some(value),
+            "input" => input = Some(value),
+            "socket" => socket = Some(value),
+            _ => fail(&format!("unknown --virtio-console option '{}'", key)),
+        }
+    }
+    let backend: Box<dyn CharBackend> = match (stdio, output, socket) {
+        (true, None, None) => Box::new(Stdio::new()),
+        (false, Some(output), None) => Box::new(
+            FileBackend::new(Path::new(output), input.map(Path::new))
+                .unwrap_or_else(|error| fail(&format!("cannot open '{}': {:}", output, error))),
+        ),
+        (false, None, Some(path)) => Box::new(
+            UnixSocketBackend::new(Path::new(path))
+                .unwrap_or_else(|error| fail(&format!("cannot listen on '{}': {:}", path, error))),
+        ),
+        _ => fail("--virtio-console needs exactly one of stdio, file= or socket="),
+    };
+    (name, backend)
+}
+
+/// Parse a number given in decimal or as 0x prefixed hex.
+fn parse_number(value: &str) -> u64 {
+    let result = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    result.unwrap_or_else(|_| fail(&format!("invalid number '{}'", value)))
+}
+
+/// Parse a `--trace` value into a tracer writing to its output.
+fn parse_trace(value: &str) -> Tracer {
+    let mut options = parse_option_list(value).into_iter();
+    let (name, _) = options.next().unwrap();
+    let format = TraceFormat::parse(name)
+        .unwrap_or_else(|| fail(&format!("unknown trace format '{}'", name)));
+    let mut output: Box<dyn Write + Send> = Box::new(BufWriter::new(std::io::stdout()));
+    let mut pc_ranges = Vec::new();
+    let mut from = 0;
+    let mut count = None;
+    for (key, value) in options {
+        match key {
+            "file" => {
+                let file = File::create(value)
+                    .unwrap_or_else(|error| fail(&format!("cannot open '{}': {:}", value, error)));
+                output = Box::new(BufWriter::new(file));
+            }
+            "pc" => {
+                let (start, end) = value
+                    .split_once("..")
+                    .unwrap_or_else(|| fail(&format!("invalid pc range '{}'", value)));
+                pc_ranges.push(parse_number(start)..parse_number(end));
+            }
+            "from" => from = parse_number(value),
+            "count" => count = Some(parse_number(value)),
+            _ => fail(&format!("unknown --trace option '{}'", key)),
+        }
+    }
+    let end = count.map_or(u64::MAX, |count| from.saturating_add(count));
+    let mut tracer = Tracer::new(format, output).with_instret_range(from..end);
+    for range in pc_ranges {
+        tracer = tracer.with_pc_range(range);
+    }
+    tracer
+}
+
+/// Parse a `--lockstep` value into the reference model to compare against.
+fn parse_lockstep(value: &str) -> Box<dyn ReferenceModel> {
+    // the command line of a process may contain commas
+    if let Some(command) = value.strip_prefix("exec=") {
+        let mut words = command.split_whitespace();
+        let program = words
+            .next()
+            .unwrap_or_else(|| fail("--lockstep exec= needs a command"));
+        let model = ProcessModel::spawn(Command::new(program).args(words))
+            .unwrap_or_else(|error| fail(&format!("cannot run '{}': {:}", program, error)));
+        return Box::new(model);
+    }
+    let mut path = None;
+    let mut start = None;
+    for (key, value) in parse_option_list(value) {
+        match key {
+            "log" => path = Some(value),
+            "start" => start = Some(parse_number(value)),
+            _ => fail(&format!("unknown --lockstep option '{}'", key)),
+        }
+    }
+    let path = path.unwrap_or_else(|| fail("--lockstep needs log= or exec="));
+    let mut log = SpikeLog::open(Path::new(path))
+        .unwrap_or_else(|error| fail(&format!("cannot open '{}': {:}", path, error)));
+    if let Some(pc) = start {
+        log = log.starting_at(pc);
+    }
+    Box::new(log)
+}
+
+/// Run the riscv-tests in the directory of a `--riscv-tests` value and exit with 0 if all of
+/// them passed.
+fn run_riscv_tests(value: &str) -> ! {
+    let mut options = parse_option_list(value).into_iter();
+    let (dir, _) = options.next().unwrap();
+    let mut only = "";
+    let mut timeout = 10;
+    for (key, value) in options {
+        match key {
+            "only" => only = value,
+            "timeout" => timeout = parse_number(value),
+            _ => fail(&format!("unknown --riscv-tests option '{}'", key)),
+        }
+    }
+    let tests = riscv_tests::discover(Path::new(dir))
+        .unwrap_or_else(|error| fail(&format!("cannot read '{}': {:}", dir, error)));
+    let mut failed = 0;
+    let mut count = 0;
+    for path in tests {
+        if !path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(only)
+        {
+            continue;
+        }
+        let result = riscv_tests::run_test(&path, Duration::from_secs(timeout));
+        println!("{}", result);
+        count += 1;
+        if result.outcome != riscv_tests::Outcome::Pass {
+            failed += 1;
+        }
+    }
+    println!("{} passed, {} failed", count - failed, failed);
+    std::process::exit((failed > 0) as i32);
+}
+
+/// Let GDB drive the machine. Once it detached, the guest runs on without it.
+fn run_under_gdb(cpu: &mut Cpu, address: &str) -> Option<Exception> {
+    // machines that cannot be forked are debugged without going back
+    let _ = cpu.enable_reverse_execution(REVERSE_INTERVAL, REVERSE_CHECKPOINTS);
+    let listener = TcpListener::bind(address)
+        .unwrap_or_else(|error| fail(&format!("cannot listen on '{}': {:}", address, error)));
+    println!("waiting for gdb on {}", address);
+    let (stream, _) = listener
+        .accept()
+        .unwrap_or_else(|error| fail(&format!("cannot accept gdb: {:}", error)));
+    match gdbstub::serve(cpu, stream) {
+        Ok(SessionEnd::Detached) if cpu.exit_code.is_none() => {
+            cpu.disable_reverse_execution();
+            cpu.execute()
+        }
+        Ok(_) => None,
+        Err(error) => fail(&format!("gdb connection failed: {:}", error)),
+    }
+}
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        println!(
-            "Usage:\n\
-            - cargo run <filename>"
-        );
-        return;
-    }
-    let mut file = std::fs::File::open(&args[1]).unwrap_or_else(|error| {
-        println!("cannot open file '{}': {:}", args[1], error);
+    let mut filename = None;
+    let mut shares = Vec::new();
+    let mut consoles = Vec::new();
+    let mut rng = None;
+    let mut vsock = None;
+    let mut inputs = Vec::new();
+    let mut framebuffer = None;
+    let mut screenshots = Vec::new();
+    let mut guest_args = Vec::new();
+    let mut semihosting = false;
+    let mut linux_user = false;
+    let mut sysroot = None;
+    let mut plugin_options = Vec::new();
+    let mut save_snapshot = None;
+    let mut restore_snapshot = None;
+    let mut journal = None;
+    let mut tracer = None;
+    let mut lockstep = None;
+    let mut signature = None;
+    let mut gdb = None;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--virtio-9p" => {
+                i += 1;
+                let value = args.get(i).unwrap_or_else(|| fail(USAGE));
+                let mut tag = None;
+                let mut path = None;
+                let mut read_only = false;
+                for (key, value) in parse_option_list(value) {
+                    match key {
+                        "tag" => tag = Some(value.to_string()),
+                        "path" => path = Some(value.to_string()),
+                        "readonly" => read_only = true,
+                        _ => fail(&format!("unknown --virtio-9p option '{}'", key)),
+                    }
+                }
+                match (tag, path) {
+                    (Some(tag), Some(path)) => shares.push((tag, path, read_only)),
+                    _ => fail("--virtio-9p needs both tag= and path="),
+                }
+            }
+            "--virtio-console" => {
+                i += 1;
+                consoles.push(parse_console(args.get(i).unwrap_or_else(|| fail(USAGE))));
+            }
+            "--virtio-rng" => {
+                i += 1;
+                let value = args.get(i).unwrap_or_else(|| fail(USAGE));
+                let source = match value.split_once('=') {
+                    Some(("seed", seed)) => EntropySource::Seeded(
+                        seed.parse()
+                            .unwrap_or_else(|_| fail(&format!("invalid seed '{}'", seed))),
+                    ),
+                    None if value == "host" => EntropySource::host().unwrap_or_else(|error| {
+                        fail(&format!("cannot open /dev/urandom: {:}", error))
+                    }),
+                    _ => fail(USAGE),
+                };
+                rng = Some(source);
+            }
+            "--virtio-vsock" => {
+                i += 1;
+                let value = args.get(i).unwrap_or_else(|| fail(USAGE));
+                let mut cid = None;
+                let mut path = None;
+                for (key, value) in parse_option_list(value) {
+                    match key {
+                        "cid" => {
+                            cid = Some(value.parse::<u64>().unwrap_or_else(|_| {
+                                fail(&format!("invalid vsock cid '{}'", value))
+                            }))
+                        }
+                        "path" => path = Some(value.to_string()),
+                        _ => fail(&format!("unknown --virtio-vsock option '{}'", key)),
+                    }
+                }
+                match (cid, path) {
+                    (Some(cid), Some(path)) if cid > 2 => vsock = Some((cid, path)),
+                    _ => fail("--virtio-vsock needs path= and a cid= greater than 2"),
+                }
+            }
+            "--virtio-input" => {
+                i += 1;
+                let value = args.get(i).unwrap_or_else(|| fail(USAGE));
+                let mut options = parse_option_list(value).into_iter();
+                let mut device = match options.next().unwrap().0 {
+                    "keyboard" => VirtioInput::new(InputKind::Keyboard),
+                    "mouse" => VirtioInput::new(InputKind::Mouse),
+                    kind => fail(&format!("unknown input device '{}'", kind)),
+                };
+                let mut stdio = false;
+                for (key, value) in options {
+                    match key {
+                        "script" => {
+                            let text = std::fs::read_to_string(value).unwrap_or_else(|error| {
+                                fail(&format!("cannot read '{}': {:}", value, error))
+                            });
+                            let events = parse_script(&text)
+                                .unwrap_or_else(|error| fail(&format!("{}: {}", value, error)));
+                            device = device.with_script(events);
+                        }
+                        "stdio" => stdio = true,
+                        _ => fail(&format!("unknown --virtio-input option '{}'", key)),
+                    }
+                }
+                inputs.push((device, stdio));
+            }
+            "--semihosting" => semihosting = true,
+            "--linux-user" => linux_user = true,
+            "--plugin" => {
+                i += 1;
+                let value = args.get(i).unwrap_or_else(|| fail(USAGE));
+                let mut options = parse_option_list(value).into_iter();
+                let (path, _) = options.next().unwrap();
+                let arguments = options
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect::<Vec<_>>();
+                plugin_options.push((path.to_string(), arguments));
+            }
+            "--sysroot" => {
+                i += 1;
+                sysroot = Some(PathBuf::from(args.get(i).unwrap_or_else(|| fail(USAGE))));
+            }
+            "--framebuffer" => {
+                i += 1;
+                let value = args.get(i).unwrap_or_else(|| fail(USAGE));
+                let size = value
+                    .split_once('x')
+                    .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)));
+                match size {
+                    Some((width, height)) if Framebuffer::fits(width, height) => {
+                        framebuffer = Some(Framebuffer::new(width, height))
+                    }
+                    _ => fail(&format!("invalid framebuffer size '{}'", value)),
+                }
+            }
+            "--screenshot" => {
+                i += 1;
+                let value = args.get(i).unwrap_or_else(|| fail(USAGE));
+                let mut options = parse_option_list(value).into_iter();
+                let (path, _) = options.next().unwrap();
+                let mut at = None;
+                for (key, value) in options {
+                    match key {
+                        "at" => {
+                            at = Some(value.parse::<u64>().unwrap_or_else(|_| {
+                                fail(&format!("invalid instruction count '{}'", value))
+                            }))
+                        }
+                        _ => fail(&format!("unknown --screenshot option '{}'", key)),
+                    }
+                }
+                screenshots.push((path.to_string(), at));
+            }
+            "--save-snapshot" => {
+                i += 1;
+                let value = args.get(i).unwrap_or_else(|| fail(USAGE));
+                let mut options = parse_option_list(value).into_iter();
+                let (path, _) = options.next().unwrap();
+                let mut at = None;
+                for (key, value) in options {
+                    match key {
+                        "at" => {
+                            at = Some(value.parse::<u64>().unwrap_or_else(|_| {
+                                fail(&format!("invalid instruction count '{}'", value))
+                            }))
+                        }
+                        _ => fail(&format!("unknown --save-snapshot option '{}'", key)),
+                    }
+                }
+                save_snapshot = Some((PathBuf::from(path), at));
+            }
+            "--record" | "--replay" => {
+                let record = args[i] == "--record";
+                i += 1;
+                let path = Path::new(args.get(i).unwrap_or_else(|| fail(USAGE)));
+                if journal.is_some() {
+                    fail("--record and --replay can only be given once");
+                }
+                let result = match record {
+                    true => Journal::record(path),
+                    false => Journal::replay(path),
+                };
+                journal = Some(result.unwrap_or_else(|error| {
+                    fail(&format!("cannot open '{}': {:}", path.display(), error))
+                }));
+            }
+            "--restore-snapshot" => {
+                i += 1;
+                restore_snapshot = Some(PathBuf::from(args.get(i).unwrap_or_else(|| fail(USAGE))));
+            }
+            "--riscv-tests" => {
+                run_riscv_tests(args.get(i + 1).unwrap_or_else(|| fail(USAGE)));
+            }
+            "--riscof-config" => {
+                let dir = args.get(i + 1).unwrap_or_else(|| fail(USAGE));
+                if let Err(error) = arch_test::write_riscof_config(Path::new(dir)) {
+                    fail(&format!("cannot write '{}': {:}", dir, error));
+                }
+                std::process::exit(0);
+            }
+            "--signature" => {
+                i += 1;
+                let value = args.get(i).unwrap_or_else(|| fail(USAGE));
+                let mut options = parse_option_list(value).into_iter();
+                let (path, _) = options.next().unwrap();
+                let mut granularity = arch_test::DEFAULT_GRANULARITY;
+                for (key, value) in options {
+                    match key {
+                        "granularity" => granularity = parse_number(value),
+                        _ => fail(&format!("unknown --signature option '{}'", key)),
+                    }
+                }
+                signature = Some((PathBuf::from(path), granularity));
+            }
+            "--trace" => {
+                i += 1;
+                tracer = Some(parse_trace(args.get(i).unwrap_or_else(|| fail(USAGE))));
+            }
+            "--gdb" => {
+                i += 1;
+                gdb = Some(args.get(i).unwrap_or_else(|| fail(USAGE)).clone());
+            }
+            "--lockstep" => {
+                i += 1;
+                let value = args.get(i).unwrap_or_else(|| fail(USAGE));
+                lockstep = Some(Lockstep::new(parse_lockstep(value)));
+            }
+            arg if !arg.starts_with("--") => {
+                filename = Some(arg.to_string());
+                guest_args = args[i..].to_vec();
+                break;
+            }
+            _ => fail(USAGE),
+        }
+        i += 1;
+    }
+    let filename = filename.unwrap_or_else(|| fail(USAGE));
+
+    let mut file = std::fs::File::open(&filename).unwrap_or_else(|error| {
+        println!("cannot open file '{}': {:}", filename, error);
         std::process::exit(1);
     });
     let mut code = Vec::new();
     file.read_to_end(&mut code).unwrap_or_else(|error| {
-        println!("cannot read file '{}': {:}", args[1], error);
+        println!("cannot read file '{}': {:}", filename, error);
         std::process::exit(1);
     });
 
-    match Cpu::new(code).execute() {
-        Some(e) => panic!("{:?}", e),
-        None => (),
+    let plugins: Vec<Arc<Plugin>> = plugin_options
+        .iter()
+        .map(|(path, arguments)| {
+            Plugin::load(Path::new(path), arguments)
+                .map(Arc::new)
+                .unwrap_or_else(|error| fail(&format!("cannot load plugin '{}': {}", path, error)))
+        })
+        .collect();
+    let finish_plugins = |exit_code| plugins.iter().for_each(|plugin| plugin.exit(exit_code));
+
+    if linux_user {
+        if save_snapshot.is_some() || restore_snapshot.is_some() {
+            fail("snapshots are not supported with --linux-user");
+        }
+        if journal.is_some() {
+            fail("record and replay are not supported with --linux-user");
+        }
+        if lockstep.is_some() {
+            fail("--lockstep is not supported with --linux-user");
+        }
+        if signature.is_some() {
+            fail("--signature is not supported with --linux-user");
+        }
+        let elf =
+            Elf::parse(code).unwrap_or_else(|error| fail(&format!("{}: {}", filename, error)));
+        check_extensions(&elf).unwrap_or_else(|error| fail(&format!("{}: {}", filename, error)));
+        let env: Vec<String> = std::env::vars()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        let interpreter = load_interpreter(&elf, sysroot.as_deref())
+            .unwrap_or_else(|error| fail(&format!("cannot load interpreter: {}", error)));
+        let mut process =
+            LinuxProcess::with_interpreter(&elf, interpreter.as_ref(), &guest_args, &env)
+                .unwrap_or_else(|error| fail(&format!("cannot load '{}': {:?}", filename, error)));
+        if let Some(sysroot) = sysroot {
+            process.set_sysroot(sysroot);
+        }
+        for plugin in &plugins {
+            plugin.attach(&mut process.cpu);
+        }
+        if let Some(tracer) = tracer {
+            process.cpu.set_tracer(tracer);
+        }
+        let result = process.run();
+        // dropping the tracer flushes it, which exiting would skip
+        drop(process.cpu.take_tracer());
+        match result {
+            Ok(status) => {
+                finish_plugins(status);
+                std::process::exit(status as i32)
+            }
+            Err(e) => {
+                finish_plugins(Plugin::EXCEPTION);
+                panic!("{:?}", e)
+            }
+        }
+    }
+
+    if journal.is_some() && (!shares.is_empty() || vsock.is_some()) {
+        fail("record and replay are not supported with --virtio-9p or --virtio-vsock");
+    }
+    // host input of the devices goes through the journal when recording or replaying
+    let journaled = |backend: Box<dyn CharBackend>, name: &str| -> Box<dyn CharBackend> {
+        match &journal {
+            Some(journal) => Box::new(JournaledBackend::new(backend, journal.channel(name))),
+            None => backend,
+        }
+    };
+
+    let mut signature_range = None;
+    let mut cpu = if is_elf(&code) {
+        let elf =
+            Elf::parse(code).unwrap_or_else(|error| fail(&format!("{}: {}", filename, error)));
+        let mut cpu = Cpu::new(vec![]);
+        if let Err(error) = cpu.load_elf(&elf) {
+            fail(&format!("cannot load '{}': {:?}", filename, error));
+        }
+        if let Some(tohost) = elf.symbol("tohost") {
+            let mut htif = Htif::new(tohost, elf.symbol("fromhost")).with_args(guest_args.clone());
+            if let Some(journal) = &journal {
+                htif = htif
+                    .with_console(journaled(Box::new(Stdio::new()), "htif"))
+                    .with_journal(journal);
+            }
+            cpu.bus.set_htif(htif);
+        }
+        if signature.is_some() {
+            let range = arch_test::prepare(&mut cpu, &elf)
+                .unwrap_or_else(|error| fail(&format!("{}: {}", filename, error)));
+            signature_range = Some(range);
+        }
+        cpu
+    } else {
+        if signature.is_some() {
+            fail("--signature needs an ELF executable");
+        }
+        Cpu::new(code)
+    };
+    if semihosting {
+        let mut semihosting = Semihosting::new().with_args(guest_args);
+        if let Some(journal) = &journal {
+            semihosting = semihosting.with_journal(journal);
+        }
+        cpu.bus.set_semihosting(semihosting);
+    }
+    for (tag, path, read_only) in shares {
+        let device = Virtio9p::new(&tag, Path::new(&path), read_only).unwrap_or_else(|error| {
+            fail(&format!("cannot share directory '{}': {:}", path, error))
+        });
+        add_virtio_device(&mut cpu, Box::new(device));
+    }
+    let mut console: Option<VirtioConsole> = None;
+    for (port, (name, backend)) in consoles.into_iter().enumerate() {
+        let backend = journaled(backend, &format!("console{}", port));
+        match &mut console {
+            Some(console) => {
+                console.add_port(&name, backend);
+            }
+            None => console = Some(VirtioConsole::new(backend)),
+        }
+    }
+    if let Some(console) = console {
+        add_virtio_device(&mut cpu, Box::new(console));
+    }
+    if let Some(mut source) = rng {
+        if let Some(journal) = &journal {
+            source = source.journaled(journal.channel("rng"));
+        }
+        add_virtio_device(&mut cpu, Box::new(VirtioRng::new(source)));
+    }
+    for (n, (mut input, stdio)) in inputs.into_iter().enumerate() {
+        if stdio {
+            input = input.with_terminal(journaled(Box::new(Stdio::new()), &format!("input{}", n)));
+        }
+        add_virtio_device(&mut cpu, Box::new(input));
+    }
+    if let Some((cid, path)) = vsock {
+        let device = VirtioVsock::new(cid, Path::new(&path))
+            .unwrap_or_else(|error| fail(&format!("cannot listen on '{}': {:}", path, error)));
+        add_virtio_device(&mut cpu, Box::new(device));
+    }
+
+    if let Some(mut framebuffer) = framebuffer {
+        for (path, at) in &screenshots {
+            if let Some(at) = at {
+                framebuffer.schedule_screenshot(*at, path);
+            }
+        }
+        cpu.bus.set_framebuffer(framebuffer);
+    } else if !screenshots.is_empty() {
+        fail("--screenshot needs --framebuffer");
+    }
+
+    for plugin in &plugins {
+        plugin.attach(&mut cpu);
+    }
+    if let Some(path) = &restore_snapshot {
+        cpu.restore_snapshot_file(path).unwrap_or_else(|error| {
+            fail(&format!("cannot restore '{}': {:}", path.display(), error))
+        });
+    }
+    if let Some(journal) = &journal {
+        cpu.set_journal(journal.clone());
+    }
+    if let Some(tracer) = tracer {
+        cpu.set_tracer(tracer);
+    }
+    let save = |cpu: &Cpu, path: &Path| {
+        if let Err(error) = cpu.save_snapshot_file(path) {
+            println!("cannot write snapshot '{}': {:}", path.display(), error);
+        }
+    };
+    let result = match (&mut lockstep, &save_snapshot) {
+        (Some(_), _) if gdb.is_some() => fail("--gdb cannot be combined with --lockstep"),
+        (Some(_), Some((_, Some(_)))) => fail("--lockstep cannot save a snapshot midway"),
+        (Some(lockstep), _) => match lockstep.run(&mut cpu, u64::MAX) {
+            Ok(StopReason::Exception(exception)) => Some(exception),
+            Ok(_) => None,
+            Err(divergence) => {
+                drop(cpu.take_tracer());
+                print!("{}", divergence);
+                std::process::exit(1);
+            }
+        },
+        (None, Some((_, Some(_)))) if gdb.is_some() => fail("--gdb cannot save a snapshot midway"),
+        (None, _) if gdb.is_some() => run_under_gdb(&mut cpu, gdb.as_deref().unwrap()),
+        (None, Some((path, Some(at)))) => match cpu.execute_until(*at) {
+            None if cpu.exit_code.is_none() => {
+                save(&cpu, path);
+                cpu.execute()
+            }
+            result => result,
+        },
+        (None, _) => cpu.execute(),
     };
+    drop(cpu.take_tracer());
+    // written even when the test went wrong, the comparison shows where
+    if let (Some((path, granularity)), Some(range)) = (&signature, signature_range) {
+        let result = arch_test::dump_signature(&cpu.bus, range, *granularity)
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(error) = result {
+            println!("cannot write signature '{}': {}", path.display(), error);
+        }
+    }
+    if let Some((path, None)) = &save_snapshot {
+        save(&cpu, path);
+    }
+    if let Some(framebuffer) = cpu.bus.framebuffer() {
+        for (path, _) in screenshots.iter().filter(|(_, at)| at.is_none()) {
+            if let Err(error) = framebuffer.save_screenshot(Path::new(path)) {
+                println!("cannot write screenshot '{}': {:}", path, error);
+            }
+        }
+    }
+    if let Some(divergence) = journal.as_ref().and_then(|journal| journal.divergence()) {
+        println!("replay diverged from the recording: {}", divergence);
+    }
+    if let Some(e) = result {
+        finish_plugins(Plugin::EXCEPTION);
+        panic!("{:?}", e)
+    }
+    let exit_code = cpu.exit_code.unwrap_or(0);
+    finish_plugins(exit_code);
+    std::process::exit(exit_code as i32);
 }
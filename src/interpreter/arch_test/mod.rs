@@ -0,0 +1,135 @@
+//! Support for the RISC-V architectural tests (riscv-arch-test) as run by RISCOF. A test is
+//! an ELF executable built with the macros of `include/riscof/model_test.h`: it halts through
+//! HTIF, and its results, the signature, are the memory between the `begin_signature` and
+//! `end_signature` symbols. RISCOF compares the signature with one of a reference model.
+use std::{fs, io, ops::Range, path::Path};
+
+use super::{
+    bus::Bus,
+    cpu::{control::StopReason, csr::MISA_VALUE, Cpu},
+    elf::Elf,
+    htif::Htif,
+};
+
+/// The ISA string of what the hart implements.
+pub const ISA: &str = "RV64IZicsr";
+
+/// The target macros for building tests for this model.
+pub const MODEL_TEST_H: &str = include_str!("../../../include/riscof/model_test.h");
+
+/// Signature words are this many bytes, unless asked otherwise.
+pub const DEFAULT_GRANULARITY: u64 = 4;
+
+/// Where the signature of the test in `elf` lies.
+pub fn signature_range(elf: &Elf) -> Result<Range<u64>, String> {
+    match (elf.symbol("begin_signature"), elf.symbol("end_signature")) {
+        (Some(begin), Some(end)) if begin <= end => Ok(begin..end),
+        (Some(_), Some(_)) => Err("end_signature is before begin_signature".to_string()),
+        _ => Err("no begin_signature and end_signature symbols".to_string()),
+    }
+}
+
+/// The signature in `range` in the format of the reference models: one lowercase hex word
+/// of `granularity` bytes per line, lowest address first.
+pub fn dump_signature(bus: &Bus, range: Range<u64>, granularity: u64) -> Result<String, String> {
+    if ![1, 2, 4, 8].contains(&granularity) {
+        return Err(format!("invalid signature granularity {}", granularity));
+    }
+    let mut signature = String::new();
+    for addr in range.step_by(granularity as usize) {
+        let value = bus
+            .load(addr, granularity * 8)
+            .map_err(|_| format!("signature at 0x{:x} is not in memory", addr))?;
+        let width = granularity as usize * 2;
+        signature += &format!("{:0width$x}\n", value, width = width);
+    }
+    Ok(signature)
+}
+
+/// Prepare `cpu` to run the test in `elf`, which it has loaded, and return where its
+/// signature lies.
+pub fn prepare(cpu: &mut Cpu, elf: &Elf) -> Result<Range<u64>, String> {
+    let range = signature_range(elf)?;
+    let tohost = elf.symbol("tohost").ok_or("no tohost symbol")?;
+    cpu.bus.set_htif(Htif::new(tohost, elf.symbol("fromhost")));
+    // tests of exceptions check what their handler saw
+    cpu.set_trap_delivery(true);
+    Ok(range)
+}
+
+/// Run the test at `path` for at most `limit` instructions and return its signature.
+pub fn run_test(path: &Path, granularity: u64, limit: u64) -> Result<String, String> {
+    let elf = Elf::parse(fs::read(path).map_err(|e| e.to_string())?)?;
+    let mut cpu = Cpu::new(vec![]);
+    cpu.load_elf(&elf).map_err(|e| format!("{:?}", e))?;
+    let range = prepare(&mut cpu, &elf)?;
+    match cpu.run(limit) {
+        StopReason::Exited { .. } => dump_signature(&cpu.bus, range, granularity),
+        StopReason::Limit => Err(format!("did not halt within {} instructions", limit)),
+        StopReason::Exception(exception) => Err(format!("{:?} at 0x{:x}", exception, cpu.pc)),
+        reason => Err(format!("{:?}", reason)),
+    }
+}
+
+/// The riscv-config ISA description of the hart.
+pub fn isa_yaml() -> String {
+    let extensions = MISA_VALUE & 0x3ff_ffff;
+    format!(
+        "hart_ids: [0]\n\
+         hart0:\n\
+         \x20 ISA: {isa}\n\
+         \x20 physical_addr_sz: 56\n\
+         \x20 User_Spec_Version: '2.3'\n\
+         \x20 Privilege_Spec_Version: '1.11'\n\
+         \x20 supported_xlen: [64]\n\
+         \x20 misa:\n\
+         \x20   reset-val: {misa:#x}\n\
+         \x20   rv32:\n\
+         \x20     accessible: false\n\
+         \x20   rv64:\n\
+         \x20     accessible: true\n\
+         \x20     mxl:\n\
+         \x20       implemented: true\n\
+         \x20       type:\n\
+         \x20         warl:\n\
+         \x20           dependency_fields: []\n\
+         \x20           legal:\n\
+         \x20             - mxl[1:0] in [0x2]\n\
+         \x20           wr_illegal:\n\
+         \x20             - Unchanged\n\
+         \x20     extensions:\n\
+         \x20       implemented: true\n\
+         \x20       type:\n\
+         \x20         warl:\n\
+         \x20           dependency_fields: []\n\
+         \x20           legal:\n\
+         \x20             - extensions[25:0] bitmask [{extensions:#09x}, 0x0000000]\n\
+         \x20           wr_illegal:\n\
+         \x20             - Unchanged\n",
+        isa = ISA,
+        misa = MISA_VALUE,
+        extensions = extensions,
+    )
+}
+
+/// The riscv-config platform description. There is no timer and no NMI.
+pub fn platform_yaml() -> String {
+    "mtime:\n\
+     \x20 implemented: false\n\
+     mtimecmp:\n\
+     \x20 implemented: false\n\
+     nmi:\n\
+     \x20 label: nmi_vector\n\
+     reset:\n\
+     \x20 label: reset_vector\n"
+        .to_string()
+}
+
+/// Write what a RISCOF plugin for this model needs into `dir`: the ISA and platform
+/// descriptions and the target macros.
+pub fn write_riscof_config(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join("riscv_isa.yaml"), isa_yaml())?;
+    fs::write(dir.join("riscv_platform.yaml"), platform_yaml())?;
+    fs::write(dir.join("model_test.h"), MODEL_TEST_H)
+}
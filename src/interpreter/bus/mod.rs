@@ -1,25 +1,327 @@
-use super::{dram::Dram, exception::Exception, DRAM_BASE, DRAM_END};
+use std::{ops::RangeInclusive, sync::Arc};
+
+use super::{
+    dram::Dram,
+    exception::Exception,
+    framebuffer::Framebuffer,
+    htif::Htif,
+    linux::memory::UserMemory,
+    replay::Journal,
+    rvvi::VolatileMemory,
+    semihosting::Semihosting,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    syscon::{PowerEvent, Syscon},
+    virtio::{VirtioDevice, VirtioMmio},
+    DRAM_BASE, DRAM_END, FRAMEBUFFER_BASE, FRAMEBUFFER_END, SYSCON_BASE, SYSCON_END, VIRTIO_BASE,
+    VIRTIO_COUNT, VIRTIO_END, VIRTIO_STRIDE,
+};
+
+/// Devices are polled for host side input every this many instructions.
+const POLL_INTERVAL: u64 = 1024;
 
 pub struct Bus {
     dram: Dram,
+    syscon: Syscon,
+    virtio: Vec<VirtioMmio>,
+    framebuffer: Option<Framebuffer>,
+    htif: Option<Htif>,
+    semihosting: Option<Semihosting>,
+    /// Address space of a user-mode process. When set it replaces the whole physical map.
+    user_memory: Option<UserMemory>,
+    /// Earliest instruction count a virtio device wants to be ticked at.
+    next_deadline: u64,
+    /// Told the instruction count, so recorded inputs can be matched to it.
+    journal: Option<Arc<Journal>>,
+    /// Overrides the map where a testbench supplies the values. Not part of snapshots.
+    volatile: Option<Box<VolatileMemory>>,
 }
 
 impl Bus {
     pub fn new(code: Vec<u8>) -> Bus {
         Self {
             dram: Dram::new(code),
+            syscon: Syscon::new(),
+            virtio: Vec::new(),
+            framebuffer: None,
+            htif: None,
+            semihosting: None,
+            user_memory: None,
+            next_deadline: u64::MAX,
+            journal: None,
+            volatile: None,
+        }
+    }
+
+    /// A bus sharing this one's memory copy-on-write. Devices backed by host resources cannot
+    /// be duplicated, so machines with virtio devices, semihosting or a user-mode address
+    /// space cannot be forked. HTIF is copied, see `Htif::fork`.
+    pub fn fork(&self) -> Result<Bus, String> {
+        if !self.virtio.is_empty() {
+            return Err("cannot fork a machine with virtio devices".to_string());
+        }
+        if self.semihosting.is_some() {
+            return Err("cannot fork a machine with semihosting".to_string());
+        }
+        if self.user_memory.is_some() {
+            return Err("cannot fork a user-mode process".to_string());
+        }
+        Ok(Self {
+            dram: self.dram.clone(),
+            syscon: Syscon::new(),
+            virtio: Vec::new(),
+            framebuffer: self.framebuffer.as_ref().map(Framebuffer::fork),
+            htif: self.htif.as_ref().map(Htif::fork).transpose()?,
+            semihosting: None,
+            user_memory: None,
+            next_deadline: u64::MAX,
+            journal: None,
+            volatile: self.volatile.clone(),
+        })
+    }
+
+    /// Plug a device into the next free virtio-mmio slot. Returns the base address of its
+    /// register window, or `None` when all slots are taken.
+    pub fn add_virtio_device(&mut self, device: Box<dyn VirtioDevice>) -> Option<u64> {
+        if self.virtio.len() as u64 == VIRTIO_COUNT {
+            return None;
+        }
+        let base = VIRTIO_BASE + VIRTIO_STRIDE * self.virtio.len() as u64;
+        self.virtio.push(VirtioMmio::new(device));
+        self.update_deadline();
+        Some(base)
+    }
+
+    pub fn virtio_devices(&self) -> &[VirtioMmio] {
+        &self.virtio
+    }
+
+    /// Power off or reset requested by the guest since the last call.
+    pub fn take_power_event(&mut self) -> Option<PowerEvent> {
+        self.syscon
+            .take_event()
+            .or_else(|| self.htif.as_mut()?.take_event())
+            .or_else(|| self.semihosting.as_mut()?.take_event())
+    }
+
+    /// Let the testbench decide what is in `range`, see `VolatileMemory`.
+    pub fn add_volatile_range(&mut self, range: RangeInclusive<u64>) {
+        self.volatile
+            .get_or_insert_with(Default::default)
+            .add_range(range);
+    }
+
+    pub fn set_journal(&mut self, journal: Arc<Journal>) {
+        self.journal = Some(journal);
+    }
+
+    pub fn take_journal(&mut self) -> Option<Arc<Journal>> {
+        self.journal.take()
+    }
+
+    pub fn set_htif(&mut self, htif: Htif) {
+        self.htif = Some(htif);
+    }
+
+    pub fn set_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(semihosting);
+    }
+
+    pub fn set_user_memory(&mut self, memory: UserMemory) {
+        self.user_memory = Some(memory);
+    }
+
+    pub fn user_memory(&self) -> Option<&UserMemory> {
+        self.user_memory.as_ref()
+    }
+
+    pub fn user_memory_mut(&mut self) -> Option<&mut UserMemory> {
+        self.user_memory.as_mut()
+    }
+
+    /// Perform semihosting operation `op`. `None` when semihosting is not enabled.
+    pub fn semihosting_call(&mut self, op: u64, param: u64, instret: u64) -> Option<u64> {
+        let semihosting = self.semihosting.as_mut()?;
+        Some(semihosting.call(op, param, &mut self.dram, instret))
+    }
+
+    pub fn set_framebuffer(&mut self, framebuffer: Framebuffer) {
+        self.framebuffer = Some(framebuffer);
+    }
+
+    pub fn htif(&self) -> Option<&Htif> {
+        self.htif.as_ref()
+    }
+
+    pub fn framebuffer(&self) -> Option<&Framebuffer> {
+        self.framebuffer.as_ref()
+    }
+
+    pub fn framebuffer_mut(&mut self) -> Option<&mut Framebuffer> {
+        self.framebuffer.as_mut()
+    }
+
+    fn update_deadline(&mut self) {
+        self.next_deadline = self
+            .virtio
+            .iter()
+            .filter_map(|device| device.deadline())
+            .min()
+            .unwrap_or(u64::MAX);
+    }
+
+    /// Let devices pick up host side input.
+    pub fn poll_devices(&mut self) {
+        for device in &mut self.virtio {
+            device.poll(&mut self.dram);
+        }
+    }
+
+    /// Called by the execution loop after every retired instruction, for devices acting at a
+    /// given instruction count.
+    pub fn tick(&mut self, instret: u64) {
+        if let Some(journal) = &self.journal {
+            journal.set_instret(instret);
+        }
+        if instret.is_multiple_of(POLL_INTERVAL) {
+            self.poll_devices();
+        }
+        if instret >= self.next_deadline {
+            for device in &mut self.virtio {
+                device.tick(instret, &mut self.dram);
+            }
+            self.update_deadline();
+        }
+        if let Some(framebuffer) = &mut self.framebuffer {
+            framebuffer.tick(instret);
+        }
+        if let Some(htif) = &mut self.htif {
+            htif.tick(&mut self.dram);
+        }
+    }
+
+    /// Memory and device registers for a snapshot.
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        w.pages(&self.dram);
+        match &self.framebuffer {
+            Some(framebuffer) => {
+                w.u8(1);
+                framebuffer.save_state(w);
+            }
+            None => w.u8(0),
+        }
+        w.u32(self.virtio.len() as u32);
+        for device in &self.virtio {
+            device.save_state(w);
+        }
+        self.syscon.save_state(w);
+        match &self.htif {
+            Some(htif) => {
+                w.u8(1);
+                htif.save_state(w);
+            }
+            None => w.u8(0),
+        }
+        match &self.semihosting {
+            Some(semihosting) => {
+                w.u8(1);
+                semihosting.save_state(w);
+            }
+            None => w.u8(0),
+        }
+    }
+
+    pub(crate) fn restore_state(&mut self, r: &mut SnapshotReader) -> std::io::Result<()> {
+        let mismatch = |what| std::io::Error::new(std::io::ErrorKind::InvalidData, what);
+        r.pages(&mut self.dram)?;
+        match (r.u8()?, &mut self.framebuffer) {
+            (0, None) => (),
+            (1, Some(framebuffer)) => framebuffer.restore_state(r)?,
+            _ => return Err(mismatch("snapshot framebuffer differs")),
+        }
+        if r.u32()? as usize != self.virtio.len() {
+            return Err(mismatch("snapshot virtio devices differ"));
+        }
+        for device in &mut self.virtio {
+            device.restore_state(r)?;
+        }
+        self.syscon.restore_state(r)?;
+        match (r.u8()?, &mut self.htif) {
+            (0, None) => (),
+            (1, Some(htif)) => htif.restore_state(r)?,
+            _ => return Err(mismatch("snapshot HTIF differs")),
+        }
+        match (r.u8()?, &mut self.semihosting) {
+            (0, None) => (),
+            (1, Some(semihosting)) => semihosting.restore_state(r)?,
+            _ => return Err(mismatch("snapshot semihosting differs")),
+        }
+        self.update_deadline();
+        Ok(())
+    }
+
+    /// Copy a block out of memory, e.g. for host side proxies.
+    pub fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        match &self.user_memory {
+            Some(memory) => memory.read_bytes(addr, buf),
+            None => self.dram.read_bytes(addr, buf),
+        }
+    }
+
+    /// Copy a block into memory, e.g. when loading a program.
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        match &mut self.user_memory {
+            Some(memory) => memory.write_bytes(addr, data),
+            None => self.dram.write_bytes(addr, data),
         }
     }
 
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if let Some(memory) = &self.user_memory {
+            return memory.load(addr, size);
+        }
+        if let Some(value) = self.volatile.as_ref().and_then(|v| v.load(addr, size)) {
+            return Ok(value);
+        }
         match addr {
             DRAM_BASE..=DRAM_END => self.dram.load(addr, size),
+            SYSCON_BASE..=SYSCON_END => self.syscon.load(addr, size),
+            VIRTIO_BASE..=VIRTIO_END => {
+                let offset = addr - VIRTIO_BASE;
+                match self.virtio.get((offset / VIRTIO_STRIDE) as usize) {
+                    Some(device) => Ok(device.load(offset % VIRTIO_STRIDE, size)),
+                    None => Ok(0),
+                }
+            }
+            FRAMEBUFFER_BASE..=FRAMEBUFFER_END => match &self.framebuffer {
+                Some(framebuffer) => framebuffer.load(addr, size),
+                None => Err(Exception::LoadAccessFault { address: addr }),
+            },
             _ => Err(Exception::LoadAccessFault { address: addr }),
         }
     }
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if let Some(memory) = &mut self.user_memory {
+            return memory.store(addr, size, value);
+        }
+        if let Some(volatile) = &mut self.volatile {
+            if volatile.store(addr, size, value) {
+                return Ok(());
+            }
+        }
         match addr {
             DRAM_BASE..=DRAM_END => self.dram.store(addr, size, value),
+            SYSCON_BASE..=SYSCON_END => self.syscon.store(addr, size, value),
+            VIRTIO_BASE..=VIRTIO_END => {
+                let offset = addr - VIRTIO_BASE;
+                if let Some(device) = self.virtio.get_mut((offset / VIRTIO_STRIDE) as usize) {
+                    device.store(offset % VIRTIO_STRIDE, size, value, &mut self.dram);
+                }
+                Ok(())
+            }
+            FRAMEBUFFER_BASE..=FRAMEBUFFER_END => match &mut self.framebuffer {
+                Some(framebuffer) => framebuffer.store(addr, size, value),
+                None => Err(Exception::StoreAMOAccessFault { address: addr }),
+            },
             _ => Err(Exception::StoreAMOAccessFault { address: addr }),
         }
     }
@@ -0,0 +1,208 @@
+//! Host side endpoints for guest byte streams (consoles, serial-like channels).
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, TryRecvError},
+        Arc, Mutex,
+    },
+};
+
+/// A host endpoint for a guest byte stream. `read` never blocks: it returns 0 when no input is
+/// available right now.
+pub trait CharBackend: Send {
+    fn write(&mut self, data: &[u8]);
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+}
+
+/// Reads stdin on a helper thread, so polling for input never blocks the guest.
+pub struct Stdio {
+    input: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl Stdio {
+    pub fn new() -> Stdio {
+        let (sender, input) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0; 256];
+            loop {
+                match io::stdin().read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if sender.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            input,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hand out buffered bytes, refilling from `input` when empty.
+fn drain(pending: &mut Vec<u8>, input: &Receiver<Vec<u8>>, buf: &mut [u8]) -> usize {
+    if pending.is_empty() {
+        match input.try_recv() {
+            Ok(data) => *pending = data,
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return 0,
+        }
+    }
+    let n = buf.len().min(pending.len());
+    buf[..n].copy_from_slice(&pending[..n]);
+    pending.drain(..n);
+    n
+}
+
+impl CharBackend for Stdio {
+    fn write(&mut self, data: &[u8]) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(data);
+        let _ = stdout.flush();
+    }
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        drain(&mut self.pending, &self.input, buf)
+    }
+}
+
+/// Output appended to a file, input optionally taken from another file.
+pub struct FileBackend {
+    output: File,
+    input: Vec<u8>,
+}
+
+impl FileBackend {
+    pub fn new(output: &Path, input: Option<&Path>) -> io::Result<FileBackend> {
+        Ok(Self {
+            output: File::create(output)?,
+            input: match input {
+                Some(path) => std::fs::read(path)?,
+                None => Vec::new(),
+            },
+        })
+    }
+}
+
+impl CharBackend for FileBackend {
+    fn write(&mut self, data: &[u8]) {
+        let _ = self.output.write_all(data);
+    }
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.input.len());
+        buf[..n].copy_from_slice(&self.input[..n]);
+        self.input.drain(..n);
+        n
+    }
+}
+
+/// Listens on a UNIX socket and talks to the most recent client. Output written while no client
+/// is connected is dropped.
+pub struct UnixSocketBackend {
+    path: PathBuf,
+    listener: UnixListener,
+    client: Option<UnixStream>,
+}
+
+impl UnixSocketBackend {
+    pub fn new(path: &Path) -> io::Result<UnixSocketBackend> {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            listener,
+            client: None,
+        })
+    }
+
+    fn accept(&mut self) {
+        if let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.client = Some(stream);
+            }
+        }
+    }
+}
+
+impl Drop for UnixSocketBackend {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl CharBackend for UnixSocketBackend {
+    fn write(&mut self, data: &[u8]) {
+        self.accept();
+        if let Some(client) = &mut self.client {
+            if client.write_all(data).is_err() {
+                self.client = None;
+            }
+        }
+    }
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.accept();
+        let Some(client) = &mut self.client else {
+            return 0;
+        };
+        match client.read(buf) {
+            Ok(0) => {
+                self.client = None;
+                0
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+            Err(_) => {
+                self.client = None;
+                0
+            }
+        }
+    }
+}
+
+/// In-memory endpoint, for driving a guest from host code. Clones share the same buffers, so
+/// one clone can be handed to a device while the other stays with the caller.
+#[derive(Clone, Default)]
+pub struct BufferBackend {
+    inner: Arc<Mutex<Buffers>>,
+}
+
+#[derive(Default)]
+struct Buffers {
+    output: Vec<u8>,
+    input: Vec<u8>,
+}
+
+impl BufferBackend {
+    pub fn push_input(&self, data: &[u8]) {
+        self.inner.lock().unwrap().input.extend_from_slice(data);
+    }
+    /// Everything the guest wrote since the last call.
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.inner.lock().unwrap().output)
+    }
+}
+
+impl CharBackend for BufferBackend {
+    fn write(&mut self, data: &[u8]) {
+        self.inner.lock().unwrap().output.extend_from_slice(data);
+    }
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let input = &mut self.inner.lock().unwrap().input;
+        let n = buf.len().min(input.len());
+        buf[..n].copy_from_slice(&input[..n]);
+        input.drain(..n);
+        n
+    }
+}
@@ -0,0 +1,342 @@
+//! Lockstep co-simulation against a reference model. After every instruction the `Cpu`
+//! retires, the reference executes one too, and the two commits are compared: pc, encoding,
+//! register writes, CSR writes and memory accesses. The first difference is reported with the
+//! instructions leading up to it.
+//!
+//! References exchange commits in the format of Spike's commit log (`--log-commits`), either
+//! as a log file of a finished run or from a process driven over pipes: for each instruction
+//! it is sent a `step` line and answers with the commit line, or `halt` once it stopped.
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+};
+
+use super::cpu::{
+    control::StopReason,
+    hooks::MemoryAccess,
+    trace::{Commit, Tracer},
+    Cpu,
+};
+
+pub trait ReferenceModel {
+    /// Execute one instruction and return its commit, or `None` once the model stopped.
+    fn step(&mut self) -> Result<Option<Commit>, String>;
+}
+
+fn parse_hex(token: &str) -> Result<u64, String> {
+    token
+        .strip_prefix("0x")
+        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| format!("invalid number '{}'", token))
+}
+
+/// Parse a line of a Spike commit log. Other lines, like the instruction log or exceptions,
+/// give `None`. The size and value of loads are not logged and are left 0.
+pub fn parse_commit(line: &str) -> Result<Option<Commit>, String> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let is_commit = tokens.len() >= 5
+        && tokens[0] == "core"
+        && tokens[2].bytes().all(|b| b.is_ascii_digit())
+        && tokens[3].starts_with("0x");
+    if !is_commit {
+        return Ok(None);
+    }
+    let raw = tokens[4]
+        .strip_prefix('(')
+        .and_then(|raw| raw.strip_suffix(')'))
+        .ok_or_else(|| format!("invalid encoding '{}'", tokens[4]))?;
+    let mut commit = Commit {
+        pc: parse_hex(tokens[3])?,
+        raw: parse_hex(raw)? as u32,
+        ..Commit::default()
+    };
+    let mut rest = tokens[5..].iter().peekable();
+    while let Some(&token) = rest.next() {
+        let mut value = || -> Result<u64, String> {
+            parse_hex(rest.next().ok_or(format!("'{}' without value", token))?)
+        };
+        if token == "mem" {
+            let addr = value()?;
+            let mut access = MemoryAccess {
+                pc: commit.pc,
+                addr,
+                size: 0,
+                value: 0,
+                write: false,
+            };
+            if let Some(data) = rest.next_if(|token| token.starts_with("0x")) {
+                access.size = (data.len() as u64 - 2) * 4;
+                access.value = parse_hex(data)?;
+                access.write = true;
+            }
+            commit.accesses.push(access);
+        } else if let Some(Ok(reg)) = token.strip_prefix('x').map(str::parse::<usize>) {
+            let value = value()?;
+            // Spike also logs writes to x0
+            if reg != 0 {
+                commit.writes.push((reg, value));
+            }
+        } else if let Some(Ok(csr)) = token
+            .strip_prefix('c')
+            .and_then(|csr| csr.split('_').next())
+            .map(str::parse::<usize>)
+        {
+            commit.csr_writes.push((csr, value()?));
+        } else {
+            return Err(format!("cannot compare '{}'", token));
+        }
+    }
+    commit.writes.sort();
+    Ok(Some(commit))
+}
+
+/// The commit log of a finished run of the reference.
+pub struct SpikeLog {
+    lines: io::Lines<Box<dyn BufRead>>,
+    /// Commits before the first one at this pc are skipped, like the boot ROM of Spike.
+    start: Option<u64>,
+}
+
+impl SpikeLog {
+    pub fn new(reader: Box<dyn BufRead>) -> SpikeLog {
+        Self {
+            lines: reader.lines(),
+            start: None,
+        }
+    }
+
+    pub fn open(path: &Path) -> io::Result<SpikeLog> {
+        Ok(Self::new(Box::new(BufReader::new(File::open(path)?))))
+    }
+
+    /// Skip the commits before the first one at `pc`.
+    pub fn starting_at(mut self, pc: u64) -> SpikeLog {
+        self.start = Some(pc);
+        self
+    }
+}
+
+impl ReferenceModel for SpikeLog {
+    fn step(&mut self) -> Result<Option<Commit>, String> {
+        for line in &mut self.lines {
+            let line = line.map_err(|error| error.to_string())?;
+            match parse_commit(&line)? {
+                Some(commit) if self.start.is_some_and(|start| start != commit.pc) => (),
+                Some(commit) => {
+                    self.start = None;
+                    return Ok(Some(commit));
+                }
+                None => (),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A reference running as a child process, stepped over its stdin and stdout.
+pub struct ProcessModel {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl ProcessModel {
+    pub fn spawn(command: &mut Command) -> io::Result<ProcessModel> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+impl ReferenceModel for ProcessModel {
+    fn step(&mut self) -> Result<Option<Commit>, String> {
+        let error = |error: io::Error| format!("reference process: {}", error);
+        writeln!(self.stdin, "step").map_err(error)?;
+        self.stdin.flush().map_err(error)?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.stdout.read_line(&mut line).map_err(error)? == 0 || line.trim() == "halt" {
+                return Ok(None);
+            }
+            if let Some(commit) = parse_commit(&line)? {
+                return Ok(Some(commit));
+            }
+        }
+    }
+}
+
+impl Drop for ProcessModel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Where the run stopped following the reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Number of instructions retired before the one that differs.
+    pub instret: u64,
+    pub reason: String,
+    pub expected: Option<Commit>,
+    pub actual: Commit,
+    /// The last instructions both agreed on, oldest first.
+    pub context: Vec<Commit>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "diverged at instruction {}: {}",
+            self.instret, self.reason
+        )?;
+        for commit in &self.context {
+            writeln!(f, "           {}", commit)?;
+        }
+        if let Some(expected) = &self.expected {
+            writeln!(f, "reference: {}", expected)?;
+        }
+        writeln!(f, "cpu:       {}", self.actual)
+    }
+}
+
+/// Whether `actual` does the access the reference logged as `expected`.
+fn same_access(expected: &MemoryAccess, actual: &MemoryAccess) -> bool {
+    let mask = |value: u64, size: u64| match size {
+        64 => value,
+        size => value & ((1 << size) - 1),
+    };
+    expected.addr == actual.addr
+        && expected.write == actual.write
+        && (!expected.write
+            || (expected.size == actual.size
+                && mask(expected.value, expected.size) == mask(actual.value, actual.size)))
+}
+
+/// What differs between the commits, if anything.
+fn compare(expected: &Commit, actual: &Commit) -> Option<String> {
+    if expected.pc != actual.pc {
+        return Some(format!(
+            "pc is 0x{:x} instead of 0x{:x}",
+            actual.pc, expected.pc
+        ));
+    }
+    if expected.raw != actual.raw {
+        return Some(format!(
+            "instruction is 0x{:08x} instead of 0x{:08x}",
+            actual.raw, expected.raw
+        ));
+    }
+    if expected.writes != actual.writes {
+        return Some("register writes differ".to_string());
+    }
+    if expected.csr_writes != actual.csr_writes {
+        return Some("CSR writes differ".to_string());
+    }
+    let accesses_match = expected.accesses.len() == actual.accesses.len()
+        && expected
+            .accesses
+            .iter()
+            .zip(&actual.accesses)
+            .all(|(expected, actual)| same_access(expected, actual));
+    if !accesses_match {
+        return Some("memory accesses differ".to_string());
+    }
+    None
+}
+
+/// Runs a `Cpu` and a reference side by side.
+pub struct Lockstep {
+    reference: Box<dyn ReferenceModel>,
+    context: VecDeque<Commit>,
+    context_len: usize,
+}
+
+impl Lockstep {
+    pub fn new(reference: Box<dyn ReferenceModel>) -> Lockstep {
+        Self {
+            reference,
+            context: VecDeque::new(),
+            context_len: 8,
+        }
+    }
+
+    /// Report the last `len` matching instructions with a divergence.
+    pub fn with_context(mut self, len: usize) -> Lockstep {
+        self.context_len = len;
+        self
+    }
+
+    /// Execute at most `limit` instructions on both. Returns why `cpu` stopped, which is not
+    /// compared with the reference, or the first divergence. A tracer set on `cpu` is
+    /// suspended meanwhile.
+    pub fn run(&mut self, cpu: &mut Cpu, limit: u64) -> Result<StopReason, Box<Divergence>> {
+        let saved = cpu.take_tracer();
+        cpu.set_tracer(Tracer::collect());
+        let result = self.run_traced(cpu, limit);
+        cpu.take_tracer();
+        if let Some(tracer) = saved {
+            cpu.set_tracer(tracer);
+        }
+        result
+    }
+
+    fn run_traced(&mut self, cpu: &mut Cpu, limit: u64) -> Result<StopReason, Box<Divergence>> {
+        for _ in 0..limit {
+            let reason = cpu.step();
+            // An instruction that traps into the handler, or an interrupt taken instead of
+            // it, retires nothing, and Spike does not log a commit for it either.
+            if let Some(actual) = cpu.take_commits().pop() {
+                self.check(actual)?;
+            }
+            if reason != StopReason::Limit {
+                return Ok(reason);
+            }
+        }
+        Ok(StopReason::Limit)
+    }
+
+    fn check(&mut self, actual: Commit) -> Result<(), Box<Divergence>> {
+        let instret = actual.instret;
+        let (reason, expected) = match self.reference.step() {
+            Ok(Some(mut expected)) => {
+                expected.instret = instret;
+                match compare(&expected, &actual) {
+                    Some(reason) => (reason, Some(expected)),
+                    None => {
+                        if self.context.len() == self.context_len {
+                            self.context.pop_front();
+                        }
+                        if self.context_len > 0 {
+                            self.context.push_back(actual);
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+            Ok(None) => ("the reference stopped".to_string(), None),
+            Err(error) => (error, None),
+        };
+        Err(Box::new(Divergence {
+            instret,
+            reason,
+            expected,
+            actual,
+            context: self.context.iter().cloned().collect(),
+        }))
+    }
+}
@@ -0,0 +1,145 @@
+//! Execution control for debuggers and embedders: single steps, instruction budgets,
+//! breakpoints, watchpoints and stopping a run from another thread.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use super::Cpu;
+use crate::interpreter::exception::Exception;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/// Stops a run after an instruction that accessed `len` bytes at `addr` the way `kind` says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u64,
+    pub len: u64,
+    pub kind: WatchKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    /// The instruction budget is used up.
+    Limit,
+    /// pc reached a breakpoint or the address passed to `run_until`. The instruction there
+    /// has not been executed.
+    Breakpoint { pc: u64 },
+    /// The last instruction read or wrote watched memory at `addr`. `kind` is either `Read`
+    /// or `Write`.
+    Watchpoint { addr: u64, kind: WatchKind },
+    /// Stopped through an `InterruptHandle`.
+    Interrupted,
+    /// The guest powered the machine off.
+    Exited { code: u32 },
+    /// An exception nothing handled. pc is at the instruction that raised it.
+    Exception(Exception),
+    /// Reverse execution reached the oldest checkpoint.
+    HistoryStart,
+}
+
+/// Stops a running `Cpu` from another thread. The run returns `StopReason::Interrupted`
+/// before its next instruction.
+#[derive(Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Cpu {
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.interrupt.clone())
+    }
+
+    pub fn add_breakpoint(&mut self, pc: u64) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Returns whether there was a breakpoint at `pc`.
+    pub fn remove_breakpoint(&mut self, pc: u64) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn has_breakpoint(&self, pc: u64) -> bool {
+        self.breakpoints.contains(&pc)
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Returns whether the watchpoint was set.
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    /// Execute a single instruction.
+    pub fn step(&mut self) -> StopReason {
+        self.run_to(1, None)
+    }
+
+    /// Execute at most `limit` instructions. The instruction at pc always runs, even with a
+    /// breakpoint on it, so that a run can resume from a breakpoint.
+    pub fn run(&mut self, limit: u64) -> StopReason {
+        self.run_to(limit, None)
+    }
+
+    /// Like `run`, but also stop when pc reaches `pc`.
+    pub fn run_until(&mut self, pc: u64, limit: u64) -> StopReason {
+        self.run_to(limit, Some(pc))
+    }
+
+    /// An instruction that traps into the handler counts against `limit` like one that
+    /// retires, so that a handler which keeps faulting cannot run forever.
+    fn run_to(&mut self, limit: u64, until: Option<u64>) -> StopReason {
+        for step in 0..limit {
+            if self.interrupt.load(Ordering::Relaxed) {
+                self.interrupt.store(false, Ordering::Relaxed);
+                return StopReason::Interrupted;
+            }
+            if step != 0 && (until == Some(self.pc) || self.breakpoints.contains(&self.pc)) {
+                return StopReason::Breakpoint { pc: self.pc };
+            }
+            match self.retire() {
+                Ok(Some(code)) => return StopReason::Exited { code },
+                Ok(None) => (),
+                Err(exception) => return StopReason::Exception(exception),
+            }
+            if let Some((addr, kind)) = self.watch_hit.take() {
+                return StopReason::Watchpoint { addr, kind };
+            }
+        }
+        StopReason::Limit
+    }
+
+    /// Remember the first watchpoint hit by a guest access of `size` bits.
+    pub(super) fn check_watchpoints(&mut self, addr: u64, size: u64, write: bool) {
+        let end = addr.wrapping_add(size / 8);
+        let hit = self.watchpoints.iter().find(|w| {
+            let kind_matches = match w.kind {
+                WatchKind::Read => !write,
+                WatchKind::Write => write,
+                WatchKind::Access => true,
+            };
+            kind_matches && addr < w.addr.wrapping_add(w.len) && w.addr < end
+        });
+        if hit.is_some() {
+            let kind = if write {
+                WatchKind::Write
+            } else {
+                WatchKind::Read
+            };
+            self.watch_hit.get_or_insert((addr, kind));
+        }
+    }
+}
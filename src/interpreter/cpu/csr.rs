@@ -1,6 +1,8 @@
 pub const MHARTID: usize = 0xf14;
 /// Machine status register.
 pub const MSTATUS: usize = 0x300;
+/// Machine ISA register.
+pub const MISA: usize = 0x301;
 /// Machine exception delefation register.
 pub const MEDELEG: usize = 0x302;
 /// Machine interrupt delefation register.
@@ -42,6 +44,25 @@ pub const SIP: usize = 0x144;
 /// Supervisor address translation and protection.
 pub const SATP: usize = 0x180;
 
+/// A privilege level, encoded as in mstatus.MPP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// The level in the MPP field of `mstatus`. The reserved encoding reads as M.
+    pub fn from_mpp(mstatus: u64) -> Privilege {
+        match (mstatus & MASK_MPP) >> 11 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
 // mstatus and sstatus field mask
 pub const MASK_SIE: u64 = 1 << 1;
 pub const MASK_MIE: u64 = 1 << 3;
@@ -83,11 +104,20 @@ pub const MASK_MTIP: u64 = 1 << 7;
 pub const MASK_SEIP: u64 = 1 << 9;
 pub const MASK_MEIP: u64 = 1 << 11;
 
+/// What misa reads: MXL 64 and the I extension.
+pub const MISA_VALUE: u64 = (2 << 62) | (1 << 8);
+
 const NUM_CSRS: usize = 4096;
 
+#[derive(Clone)]
 pub struct Csr {
     csrs: [u64; NUM_CSRS],
 }
+impl Default for Csr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Csr {
     pub fn new() -> Csr {
         Self {
@@ -100,6 +130,7 @@ impl Csr {
             SIE => self.csrs[MIE] & self.csrs[MIDELEG],
             SIP => self.csrs[MIP] & self.csrs[MIDELEG],
             SSTATUS => self.csrs[MSTATUS] & MASK_SSTATUS,
+            MISA => MISA_VALUE,
             _ => self.csrs[addr],
         }
     }
@@ -117,6 +148,8 @@ impl Csr {
             SSTATUS => {
                 self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !MASK_SSTATUS) | (value & MASK_SSTATUS)
             }
+            // the extensions cannot be switched off
+            MISA => (),
             _ => self.csrs[addr] = value,
         }
     }
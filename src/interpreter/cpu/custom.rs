@@ -0,0 +1,113 @@
+//! Custom instructions: handlers for the custom-0..3 major opcodes, or for any match/mask
+//! pattern, to prototype accelerators without touching the decoder.
+use super::{
+    debug::RVABI,
+    decode::{Decoded, Format},
+    Cpu,
+};
+use crate::interpreter::exception::Exception;
+
+pub const CUSTOM_0: u32 = 0b000_1011;
+pub const CUSTOM_1: u32 = 0b010_1011;
+pub const CUSTOM_2: u32 = 0b101_1011;
+pub const CUSTOM_3: u32 = 0b111_1011;
+
+type Handler = Box<dyn FnMut(&mut Cpu, &Decoded) -> Result<(), Exception> + Send>;
+
+pub struct CustomInstruction {
+    pub mnemonic: String,
+    pub mask: u32,
+    /// The bits under `mask` an instruction must have.
+    pub value: u32,
+    /// How the fields are decoded for the handler and printed by the disassembler.
+    pub format: Format,
+    handler: Handler,
+}
+
+impl CustomInstruction {
+    /// Handle every instruction with major opcode `opcode`, usually one of `CUSTOM_0` to
+    /// `CUSTOM_3`. The handler runs with pc at the instruction; unless it changes pc, the
+    /// hart continues with the next one.
+    pub fn new(
+        mnemonic: &str,
+        opcode: u32,
+        format: Format,
+        handler: impl FnMut(&mut Cpu, &Decoded) -> Result<(), Exception> + Send + 'static,
+    ) -> CustomInstruction {
+        Self::with_pattern(mnemonic, 0x7f, opcode, format, handler)
+    }
+
+    /// Handle the instructions with `raw & mask == value`.
+    pub fn with_pattern(
+        mnemonic: &str,
+        mask: u32,
+        value: u32,
+        format: Format,
+        handler: impl FnMut(&mut Cpu, &Decoded) -> Result<(), Exception> + Send + 'static,
+    ) -> CustomInstruction {
+        Self {
+            mnemonic: mnemonic.to_string(),
+            mask,
+            value: value & mask,
+            format,
+            handler: Box::new(handler),
+        }
+    }
+
+    pub fn matches(&self, raw: u32) -> bool {
+        raw & self.mask == self.value
+    }
+
+    /// `raw` in assembler syntax.
+    pub fn disassemble(&self, raw: u32) -> String {
+        let d = Decoded::with_format(raw, self.format);
+        let imm = d.imm as i64;
+        let (rd, rs1, rs2) = (RVABI[d.rd], RVABI[d.rs1], RVABI[d.rs2]);
+        match self.format {
+            Format::R => format!("{} {}, {}, {}", self.mnemonic, rd, rs1, rs2),
+            Format::I => format!("{} {}, {}, {}", self.mnemonic, rd, rs1, imm),
+            Format::S => format!("{} {}, {}({})", self.mnemonic, rs2, imm, rs1),
+            Format::B => format!("{} {}, {}, {}", self.mnemonic, rs1, rs2, imm),
+            Format::U => format!("{} {}, {:#x}", self.mnemonic, rd, (d.imm >> 12) & 0xfffff),
+            Format::J => format!("{} {}, {}", self.mnemonic, rd, imm),
+        }
+    }
+}
+
+impl Cpu {
+    /// Register a custom instruction. When several match, the one with the most specific
+    /// mask wins, and among those the one registered first.
+    pub fn add_custom_instruction(&mut self, instruction: CustomInstruction) {
+        let bits = instruction.mask.count_ones();
+        let index = self
+            .custom
+            .iter()
+            .position(|c| c.mask.count_ones() < bits)
+            .unwrap_or(self.custom.len());
+        self.custom.insert(index, instruction);
+    }
+
+    /// The registered instruction `raw` is handled by, if any.
+    pub fn custom_instruction(&self, raw: u32) -> Option<&CustomInstruction> {
+        self.custom.iter().find(|c| c.matches(raw))
+    }
+
+    /// Run the handler registered for `inst`, if there is one.
+    pub(super) fn execute_custom(&mut self, inst: u32) -> Option<Result<(), Exception>> {
+        let index = self.custom.iter().position(|c| c.matches(inst))?;
+        // The handler gets the whole hart, so the registry is put aside meanwhile.
+        let mut custom = std::mem::take(&mut self.custom);
+        let instruction = &mut custom[index];
+        let decoded = Decoded::with_format(inst, instruction.format);
+        let pc = self.pc;
+        let result = (instruction.handler)(self, &decoded);
+        for added in std::mem::replace(&mut self.custom, custom) {
+            self.add_custom_instruction(added);
+        }
+        if result.is_ok() && self.pc != pc {
+            // the handler jumped, do not step past the target
+            self.pc = self.pc.wrapping_sub(4);
+        }
+        Some(result)
+    }
+}
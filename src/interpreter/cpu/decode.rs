@@ -0,0 +1,72 @@
+//! Field level decoding of 32-bit instructions, as handed to instrumentation.
+use super::execute::instruction;
+
+/// The fields of an instruction. Which of them are meaningful depends on the format of
+/// its opcode; `imm` is the sign extended immediate of that format, 0 for R-type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded {
+    pub raw: u32,
+    pub opcode: u32,
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+    pub funct3: u32,
+    pub funct7: u32,
+    pub imm: u64,
+}
+
+/// Instruction formats, which decide where the immediate lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    R,
+    I,
+    S,
+    B,
+    U,
+    J,
+}
+
+impl Format {
+    /// Format of the standard instructions with major opcode `opcode`. Opcodes without
+    /// immediate, and unknown ones, are R-type.
+    pub fn of(opcode: u32) -> Format {
+        match opcode {
+            // loads, op-imm, op-imm-32, jalr, system
+            0b0000011 | 0b0010011 | 0b0011011 | 0b1100111 | 0b1110011 => Format::I,
+            0b0100011 => Format::S,
+            0b1100011 => Format::B,
+            // lui, auipc
+            0b0110111 | 0b0010111 => Format::U,
+            0b1101111 => Format::J,
+            _ => Format::R,
+        }
+    }
+}
+
+impl Decoded {
+    pub fn new(raw: u32) -> Decoded {
+        Self::with_format(raw, Format::of(instruction::get_opcode(raw)))
+    }
+
+    /// Decode `raw` as an instruction of `format`.
+    pub fn with_format(raw: u32, format: Format) -> Decoded {
+        let imm = match format {
+            Format::R => 0,
+            Format::I => instruction::get_imm_type_i(raw),
+            Format::S => instruction::get_imm_type_s(raw),
+            Format::B => instruction::get_imm_type_b(raw),
+            Format::U => instruction::get_imm_type_u(raw),
+            Format::J => instruction::get_imm_type_j(raw),
+        };
+        Self {
+            raw,
+            opcode: instruction::get_opcode(raw),
+            rd: instruction::get_rd(raw),
+            rs1: instruction::get_rs1(raw),
+            rs2: instruction::get_rs2(raw),
+            funct3: instruction::get_funct3(raw),
+            funct7: instruction::get_funct7(raw),
+            imm,
+        }
+    }
+}
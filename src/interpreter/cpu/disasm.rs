@@ -0,0 +1,161 @@
+//! Disassembler for RV64IM and Zicsr, printing the canonical (non-alias) forms with ABI
+//! register names. Branch and jump targets are shown as offsets from pc.
+use super::{debug::RVABI, decode::Decoded, Cpu};
+
+const CSR_NAMES: [(u64, &str); 26] = [
+    (0x001, "fflags"),
+    (0x002, "frm"),
+    (0x003, "fcsr"),
+    (0x100, "sstatus"),
+    (0x104, "sie"),
+    (0x105, "stvec"),
+    (0x140, "sscratch"),
+    (0x141, "sepc"),
+    (0x142, "scause"),
+    (0x143, "stval"),
+    (0x144, "sip"),
+    (0x180, "satp"),
+    (0x300, "mstatus"),
+    (0x301, "misa"),
+    (0x302, "medeleg"),
+    (0x303, "mideleg"),
+    (0x304, "mie"),
+    (0x305, "mtvec"),
+    (0x306, "mcounteren"),
+    (0x340, "mscratch"),
+    (0x341, "mepc"),
+    (0x342, "mcause"),
+    (0x343, "mtval"),
+    (0x344, "mip"),
+    (0xc00, "cycle"),
+    (0xf14, "mhartid"),
+];
+
+pub(super) fn csr_name(csr: u64) -> String {
+    match CSR_NAMES.iter().find(|(number, _)| *number == csr) {
+        Some((_, name)) => name.to_string(),
+        None => format!("{:#x}", csr),
+    }
+}
+
+/// `inst` in assembler syntax, or `.word` for encodings that are not known.
+pub fn disassemble(inst: u32) -> String {
+    let d = Decoded::new(inst);
+    let (rd, rs1, rs2) = (RVABI[d.rd], RVABI[d.rs1], RVABI[d.rs2]);
+    let imm = d.imm as i64;
+    let shamt = (inst >> 20) & 0x3f;
+    let name = match (d.opcode, d.funct3, d.funct7) {
+        (0b0110111, _, _) => return format!("lui {}, {:#x}", rd, (inst >> 12)),
+        (0b0010111, _, _) => return format!("auipc {}, {:#x}", rd, (inst >> 12)),
+        (0b1101111, _, _) => return format!("jal {}, {}", rd, imm),
+        (0b1100111, 0, _) => return format!("jalr {}, {}({})", rd, imm, rs1),
+        (0b1100011, funct3, _) => {
+            let name = ["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"][funct3 as usize];
+            if name.is_empty() {
+                ""
+            } else {
+                return format!("{} {}, {}, {}", name, rs1, rs2, imm);
+            }
+        }
+        (0b0000011, funct3, _) => {
+            let name = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu", ""][funct3 as usize];
+            if name.is_empty() {
+                ""
+            } else {
+                return format!("{} {}, {}({})", name, rd, imm, rs1);
+            }
+        }
+        (0b0100011, funct3 @ 0..=3, _) => {
+            let name = ["sb", "sh", "sw", "sd"][funct3 as usize];
+            return format!("{} {}, {}({})", name, rs2, imm, rs1);
+        }
+        (0b0010011 | 0b0011011, funct3, _) => {
+            let word = d.opcode == 0b0011011;
+            let name = match (funct3, inst >> 26, word) {
+                (0, _, false) => "addi",
+                (0, _, true) => "addiw",
+                (2, _, false) => "slti",
+                (3, _, false) => "sltiu",
+                (4, _, false) => "xori",
+                (6, _, false) => "ori",
+                (7, _, false) => "andi",
+                (1, 0, false) => "slli",
+                (5, 0, false) => "srli",
+                (5, 0b010000, false) => "srai",
+                (1, 0, true) => "slliw",
+                (5, 0, true) => "srliw",
+                (5, 0b010000, true) => "sraiw",
+                _ => "",
+            };
+            if name.is_empty() {
+                ""
+            } else if funct3 == 1 || funct3 == 5 {
+                return format!("{} {}, {}, {}", name, rd, rs1, shamt);
+            } else {
+                return format!("{} {}, {}, {}", name, rd, rs1, imm);
+            }
+        }
+        (0b0110011, funct3, funct7) => match (funct7, funct3) {
+            (0, _) => ["add", "sll", "slt", "sltu", "xor", "srl", "or", "and"][funct3 as usize],
+            (0b0100000, 0) => "sub",
+            (0b0100000, 5) => "sra",
+            (1, _) => [
+                "mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu",
+            ][funct3 as usize],
+            _ => "",
+        },
+        (0b0111011, funct3, funct7) => match (funct7, funct3) {
+            (0, 0) => "addw",
+            (0b0100000, 0) => "subw",
+            (0, 1) => "sllw",
+            (0, 5) => "srlw",
+            (0b0100000, 5) => "sraw",
+            (1, 0) => "mulw",
+            (1, 4) => "divw",
+            (1, 5) => "divuw",
+            (1, 6) => "remw",
+            (1, 7) => "remuw",
+            _ => "",
+        },
+        (0b0001111, 0, _) => return "fence".to_string(),
+        (0b0001111, 1, _) => return "fence.i".to_string(),
+        (0b1110011, 0, _) => {
+            return match inst {
+                0x0000_0073 => "ecall",
+                0x0010_0073 => "ebreak",
+                0x1020_0073 => "sret",
+                0x3020_0073 => "mret",
+                0x1050_0073 => "wfi",
+                _ => return format!(".word {:#010x}", inst),
+            }
+            .to_string()
+        }
+        (0b1110011, funct3, _) if funct3 != 4 => {
+            let name = [
+                "", "csrrw", "csrrs", "csrrc", "", "csrrwi", "csrrsi", "csrrci",
+            ][funct3 as usize];
+            let csr = csr_name(d.imm & 0xfff);
+            return if funct3 >= 5 {
+                format!("{} {}, {}, {}", name, rd, csr, d.rs1)
+            } else {
+                format!("{} {}, {}, {}", name, rd, csr, rs1)
+            };
+        }
+        _ => "",
+    };
+    if name.is_empty() {
+        format!(".word {:#010x}", inst)
+    } else {
+        format!("{} {}, {}, {}", name, rd, rs1, rs2)
+    }
+}
+
+impl Cpu {
+    /// Like `disassemble`, but knowing the custom instructions registered on this hart.
+    pub fn disassemble(&self, inst: u32) -> String {
+        match self.custom_instruction(inst) {
+            Some(custom) => custom.disassemble(inst),
+            None => disassemble(inst),
+        }
+    }
+}
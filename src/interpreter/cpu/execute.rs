@@ -1,8 +1,27 @@
-use crate::interpreter::exception::Exception;
+use crate::interpreter::{
+    exception::Exception,
+    semihosting::{SEMIHOSTING_ENTRY, SEMIHOSTING_EXIT},
+    syscon::PowerEvent,
+};
 
-use super::Cpu;
+use super::{
+    csr::{
+        Privilege, MASK_MIE, MASK_MPIE, MASK_MPP, MCAUSE, MEPC, MIE, MIP, MSTATUS, MTVAL, MTVEC,
+    },
+    decode::Decoded,
+    hooks::{CsrAccess, Trap, TrapReturn},
+    Cpu,
+};
 
-mod instruction {
+const MRET: u32 = 0x3020_0073;
+const WFI: u32 = 0x1050_0073;
+
+/// Set in mcause when the trap is an interrupt.
+pub const INTERRUPT: u64 = 1 << 63;
+/// The M-mode interrupts by priority, external, software and timer, then supervisor ones.
+const INTERRUPT_PRIORITY: [u64; 6] = [11, 3, 7, 9, 1, 5];
+
+pub(super) mod instruction {
     pub fn get_opcode(inst: u32) -> u32 {
         inst & 0x7f
     }
@@ -89,18 +108,141 @@ impl Cpu {
         self.tunning_for_increase_pc();
     }
 
+    /// An `ebreak` between the semihosting entry and exit markers is a host call with the
+    /// operation in a0 and its parameter in a1.
+    fn semihosting_call(&mut self) -> Option<u64> {
+        let entry = self.bus.load(self.pc.wrapping_sub(4), 32).ok()? as u32;
+        let exit = self.bus.load(self.pc.wrapping_add(4), 32).ok()? as u32;
+        if entry != SEMIHOSTING_ENTRY || exit != SEMIHOSTING_EXIT {
+            return None;
+        }
+        self.bus
+            .semihosting_call(self.regs[10], self.regs[11], self.instret)
+    }
+
+    /// Run until an exception, or until the guest powers the machine off, in which case `None`
+    /// is returned and `exit_code` is set.
     pub fn execute(&mut self) -> Option<Exception> {
-        loop {
-            let inst = match self.instructure_fetch() {
-                Ok(inst) => inst,
-                Err(err) => return Some(err),
-            };
-            match self.execute_instruction(inst) {
-                Ok(_) => (),
+        self.execute_until(u64::MAX)
+    }
+
+    /// Like `execute`, but also returns `None` once `instret` instructions have retired, with
+    /// `exit_code` left unset.
+    pub fn execute_until(&mut self, instret: u64) -> Option<Exception> {
+        while self.instret < instret {
+            match self.retire() {
+                Ok(Some(_)) => return None,
+                Ok(None) => (),
                 Err(err) => return Some(err),
+            }
+        }
+        None
+    }
+
+    /// Execute the instruction at pc and let the devices catch up. Returns the exit code if
+    /// the guest powered the machine off.
+    pub(super) fn retire(&mut self) -> Result<Option<u32>, Exception> {
+        if self.history.is_some() {
+            self.take_checkpoint();
+        }
+        if let Some(cause) = self.pending_interrupt() {
+            self.trap_hooks(self.pc, Trap::Interrupt(cause));
+            self.enter_trap(cause, 0);
+            return Ok(None);
+        }
+        let before = self.tracer.as_ref().map(|_| (self.pc, self.regs));
+        let result = self.fetch_and_execute();
+        if let Err(exception) = result {
+            self.trap_hooks(self.pc, Trap::Exception(exception.clone()));
+            return match self.take_trap(&exception) {
+                true => Ok(None),
+                false => Err(exception),
             };
-            self.increase_pc();
         }
+        self.increase_pc();
+        if let Some((pc, regs)) = before {
+            self.trace(pc, self.instret, &regs);
+        }
+        self.instret += 1;
+        self.bus.tick(self.instret);
+        match self.bus.take_power_event() {
+            Some(PowerEvent::PowerOff { exit_code }) => {
+                self.exit_code = Some(exit_code);
+                return Ok(Some(exit_code));
+            }
+            Some(PowerEvent::Reset) => self.reset(),
+            None => (),
+        }
+        Ok(None)
+    }
+
+    /// Let exceptions enter the handler at mtvec as on hardware, instead of stopping the run.
+    /// Off by default, so that tools see every exception. A handler that faults itself traps
+    /// again until the instruction limit of the run is reached. Interrupts the guest enabled
+    /// are always taken.
+    pub fn set_trap_delivery(&mut self, enabled: bool) {
+        self.trap_delivery = enabled;
+    }
+
+    /// The cause of the interrupt taken before the next instruction: the one of highest
+    /// priority that is pending in mip and enabled in mie, while mstatus.MIE is set.
+    pub fn pending_interrupt(&self) -> Option<u64> {
+        if self.csr.load(MSTATUS) & MASK_MIE == 0 {
+            return None;
+        }
+        let pending = self.csr.load(MIP) & self.csr.load(MIE);
+        if pending == 0 {
+            return None;
+        }
+        let irq = INTERRUPT_PRIORITY
+            .into_iter()
+            .find(|irq| pending & (1 << irq) != 0)
+            .unwrap_or(pending.trailing_zeros() as u64);
+        Some(INTERRUPT | irq)
+    }
+
+    /// Enter the M-mode handler for `exception` raised by the instruction at pc. Returns
+    /// whether it did.
+    fn take_trap(&mut self, exception: &Exception) -> bool {
+        if !self.trap_delivery {
+            return false;
+        }
+        self.enter_trap(exception.code(), exception.value());
+        true
+    }
+
+    fn enter_trap(&mut self, cause: u64, tval: u64) {
+        self.csr.store(MEPC, self.pc);
+        self.csr.store(MCAUSE, cause);
+        self.csr.store(MTVAL, tval);
+        let mstatus = self.csr.load(MSTATUS);
+        let mpie = (mstatus & MASK_MIE) << 4;
+        let mpp = (self.privilege as u64) << 11;
+        let mstatus = (mstatus & !(MASK_MIE | MASK_MPIE | MASK_MPP)) | mpie | mpp;
+        self.csr.store(MSTATUS, mstatus);
+        self.set_privilege(self.pc, Privilege::Machine);
+        let mtvec = self.csr.load(MTVEC);
+        self.pc = match mtvec & 0b11 {
+            // vectored
+            1 if cause & INTERRUPT != 0 => (mtvec & !0b11).wrapping_add(4 * (cause & !INTERRUPT)),
+            _ => mtvec & !0b11,
+        };
+    }
+
+    fn fetch_and_execute(&mut self) -> Result<(), Exception> {
+        let inst = self.instructure_fetch()?;
+        if self.tracer.is_some() {
+            self.trace_fetch(inst);
+        }
+        if let Some(hooks) = &mut self.hooks {
+            if !hooks.instruction.is_empty() {
+                let decoded = Decoded::new(inst);
+                for hook in &mut hooks.instruction {
+                    hook(self.pc, &decoded);
+                }
+            }
+        }
+        self.execute_instruction(inst)
     }
 
     pub fn execute_instruction(&mut self, inst: u32) -> Result<(), Exception> {
@@ -108,37 +250,47 @@ impl Cpu {
             return Err(Exception::InvalidInstruction);
         }
         self.regs[0] = 0;
-        if inst == 0b000000000001_00000_000_00000_1110011 {
+        if inst == 0b0000_0000_0001_0000_0000_0000_0111_0011 {
             // ebreak
+            if let Some(result) = self.semihosting_call() {
+                self.regs[10] = result;
+                return Ok(());
+            }
             return Err(Exception::Breakpoint);
         }
-        if inst == 0b000000000000_00000_000_00000_1110011 {
+        if inst == 0b0000_0000_0000_0000_0000_0000_0111_0011 {
             // ecall
             return Err(Exception::EnvironmentCall);
         }
+        if !self.custom.is_empty() {
+            if let Some(result) = self.execute_custom(inst) {
+                return result;
+            }
+        }
         match instruction::get_opcode(inst) {
             0b0000011 => {
                 let rd = instruction::get_rd(inst);
                 let rs1 = instruction::get_rs1(inst);
                 let imm = instruction::get_imm_type_i(inst);
                 let address = wrapping_add(self.read_reg(rs1), sext(imm));
-                match instruction::get_funct3(inst) {
+                let value = match instruction::get_funct3(inst) {
                     // lb
-                    0b000 => self.write_reg(rd, sext(self.bus.load(address, 8)?)),
+                    0b000 => sext(self.load(address, 8)?),
                     // lbu
-                    0b100 => self.write_reg(rd, self.bus.load(address, 8)?),
+                    0b100 => self.load(address, 8)?,
                     // lh
-                    0b001 => self.write_reg(rd, sext(self.bus.load(address, 16)?)),
+                    0b001 => sext(self.load(address, 16)?),
                     // lhu
-                    0b101 => self.write_reg(rd, self.bus.load(address, 16)?),
+                    0b101 => self.load(address, 16)?,
                     // lw
-                    0b010 => self.write_reg(rd, sext(self.bus.load(address, 32)?)),
+                    0b010 => sext(self.load(address, 32)?),
                     // lwu
-                    0b110 => self.write_reg(rd, self.bus.load(address, 32)?),
+                    0b110 => self.load(address, 32)?,
                     // ld
-                    0b011 => self.write_reg(rd, self.bus.load(address, 64)?),
-                    _ => todo!(),
+                    0b011 => self.load(address, 64)?,
+                    _ => return Err(Exception::InvalidInstruction),
                 };
+                self.write_reg(rd, value);
             }
             0b0100011 => {
                 let rs1 = instruction::get_rs1(inst);
@@ -147,14 +299,14 @@ impl Cpu {
                 let address = wrapping_add(self.read_reg(rs1), sext(imm));
                 match instruction::get_funct3(inst) {
                     // sb
-                    0b000 => self.bus.store(address, 8, self.read_reg(rs2)),
+                    0b000 => self.store(address, 8, self.read_reg(rs2)),
                     // sh
-                    0b001 => self.bus.store(address, 16, self.read_reg(rs2)),
+                    0b001 => self.store(address, 16, self.read_reg(rs2)),
                     // sw
-                    0b010 => self.bus.store(address, 32, self.read_reg(rs2)),
+                    0b010 => self.store(address, 32, self.read_reg(rs2)),
                     // sd
-                    0b011 => self.bus.store(address, 64, self.read_reg(rs2)),
-                    _ => todo!(),
+                    0b011 => self.store(address, 64, self.read_reg(rs2)),
+                    _ => return Err(Exception::InvalidInstruction),
                 }?
             }
             0b0010011 => {
@@ -187,10 +339,10 @@ impl Cpu {
                     // srli
                     0b101 if (shamt_reserved == 0b0000000) => set_rd(rs1_value >> shamt),
                     // srai
-                    0b101 if (shamt_reserved == 0b0100000) => {
+                    0b101 if (shamt_reserved == 0b01_0000) => {
                         set_rd(signed_left_shift(rs1_value, shamt))
                     }
-                    _ => todo!(),
+                    _ => return Err(Exception::InvalidInstruction),
                 };
             }
             0b0110011 => {
@@ -204,7 +356,7 @@ impl Cpu {
                     // sub
                     0b000 if (funct7 == 0b0100000) => set_rd(wrapping_sub(rs1_value, rs2_value)),
                     // sll
-                    0b001 if (funct7 == 0b0000000) => set_rd(rs1_value << rs2_value),
+                    0b001 if (funct7 == 0b0000000) => set_rd(rs1_value << (rs2_value & 0b11_1111)),
                     // slt
                     0b010 if (funct7 == 0b0000000) => {
                         if (rs1_value as i64) < (rs2_value as i64) {
@@ -224,16 +376,16 @@ impl Cpu {
                     // xor
                     0b100 if (funct7 == 0b0000000) => set_rd(rs1_value ^ rs2_value),
                     // srl
-                    0b101 if (funct7 == 0b0000000) => set_rd(rs1_value >> rs2_value),
+                    0b101 if (funct7 == 0b0000000) => set_rd(rs1_value >> (rs2_value & 0b11_1111)),
                     // sra
                     0b101 if (funct7 == 0b0100000) => {
-                        set_rd(((rs1_value as i64) >> rs2_value) as u64)
+                        set_rd(signed_left_shift(rs1_value, rs2_value & 0b11_1111))
                     }
                     // or
                     0b110 if (funct7 == 0b0000000) => set_rd(rs1_value | rs2_value),
                     // and
                     0b111 if (funct7 == 0b0000000) => set_rd(rs1_value & rs2_value),
-                    _ => todo!(),
+                    _ => return Err(Exception::InvalidInstruction),
                 }
             }
             0b0011011 => {
@@ -255,7 +407,7 @@ impl Cpu {
                     0b101 if (shamt_reserved == 0b01_0000) => {
                         set_rd(signed_left_shift(rs1_value, shamt))
                     }
-                    _ => todo!(),
+                    _ => return Err(Exception::InvalidInstruction),
                 }
             }
             0b0111011 => {
@@ -278,7 +430,7 @@ impl Cpu {
                     0b101 if (funct7 == 0b010_0000) => {
                         set_rd(signed_left_shift(rs1_value, rs2_value & 0b1_1111))
                     }
-                    _ => todo!(),
+                    _ => return Err(Exception::InvalidInstruction),
                 }
             }
             0b0110111 => {
@@ -345,7 +497,7 @@ impl Cpu {
                             self.set_pc_with_tunning(jump_target_pc);
                         }
                     }
-                    _ => todo!(),
+                    _ => return Err(Exception::InvalidInstruction),
                 };
             }
             0b1100111 => match instruction::get_funct3(inst) {
@@ -358,7 +510,7 @@ impl Cpu {
                     ));
                     self.write_reg(instruction::get_rd(inst), t);
                 }
-                _ => todo!(),
+                _ => return Err(Exception::InvalidInstruction),
             },
             0b1101111 => {
                 // jal
@@ -371,31 +523,81 @@ impl Cpu {
             // fence
             0b0001111 => (),
 
+            // mret, wfi, sfence.vma
+            0b1110011 if instruction::get_funct3(inst) == 0 => match inst {
+                MRET => {
+                    let mstatus = self.csr.load(MSTATUS);
+                    let mpie = (mstatus & MASK_MPIE) >> 4;
+                    let privilege = Privilege::from_mpp(mstatus);
+                    // MPP becomes M, the least privileged level in misa
+                    let mstatus = (mstatus & !MASK_MIE) | mpie | MASK_MPIE | MASK_MPP;
+                    self.csr.store(MSTATUS, mstatus);
+                    let pc = self.pc;
+                    let target = self.csr.load(MEPC);
+                    self.set_pc_with_tunning(target);
+                    self.set_privilege(pc, privilege);
+                    self.trap_return_hooks(pc, TrapReturn { target, privilege });
+                }
+                // pending interrupts are taken before the next instruction, no translation
+                // is cached
+                WFI => (),
+                _ if inst >> 25 == 0b0001001 && inst & 0x7fff == 0b111_0011 => (),
+                _ => return Err(Exception::InvalidInstruction),
+            },
             // csrc
             0b1110011 => {
                 let csr = instruction::get_imm_type_i(inst) as usize;
                 let rs1 = instruction::get_rs1(inst);
                 let zimm = rs1 as u64 & 0b11111;
                 let rd = instruction::get_rd(inst);
+                let funct3 = instruction::get_funct3(inst);
                 let t = self.csr.load(csr);
-                match instruction::get_funct3(inst) {
+                // Setting or clearing no bits is a pure read.
+                let value = match funct3 {
                     // csrrw
-                    0b001 => self.csr.store(csr, self.read_reg(rs1)),
+                    0b001 => Some(self.read_reg(rs1)),
                     // csrrwi
-                    0b101 => self.csr.store(csr, zimm),
+                    0b101 => Some(zimm),
                     // csrrs
-                    0b010 => self.csr.store(csr, t | self.read_reg(rs1)),
+                    0b010 => (rs1 != 0).then(|| t | self.read_reg(rs1)),
                     // csrrsi
-                    0b110 => self.csr.store(csr, t | zimm),
+                    0b110 => (zimm != 0).then_some(t | zimm),
                     // csrrc
-                    0b011 => self.csr.store(csr, t & !self.read_reg(rs1)),
+                    0b011 => (rs1 != 0).then(|| t & !self.read_reg(rs1)),
                     // csrrci
-                    0b111 => self.csr.store(csr, t & !zimm),
-                    _ => todo!(),
+                    0b111 => (zimm != 0).then_some(t & !zimm),
+                    _ => return Err(Exception::InvalidInstruction),
+                };
+                if let Some(value) = value {
+                    self.csr.store(csr, value);
+                    if self.tracer.is_some() {
+                        self.trace_csr_write(csr, self.csr.load(csr));
+                    }
+                }
+                if let Some(hooks) = &mut self.hooks {
+                    let pc = self.pc;
+                    let mut notify = |value, write| {
+                        let access = CsrAccess {
+                            pc,
+                            csr,
+                            value,
+                            write,
+                        };
+                        for hook in &mut hooks.csr {
+                            hook(&access);
+                        }
+                    };
+                    // csrrw(i) to x0 does not read the CSR
+                    if rd != 0 || funct3 & 0b011 != 0b001 {
+                        notify(t, false);
+                    }
+                    if value.is_some() {
+                        notify(self.csr.load(csr), true);
+                    }
                 }
                 self.write_reg(rd, t);
             }
-            _ => todo!(),
+            _ => return Err(Exception::InvalidInstruction),
         };
         Ok(())
     }
@@ -0,0 +1,134 @@
+//! Instrumentation callbacks for analysis tools. The `Cpu` keeps them behind a single
+//! `Option`, so a hart without hooks only pays for one untaken branch per event site.
+use super::{csr::Privilege, decode::Decoded, Cpu};
+use crate::interpreter::exception::Exception;
+
+/// A guest load or store of `size` bits. `value` is what was read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub pc: u64,
+    pub addr: u64,
+    pub size: u64,
+    pub value: u64,
+    pub write: bool,
+}
+
+/// A CSR read or write by a Zicsr instruction. Writes report the value as stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsrAccess {
+    pub pc: u64,
+    pub csr: usize,
+    pub value: u64,
+    pub write: bool,
+}
+
+/// Why the hart entered a trap: an exception the instruction at pc raised, or an interrupt
+/// taken before it.
+#[derive(Debug, PartialEq)]
+pub enum Trap {
+    Exception(Exception),
+    /// mcause of the interrupt, with the interrupt bit set.
+    Interrupt(u64),
+}
+
+/// An `mret` back to `target` in `privilege`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapReturn {
+    pub target: u64,
+    pub privilege: Privilege,
+}
+
+/// The hart switching from one privilege level to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrivilegeChange {
+    pub from: Privilege,
+    pub to: Privilege,
+}
+
+type Hook<T> = Box<dyn FnMut(&T) + Send>;
+/// Hooks that also get the pc of the instruction.
+type PcHook<T> = Box<dyn FnMut(u64, &T) + Send>;
+
+#[derive(Default)]
+pub(super) struct Hooks {
+    pub instruction: Vec<PcHook<Decoded>>,
+    pub memory: Vec<Hook<MemoryAccess>>,
+    pub csr: Vec<Hook<CsrAccess>>,
+    pub trap: Vec<PcHook<Trap>>,
+    pub trap_return: Vec<PcHook<TrapReturn>>,
+    pub privilege: Vec<PcHook<PrivilegeChange>>,
+}
+
+impl Cpu {
+    fn hooks(&mut self) -> &mut Hooks {
+        self.hooks.get_or_insert_with(Default::default)
+    }
+
+    /// Call `hook` with pc and the decoded instruction before each instruction executes.
+    pub fn on_instruction(&mut self, hook: impl FnMut(u64, &Decoded) + Send + 'static) {
+        self.hooks().instruction.push(Box::new(hook));
+    }
+
+    /// Call `hook` after each successful guest load or store.
+    pub fn on_memory(&mut self, hook: impl FnMut(&MemoryAccess) + Send + 'static) {
+        self.hooks().memory.push(Box::new(hook));
+    }
+
+    /// Call `hook` for each CSR an instruction reads or writes.
+    pub fn on_csr(&mut self, hook: impl FnMut(&CsrAccess) + Send + 'static) {
+        self.hooks().csr.push(Box::new(hook));
+    }
+
+    /// Call `hook` with pc whenever an instruction raises an exception, before it is
+    /// handled or returned from the run, and whenever the hart takes an interrupt before
+    /// the instruction at pc. Exceptions include `ecall` and `ebreak`.
+    pub fn on_trap(&mut self, hook: impl FnMut(u64, &Trap) + Send + 'static) {
+        self.hooks().trap.push(Box::new(hook));
+    }
+
+    /// Call `hook` with the pc of each `mret` after it executed.
+    pub fn on_trap_return(&mut self, hook: impl FnMut(u64, &TrapReturn) + Send + 'static) {
+        self.hooks().trap_return.push(Box::new(hook));
+    }
+
+    /// Call `hook` whenever the privilege level changes, on entering a trap handler or
+    /// returning from one. pc is the instruction that trapped, was interrupted or returned.
+    pub fn on_privilege_change(
+        &mut self,
+        hook: impl FnMut(u64, &PrivilegeChange) + Send + 'static,
+    ) {
+        self.hooks().privilege.push(Box::new(hook));
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks = None;
+    }
+}
+
+impl Cpu {
+    /// Switch to privilege level `to` for the instruction at `pc`.
+    pub(super) fn set_privilege(&mut self, pc: u64, to: Privilege) {
+        let from = std::mem::replace(&mut self.privilege, to);
+        if let (Some(hooks), true) = (&mut self.hooks, from != to) {
+            for hook in &mut hooks.privilege {
+                hook(pc, &PrivilegeChange { from, to });
+            }
+        }
+    }
+
+    pub(super) fn trap_hooks(&mut self, pc: u64, trap: Trap) {
+        if let Some(hooks) = &mut self.hooks {
+            for hook in &mut hooks.trap {
+                hook(pc, &trap);
+            }
+        }
+    }
+
+    pub(super) fn trap_return_hooks(&mut self, pc: u64, trap_return: TrapReturn) {
+        if let Some(hooks) = &mut self.hooks {
+            for hook in &mut hooks.trap_return {
+                hook(pc, &trap_return);
+            }
+        }
+    }
+}
@@ -1,14 +1,50 @@
-use self::csr::Csr;
-use super::{bus::Bus, exception::Exception, DRAM_BASE, DRAM_END};
+use std::{
+    collections::BTreeSet,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use self::{
+    control::{WatchKind, Watchpoint},
+    csr::{Csr, Privilege},
+    custom::CustomInstruction,
+    hooks::{Hooks, MemoryAccess},
+    reverse::History,
+    trace::Tracer,
+};
+use super::{bus::Bus, elf::Elf, exception::Exception, DRAM_BASE, DRAM_END, DRAM_SIZE};
+pub mod control;
 pub mod csr;
+pub mod custom;
 pub mod debug;
+pub mod decode;
+pub mod disasm;
 pub mod execute;
+pub mod hooks;
+pub mod reverse;
+pub mod trace;
 
 pub struct Cpu {
     pub regs: [u64; 32], // RISC-V has 32 registers
     pub pc: u64,
     pub bus: Bus,
     pub csr: Csr,
+    /// Number of retired instructions.
+    pub instret: u64,
+    /// Set once the guest powered the machine off.
+    pub exit_code: Option<u32>,
+    breakpoints: BTreeSet<u64>,
+    watchpoints: Vec<Watchpoint>,
+    /// Address and kind of the first watched access of the current instruction.
+    watch_hit: Option<(u64, WatchKind)>,
+    interrupt: Arc<AtomicBool>,
+    hooks: Option<Box<Hooks>>,
+    custom: Vec<CustomInstruction>,
+    history: Option<Box<History>>,
+    tracer: Option<Box<Tracer>>,
+    /// Exceptions enter the handler at mtvec instead of stopping the run.
+    trap_delivery: bool,
+    /// Privilege level as set by trap entry and `mret`. Nothing is checked against it.
+    pub(crate) privilege: Privilege,
 }
 
 impl Cpu {
@@ -20,7 +56,74 @@ impl Cpu {
             pc: DRAM_BASE,
             bus: Bus::new(code),
             csr: Csr::new(),
+            instret: 0,
+            exit_code: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            hooks: None,
+            custom: Vec::new(),
+            history: None,
+            tracer: None,
+            trap_delivery: false,
+            privilege: Privilege::Machine,
+        }
+    }
+
+    /// Copy the loadable segments of `elf` to their physical addresses, zero filling the rest
+    /// of each segment, and start at its entry point.
+    pub fn load_elf(&mut self, elf: &Elf) -> Result<(), Exception> {
+        for header in elf.loadable() {
+            if header.memsz > DRAM_SIZE {
+                return Err(Exception::StoreAMOAccessFault {
+                    address: header.paddr,
+                });
+            }
+            let mut data = elf.segment_data(header).to_vec();
+            data.resize(header.memsz as usize, 0);
+            self.bus.write_bytes(header.paddr, &data)?;
         }
+        self.pc = elf.entry;
+        Ok(())
+    }
+
+    /// A child machine continuing from this one's state, sharing memory pages copy-on-write
+    /// so it is cheap to create and can run on another thread. Breakpoints, watchpoints and
+    /// trap delivery are inherited; hooks, custom instructions, reverse execution and tracing
+    /// are not and need to be set up on the child again.
+    pub fn fork(&self) -> Result<Cpu, String> {
+        Ok(Self {
+            regs: self.regs,
+            pc: self.pc,
+            bus: self.bus.fork()?,
+            csr: self.csr.clone(),
+            instret: self.instret,
+            exit_code: self.exit_code,
+            breakpoints: self.breakpoints.clone(),
+            watchpoints: self.watchpoints.clone(),
+            watch_hit: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            hooks: None,
+            custom: Vec::new(),
+            history: None,
+            tracer: None,
+            trap_delivery: self.trap_delivery,
+            privilege: self.privilege,
+        })
+    }
+
+    /// Put the hart back into its power-on state. Memory and devices keep their contents.
+    pub fn reset(&mut self) {
+        self.regs = [0; 32];
+        self.regs[2] = DRAM_END;
+        self.pc = DRAM_BASE;
+        self.csr = Csr::new();
+        self.privilege = Privilege::Machine;
+    }
+
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
     }
 
     pub fn write_reg(&mut self, reg: usize, value: u64) {
@@ -30,12 +133,43 @@ impl Cpu {
         self.regs[reg]
     }
 
+    /// Guest data access of `size` bits, watchpoints included.
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        self.bus.load(addr, size)
+        let value = self.bus.load(addr, size)?;
+        // a faulting access does not hit a watchpoint
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, size, false);
+        }
+        self.memory_hooks(addr, size, value, false);
+        Ok(value)
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        self.bus.store(addr, size, value)
+        self.bus.store(addr, size, value)?;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, size, true);
+        }
+        self.memory_hooks(addr, size, value, true);
+        Ok(())
+    }
+
+    fn memory_hooks(&mut self, addr: u64, size: u64, value: u64, write: bool) {
+        if self.hooks.is_none() && self.tracer.is_none() {
+            return;
+        }
+        let access = MemoryAccess {
+            pc: self.pc,
+            addr,
+            size,
+            value,
+            write,
+        };
+        if let Some(hooks) = &mut self.hooks {
+            for hook in &mut hooks.memory {
+                hook(&access);
+            }
+        }
+        self.trace_access(access);
     }
 
     pub fn instructure_fetch(&mut self) -> Result<u32, Exception> {
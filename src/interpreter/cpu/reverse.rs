@@ -0,0 +1,205 @@
+//! Reverse execution: the hart takes a checkpoint every so many instructions, sharing memory
+//! pages copy-on-write with the running machine, and going back means restoring the
+//! checkpoint before the target and executing forward again. This relies on execution being
+//! deterministic, so it is limited to machines `Cpu::fork` accepts and that have no HTIF,
+//! whose host side effects would happen again. Hooks see the re-executed instructions again.
+use std::collections::VecDeque;
+
+use super::{
+    control::StopReason,
+    csr::{Csr, Privilege},
+    Cpu,
+};
+use crate::interpreter::{bus::Bus, exception::Exception};
+
+struct Checkpoint {
+    instret: u64,
+    regs: [u64; 32],
+    pc: u64,
+    csr: Csr,
+    privilege: Privilege,
+    bus: Bus,
+}
+
+pub(super) struct History {
+    interval: u64,
+    limit: usize,
+    /// Ordered by instruction count.
+    checkpoints: VecDeque<Checkpoint>,
+}
+
+impl History {
+    fn next(&self) -> u64 {
+        self.checkpoints
+            .back()
+            .map_or(0, |checkpoint| checkpoint.instret + self.interval)
+    }
+}
+
+/// Something a reverse run stops at: a breakpoint reached or a watchpoint hit by the
+/// instruction at `instret`.
+type Stop = (u64, StopReason);
+
+impl Cpu {
+    /// Keep a checkpoint every `interval` instructions, at most `limit` of them, so that the
+    /// run can be stepped backwards as far as the oldest one. Fails for machines that cannot
+    /// be forked.
+    pub fn enable_reverse_execution(&mut self, interval: u64, limit: usize) -> Result<(), String> {
+        self.history = Some(Box::new(History {
+            interval: interval.max(1),
+            limit: limit.max(1),
+            checkpoints: VecDeque::new(),
+        }));
+        let result = self.checkpoint();
+        if result.is_err() {
+            self.history = None;
+        }
+        result
+    }
+
+    pub fn disable_reverse_execution(&mut self) {
+        self.history = None;
+    }
+
+    pub fn reverse_execution_enabled(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Called before the instruction at pc executes.
+    pub(super) fn take_checkpoint(&mut self) {
+        let due = self
+            .history
+            .as_ref()
+            .is_some_and(|history| self.instret >= history.next());
+        // a machine that gained host devices can no longer go back
+        if due && self.checkpoint().is_err() {
+            self.history = None;
+        }
+    }
+
+    fn checkpoint(&mut self) -> Result<(), String> {
+        if self.bus.htif().is_some() {
+            return Err("cannot go back on a machine with HTIF".to_string());
+        }
+        let checkpoint = Checkpoint {
+            instret: self.instret,
+            regs: self.regs,
+            pc: self.pc,
+            csr: self.csr.clone(),
+            privilege: self.privilege,
+            bus: self.bus.fork()?,
+        };
+        let history = self.history.as_mut().unwrap();
+        if history.checkpoints.len() == history.limit {
+            history.checkpoints.pop_front();
+        }
+        history.checkpoints.push_back(checkpoint);
+        Ok(())
+    }
+
+    /// Go back to the state right before the instruction at `target` executed, which must
+    /// not be older than the oldest checkpoint. Later checkpoints are dropped, as the state
+    /// may be changed from there.
+    fn rewind(&mut self, target: u64) -> Result<(), Exception> {
+        let history = self.history.as_mut().unwrap();
+        while history.checkpoints.len() > 1 && history.checkpoints.back().unwrap().instret > target
+        {
+            history.checkpoints.pop_back();
+        }
+        let checkpoint = history.checkpoints.back().unwrap();
+        self.regs = checkpoint.regs;
+        self.pc = checkpoint.pc;
+        self.csr = checkpoint.csr.clone();
+        self.privilege = checkpoint.privilege;
+        self.instret = checkpoint.instret;
+        let journal = self.bus.take_journal();
+        self.bus = checkpoint
+            .bus
+            .fork()
+            .expect("checkpoints have no host devices");
+        // forks start without one, but the run goes on recording or replaying
+        if let Some(journal) = journal {
+            journal.set_instret(self.instret);
+            self.bus.set_journal(journal);
+        }
+        self.exit_code = None;
+        while self.instret < target {
+            self.retire()?;
+        }
+        self.watch_hit = None;
+        Ok(())
+    }
+
+    fn oldest_checkpoint(&self) -> Result<u64, String> {
+        match &self.history {
+            Some(history) => Ok(history.checkpoints.front().unwrap().instret),
+            None => Err("reverse execution is not enabled".to_string()),
+        }
+    }
+
+    /// Undo the last instruction.
+    pub fn reverse_step(&mut self) -> Result<StopReason, String> {
+        if self.instret <= self.oldest_checkpoint()? {
+            return Ok(StopReason::HistoryStart);
+        }
+        match self.rewind(self.instret - 1) {
+            Ok(()) => Ok(StopReason::Limit),
+            Err(exception) => Err(format!("re-execution diverged: {:?}", exception)),
+        }
+    }
+
+    /// Run backwards to the previous breakpoint, or to right before the last instruction
+    /// that hit a watchpoint.
+    pub fn reverse_continue(&mut self) -> Result<StopReason, String> {
+        let oldest = self.oldest_checkpoint()?;
+        let now = self.instret;
+        let mut end = now;
+        while end > oldest {
+            // look for stops between the checkpoint before `end` and `end`
+            let start = {
+                let history = self.history.as_ref().unwrap();
+                let index = history
+                    .checkpoints
+                    .iter()
+                    .rposition(|checkpoint| checkpoint.instret < end)
+                    .unwrap();
+                history.checkpoints[index].instret
+            };
+            let stop = self.last_stop(start, end, now);
+            let result = match &stop {
+                Some((instret, _)) => self.rewind(*instret),
+                None => self.rewind(start),
+            };
+            if let Err(exception) = result {
+                return Err(format!("re-execution diverged: {:?}", exception));
+            }
+            if let Some((_, reason)) = stop {
+                return Ok(reason);
+            }
+            end = start;
+        }
+        Ok(StopReason::HistoryStart)
+    }
+
+    /// The last stop in `start..end`, executing forward from the checkpoint at `start`. A
+    /// breakpoint at `now`, where the reverse run begins, does not count.
+    fn last_stop(&mut self, start: u64, end: u64, now: u64) -> Option<Stop> {
+        if self.rewind(start).is_err() {
+            return None;
+        }
+        let mut last = None;
+        while self.instret < end {
+            if self.instret != now && self.breakpoints.contains(&self.pc) {
+                last = Some((self.instret, StopReason::Breakpoint { pc: self.pc }));
+            }
+            let instret = self.instret;
+            if self.retire().is_err() {
+                break;
+            }
+            if let Some((addr, kind)) = self.watch_hit.take() {
+                last = Some((instret, StopReason::Watchpoint { addr, kind }));
+            }
+        }
+        last
+    }
+}
@@ -0,0 +1,281 @@
+//! Execution trace: one record per retired instruction with its pc, encoding, disassembly,
+//! register and CSR writeback and memory accesses.
+use std::{fmt, io::Write, ops::Range};
+
+use super::{
+    debug::RVABI,
+    decode::{Decoded, Format},
+    disasm::csr_name,
+    hooks::MemoryAccess,
+    Cpu,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// The commit log of Spike's `--log-commits`.
+    Spike,
+    /// Like QEMU's `-d in_asm,cpu`, one instruction per block.
+    Qemu,
+    /// One JSON object per line.
+    Json,
+}
+
+impl TraceFormat {
+    pub fn parse(name: &str) -> Option<TraceFormat> {
+        match name {
+            "spike" => Some(TraceFormat::Spike),
+            "qemu" => Some(TraceFormat::Qemu),
+            "json" => Some(TraceFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// What one retired instruction did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Commit {
+    /// Number of instructions retired before this one.
+    pub instret: u64,
+    pub pc: u64,
+    pub raw: u32,
+    /// Registers written, in ascending order, x0 left out.
+    pub writes: Vec<(usize, u64)>,
+    /// CSRs written, in program order, with the value as stored.
+    pub csr_writes: Vec<(usize, u64)>,
+    pub accesses: Vec<MemoryAccess>,
+}
+
+/// The line of the commit in the Spike format.
+impl fmt::Display for Commit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "core   0: 3 0x{:016x} (0x{:08x})", self.pc, self.raw)?;
+        for (reg, value) in &self.writes {
+            write!(f, " x{:<2} 0x{:016x}", reg, value)?;
+        }
+        for (csr, value) in &self.csr_writes {
+            write!(f, " c{}_{} 0x{:016x}", csr, csr_name(*csr as u64), value)?;
+        }
+        for access in &self.accesses {
+            write!(f, " mem 0x{:016x}", access.addr)?;
+            if access.write {
+                let width = access.size as usize / 4;
+                write!(f, " 0x{:0width$x}", access.value, width = width)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Output {
+    Text(TraceFormat, Box<dyn Write + Send>),
+    /// Kept for `Cpu::take_commits`.
+    Commits(Vec<Commit>),
+}
+
+pub struct Tracer {
+    output: Output,
+    /// Only instructions at a pc in one of these are traced, all when empty.
+    pc_ranges: Vec<Range<u64>>,
+    /// Only instructions retiring as the n-th one for n in here are traced.
+    instret: Range<u64>,
+    /// The instruction being executed.
+    current: Commit,
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat, output: Box<dyn Write + Send>) -> Tracer {
+        Self::with_output(Output::Text(format, output))
+    }
+
+    /// A tracer keeping the commits for `Cpu::take_commits` instead of writing them out.
+    pub fn collect() -> Tracer {
+        Self::with_output(Output::Commits(Vec::new()))
+    }
+
+    fn with_output(output: Output) -> Tracer {
+        Self {
+            output,
+            pc_ranges: Vec::new(),
+            instret: 0..u64::MAX,
+            current: Commit::default(),
+        }
+    }
+
+    /// Also trace instructions in `range`. Without any range every pc is traced.
+    pub fn with_pc_range(mut self, range: Range<u64>) -> Tracer {
+        self.pc_ranges.push(range);
+        self
+    }
+
+    /// Only trace the instructions retiring when `instret` is in `range`.
+    pub fn with_instret_range(mut self, range: Range<u64>) -> Tracer {
+        self.instret = range;
+        self
+    }
+
+    fn traces(&self, pc: u64, instret: u64) -> bool {
+        self.instret.contains(&instret)
+            && (self.pc_ranges.is_empty() || self.pc_ranges.iter().any(|r| r.contains(&pc)))
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.output {
+            Output::Text(_, output) => output.flush(),
+            Output::Commits(_) => Ok(()),
+        }
+    }
+}
+
+fn qemu(commit: &Commit, disassembly: &str, next_pc: u64, regs: &[u64; 32]) -> String {
+    let mut block = format!(
+        "----------------\nIN: \n0x{:016x}:  {:08x}          {}\n\n pc       {:016x}\n",
+        commit.pc, commit.raw, disassembly, next_pc
+    );
+    for (i, value) in regs.iter().enumerate() {
+        let name = format!("x{}/{}", i, RVABI[i]);
+        block += &format!(" {:<8} {:016x}", name, value);
+        if i % 4 == 3 {
+            block += "\n";
+        }
+    }
+    block
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out + "\""
+}
+
+fn json(commit: &Commit, disassembly: &str) -> String {
+    let writes: Vec<_> = commit
+        .writes
+        .iter()
+        .map(|(reg, value)| {
+            format!(
+                "{{\"reg\":\"{}\",\"value\":\"0x{:x}\"}}",
+                RVABI[*reg], value
+            )
+        })
+        .chain(commit.csr_writes.iter().map(|(csr, value)| {
+            let name = json_string(&csr_name(*csr as u64));
+            format!("{{\"csr\":{},\"value\":\"0x{:x}\"}}", name, value)
+        }))
+        .collect();
+    let accesses: Vec<_> = commit
+        .accesses
+        .iter()
+        .map(|access| {
+            format!(
+                "{{\"addr\":\"0x{:x}\",\"size\":{},\"value\":\"0x{:x}\",\"write\":{}}}",
+                access.addr,
+                access.size / 8,
+                access.value,
+                access.write
+            )
+        })
+        .collect();
+    format!(
+        "{{\"instret\":{},\"pc\":\"0x{:x}\",\"raw\":\"0x{:08x}\",\"disasm\":{},\"writes\":[{}],\"mem\":[{}]}}\n",
+        commit.instret,
+        commit.pc,
+        commit.raw,
+        json_string(disassembly),
+        writes.join(","),
+        accesses.join(",")
+    )
+}
+
+impl Cpu {
+    /// Write a record for every retired instruction `tracer` selects.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    /// Stop tracing. Dropping the tracer flushes its output.
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take().map(|tracer| *tracer)
+    }
+
+    /// The commits a `Tracer::collect` tracer kept since the last call.
+    pub fn take_commits(&mut self) -> Vec<Commit> {
+        match self.tracer.as_deref_mut().map(|tracer| &mut tracer.output) {
+            Some(Output::Commits(commits)) => std::mem::take(commits),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Start the trace of the instruction `raw`, forgetting what one that trapped did.
+    pub(super) fn trace_fetch(&mut self, raw: u32) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.current.raw = raw;
+            tracer.current.csr_writes.clear();
+            tracer.current.accesses.clear();
+        }
+    }
+
+    /// Note a guest memory access for the trace of the current instruction.
+    pub(super) fn trace_access(&mut self, access: MemoryAccess) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.current.accesses.push(access);
+        }
+    }
+
+    pub(super) fn trace_csr_write(&mut self, csr: usize, value: u64) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.current.csr_writes.push((csr, value));
+        }
+    }
+
+    /// Emit the record of the instruction at `pc` that just retired as the `instret`-th one,
+    /// with `before` the registers before it executed.
+    pub(super) fn trace(&mut self, pc: u64, instret: u64, before: &[u64; 32]) {
+        let Some(tracer) = &self.tracer else {
+            return;
+        };
+        if !tracer.traces(pc, instret) {
+            return;
+        }
+        let decoded = Decoded::new(tracer.current.raw);
+        let writes_rd = !matches!(Format::of(decoded.opcode), Format::S | Format::B);
+        // rd of the standard formats, plus whatever custom instructions changed
+        let writes = (1..32)
+            .filter(|&reg| (writes_rd && reg == decoded.rd) || self.regs[reg] != before[reg])
+            .map(|reg| (reg, self.regs[reg]))
+            .collect();
+        let disassembly = match &tracer.output {
+            Output::Text(TraceFormat::Qemu | TraceFormat::Json, _) => {
+                self.disassemble(tracer.current.raw)
+            }
+            _ => String::new(),
+        };
+        let tracer = self.tracer.as_mut().unwrap();
+        let commit = Commit {
+            instret,
+            pc,
+            raw: tracer.current.raw,
+            writes,
+            csr_writes: std::mem::take(&mut tracer.current.csr_writes),
+            accesses: std::mem::take(&mut tracer.current.accesses),
+        };
+        let text = match &mut tracer.output {
+            Output::Text(TraceFormat::Spike, _) => format!("{}\n", commit),
+            Output::Text(TraceFormat::Qemu, _) => qemu(&commit, &disassembly, self.pc, &self.regs),
+            Output::Text(TraceFormat::Json, _) => json(&commit, &disassembly),
+            Output::Commits(commits) => {
+                commits.push(commit);
+                return;
+            }
+        };
+        if let Output::Text(_, output) = &mut tracer.output {
+            let _ = output.write_all(text.as_bytes());
+        }
+    }
+}
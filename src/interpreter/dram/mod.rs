@@ -1,43 +1,111 @@
+//! Main memory, kept as reference counted pages so forked machines share them until one of
+//! them writes.
+use std::sync::Arc;
+
 use super::{exception::Exception, DRAM_BASE, DRAM_SIZE};
 
+pub const PAGE_SIZE: u64 = 4096;
+
+type Page = [u8; PAGE_SIZE as usize];
+
+#[derive(Clone)]
 pub struct Dram {
-    pub dram: Vec<u8>,
+    /// Pages that were never written all share one zero page.
+    pages: Vec<Arc<Page>>,
 }
 
 impl Dram {
     pub fn new(code: Vec<u8>) -> Dram {
-        let mut dram = vec![0; DRAM_SIZE as usize];
-        dram.splice(..code.len(), code.into_iter());
-        Self { dram }
+        let zero = Arc::new([0; PAGE_SIZE as usize]);
+        let mut dram = Self {
+            pages: (0..DRAM_SIZE / PAGE_SIZE).map(|_| zero.clone()).collect(),
+        };
+        dram.write_bytes(DRAM_BASE, &code)
+            .expect("program larger than DRAM");
+        dram
+    }
+
+    pub fn size(&self) -> u64 {
+        self.pages.len() as u64 * PAGE_SIZE
+    }
+
+    pub fn page(&self, index: usize) -> &[u8] {
+        &self.pages[index][..]
+    }
+
+    /// The page for writing, copied first if another machine shares it.
+    pub fn page_mut(&mut self, index: usize) -> &mut [u8] {
+        &mut Arc::make_mut(&mut self.pages[index])[..]
+    }
+
+    /// Drop the contents of every page.
+    pub fn clear(&mut self) {
+        let zero = Arc::new([0; PAGE_SIZE as usize]);
+        self.pages.fill(zero);
     }
 
-    // addr/size must be valid. Check in bus
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
         if ![8, 16, 32, 64].contains(&size) {
             return Err(Exception::LoadAccessFault { address: addr });
         }
-        let nbytes = size / 8;
-        let index = (addr - DRAM_BASE) as usize;
-        let mut code = self.dram[index] as u64;
-        // shift the bytes to build up the desired value
-        for i in 1..nbytes {
-            code |= (self.dram[index + i as usize] as u64) << (i * 8);
-        }
-
-        return Ok(code);
+        let mut bytes = [0; 8];
+        self.read_bytes(addr, &mut bytes[..(size / 8) as usize])?;
+        Ok(u64::from_le_bytes(bytes))
     }
 
-    // addr/size must be valid. Check in bus
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         if ![8, 16, 32, 64].contains(&size) {
             return Err(Exception::StoreAMOAccessFault { address: addr });
         }
-        let nbytes = size / 8;
-        let index = (addr - DRAM_BASE) as usize;
-        for i in 0..nbytes {
-            let offset = 8 * i as usize;
-            self.dram[index + i as usize] = ((value >> offset) & 0xff) as u8;
+        self.write_bytes(addr, &value.to_le_bytes()[..(size / 8) as usize])
+    }
+
+    /// Copy `buf.len()` bytes starting at `addr` out of memory. Also used by devices doing
+    /// DMA, so the range is checked.
+    pub fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        let index = self
+            .index_of(addr, buf.len())
+            .ok_or(Exception::LoadAccessFault { address: addr })?;
+        let mut done = 0;
+        while done < buf.len() {
+            let current = index + done as u64;
+            let offset = (current % PAGE_SIZE) as usize;
+            let n = (PAGE_SIZE as usize - offset).min(buf.len() - done);
+            let page = &self.pages[(current / PAGE_SIZE) as usize];
+            buf[done..done + n].copy_from_slice(&page[offset..offset + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Copy `data` into memory starting at `addr`. The range is checked.
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        let index = self
+            .index_of(addr, data.len())
+            .ok_or(Exception::StoreAMOAccessFault { address: addr })?;
+        let mut done = 0;
+        while done < data.len() {
+            let current = index + done as u64;
+            let offset = (current % PAGE_SIZE) as usize;
+            let n = (PAGE_SIZE as usize - offset).min(data.len() - done);
+            let page = self.page_mut((current / PAGE_SIZE) as usize);
+            page[offset..offset + n].copy_from_slice(&data[done..done + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Whether the `len` bytes starting at `addr` are inside memory.
+    pub fn contains(&self, addr: u64, len: usize) -> bool {
+        self.index_of(addr, len).is_some()
+    }
+
+    /// Offset of `addr` into memory, if `len` bytes from there are inside it.
+    fn index_of(&self, addr: u64, len: usize) -> Option<u64> {
+        let index = addr.checked_sub(DRAM_BASE)?;
+        if index.checked_add(len as u64)? > self.size() {
+            return None;
         }
-        return Ok(());
+        Some(index)
     }
 }
@@ -0,0 +1,249 @@
+//! Minimal reader for little endian ELF64 RISC-V executables: program headers to load, the
+//! symbol table and the ISA the file was built for.
+use std::collections::HashMap;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RISCV_ATTRIBUTES: u32 = 0x7000_0003;
+/// Attributes that apply to the whole file.
+const TAG_FILE: u8 = 1;
+const TAG_RISCV_ARCH: u64 = 5;
+
+#[derive(Debug, Clone)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub kind: u16,
+    pub entry: u64,
+    pub phoff: u64,
+    pub phentsize: u16,
+    pub program_headers: Vec<ProgramHeader>,
+    pub symbols: HashMap<String, u64>,
+    /// ISA string from the build attributes, e.g. `rv64i2p1_m2p0_a2p1`, if there are any.
+    pub arch: Option<String>,
+    data: Vec<u8>,
+}
+
+fn truncated() -> String {
+    "truncated ELF file".to_string()
+}
+
+fn bytes_at<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], String> {
+    offset
+        .checked_add(N)
+        .and_then(|end| data.get(offset..end))
+        .map(|b| b.try_into().unwrap())
+        .ok_or_else(truncated)
+}
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    bytes_at(data, offset).map(u16::from_le_bytes)
+}
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    bytes_at(data, offset).map(u32::from_le_bytes)
+}
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, String> {
+    bytes_at(data, offset).map(u64::from_le_bytes)
+}
+
+/// Offset of entry `index` of the table at `table` with entries of `size` bytes, if it is
+/// inside `data`.
+fn entry_at(data: &[u8], table: u64, index: usize, size: u16) -> Result<usize, String> {
+    index
+        .checked_mul(size as usize)
+        .and_then(|offset| (table as usize).checked_add(offset))
+        .filter(|offset| *offset < data.len())
+        .ok_or_else(truncated)
+}
+
+/// The `len` bytes at `offset`, if all of them are in `data`.
+fn range_at(data: &[u8], offset: u64, len: u64) -> Option<&[u8]> {
+    let end = offset.checked_add(len)?;
+    data.get(offset.try_into().ok()?..end.try_into().ok()?)
+}
+
+fn uleb128(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// The Tag_RISCV_arch string of a `.riscv.attributes` section. Attributes with an odd tag
+/// are strings, the others numbers.
+fn attributes_arch(section: &[u8]) -> Option<String> {
+    let mut rest = section.strip_prefix(b"A")?;
+    while !rest.is_empty() {
+        let len = u32_at(rest, 0).ok()? as usize;
+        let vendor = rest.get(4..len)?;
+        rest = &rest[len..];
+        let end = vendor.iter().position(|b| *b == 0)?;
+        if &vendor[..end] != b"riscv" {
+            continue;
+        }
+        let mut subsections = &vendor[end + 1..];
+        while !subsections.is_empty() {
+            let tag = subsections[0];
+            let len = u32_at(subsections, 1).ok()? as usize;
+            let mut attributes = subsections.get(5..len)?;
+            subsections = &subsections[len..];
+            if tag != TAG_FILE {
+                continue;
+            }
+            while !attributes.is_empty() {
+                let (tag, n) = uleb128(attributes)?;
+                attributes = &attributes[n..];
+                if tag % 2 == 0 {
+                    attributes = &attributes[uleb128(attributes)?.1..];
+                    continue;
+                }
+                let end = attributes.iter().position(|b| *b == 0)?;
+                if tag == TAG_RISCV_ARCH {
+                    return Some(String::from_utf8_lossy(&attributes[..end]).into_owned());
+                }
+                attributes = &attributes[end + 1..];
+            }
+        }
+    }
+    None
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7fELF")
+}
+
+impl Elf {
+    pub fn parse(data: Vec<u8>) -> Result<Elf, String> {
+        if !is_elf(&data) {
+            return Err("not an ELF file".to_string());
+        }
+        if data.get(4) != Some(&ELFCLASS64) || data.get(5) != Some(&ELFDATA2LSB) {
+            return Err("only little endian ELF64 files are supported".to_string());
+        }
+        if u16_at(&data, 18)? != EM_RISCV {
+            return Err("not a RISC-V ELF file".to_string());
+        }
+        let kind = u16_at(&data, 16)?;
+        let entry = u64_at(&data, 24)?;
+        let phoff = u64_at(&data, 32)?;
+        let shoff = u64_at(&data, 40)?;
+        let phentsize = u16_at(&data, 54)?;
+        let phnum = u16_at(&data, 56)?;
+        let shentsize = u16_at(&data, 58)?;
+        let shnum = u16_at(&data, 60)?;
+
+        let mut program_headers = Vec::new();
+        for i in 0..phnum as usize {
+            let base = entry_at(&data, phoff, i, phentsize)?;
+            let header = ProgramHeader {
+                kind: u32_at(&data, base)?,
+                flags: u32_at(&data, base + 4)?,
+                offset: u64_at(&data, base + 8)?,
+                vaddr: u64_at(&data, base + 16)?,
+                paddr: u64_at(&data, base + 24)?,
+                filesz: u64_at(&data, base + 32)?,
+                memsz: u64_at(&data, base + 40)?,
+                align: u64_at(&data, base + 48)?,
+            };
+            if range_at(&data, header.offset, header.filesz).is_none() {
+                return Err("ELF segment outside of the file".to_string());
+            }
+            if header.kind == PT_LOAD && header.filesz > header.memsz {
+                return Err("ELF segment larger in the file than in memory".to_string());
+            }
+            program_headers.push(header);
+        }
+
+        let mut symbols = HashMap::new();
+        let mut arch = None;
+        for i in 0..shnum as usize {
+            let base = entry_at(&data, shoff, i, shentsize)?;
+            let kind = u32_at(&data, base + 4)?;
+            if kind == SHT_RISCV_ATTRIBUTES {
+                let offset = u64_at(&data, base + 24)?;
+                let size = u64_at(&data, base + 32)?;
+                // malformed attributes are only advisory and do not make the file unusable
+                arch = range_at(&data, offset, size).and_then(attributes_arch);
+                continue;
+            }
+            if kind != SHT_SYMTAB {
+                continue;
+            }
+            let offset = u64_at(&data, base + 24)?;
+            let size = u64_at(&data, base + 32)?;
+            let link = u32_at(&data, base + 40)? as usize;
+            let entsize = (u64_at(&data, base + 56)? as usize).max(24);
+            let strtab_header = entry_at(&data, shoff, link, shentsize)?;
+            let strtab = u64_at(&data, strtab_header + 24)? as usize;
+            let table = range_at(&data, offset, size).ok_or_else(truncated)?;
+            for sym in table.chunks(entsize) {
+                let name = u32_at(sym, 0)? as usize;
+                let value = u64_at(sym, 8)?;
+                let name = strtab
+                    .checked_add(name)
+                    .and_then(|start| data.get(start..))
+                    .and_then(|rest| Some(&rest[..rest.iter().position(|b| *b == 0)?]))
+                    .ok_or_else(|| "truncated ELF string table".to_string())?;
+                if !name.is_empty() {
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    symbols.entry(name).or_insert(value);
+                }
+            }
+        }
+
+        Ok(Self {
+            kind,
+            entry,
+            phoff,
+            phentsize,
+            program_headers,
+            symbols,
+            arch,
+            data,
+        })
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.get(name).copied()
+    }
+
+    /// File contents backing `header`, which `parse` checked to be inside the file.
+    pub fn segment_data(&self, header: &ProgramHeader) -> &[u8] {
+        range_at(&self.data, header.offset, header.filesz).unwrap_or_default()
+    }
+
+    pub fn loadable(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers.iter().filter(|h| h.kind == PT_LOAD)
+    }
+
+    /// Path of the program interpreter named by PT_INTERP.
+    pub fn interpreter(&self) -> Option<String> {
+        let header = self.program_headers.iter().find(|h| h.kind == PT_INTERP)?;
+        let data = self.segment_data(header);
+        let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+        Some(String::from_utf8_lossy(&data[..end]).into_owned())
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Exception {
     LoadAccessFault { address: u64 },
     StoreAMOAccessFault { address: u64 },
@@ -6,3 +6,26 @@ pub enum Exception {
     Breakpoint,
     InvalidInstruction,
 }
+
+impl Exception {
+    /// The exception code mcause would hold, for an ecall from M-mode.
+    pub fn code(&self) -> u64 {
+        match self {
+            Exception::InvalidInstruction => 2,
+            Exception::Breakpoint => 3,
+            Exception::LoadAccessFault { .. } => 5,
+            Exception::StoreAMOAccessFault { .. } => 7,
+            Exception::EnvironmentCall => 11,
+        }
+    }
+
+    /// What mtval would hold: the faulting address for access faults, else 0.
+    pub fn value(&self) -> u64 {
+        match self {
+            Exception::LoadAccessFault { address } | Exception::StoreAMOAccessFault { address } => {
+                *address
+            }
+            _ => 0,
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! Linear framebuffer in x8r8g8b8 format, meant to be described to the guest as a
+//! `simple-framebuffer` device tree node.
+use std::{io, path::Path};
+
+use super::{
+    exception::Exception,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    FRAMEBUFFER_BASE, FRAMEBUFFER_END,
+};
+
+const BYTES_PER_PIXEL: u64 = 4;
+
+struct Screenshot {
+    at: u64,
+    path: String,
+}
+
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    /// Pending screenshots, ordered by instruction count.
+    screenshots: Vec<Screenshot>,
+}
+
+impl Framebuffer {
+    /// Whether a screen of `width` by `height` pixels fits in the framebuffer window of the
+    /// bus. `new` must only be called with sizes that do.
+    pub fn fits(width: u32, height: u32) -> bool {
+        let size = (width as u64)
+            .checked_mul(height as u64)
+            .and_then(|pixels| pixels.checked_mul(BYTES_PER_PIXEL));
+        width > 0
+            && height > 0
+            && size.is_some_and(|size| size <= FRAMEBUFFER_END - FRAMEBUFFER_BASE + 1)
+    }
+
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        Self {
+            width,
+            height,
+            pixels: vec![0; (width as u64 * height as u64 * BYTES_PER_PIXEL) as usize],
+            screenshots: Vec::new(),
+        }
+    }
+
+    /// A copy of the screen for a forked machine, without the pending screenshots.
+    pub fn fork(&self) -> Framebuffer {
+        Self {
+            width: self.width,
+            height: self.height,
+            pixels: self.pixels.clone(),
+            screenshots: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn size(&self) -> u64 {
+        self.pixels.len() as u64
+    }
+
+    pub fn stride(&self) -> u32 {
+        self.width * BYTES_PER_PIXEL as u32
+    }
+
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        w.u32(self.width);
+        w.u32(self.height);
+        w.packed(&self.pixels);
+    }
+
+    /// Restore the pixels saved by `save_state`. Pending screenshots are kept.
+    pub(crate) fn restore_state(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        if (r.u32()?, r.u32()?) != (self.width, self.height) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot framebuffer size differs",
+            ));
+        }
+        r.packed(&mut self.pixels)
+    }
+
+    /// The node to put into the guest's device tree.
+    pub fn device_tree_node(&self) -> String {
+        format!(
+            "framebuffer@{base:x} {{\n\
+            \tcompatible = \"simple-framebuffer\";\n\
+            \treg = <0x0 {base:#x} 0x0 {size:#x}>;\n\
+            \twidth = <{width}>;\n\
+            \theight = <{height}>;\n\
+            \tstride = <{stride}>;\n\
+            \tformat = \"x8r8g8b8\";\n\
+            }};\n",
+            base = FRAMEBUFFER_BASE,
+            size = self.size(),
+            width = self.width,
+            height = self.height,
+            stride = self.stride(),
+        )
+    }
+
+    // addr must be inside the framebuffer. Check in bus
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let index = (addr - FRAMEBUFFER_BASE) as usize;
+        let nbytes = (size / 8) as usize;
+        if ![8, 16, 32, 64].contains(&size) || index + nbytes > self.pixels.len() {
+            return Err(Exception::LoadAccessFault { address: addr });
+        }
+        let mut value = 0;
+        for i in 0..nbytes {
+            value |= (self.pixels[index + i] as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    // addr must be inside the framebuffer. Check in bus
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let index = (addr - FRAMEBUFFER_BASE) as usize;
+        let nbytes = (size / 8) as usize;
+        if ![8, 16, 32, 64].contains(&size) || index + nbytes > self.pixels.len() {
+            return Err(Exception::StoreAMOAccessFault { address: addr });
+        }
+        for i in 0..nbytes {
+            self.pixels[index + i] = (value >> (i * 8)) as u8;
+        }
+        Ok(())
+    }
+
+    /// Pixel at (x, y) as (r, g, b).
+    pub fn pixel(&self, x: u32, y: u32) -> (u8, u8, u8) {
+        let index = ((y as u64 * self.width as u64 + x as u64) * BYTES_PER_PIXEL) as usize;
+        let pixel = &self.pixels[index..index + 4];
+        (pixel[2], pixel[1], pixel[0])
+    }
+
+    fn rgb_rows(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        (0..self.height).map(move |y| {
+            (0..self.width)
+                .flat_map(|x| {
+                    let (r, g, b) = self.pixel(x, y);
+                    [r, g, b]
+                })
+                .collect()
+        })
+    }
+
+    /// Binary PPM (P6) image of the current contents.
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut image = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        for row in self.rgb_rows() {
+            image.extend_from_slice(&row);
+        }
+        image
+    }
+
+    /// PNG image of the current contents. The image data uses stored (uncompressed) deflate
+    /// blocks, which keeps the encoder dependency free.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for row in self.rgb_rows() {
+            // filter type "none"
+            raw.push(0);
+            raw.extend_from_slice(&row);
+        }
+
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&self.width.to_be_bytes());
+        ihdr.extend_from_slice(&self.height.to_be_bytes());
+        // 8 bit depth, truecolor, deflate, adaptive filtering, no interlace
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut image, b"IHDR", &ihdr);
+        png_chunk(&mut image, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut image, b"IEND", &[]);
+        image
+    }
+
+    /// Write a screenshot, as PNG if `path` ends in `.png` and as PPM otherwise.
+    pub fn save_screenshot(&self, path: &Path) -> io::Result<()> {
+        let image = match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("png") => self.to_png(),
+            _ => self.to_ppm(),
+        };
+        std::fs::write(path, image)
+    }
+
+    /// Take a screenshot once `at` instructions have retired.
+    pub fn schedule_screenshot(&mut self, at: u64, path: &str) {
+        let index = self.screenshots.partition_point(|s| s.at <= at);
+        self.screenshots.insert(
+            index,
+            Screenshot {
+                at,
+                path: path.to_string(),
+            },
+        );
+    }
+
+    pub fn tick(&mut self, instret: u64) {
+        while self.screenshots.first().is_some_and(|s| s.at <= instret) {
+            let screenshot = self.screenshots.remove(0);
+            if let Err(error) = self.save_screenshot(Path::new(&screenshot.path)) {
+                eprintln!("cannot write screenshot '{}': {}", screenshot.path, error);
+            }
+        }
+    }
+}
+
+fn png_chunk(image: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    image.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = image.len();
+    image.extend_from_slice(kind);
+    image.extend_from_slice(data);
+    let crc = crc32(&image[start..]);
+    image.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// zlib stream made of stored deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut chunks = data.chunks(0xffff).peekable();
+    if chunks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        stream.push(last as u8);
+        let len = chunk.len() as u16;
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(chunk);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
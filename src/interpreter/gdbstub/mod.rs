@@ -0,0 +1,415 @@
+//! Stub for the GDB remote serial protocol, so a guest can be debugged with `target remote`.
+//! It covers what GDB needs for a single-threaded target: registers, memory, stepping,
+//! continuing, breakpoints and watchpoints, plus reverse step and continue (`bs`/`bc`) when
+//! the `Cpu` has reverse execution enabled.
+//!
+//! Continuing runs in chunks of instructions, and between them the stub looks for a Ctrl-C
+//! from GDB to stop the guest.
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+};
+
+use super::{
+    cpu::{
+        control::{StopReason, WatchKind, Watchpoint},
+        debug::RVABI,
+        Cpu,
+    },
+    exception::Exception,
+};
+
+/// Largest packet GDB may send, advertised in hex through qSupported.
+const PACKET_SIZE: usize = 0x4000;
+/// Instructions run between checks for a Ctrl-C while continuing.
+const CHUNK: u64 = 100_000;
+/// x0 to x31 and pc.
+const REGISTER_COUNT: usize = 33;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+/// Errno sent back for memory that cannot be accessed.
+const EFAULT: u8 = 14;
+
+/// How a debugging session ended.
+#[derive(Debug, PartialEq)]
+pub enum SessionEnd {
+    /// GDB detached or hung up. The guest can go on running without it.
+    Detached,
+    /// GDB killed the guest.
+    Killed,
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Whether packets are acknowledged, until GDB turns that off with QStartNoAckMode.
+    ack: bool,
+}
+
+impl Connection {
+    /// The next byte, `None` once GDB hung up.
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Payload of the next packet with a valid checksum. Acknowledgements and Ctrl-C outside
+    /// of a run are skipped.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                Some(b'$') => (),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut payload = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) if payload.len() < PACKET_SIZE => payload.push(byte),
+                    Some(_) => (),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok())
+                == Some(checksum_of(&payload));
+            if self.ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&payload).into_owned()));
+            }
+        }
+    }
+
+    /// Send a packet, again until GDB acknowledges it.
+    fn send(&mut self, payload: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", payload, checksum_of(payload.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if !self.ack {
+                return Ok(());
+            }
+            loop {
+                match self.read_byte()? {
+                    Some(b'+') => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => (),
+                    None => return Err(ErrorKind::UnexpectedEof.into()),
+                }
+            }
+        }
+    }
+
+    /// Whether GDB sent a Ctrl-C, without waiting for one.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = match self.stream.read(&mut byte) {
+            Ok(n) => Ok(n == 1 && byte[0] == 0x03),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+/// `addr,len` as in memory and breakpoint packets.
+fn parse_range(text: &str) -> Option<(u64, u64)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((parse_number(addr)?, parse_number(len)?))
+}
+
+/// A register value as GDB expects it: target byte order, little endian.
+fn decode_register(text: &str) -> Option<u64> {
+    let bytes: [u8; 8] = decode_hex(text)?.try_into().ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn read_register(cpu: &Cpu, n: usize) -> u64 {
+    match n {
+        32 => cpu.pc,
+        _ => cpu.read_reg(n),
+    }
+}
+
+fn write_register(cpu: &mut Cpu, n: usize, value: u64) {
+    match n {
+        0 => (),
+        32 => cpu.pc = value,
+        _ => cpu.write_reg(n, value),
+    }
+}
+
+/// Description of the registers `g` sends, so that GDB does not expect floating point ones.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><architecture>riscv:rv64</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (n, name) in RVABI.iter().enumerate() {
+        xml += &format!("<reg name=\"{}\" bitsize=\"64\" regnum=\"{}\"/>", name, n);
+    }
+    xml += "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"32\"/></feature></target>";
+    xml
+}
+
+/// `offset,length` of a qXfer read out of `data`.
+fn transfer(data: &str, range: &str) -> String {
+    let Some((offset, len)) = parse_range(range) else {
+        return "E01".to_string();
+    };
+    let start = (offset as usize).min(data.len());
+    let end = start.saturating_add(len as usize).min(data.len());
+    let more = if end < data.len() { "m" } else { "l" };
+    format!("{}{}", more, &data[start..end])
+}
+
+fn stop_reply(stop: &StopReason) -> String {
+    let signal = match stop {
+        StopReason::Exited { code } => return format!("W{:02x}", code & 0xff),
+        StopReason::Watchpoint { addr, kind } => {
+            let name = match kind {
+                WatchKind::Write => "watch",
+                _ => "rwatch",
+            };
+            return format!("T{:02x}{}:{:x};", SIGTRAP, name, addr);
+        }
+        StopReason::HistoryStart => return format!("T{:02x}replaylog:begin;", SIGTRAP),
+        StopReason::Interrupted => SIGINT,
+        StopReason::Exception(Exception::InvalidInstruction) => SIGILL,
+        StopReason::Exception(Exception::LoadAccessFault { .. })
+        | StopReason::Exception(Exception::StoreAMOAccessFault { .. }) => SIGSEGV,
+        _ => SIGTRAP,
+    };
+    format!("S{:02x}", signal)
+}
+
+/// Continue until something stops the guest or GDB sends a Ctrl-C.
+fn resume(cpu: &mut Cpu, connection: &mut Connection) -> io::Result<StopReason> {
+    loop {
+        match cpu.run(CHUNK) {
+            StopReason::Limit => {
+                // the next chunk would run the instruction at pc unconditionally
+                if cpu.has_breakpoint(cpu.pc) {
+                    return Ok(StopReason::Breakpoint { pc: cpu.pc });
+                }
+                if connection.interrupted()? {
+                    return Ok(StopReason::Interrupted);
+                }
+            }
+            stop => return Ok(stop),
+        }
+    }
+}
+
+/// Z and z packets: `type,addr,kind` where kind is the length for watchpoints.
+fn set_breakpoint(cpu: &mut Cpu, args: &str, insert: bool) -> String {
+    let Some((kind, range)) = args.split_once(',') else {
+        return "E01".to_string();
+    };
+    let Some((addr, len)) = parse_range(range.split(';').next().unwrap_or_default()) else {
+        return "E01".to_string();
+    };
+    let kind = match kind {
+        "0" | "1" => {
+            if insert {
+                cpu.add_breakpoint(addr);
+            } else {
+                cpu.remove_breakpoint(addr);
+            }
+            return "OK".to_string();
+        }
+        "2" => WatchKind::Write,
+        "3" => WatchKind::Read,
+        "4" => WatchKind::Access,
+        _ => return String::new(),
+    };
+    let watchpoint = Watchpoint { addr, len, kind };
+    if insert {
+        cpu.add_watchpoint(watchpoint);
+    } else {
+        cpu.remove_watchpoint(&watchpoint);
+    }
+    "OK".to_string()
+}
+
+/// Answer a packet that does not resume the guest.
+fn query(cpu: &mut Cpu, packet: &str) -> String {
+    if let Some(args) = packet.strip_prefix('p') {
+        return match parse_number(args) {
+            Some(n) if (n as usize) < REGISTER_COUNT => {
+                encode_hex(&read_register(cpu, n as usize).to_le_bytes())
+            }
+            _ => "E01".to_string(),
+        };
+    }
+    if let Some(args) = packet.strip_prefix('P') {
+        let parsed = args
+            .split_once('=')
+            .and_then(|(n, value)| Some((parse_number(n)?, decode_register(value)?)));
+        return match parsed {
+            Some((n, value)) if (n as usize) < REGISTER_COUNT => {
+                write_register(cpu, n as usize, value);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        };
+    }
+    if let Some(args) = packet.strip_prefix('G') {
+        for (n, chunk) in args.as_bytes().chunks(16).take(REGISTER_COUNT).enumerate() {
+            match std::str::from_utf8(chunk).ok().and_then(decode_register) {
+                Some(value) => write_register(cpu, n, value),
+                None => return "E01".to_string(),
+            }
+        }
+        return "OK".to_string();
+    }
+    if let Some(args) = packet.strip_prefix('m') {
+        let Some((addr, len)) = parse_range(args) else {
+            return "E01".to_string();
+        };
+        let mut data = vec![0; (len as usize).min(PACKET_SIZE / 2)];
+        return match cpu.bus.read_bytes(addr, &mut data) {
+            Ok(()) => encode_hex(&data),
+            Err(_) => format!("E{:02x}", EFAULT),
+        };
+    }
+    if let Some(args) = packet.strip_prefix('M') {
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+            let (addr, len) = parse_range(range)?;
+            Some((
+                addr,
+                decode_hex(data).filter(|data| data.len() as u64 == len)?,
+            ))
+        });
+        let Some((addr, data)) = parsed else {
+            return "E01".to_string();
+        };
+        return match cpu.bus.write_bytes(addr, &data) {
+            Ok(()) => "OK".to_string(),
+            Err(_) => format!("E{:02x}", EFAULT),
+        };
+    }
+    if let Some(args) = packet.strip_prefix('Z') {
+        return set_breakpoint(cpu, args, true);
+    }
+    if let Some(args) = packet.strip_prefix('z') {
+        return set_breakpoint(cpu, args, false);
+    }
+    if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+        return transfer(&target_xml(), range);
+    }
+    if packet.starts_with("qSupported") {
+        let mut features = format!(
+            "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+            PACKET_SIZE
+        );
+        if cpu.reverse_execution_enabled() {
+            features += ";ReverseStep+;ReverseContinue+";
+        }
+        return features;
+    }
+    match packet {
+        "?" => format!("S{:02x}", SIGTRAP),
+        "g" => (0..REGISTER_COUNT)
+            .map(|n| encode_hex(&read_register(cpu, n).to_le_bytes()))
+            .collect(),
+        "qAttached" => "1".to_string(),
+        "qC" => "QC1".to_string(),
+        "qfThreadInfo" => "m1".to_string(),
+        "qsThreadInfo" => "l".to_string(),
+        "vCont?" => "vCont;c;C;s;S".to_string(),
+        _ if packet.starts_with('H') || packet.starts_with('T') => "OK".to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Serve GDB on `stream` until it detaches, kills the guest or hangs up.
+pub fn serve(cpu: &mut Cpu, stream: TcpStream) -> io::Result<SessionEnd> {
+    stream.set_nodelay(true)?;
+    let mut connection = Connection { stream, ack: true };
+    while let Some(packet) = connection.receive()? {
+        // c and s may carry an address to resume at, C and S a signal to ignore
+        let (command, resume_at) = match packet.split_at_checked(1) {
+            Some(("c" | "s", addr)) => (&packet[..1], parse_number(addr)),
+            Some(("C" | "S", _)) => (&packet[..1], None),
+            _ => (packet.as_str(), None),
+        };
+        let command = match command {
+            "C" => "c",
+            "S" => "s",
+            _ if command.starts_with("vCont;c") || command.starts_with("vCont;C") => "c",
+            _ if command.starts_with("vCont;s") || command.starts_with("vCont;S") => "s",
+            _ => command,
+        };
+        let reply = match command {
+            "c" | "s" => {
+                if let Some(pc) = resume_at {
+                    cpu.pc = pc;
+                }
+                let stop = match command {
+                    "c" => resume(cpu, &mut connection)?,
+                    _ => cpu.step(),
+                };
+                stop_reply(&stop)
+            }
+            "bs" | "bc" => {
+                let result = match command {
+                    "bs" => cpu.reverse_step(),
+                    _ => cpu.reverse_continue(),
+                };
+                match result {
+                    Ok(stop) => stop_reply(&stop),
+                    Err(_) => "E01".to_string(),
+                }
+            }
+            "QStartNoAckMode" => {
+                connection.send("OK")?;
+                connection.ack = false;
+                continue;
+            }
+            "D" => {
+                connection.send("OK")?;
+                return Ok(SessionEnd::Detached);
+            }
+            "k" => return Ok(SessionEnd::Killed),
+            _ => query(cpu, command),
+        };
+        connection.send(&reply)?;
+    }
+    Ok(SessionEnd::Detached)
+}
@@ -0,0 +1,290 @@
+//! Host filesystem access on behalf of the guest, shared by the devices and environments that
+//! proxy file operations (9p, HTIF syscalls).
+use std::{
+    collections::BTreeMap,
+    fs::{File, Metadata, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::{
+        fd::AsFd,
+        unix::fs::{FileExt, OpenOptionsExt},
+    },
+    path::Path,
+};
+
+use super::replay::Channel;
+
+// Linux errno values, which is what guests expect back.
+pub const ENOENT: u32 = 2;
+pub const ESRCH: u32 = 3;
+pub const EIO: u32 = 5;
+pub const EBADF: u32 = 9;
+pub const EAGAIN: u32 = 11;
+pub const ENOMEM: u32 = 12;
+pub const EFAULT: u32 = 14;
+pub const EINVAL: u32 = 22;
+pub const ENOTTY: u32 = 25;
+pub const ESPIPE: u32 = 29;
+pub const ERANGE: u32 = 34;
+pub const ENAMETOOLONG: u32 = 36;
+pub const ENOSYS: u32 = 38;
+pub const ETIMEDOUT: u32 = 110;
+
+// Guest open flags (Linux generic values).
+pub const O_ACCMODE: u32 = 0o3;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+
+/// Directory file descriptor meaning "relative to the working directory".
+pub const AT_FDCWD: i64 = -100;
+
+/// First byte of a journaled stdin read that succeeded, followed by the data.
+const STDIN_DATA: u8 = 0;
+/// First byte of a journaled stdin read that failed, followed by the errno.
+const STDIN_ERROR: u8 = 1;
+
+pub fn errno(error: io::Error) -> u32 {
+    error.raw_os_error().map_or(EIO, |e| e as u32)
+}
+
+/// Host open options for guest open flags.
+pub fn open_options(flags: u32) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    match flags & O_ACCMODE {
+        O_WRONLY => options.write(true),
+        O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+    if flags & O_TRUNC != 0 {
+        options.truncate(true);
+    }
+    if flags & O_APPEND != 0 {
+        options.append(true);
+    }
+    if flags & O_CREAT != 0 {
+        if flags & O_ACCMODE == 0 {
+            options.write(true);
+        }
+        if flags & O_EXCL != 0 {
+            options.create_new(true);
+        } else {
+            options.create(true);
+        }
+    }
+    options
+}
+
+pub enum HostFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+impl HostFile {
+    pub fn try_clone(&self) -> Result<HostFile, u32> {
+        Ok(match self {
+            HostFile::Stdin => HostFile::Stdin,
+            HostFile::Stdout => HostFile::Stdout,
+            HostFile::Stderr => HostFile::Stderr,
+            HostFile::File(file) => HostFile::File(file.try_clone().map_err(errno)?),
+        })
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, u32> {
+        let fd = match self {
+            HostFile::File(file) => return file.metadata().map_err(errno),
+            HostFile::Stdin => io::stdin().as_fd().try_clone_to_owned(),
+            HostFile::Stdout => io::stdout().as_fd().try_clone_to_owned(),
+            HostFile::Stderr => io::stderr().as_fd().try_clone_to_owned(),
+        };
+        File::from(fd.map_err(errno)?).metadata().map_err(errno)
+    }
+}
+
+/// Read the host's stdin through `channel`. A read is journaled with a leading byte telling
+/// whether it succeeded, so that failures are replayed too. End of file is not journaled.
+fn read_journaled_stdin(channel: &Channel, buf: &mut [u8]) -> Result<usize, u32> {
+    let mut record = vec![0; buf.len().max(4) + 1];
+    let n = channel.input(&mut record, |record| {
+        let (status, rest) = record.split_first_mut().unwrap();
+        match io::stdin().read(&mut rest[..buf.len()]) {
+            Ok(0) => 0,
+            Ok(n) => {
+                *status = STDIN_DATA;
+                n + 1
+            }
+            Err(error) => {
+                *status = STDIN_ERROR;
+                rest[..4].copy_from_slice(&errno(error).to_le_bytes());
+                5
+            }
+        }
+    });
+    match record[..n].split_first() {
+        None => Ok(0),
+        Some((&STDIN_ERROR, errno)) if errno.len() == 4 => {
+            Err(u32::from_le_bytes(errno.try_into().unwrap()))
+        }
+        Some((_, data)) => {
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+    }
+}
+
+/// Guest file descriptors backed by host files. Descriptors 0, 1 and 2 start out as the
+/// host's standard streams, new ones get the lowest free number like on POSIX.
+pub struct FileTable {
+    files: BTreeMap<u64, HostFile>,
+    /// Records or replays what the guest reads from the host's stdin.
+    stdin_journal: Option<Channel>,
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileTable {
+    pub fn new() -> FileTable {
+        let mut files = BTreeMap::new();
+        files.insert(0, HostFile::Stdin);
+        files.insert(1, HostFile::Stdout);
+        files.insert(2, HostFile::Stderr);
+        Self {
+            files,
+            stdin_journal: None,
+        }
+    }
+
+    /// Record or replay reads from the host's stdin through `channel`.
+    pub fn set_stdin_journal(&mut self, channel: Channel) {
+        self.stdin_journal = Some(channel);
+    }
+
+    /// Store `file` under the lowest free descriptor.
+    pub fn insert(&mut self, file: HostFile) -> u64 {
+        self.insert_from(0, file)
+    }
+
+    /// Store `file` under the lowest free descriptor not below `min`.
+    pub fn insert_from(&mut self, min: u64, file: HostFile) -> u64 {
+        let fd = (min..).find(|fd| !self.files.contains_key(fd)).unwrap();
+        self.files.insert(fd, file);
+        fd
+    }
+
+    /// Make `fd` refer to `file`, closing what it referred to before.
+    pub fn insert_at(&mut self, fd: u64, file: HostFile) {
+        self.files.insert(fd, file);
+    }
+
+    /// A table with every descriptor duplicated, sharing file offsets like after fork(2).
+    pub fn try_clone(&self) -> Result<FileTable, u32> {
+        let files = self
+            .files
+            .iter()
+            .map(|(fd, file)| Ok((*fd, file.try_clone()?)))
+            .collect::<Result<_, u32>>()?;
+        Ok(Self {
+            files,
+            stdin_journal: self.stdin_journal.clone(),
+        })
+    }
+
+    pub fn dup(&mut self, fd: u64, min: u64) -> Result<u64, u32> {
+        let file = self.get_mut(fd)?.try_clone()?;
+        Ok(self.insert_from(min, file))
+    }
+
+    pub fn get_mut(&mut self, fd: u64) -> Result<&mut HostFile, u32> {
+        self.files.get_mut(&fd).ok_or(EBADF)
+    }
+
+    pub fn open(&mut self, path: &Path, flags: u32, mode: u32) -> Result<u64, u32> {
+        let file = open_options(flags)
+            .mode(mode & 0o7777)
+            .open(path)
+            .map_err(errno)?;
+        Ok(self.insert(HostFile::File(file)))
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<(), u32> {
+        self.files.remove(&fd).map(|_| ()).ok_or(EBADF)
+    }
+
+    pub fn read(&mut self, fd: u64, buf: &mut [u8]) -> Result<usize, u32> {
+        match self.files.get_mut(&fd).ok_or(EBADF)? {
+            HostFile::Stdin => match &self.stdin_journal {
+                Some(channel) => read_journaled_stdin(channel, buf),
+                None => io::stdin().read(buf).map_err(errno),
+            },
+            HostFile::File(file) => file.read(buf).map_err(errno),
+            HostFile::Stdout | HostFile::Stderr => Err(EBADF),
+        }
+    }
+
+    pub fn write(&mut self, fd: u64, data: &[u8]) -> Result<usize, u32> {
+        match self.get_mut(fd)? {
+            HostFile::Stdout => {
+                let mut stdout = io::stdout();
+                stdout.write_all(data).map_err(errno)?;
+                stdout.flush().map_err(errno)?;
+                Ok(data.len())
+            }
+            HostFile::Stderr => io::stderr()
+                .write_all(data)
+                .map(|_| data.len())
+                .map_err(errno),
+            HostFile::File(file) => file.write(data).map_err(errno),
+            HostFile::Stdin => Err(EBADF),
+        }
+    }
+
+    pub fn read_at(&mut self, fd: u64, buf: &mut [u8], offset: u64) -> Result<usize, u32> {
+        match self.get_mut(fd)? {
+            HostFile::File(file) => file.read_at(buf, offset).map_err(errno),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    pub fn write_at(&mut self, fd: u64, data: &[u8], offset: u64) -> Result<usize, u32> {
+        match self.get_mut(fd)? {
+            HostFile::File(file) => file.write_at(data, offset).map_err(errno),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    /// `whence` uses the SEEK_SET/SEEK_CUR/SEEK_END values 0, 1 and 2.
+    pub fn seek(&mut self, fd: u64, offset: i64, whence: u64) -> Result<u64, u32> {
+        let position = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+        match self.get_mut(fd)? {
+            HostFile::File(file) => file.seek(position).map_err(errno),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    /// Size of the file behind `fd`. Standard streams have none.
+    pub fn file_len(&mut self, fd: u64) -> Result<u64, u32> {
+        match self.get_mut(fd)? {
+            HostFile::File(file) => file.metadata().map(|m| m.len()).map_err(errno),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    /// Whether `fd` is one of the standard streams, which are treated as a terminal.
+    pub fn is_tty(&mut self, fd: u64) -> Result<bool, u32> {
+        Ok(!matches!(self.get_mut(fd)?, HostFile::File(_)))
+    }
+}
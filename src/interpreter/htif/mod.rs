@@ -0,0 +1,293 @@
+//! Berkeley host-target interface, as used by riscv-tests, riscv-pk and many bare-metal
+//! programs. The guest writes a command to the `tohost` word and the host answers through
+//! `fromhost`. Both are plain memory locations found through the ELF symbol table, so the
+//! execution loop polls them instead of them being decoded on the bus.
+//!
+//! A command is `device[63:56] cmd[55:48] payload[47:0]`:
+//! - device 0, payload with bit 0 set: exit with code `payload >> 1`.
+//! - device 0, otherwise: `payload` points to a syscall block for the proxy kernel.
+//! - device 1 cmd 0/1: console getchar/putchar.
+use std::{collections::VecDeque, io, path::Path, sync::Arc};
+
+use super::{
+    chardev::{CharBackend, Stdio},
+    dram::Dram,
+    exception::Exception,
+    host::{FileTable, AT_FDCWD, EFAULT, EINVAL, ENOMEM, ENOSYS},
+    replay::Journal,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    syscon::{restore_event, save_event, PowerEvent},
+    DRAM_SIZE,
+};
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+// Syscall numbers of the riscv-fesvr proxy, which follow the Linux generic ABI.
+pub const SYS_OPENAT: u64 = 56;
+pub const SYS_CLOSE: u64 = 57;
+pub const SYS_LSEEK: u64 = 62;
+pub const SYS_READ: u64 = 63;
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_EXIT_GROUP: u64 = 94;
+pub const SYS_OPEN: u64 = 1024;
+pub const SYS_GETMAINVARS: u64 = 2011;
+
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    /// Started on first use so a program that never touches the console leaves stdin alone.
+    console: Option<Box<dyn CharBackend>>,
+    files: FileTable,
+    /// Command line handed to the proxy kernel, program name first.
+    args: Vec<String>,
+    /// Answers waiting for the guest to clear `fromhost`.
+    responses: VecDeque<u64>,
+    getchar_pending: bool,
+    event: Option<PowerEvent>,
+}
+
+fn command(device: u64, cmd: u64, payload: u64) -> u64 {
+    (device << 56) | (cmd << 48) | (payload & 0xffff_ffff_ffff)
+}
+
+fn result<T: Into<u64>>(value: Result<T, u32>) -> u64 {
+    match value {
+        Ok(value) => value.into(),
+        Err(errno) => (errno as i64).wrapping_neg() as u64,
+    }
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>) -> Htif {
+        Self {
+            tohost,
+            fromhost,
+            console: None,
+            files: FileTable::new(),
+            args: Vec::new(),
+            responses: VecDeque::new(),
+            getchar_pending: false,
+            event: None,
+        }
+    }
+
+    pub fn with_console(mut self, console: Box<dyn CharBackend>) -> Htif {
+        self.console = Some(console);
+        self
+    }
+
+    pub fn with_args(mut self, args: Vec<String>) -> Htif {
+        self.args = args;
+        self
+    }
+
+    /// Record or replay what the proxied syscalls read from stdin. The console is journaled
+    /// by its backend.
+    pub fn with_journal(mut self, journal: &Arc<Journal>) -> Htif {
+        self.files.set_stdin_journal(journal.channel("htif-stdin"));
+        self
+    }
+
+    /// A copy for a forked machine. Open files are duplicated; the console is not, the
+    /// child's one starts on stdio when first used.
+    pub fn fork(&self) -> Result<Htif, String> {
+        Ok(Self {
+            tohost: self.tohost,
+            fromhost: self.fromhost,
+            console: None,
+            files: self
+                .files
+                .try_clone()
+                .map_err(|errno| format!("cannot duplicate HTIF files: errno {}", errno))?,
+            args: self.args.clone(),
+            responses: self.responses.clone(),
+            getchar_pending: self.getchar_pending,
+            event: self.event,
+        })
+    }
+
+    pub fn take_event(&mut self) -> Option<PowerEvent> {
+        self.event.take()
+    }
+
+    /// Answers not yet delivered and pending requests for a snapshot. Open files are host
+    /// state and not saved.
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        w.u32(self.responses.len() as u32);
+        for response in &self.responses {
+            w.u64(*response);
+        }
+        w.u8(self.getchar_pending as u8);
+        save_event(w, self.event);
+    }
+
+    pub(crate) fn restore_state(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        let count = r.u32()?;
+        self.responses.clear();
+        for _ in 0..count {
+            self.responses.push_back(r.u64()?);
+        }
+        self.getchar_pending = r.u8()? != 0;
+        self.event = restore_event(r)?;
+        Ok(())
+    }
+
+    fn console(&mut self) -> &mut dyn CharBackend {
+        self.console
+            .get_or_insert_with(|| Box::new(Stdio::new()))
+            .as_mut()
+    }
+
+    /// Handle a pending command, then deliver a queued answer if `fromhost` is free.
+    pub fn tick(&mut self, dram: &mut Dram) {
+        let mut word = [0; 8];
+        if dram.read_bytes(self.tohost, &mut word).is_err() {
+            return;
+        }
+        let value = u64::from_le_bytes(word);
+        if value != 0 {
+            let _ = dram.write_bytes(self.tohost, &[0; 8]);
+            self.handle(value, dram);
+        }
+
+        if self.getchar_pending {
+            let mut byte = [0];
+            if self.console().read(&mut byte) == 1 {
+                self.getchar_pending = false;
+                self.respond(command(DEVICE_CONSOLE, CONSOLE_GETCHAR, byte[0] as u64));
+            }
+        }
+        if let (Some(fromhost), Some(response)) = (self.fromhost, self.responses.front()) {
+            let mut word = [0; 8];
+            if dram.read_bytes(fromhost, &mut word).is_ok() && u64::from_le_bytes(word) == 0 {
+                let _ = dram.write_bytes(fromhost, &response.to_le_bytes());
+                self.responses.pop_front();
+            }
+        }
+    }
+
+    /// Queue an answer, unless there is no `fromhost` to ever deliver it through.
+    fn respond(&mut self, response: u64) {
+        if self.fromhost.is_some() {
+            self.responses.push_back(response);
+        }
+    }
+
+    fn handle(&mut self, value: u64, dram: &mut Dram) {
+        let device = value >> 56;
+        let cmd = (value >> 48) & 0xff;
+        let payload = value & 0xffff_ffff_ffff;
+        match (device, cmd) {
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => {
+                self.event = Some(PowerEvent::PowerOff {
+                    exit_code: (payload >> 1) as u32,
+                });
+            }
+            (DEVICE_SYSCALL, 0) => {
+                if self.syscall(payload, dram).is_ok() {
+                    self.respond(command(DEVICE_SYSCALL, 0, 1));
+                }
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                self.console().write(&[payload as u8]);
+                self.respond(command(DEVICE_CONSOLE, CONSOLE_PUTCHAR, 0));
+            }
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => self.getchar_pending = true,
+            _ => eprintln!("htif: unsupported command {:#x}", value),
+        }
+    }
+
+    /// Run the syscall described by the 8 words at `addr` (number and arguments) and store the
+    /// result in the first word.
+    fn syscall(&mut self, addr: u64, dram: &mut Dram) -> Result<(), Exception> {
+        let mut block = [0; 64];
+        dram.read_bytes(addr, &mut block)?;
+        let words: Vec<u64> = block
+            .chunks(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        let args = &words[1..];
+        let ret = match words[0] {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.event = Some(PowerEvent::PowerOff {
+                    exit_code: args[0] as u32,
+                });
+                0
+            }
+            SYS_READ => result(self.read(dram, args[0], args[1], args[2])),
+            SYS_WRITE => result(self.write(dram, args[0], args[1], args[2])),
+            SYS_OPENAT => {
+                result(self.openat(dram, args[0] as i64, args[1], args[2], args[3], args[4]))
+            }
+            SYS_OPEN => result(self.openat(dram, AT_FDCWD, args[0], args[1], args[2], args[3])),
+            SYS_CLOSE => result(self.files.close(args[0]).map(|_| 0u64)),
+            SYS_LSEEK => result(self.files.seek(args[0], args[1] as i64, args[2])),
+            SYS_GETMAINVARS => result(self.getmainvars(dram, args[0], args[1])),
+            number => {
+                eprintln!("htif: unsupported syscall {}", number);
+                result::<u64>(Err(ENOSYS))
+            }
+        };
+        dram.write_bytes(addr, &ret.to_le_bytes())
+    }
+
+    fn read(&mut self, dram: &mut Dram, fd: u64, buf: u64, len: u64) -> Result<u64, u32> {
+        let mut data = vec![0; len.min(DRAM_SIZE) as usize];
+        let n = self.files.read(fd, &mut data)?;
+        dram.write_bytes(buf, &data[..n]).map_err(|_| EFAULT)?;
+        Ok(n as u64)
+    }
+
+    fn write(&mut self, dram: &Dram, fd: u64, buf: u64, len: u64) -> Result<u64, u32> {
+        let mut data = vec![0; len.min(DRAM_SIZE) as usize];
+        dram.read_bytes(buf, &mut data).map_err(|_| EFAULT)?;
+        self.files.write(fd, &data).map(|n| n as u64)
+    }
+
+    /// `len` counts the terminating NUL of the path.
+    fn openat(
+        &mut self,
+        dram: &Dram,
+        dirfd: i64,
+        path: u64,
+        len: u64,
+        flags: u64,
+        mode: u64,
+    ) -> Result<u64, u32> {
+        let mut name = vec![0; len.min(DRAM_SIZE) as usize];
+        dram.read_bytes(path, &mut name).map_err(|_| EFAULT)?;
+        if let Some(end) = name.iter().position(|b| *b == 0) {
+            name.truncate(end);
+        }
+        let name = String::from_utf8(name).map_err(|_| EINVAL)?;
+        // Descriptors are not tied to host directories, so only cwd-relative lookups work.
+        if dirfd != AT_FDCWD && !name.starts_with('/') {
+            return Err(EINVAL);
+        }
+        self.files.open(Path::new(&name), flags as u32, mode as u32)
+    }
+
+    /// Lay out argc, argv and an empty envp, followed by the argument strings, at `buf`.
+    fn getmainvars(&mut self, dram: &mut Dram, buf: u64, limit: u64) -> Result<u64, u32> {
+        let header = (self.args.len() as u64 + 3) * 8;
+        let mut words = vec![self.args.len() as u64];
+        let mut strings = Vec::new();
+        for arg in &self.args {
+            words.push(buf + header + strings.len() as u64);
+            strings.extend_from_slice(arg.as_bytes());
+            strings.push(0);
+        }
+        words.extend_from_slice(&[0, 0]);
+        let mut data: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        data.extend_from_slice(&strings);
+        if data.len() as u64 > limit {
+            return Err(ENOMEM);
+        }
+        dram.write_bytes(buf, &data).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+}
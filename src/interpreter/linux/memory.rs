@@ -0,0 +1,114 @@
+//! Sparse address space of a user-mode process. Pages are mapped explicitly (ELF segments,
+//! stack, brk, mmap) and only get backing storage once written.
+use std::collections::HashMap;
+
+use crate::interpreter::exception::Exception;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+type Page = Box<[u8; PAGE_SIZE as usize]>;
+
+pub fn page_align_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+pub fn page_align_up(addr: u64) -> u64 {
+    addr.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[derive(Default)]
+pub struct UserMemory {
+    /// Mapped pages by page number. `None` is a page that still reads as zero.
+    pages: HashMap<u64, Option<Page>>,
+}
+
+impl UserMemory {
+    pub fn new() -> UserMemory {
+        Self {
+            pages: HashMap::new(),
+        }
+    }
+
+    fn page_range(addr: u64, len: u64) -> std::ops::Range<u64> {
+        page_align_down(addr) / PAGE_SIZE..page_align_up(addr.saturating_add(len)) / PAGE_SIZE
+    }
+
+    /// Map the pages covering `addr..addr + len` as zero filled memory, replacing whatever
+    /// was mapped there.
+    pub fn map(&mut self, addr: u64, len: u64) {
+        for page in Self::page_range(addr, len) {
+            self.pages.insert(page, None);
+        }
+    }
+
+    pub fn unmap(&mut self, addr: u64, len: u64) {
+        for page in Self::page_range(addr, len) {
+            self.pages.remove(&page);
+        }
+    }
+
+    pub fn is_mapped(&self, addr: u64, len: u64) -> bool {
+        Self::page_range(addr, len).all(|page| self.pages.contains_key(&page))
+    }
+
+    /// Lowest page aligned address at or above `from` with `len` unmapped bytes.
+    pub fn find_free(&self, from: u64, len: u64) -> u64 {
+        let pages = page_align_up(len) / PAGE_SIZE;
+        let mut start = page_align_up(from) / PAGE_SIZE;
+        loop {
+            match (start..start + pages).find(|page| self.pages.contains_key(page)) {
+                Some(used) => start = used + 1,
+                None => return start * PAGE_SIZE,
+            }
+        }
+    }
+
+    pub fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        let mut done = 0;
+        while done < buf.len() {
+            let current = addr.wrapping_add(done as u64);
+            let offset = (current % PAGE_SIZE) as usize;
+            let n = (PAGE_SIZE as usize - offset).min(buf.len() - done);
+            match self.pages.get(&(current / PAGE_SIZE)) {
+                Some(Some(page)) => buf[done..done + n].copy_from_slice(&page[offset..offset + n]),
+                Some(None) => buf[done..done + n].fill(0),
+                None => return Err(Exception::LoadAccessFault { address: current }),
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        let mut done = 0;
+        while done < data.len() {
+            let current = addr.wrapping_add(done as u64);
+            let offset = (current % PAGE_SIZE) as usize;
+            let n = (PAGE_SIZE as usize - offset).min(data.len() - done);
+            let page = self
+                .pages
+                .get_mut(&(current / PAGE_SIZE))
+                .ok_or(Exception::StoreAMOAccessFault { address: current })?
+                .get_or_insert_with(|| Box::new([0; PAGE_SIZE as usize]));
+            page[offset..offset + n].copy_from_slice(&data[done..done + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if ![8, 16, 32, 64].contains(&size) {
+            return Err(Exception::LoadAccessFault { address: addr });
+        }
+        let mut bytes = [0; 8];
+        self.read_bytes(addr, &mut bytes[..(size / 8) as usize])?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if ![8, 16, 32, 64].contains(&size) {
+            return Err(Exception::StoreAMOAccessFault { address: addr });
+        }
+        self.write_bytes(addr, &value.to_le_bytes()[..(size / 8) as usize])
+    }
+}
@@ -0,0 +1,381 @@
+//! Linux user-mode emulation in the style of qemu-user: a static RV64 ELF runs in its own
+//! sparse address space and its `ecall`s are translated to host system calls. Dynamically
+//! linked programs get their loader, and through it their libraries, from a guest sysroot.
+//! The hart only implements RV64I, see `check_extensions`.
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use self::{
+    memory::{page_align_down, page_align_up, UserMemory, PAGE_SIZE},
+    signal::{Signals, SIGRETURN_CODE},
+    thread::{Reschedule, Thread, QUANTUM},
+};
+use super::{
+    cpu::Cpu,
+    elf::{Elf, ET_DYN, PT_PHDR},
+    exception::Exception,
+    host::FileTable,
+};
+
+pub mod memory;
+pub mod signal;
+mod syscall;
+pub mod thread;
+
+/// End of the Sv39 user address space.
+pub const USER_TOP: u64 = 0x40_0000_0000;
+/// The initial stack ends right below the top of the Sv39 user address space.
+pub const STACK_TOP: u64 = 0x3f_ffff_f000;
+pub const STACK_SIZE: u64 = 8 * 1024 * 1024;
+/// Signal handlers return to code at the page right above the stack.
+pub const SIGRETURN_TRAMPOLINE: u64 = STACK_TOP;
+/// Where position independent executables are loaded.
+pub const PIE_BASE: u64 = 0x10_0000_0000;
+/// Start of the area searched for mmap'ed ranges without an address hint.
+pub const MMAP_BASE: u64 = 0x20_0000_0000;
+/// Where the program interpreter (the dynamic loader) is loaded.
+pub const INTERPRETER_BASE: u64 = 0x3e_0000_0000;
+/// AT_HWCAP has one bit per single letter extension, the hart only implements the base ISA.
+pub const HWCAP: u64 = 1 << (b'i' - b'a');
+
+// Auxiliary vector entry types.
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_HWCAP: u64 = 16;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_PLATFORM: u64 = 15;
+pub const AT_CLKTCK: u64 = 17;
+pub const AT_SECURE: u64 = 23;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+
+pub struct LinuxProcess {
+    pub cpu: Cpu,
+    files: FileTable,
+    brk_start: u64,
+    brk: u64,
+    /// Host path of the executable, for `/proc/self/exe`.
+    exe: PathBuf,
+    /// Directory absolute guest paths are looked up in first.
+    sysroot: Option<PathBuf>,
+    start: Instant,
+    exit_status: Option<u32>,
+    /// Thread group id, which is the tid of the first thread.
+    pid: u64,
+    /// The thread on the hart.
+    tid: u64,
+    clear_child_tid: u64,
+    next_tid: u64,
+    /// Threads waiting for the hart, in scheduling order.
+    threads: VecDeque<Thread>,
+    reschedule: Option<Reschedule>,
+    signals: Signals,
+}
+
+/// Fill `buf` from the host's entropy pool.
+fn random_bytes(buf: &mut [u8]) {
+    let filled = File::open("/dev/urandom").and_then(|mut file| file.read_exact(buf));
+    if filled.is_err() {
+        buf.fill(0);
+    }
+}
+
+/// Map and copy the PT_LOAD segments of `elf`, shifted by `bias`. Returns the end of the
+/// highest segment. Segments must end below the stack.
+fn load_segments(memory: &mut UserMemory, elf: &Elf, bias: u64) -> Result<u64, Exception> {
+    let mut end = 0;
+    for header in elf.loadable() {
+        let start = header.vaddr.wrapping_add(bias);
+        let segment_end = start
+            .checked_add(header.memsz)
+            .filter(|end| *end <= STACK_TOP - STACK_SIZE)
+            .ok_or(Exception::StoreAMOAccessFault { address: start })?;
+        memory.map(start, header.memsz);
+        memory.write_bytes(start, elf.segment_data(header))?;
+        end = end.max(segment_end);
+    }
+    Ok(end)
+}
+
+/// Address the program headers of `elf` end up at once loaded with `bias`.
+fn program_headers_address(elf: &Elf, bias: u64) -> u64 {
+    let vaddr = match elf.program_headers.iter().find(|h| h.kind == PT_PHDR) {
+        Some(header) => header.vaddr,
+        None => elf
+            .loadable()
+            .find(|h| h.offset <= elf.phoff && elf.phoff < h.offset + h.filesz)
+            .map_or(0, |h| h.vaddr.wrapping_add(elf.phoff - h.offset)),
+    };
+    vaddr.wrapping_add(bias)
+}
+
+/// Host path for the absolute guest path `path`: the file under `sysroot` if there is one,
+/// otherwise the host's own.
+pub fn sysroot_path(sysroot: Option<&Path>, path: &str) -> PathBuf {
+    if let (Some(sysroot), Some(relative)) = (sysroot, path.strip_prefix('/')) {
+        let candidate = sysroot.join(relative);
+        if fs::symlink_metadata(&candidate).is_ok() {
+            return candidate;
+        }
+    }
+    PathBuf::from(path)
+}
+
+/// Refuse `elf` if its build attributes ask for single letter extensions the hart lacks. Only
+/// RV64I is implemented, so for instance the lr/sc and atomic memory operations of A, which
+/// C libraries use for locks, would only raise SIGILL once the program runs.
+pub fn check_extensions(elf: &Elf) -> Result<(), String> {
+    let Some(arch) = &elf.arch else {
+        return Ok(());
+    };
+    let mut missing = Vec::new();
+    for part in arch.to_lowercase().trim_start_matches("rv64").split('_') {
+        // multi-letter extensions such as zicsr
+        if part.starts_with(['z', 's', 'x']) {
+            continue;
+        }
+        let mut previous = ' ';
+        for c in part.chars() {
+            // versions like 2p1 follow each extension
+            let version = c.is_ascii_digit() || (c == 'p' && previous.is_ascii_digit());
+            previous = c;
+            if version {
+                continue;
+            }
+            let letters = if c == 'g' {
+                "IMAFD".to_string()
+            } else {
+                c.to_uppercase().collect()
+            };
+            for letter in letters.chars() {
+                if letter != 'I' && !missing.contains(&letter) {
+                    missing.push(letter);
+                }
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let missing: Vec<String> = missing.iter().map(char::to_string).collect();
+    Err(format!(
+        "built for {}, but only RV64I is implemented and {} is missing",
+        arch,
+        missing.join(", ")
+    ))
+}
+
+/// Read and parse the program interpreter `elf` asks for in PT_INTERP, if any.
+pub fn load_interpreter(elf: &Elf, sysroot: Option<&Path>) -> Result<Option<Elf>, String> {
+    let Some(name) = elf.interpreter() else {
+        return Ok(None);
+    };
+    let path = sysroot_path(sysroot, &name);
+    let data = fs::read(&path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    let interpreter = Elf::parse(data).map_err(|error| format!("{}: {}", path.display(), error))?;
+    if interpreter.kind != ET_DYN {
+        return Err(format!("{}: interpreter is not position independent", name));
+    }
+    check_extensions(&interpreter).map_err(|error| format!("{}: {}", name, error))?;
+    Ok(Some(interpreter))
+}
+
+impl LinuxProcess {
+    /// Load the static executable `elf` and set up the initial stack with `args` (program
+    /// name first) and `env`.
+    pub fn new(elf: &Elf, args: &[String], env: &[String]) -> Result<LinuxProcess, Exception> {
+        Self::with_interpreter(elf, None, args, env)
+    }
+
+    /// Like `new`, but also load the program interpreter of a dynamically linked `elf` (see
+    /// `load_interpreter`) and start there. The interpreter finds the executable through the
+    /// auxiliary vector.
+    pub fn with_interpreter(
+        elf: &Elf,
+        interpreter: Option<&Elf>,
+        args: &[String],
+        env: &[String],
+    ) -> Result<LinuxProcess, Exception> {
+        let mut memory = UserMemory::new();
+        let bias = if elf.kind == ET_DYN { PIE_BASE } else { 0 };
+        let end = load_segments(&mut memory, elf, bias)?;
+        let (entry, base) = match interpreter {
+            Some(interpreter) => {
+                let low = interpreter.loadable().map(|h| h.vaddr).min().unwrap_or(0);
+                let interpreter_bias = INTERPRETER_BASE.wrapping_sub(page_align_down(low));
+                load_segments(&mut memory, interpreter, interpreter_bias)?;
+                (
+                    interpreter.entry.wrapping_add(interpreter_bias),
+                    interpreter_bias,
+                )
+            }
+            None => (elf.entry.wrapping_add(bias), 0),
+        };
+        memory.map(STACK_TOP - STACK_SIZE, STACK_SIZE);
+        let trampoline: Vec<u8> = SIGRETURN_CODE
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        memory.map(SIGRETURN_TRAMPOLINE, PAGE_SIZE);
+        memory.write_bytes(SIGRETURN_TRAMPOLINE, &trampoline)?;
+
+        let auxv = vec![
+            (AT_PHDR, program_headers_address(elf, bias)),
+            (AT_PHENT, elf.phentsize as u64),
+            (AT_PHNUM, elf.program_headers.len() as u64),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_BASE, base),
+            (AT_ENTRY, elf.entry.wrapping_add(bias)),
+            (AT_HWCAP, HWCAP),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+        ];
+        let sp = build_stack(&mut memory, args, env, auxv)?;
+
+        let mut cpu = Cpu::new(vec![]);
+        cpu.bus.set_user_memory(memory);
+        cpu.pc = entry;
+        cpu.regs = [0; 32];
+        cpu.regs[2] = sp;
+        let brk = page_align_up(end);
+        Ok(Self {
+            cpu,
+            files: FileTable::new(),
+            brk_start: brk,
+            brk,
+            exe: PathBuf::from(args.first().map_or("", |arg| arg.as_str())),
+            sysroot: None,
+            start: Instant::now(),
+            exit_status: None,
+            pid: std::process::id() as u64,
+            tid: std::process::id() as u64,
+            clear_child_tid: 0,
+            next_tid: std::process::id() as u64 + 1,
+            threads: VecDeque::new(),
+            reschedule: None,
+            signals: Signals::default(),
+        })
+    }
+
+    /// Run until the program exits and return its exit status, which is 128 plus the signal
+    /// number if a signal killed it, or `thread::DEADLOCK_STATUS` if all its threads wait
+    /// forever. An exception other than a system call, such as an access fault, ends the run
+    /// with that exception unless the program handles the signal for it.
+    pub fn run(&mut self) -> Result<u32, Exception> {
+        loop {
+            if let Some(status) = self.exit_status {
+                return Ok(status);
+            }
+            self.check_alarm();
+            if let Some(status) = self.deliver_signal()? {
+                return Ok(status);
+            }
+            let limit = if self.threads.is_empty() && self.signals.alarm.is_none() {
+                u64::MAX
+            } else {
+                self.cpu.instret + QUANTUM
+            };
+            match self.cpu.execute_until(limit) {
+                Some(Exception::EnvironmentCall) => {
+                    self.cpu.pc = self.cpu.pc.wrapping_add(4);
+                    self.syscall();
+                    self.cpu.instret += 1;
+                    if let Some(status) = self.exit_status {
+                        return Ok(status);
+                    }
+                    match self.reschedule.take() {
+                        Some(Reschedule::Yield) => self.switch_thread(false, None),
+                        Some(Reschedule::Wait { futex, deadline }) => {
+                            self.switch_thread(false, Some((futex, deadline)))
+                        }
+                        Some(Reschedule::Exit) => self.switch_thread(true, None),
+                        None => (),
+                    }
+                }
+                Some(exception) => self.fault(exception)?,
+                None if self.cpu.exit_code.is_some() => return Ok(self.cpu.exit_code.unwrap()),
+                // the time slice is used up
+                None => self.switch_thread(false, None),
+            }
+        }
+    }
+
+    /// Look up absolute paths the program uses under `sysroot` first, like the loader and
+    /// the libraries it opens.
+    pub fn set_sysroot(&mut self, sysroot: PathBuf) {
+        self.sysroot = Some(sysroot);
+    }
+
+    /// Number of threads, including the running one.
+    pub fn thread_count(&self) -> usize {
+        self.threads.len() + 1
+    }
+
+    fn memory(&mut self) -> &mut UserMemory {
+        self.cpu.bus.user_memory_mut().unwrap()
+    }
+}
+
+/// Lay out the strings, argv, envp and auxv the way the kernel does and return the initial
+/// stack pointer, which points at argc.
+fn build_stack(
+    memory: &mut UserMemory,
+    args: &[String],
+    env: &[String],
+    mut auxv: Vec<(u64, u64)>,
+) -> Result<u64, Exception> {
+    let mut sp = STACK_TOP;
+    let mut push = |memory: &mut UserMemory, data: &[u8]| {
+        sp -= data.len() as u64;
+        memory.write_bytes(sp, data).map(|_| sp)
+    };
+    let execfn = push(
+        memory,
+        format!("{}\0", args.first().map_or("", |a| a)).as_bytes(),
+    )?;
+    let mut string_addrs = Vec::new();
+    for string in args.iter().chain(env) {
+        string_addrs.push(push(memory, format!("{}\0", string).as_bytes())?);
+    }
+    let platform = push(memory, b"riscv64\0")?;
+    let mut random = [0; 16];
+    random_bytes(&mut random);
+    let random = push(memory, &random)?;
+    auxv.extend_from_slice(&[
+        (AT_PLATFORM, platform),
+        (AT_RANDOM, random),
+        (AT_EXECFN, execfn),
+        (AT_NULL, 0),
+    ]);
+
+    let (arg_addrs, env_addrs) = string_addrs.split_at(args.len());
+    let mut words = vec![args.len() as u64];
+    words.extend_from_slice(arg_addrs);
+    words.push(0);
+    words.extend_from_slice(env_addrs);
+    words.push(0);
+    for (key, value) in auxv {
+        words.extend_from_slice(&[key, value]);
+    }
+    let data: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let sp = (sp - data.len() as u64) & !0xf;
+    memory.write_bytes(sp, &data)?;
+    Ok(sp)
+}
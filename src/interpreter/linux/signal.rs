@@ -0,0 +1,388 @@
+//! POSIX signals. Pending signals belong to the process and are delivered to the running
+//! thread between instructions: its registers are saved in a `ucontext` frame on the guest
+//! stack, the handler runs and returns through a trampoline calling `rt_sigreturn`, which
+//! restores them. Access faults become SIGSEGV if the guest handles it.
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use super::{LinuxProcess, SIGRETURN_TRAMPOLINE};
+use crate::interpreter::{
+    exception::Exception,
+    host::{EINVAL, ESRCH},
+};
+
+pub const SIGINT: u64 = 2;
+pub const SIGILL: u64 = 4;
+pub const SIGTRAP: u64 = 5;
+pub const SIGABRT: u64 = 6;
+pub const SIGKILL: u64 = 9;
+pub const SIGUSR1: u64 = 10;
+pub const SIGSEGV: u64 = 11;
+pub const SIGUSR2: u64 = 12;
+pub const SIGALRM: u64 = 14;
+pub const SIGTERM: u64 = 15;
+pub const SIGCHLD: u64 = 17;
+pub const SIGCONT: u64 = 18;
+pub const SIGSTOP: u64 = 19;
+pub const SIGTSTP: u64 = 20;
+pub const SIGTTIN: u64 = 21;
+pub const SIGTTOU: u64 = 22;
+pub const SIGURG: u64 = 23;
+pub const SIGWINCH: u64 = 28;
+pub const NSIG: u64 = 64;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+pub const SA_SIGINFO: u64 = 0x4;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+const SIG_BLOCK: u64 = 0;
+const SIG_UNBLOCK: u64 = 1;
+const SIG_SETMASK: u64 = 2;
+
+// si_code values.
+const SI_USER: i32 = 0;
+const SI_KERNEL: i32 = 0x80;
+const SI_TKILL: i32 = -6;
+const SEGV_MAPERR: i32 = 1;
+const ILL_ILLOPC: i32 = 1;
+const TRAP_BRKPT: i32 = 1;
+
+const ITIMER_REAL: u64 = 0;
+
+/// The signal frame is a `siginfo_t` followed by a `ucontext_t`. The machine context in
+/// there starts with pc and x1..x31, then the floating point state, which stays zero.
+const SIGINFO_SIZE: u64 = 128;
+const UC_STACK_FLAGS: u64 = 24;
+const UC_SIGMASK: u64 = 40;
+const UC_MCONTEXT: u64 = 176;
+const UCONTEXT_SIZE: u64 = UC_MCONTEXT + 32 * 8 + 528;
+const SS_DISABLE: u32 = 2;
+
+/// `li a7, 139 (rt_sigreturn); ecall`, what handlers return to.
+pub(super) const SIGRETURN_CODE: [u32; 2] = [0x08b0_0893, 0x0000_0073];
+
+fn bit(signal: u64) -> u64 {
+    1 << (signal - 1)
+}
+
+/// SIGKILL and SIGSTOP can neither be blocked nor handled.
+const UNBLOCKABLE: u64 = 1 << (SIGKILL - 1) | 1 << (SIGSTOP - 1);
+
+/// `struct sigaction` of the generic ABI, which on RISC-V has no restorer.
+#[derive(Clone, Copy, Default)]
+pub(super) struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub mask: u64,
+}
+
+/// What ends up in the `siginfo_t` of a pending signal.
+#[derive(Clone, Copy)]
+pub(super) struct SigInfo {
+    pub code: i32,
+    /// The faulting address, or the sending pid.
+    pub value: u64,
+}
+
+/// ITIMER_REAL, which raises SIGALRM.
+pub(super) struct Alarm {
+    pub deadline: Instant,
+    pub interval: Duration,
+}
+
+pub(super) struct Signals {
+    pub actions: [SigAction; NSIG as usize],
+    pub pending: BTreeMap<u64, SigInfo>,
+    /// Blocked signals of the running thread.
+    pub mask: u64,
+    pub alarm: Option<Alarm>,
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self {
+            actions: [SigAction::default(); NSIG as usize],
+            pending: BTreeMap::new(),
+            mask: 0,
+            alarm: None,
+        }
+    }
+}
+
+/// Whether the default action of `signal` is to do nothing (stop signals included, as there
+/// is nobody to continue the process).
+fn ignored_by_default(signal: u64) -> bool {
+    matches!(
+        signal,
+        SIGCHLD | SIGCONT | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU | SIGURG | SIGWINCH
+    )
+}
+
+/// timeval pair of a `struct itimerval`.
+fn timeval(duration: Duration) -> [u64; 2] {
+    [duration.as_secs(), duration.subsec_micros() as u64]
+}
+
+impl LinuxProcess {
+    /// Make `signal` pending; a signal that is already pending is not queued again.
+    pub fn raise(&mut self, signal: u64) {
+        self.raise_with(
+            signal,
+            SigInfo {
+                code: SI_KERNEL,
+                value: 0,
+            },
+        );
+    }
+
+    fn raise_with(&mut self, signal: u64, info: SigInfo) {
+        self.signals.pending.entry(signal).or_insert(info);
+    }
+
+    /// rt_sigaction(signal, act, oldact, sigsetsize)
+    pub(super) fn sigaction(&mut self, a: [u64; 6]) -> Result<u64, u32> {
+        let (signal, act, old) = (a[0], a[1], a[2]);
+        if !(1..=NSIG).contains(&signal) || act != 0 && UNBLOCKABLE & bit(signal) != 0 {
+            return Err(EINVAL);
+        }
+        let current = self.signals.actions[signal as usize - 1];
+        if old != 0 {
+            let words = [current.handler, current.flags, current.mask];
+            self.write_guest(old, &words.map(u64::to_le_bytes).concat())?;
+        }
+        if act != 0 {
+            self.signals.actions[signal as usize - 1] = SigAction {
+                handler: self.read_word(act)?,
+                flags: self.read_word(act + 8)?,
+                mask: self.read_word(act + 16)? & !UNBLOCKABLE,
+            };
+        }
+        Ok(0)
+    }
+
+    /// rt_sigprocmask(how, set, oldset, sigsetsize)
+    pub(super) fn sigprocmask(&mut self, a: [u64; 6]) -> Result<u64, u32> {
+        let (how, set, old) = (a[0], a[1], a[2]);
+        if old != 0 {
+            self.write_guest(old, &self.signals.mask.to_le_bytes())?;
+        }
+        if set != 0 {
+            let set = self.read_word(set)? & !UNBLOCKABLE;
+            self.signals.mask = match how {
+                SIG_BLOCK => self.signals.mask | set,
+                SIG_UNBLOCK => self.signals.mask & !set,
+                SIG_SETMASK => set,
+                _ => return Err(EINVAL),
+            };
+        }
+        Ok(0)
+    }
+
+    /// kill(pid, signal), tkill(tid, signal) and tgkill(pid, tid, signal). Only the process
+    /// itself can be signalled; which of its threads is named makes no difference.
+    pub(super) fn kill(
+        &mut self,
+        pid: Option<u64>,
+        tid: Option<u64>,
+        signal: u64,
+    ) -> Result<u64, u32> {
+        if signal > NSIG {
+            return Err(EINVAL);
+        }
+        if pid.is_some_and(|pid| ![0, self.pid, u64::MAX].contains(&pid)) {
+            return Err(ESRCH);
+        }
+        if tid.is_some_and(|tid| tid != self.tid && !self.threads.iter().any(|t| t.tid == tid)) {
+            return Err(ESRCH);
+        }
+        if signal != 0 {
+            let code = if tid.is_some() { SI_TKILL } else { SI_USER };
+            self.raise_with(
+                signal,
+                SigInfo {
+                    code,
+                    value: self.pid,
+                },
+            );
+        }
+        Ok(0)
+    }
+
+    /// getitimer(which, value)
+    pub(super) fn getitimer(&mut self, a: [u64; 6]) -> Result<u64, u32> {
+        if a[0] != ITIMER_REAL {
+            return Err(EINVAL);
+        }
+        self.write_itimer(a[1])?;
+        Ok(0)
+    }
+
+    /// setitimer(which, new, old), which is also how `alarm` is implemented.
+    pub(super) fn setitimer(&mut self, a: [u64; 6]) -> Result<u64, u32> {
+        if a[0] != ITIMER_REAL {
+            return Err(EINVAL);
+        }
+        if a[2] != 0 {
+            self.write_itimer(a[2])?;
+        }
+        let mut words = [0; 4];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = self.read_word(a[1] + i as u64 * 8)?;
+        }
+        let duration = |sec: u64, usec: u64| Duration::new(sec, (usec.min(999_999) * 1000) as u32);
+        let value = duration(words[2], words[3]);
+        self.signals.alarm = (!value.is_zero()).then(|| Alarm {
+            deadline: Instant::now() + value,
+            interval: duration(words[0], words[1]),
+        });
+        Ok(0)
+    }
+
+    fn write_itimer(&mut self, addr: u64) -> Result<(), u32> {
+        let (interval, value) = match &self.signals.alarm {
+            Some(alarm) => (
+                alarm.interval,
+                alarm.deadline.saturating_duration_since(Instant::now()),
+            ),
+            None => (Duration::ZERO, Duration::ZERO),
+        };
+        let words = [timeval(interval), timeval(value)].concat();
+        self.write_guest(
+            addr,
+            &words
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Raise SIGALRM if the timer expired.
+    pub(super) fn check_alarm(&mut self) {
+        let Some(alarm) = &mut self.signals.alarm else {
+            return;
+        };
+        let now = Instant::now();
+        if now < alarm.deadline {
+            return;
+        }
+        if alarm.interval.is_zero() {
+            self.signals.alarm = None;
+        } else {
+            alarm.deadline = now + alarm.interval;
+        }
+        self.raise(SIGALRM);
+    }
+
+    /// Turn an exception of the running thread into a synchronous signal. Without a handler
+    /// for it, or with it blocked, the exception ends the run.
+    pub(super) fn fault(&mut self, exception: Exception) -> Result<(), Exception> {
+        let (signal, info) = match exception {
+            Exception::LoadAccessFault { address } | Exception::StoreAMOAccessFault { address } => {
+                (
+                    SIGSEGV,
+                    SigInfo {
+                        code: SEGV_MAPERR,
+                        value: address,
+                    },
+                )
+            }
+            Exception::InvalidInstruction => (
+                SIGILL,
+                SigInfo {
+                    code: ILL_ILLOPC,
+                    value: self.cpu.pc,
+                },
+            ),
+            Exception::Breakpoint => (
+                SIGTRAP,
+                SigInfo {
+                    code: TRAP_BRKPT,
+                    value: self.cpu.pc,
+                },
+            ),
+            Exception::EnvironmentCall => return Err(exception),
+        };
+        let handler = self.signals.actions[signal as usize - 1].handler;
+        if handler == SIG_DFL || handler == SIG_IGN || self.signals.mask & bit(signal) != 0 {
+            return Err(exception);
+        }
+        self.signals.pending.insert(signal, info);
+        Ok(())
+    }
+
+    /// Deliver the lowest pending signal the running thread does not block. Returns the exit
+    /// status if that terminates the process.
+    pub(super) fn deliver_signal(&mut self) -> Result<Option<u32>, Exception> {
+        let mask = self.signals.mask;
+        let Some(&signal) = self.signals.pending.keys().find(|&&s| mask & bit(s) == 0) else {
+            return Ok(None);
+        };
+        let info = self.signals.pending.remove(&signal).unwrap();
+        let action = self.signals.actions[signal as usize - 1];
+        match action.handler {
+            SIG_IGN => return Ok(None),
+            SIG_DFL if ignored_by_default(signal) => return Ok(None),
+            // what a shell reports for a process killed by a signal
+            SIG_DFL => return Ok(Some(128 + signal as u32)),
+            _ => (),
+        }
+
+        let frame = self.cpu.regs[2].wrapping_sub(SIGINFO_SIZE + UCONTEXT_SIZE) & !0xf;
+        let mut data = vec![0; (SIGINFO_SIZE + UCONTEXT_SIZE) as usize];
+        data[0..4].copy_from_slice(&(signal as i32).to_le_bytes());
+        data[8..12].copy_from_slice(&info.code.to_le_bytes());
+        data[16..24].copy_from_slice(&info.value.to_le_bytes());
+        let uc = SIGINFO_SIZE as usize;
+        let flags = uc + UC_STACK_FLAGS as usize;
+        data[flags..flags + 4].copy_from_slice(&SS_DISABLE.to_le_bytes());
+        let sigmask = uc + UC_SIGMASK as usize;
+        data[sigmask..sigmask + 8].copy_from_slice(&mask.to_le_bytes());
+        for i in 0..32 {
+            let value = if i == 0 {
+                self.cpu.pc
+            } else {
+                self.cpu.regs[i]
+            };
+            let offset = uc + UC_MCONTEXT as usize + i * 8;
+            data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        if self.write_guest(frame, &data).is_err() {
+            // no room for the frame, the kernel would kill the process with SIGSEGV
+            return Err(Exception::StoreAMOAccessFault { address: frame });
+        }
+
+        self.cpu.regs[10] = signal;
+        self.cpu.regs[11] = frame;
+        self.cpu.regs[12] = frame + SIGINFO_SIZE;
+        self.cpu.regs[2] = frame;
+        self.cpu.regs[1] = SIGRETURN_TRAMPOLINE;
+        self.cpu.pc = action.handler;
+        self.signals.mask |= action.mask;
+        if action.flags & SA_NODEFER == 0 {
+            self.signals.mask |= bit(signal);
+        }
+        if action.flags & SA_RESETHAND != 0 {
+            self.signals.actions[signal as usize - 1] = SigAction::default();
+        }
+        Ok(None)
+    }
+
+    /// rt_sigreturn: restore the registers and signal mask saved in the frame at sp. Returns
+    /// a0, which the system call return must not clobber.
+    pub(super) fn sigreturn(&mut self) -> Result<u64, u32> {
+        let uc = self.cpu.regs[2] + SIGINFO_SIZE;
+        let mask = self.read_word(uc + UC_SIGMASK)?;
+        let mut regs = [0; 32];
+        for (i, reg) in regs.iter_mut().enumerate() {
+            *reg = self.read_word(uc + UC_MCONTEXT + i as u64 * 8)?;
+        }
+        self.cpu.pc = regs[0];
+        self.cpu.regs[1..].copy_from_slice(&regs[1..]);
+        self.signals.mask = mask & !UNBLOCKABLE;
+        Ok(self.cpu.regs[10])
+    }
+}
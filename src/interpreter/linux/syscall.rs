@@ -0,0 +1,522 @@
+//! Translation of the Linux RISC-V system call ABI: the number is in a7, arguments in a0..a5
+//! and the result, or a negated errno, goes back to a0.
+use std::{
+    fs::{self, Metadata},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{DirBuilderExt, MetadataExt},
+    },
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    memory::{page_align_up, PAGE_SIZE},
+    random_bytes, sysroot_path,
+    thread::Reschedule,
+    LinuxProcess, MMAP_BASE, STACK_SIZE, USER_TOP,
+};
+use crate::interpreter::host::{
+    errno, HostFile, AT_FDCWD, EBADF, EFAULT, EINVAL, ENAMETOOLONG, ENOMEM, ENOSYS, ENOTTY, ERANGE,
+    O_RDWR, O_WRONLY,
+};
+
+pub const SYS_GETCWD: u64 = 17;
+pub const SYS_DUP: u64 = 23;
+pub const SYS_DUP3: u64 = 24;
+pub const SYS_FCNTL: u64 = 25;
+pub const SYS_IOCTL: u64 = 29;
+pub const SYS_MKDIRAT: u64 = 34;
+pub const SYS_UNLINKAT: u64 = 35;
+pub const SYS_FACCESSAT: u64 = 48;
+pub const SYS_OPENAT: u64 = 56;
+pub const SYS_CLOSE: u64 = 57;
+pub const SYS_LSEEK: u64 = 62;
+pub const SYS_READ: u64 = 63;
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_READV: u64 = 65;
+pub const SYS_WRITEV: u64 = 66;
+pub const SYS_PREAD64: u64 = 67;
+pub const SYS_PWRITE64: u64 = 68;
+pub const SYS_READLINKAT: u64 = 78;
+pub const SYS_NEWFSTATAT: u64 = 79;
+pub const SYS_FSTAT: u64 = 80;
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_EXIT_GROUP: u64 = 94;
+pub const SYS_SET_TID_ADDRESS: u64 = 96;
+pub const SYS_FUTEX: u64 = 98;
+pub const SYS_SET_ROBUST_LIST: u64 = 99;
+pub const SYS_GETITIMER: u64 = 102;
+pub const SYS_SETITIMER: u64 = 103;
+pub const SYS_CLOCK_GETTIME: u64 = 113;
+pub const SYS_SCHED_YIELD: u64 = 124;
+pub const SYS_KILL: u64 = 129;
+pub const SYS_TKILL: u64 = 130;
+pub const SYS_TGKILL: u64 = 131;
+pub const SYS_RT_SIGACTION: u64 = 134;
+pub const SYS_RT_SIGPROCMASK: u64 = 135;
+pub const SYS_RT_SIGRETURN: u64 = 139;
+pub const SYS_UNAME: u64 = 160;
+pub const SYS_GETTIMEOFDAY: u64 = 169;
+pub const SYS_GETPID: u64 = 172;
+pub const SYS_GETPPID: u64 = 173;
+pub const SYS_GETUID: u64 = 174;
+pub const SYS_GETEUID: u64 = 175;
+pub const SYS_GETGID: u64 = 176;
+pub const SYS_GETEGID: u64 = 177;
+pub const SYS_GETTID: u64 = 178;
+pub const SYS_BRK: u64 = 214;
+pub const SYS_MUNMAP: u64 = 215;
+pub const SYS_CLONE: u64 = 220;
+pub const SYS_MMAP: u64 = 222;
+pub const SYS_MPROTECT: u64 = 226;
+pub const SYS_MADVISE: u64 = 233;
+pub const SYS_PRLIMIT64: u64 = 261;
+pub const SYS_GETRANDOM: u64 = 278;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const F_DUPFD: u64 = 0;
+const F_GETFD: u64 = 1;
+const F_SETFD: u64 = 2;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+const F_DUPFD_CLOEXEC: u64 = 1030;
+
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_REMOVEDIR: u64 = 0x200;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_REALTIME_COARSE: u64 = 5;
+const RLIMIT_STACK: u64 = 3;
+const RLIM_INFINITY: u64 = u64::MAX;
+
+const PATH_MAX: usize = 4096;
+/// Upper bound for a single transfer; larger requests complete partially, which the guest
+/// has to handle anyway.
+const MAX_IO: u64 = 1 << 24;
+
+type SyscallResult = Result<u64, u32>;
+
+/// `struct stat` of the generic Linux ABI used by RISC-V.
+fn stat_bytes(metadata: &Metadata) -> Vec<u8> {
+    let mut data = Vec::with_capacity(128);
+    data.extend_from_slice(&metadata.dev().to_le_bytes());
+    data.extend_from_slice(&metadata.ino().to_le_bytes());
+    data.extend_from_slice(&metadata.mode().to_le_bytes());
+    data.extend_from_slice(&(metadata.nlink() as u32).to_le_bytes());
+    data.extend_from_slice(&metadata.uid().to_le_bytes());
+    data.extend_from_slice(&metadata.gid().to_le_bytes());
+    data.extend_from_slice(&metadata.rdev().to_le_bytes());
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.extend_from_slice(&metadata.size().to_le_bytes());
+    data.extend_from_slice(&(metadata.blksize() as u32).to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&metadata.blocks().to_le_bytes());
+    for (sec, nsec) in [
+        (metadata.atime(), metadata.atime_nsec()),
+        (metadata.mtime(), metadata.mtime_nsec()),
+        (metadata.ctime(), metadata.ctime_nsec()),
+    ] {
+        data.extend_from_slice(&sec.to_le_bytes());
+        data.extend_from_slice(&nsec.to_le_bytes());
+    }
+    data.resize(128, 0);
+    data
+}
+
+/// `struct utsname` field: 65 bytes, NUL padded.
+fn uts_field(value: &str) -> [u8; 65] {
+    let mut field = [0; 65];
+    field[..value.len()].copy_from_slice(value.as_bytes());
+    field
+}
+
+impl LinuxProcess {
+    /// Service the system call the hart is stopped at.
+    pub(super) fn syscall(&mut self) {
+        let number = self.cpu.regs[17];
+        let args: [u64; 6] = self.cpu.regs[10..16].try_into().unwrap();
+        let result = match self.dispatch(number, args) {
+            Ok(value) => value,
+            Err(errno) => (errno as i64).wrapping_neg() as u64,
+        };
+        if self.exit_status.is_none() {
+            self.cpu.regs[10] = result;
+        }
+    }
+
+    fn dispatch(&mut self, number: u64, a: [u64; 6]) -> SyscallResult {
+        match number {
+            SYS_GETCWD => {
+                let cwd = std::env::current_dir().map_err(errno)?;
+                let path = format!("{}\0", cwd.display());
+                if path.len() as u64 > a[1] {
+                    return Err(ERANGE);
+                }
+                self.write_guest(a[0], path.as_bytes())?;
+                Ok(path.len() as u64)
+            }
+            SYS_DUP => self.files.dup(a[0], 0),
+            SYS_DUP3 => {
+                if a[0] == a[1] {
+                    return Err(EINVAL);
+                }
+                let file = self.files.get_mut(a[0])?.try_clone()?;
+                self.files.insert_at(a[1], file);
+                Ok(a[1])
+            }
+            SYS_FCNTL => match a[1] {
+                F_DUPFD | F_DUPFD_CLOEXEC => self.files.dup(a[0], a[2]),
+                F_GETFD | F_SETFD | F_SETFL => self.files.get_mut(a[0]).map(|_| 0),
+                F_GETFL => Ok(match self.files.get_mut(a[0])? {
+                    HostFile::Stdin => 0,
+                    HostFile::Stdout | HostFile::Stderr => O_WRONLY as u64,
+                    HostFile::File(_) => O_RDWR as u64,
+                }),
+                _ => Err(EINVAL),
+            },
+            // Terminal control is not emulated, every descriptor reports it is no terminal.
+            SYS_IOCTL => self.files.get_mut(a[0]).and(Err(ENOTTY)),
+            SYS_MKDIRAT => {
+                let path = self.path(a[0], a[1])?;
+                fs::DirBuilder::new()
+                    .mode(a[2] as u32 & 0o7777)
+                    .create(path)
+                    .map(|_| 0)
+                    .map_err(errno)
+            }
+            SYS_UNLINKAT => {
+                let path = self.path(a[0], a[1])?;
+                if a[2] & AT_REMOVEDIR != 0 {
+                    fs::remove_dir(path).map(|_| 0).map_err(errno)
+                } else {
+                    fs::remove_file(path).map(|_| 0).map_err(errno)
+                }
+            }
+            SYS_FACCESSAT => {
+                let path = self.path(a[0], a[1])?;
+                fs::metadata(path).map(|_| 0).map_err(errno)
+            }
+            SYS_OPENAT => {
+                let path = self.path(a[0], a[1])?;
+                self.files.open(&path, a[2] as u32, a[3] as u32)
+            }
+            SYS_CLOSE => self.files.close(a[0]).map(|_| 0),
+            SYS_LSEEK => self.files.seek(a[0], a[1] as i64, a[2]),
+            SYS_READ => {
+                let mut data = vec![0; a[2].min(MAX_IO) as usize];
+                let n = self.files.read(a[0], &mut data)?;
+                self.write_guest(a[1], &data[..n])?;
+                Ok(n as u64)
+            }
+            SYS_WRITE => {
+                let data = self.read_guest(a[1], a[2].min(MAX_IO))?;
+                self.files.write(a[0], &data).map(|n| n as u64)
+            }
+            SYS_READV | SYS_WRITEV => {
+                let mut total = 0;
+                for i in 0..a[2] {
+                    let iov = a[1]
+                        .checked_add(i * 16)
+                        .filter(|iov| iov.checked_add(16).is_some())
+                        .ok_or(EFAULT)?;
+                    let base = self.read_word(iov)?;
+                    let len = self.read_word(iov + 8)?.min(MAX_IO);
+                    if base.checked_add(len).is_none() {
+                        return Err(EFAULT);
+                    }
+                    let n = if number == SYS_READV {
+                        let mut data = vec![0; len as usize];
+                        let n = self.files.read(a[0], &mut data)?;
+                        self.write_guest(base, &data[..n])?;
+                        n as u64
+                    } else {
+                        let data = self.read_guest(base, len)?;
+                        self.files.write(a[0], &data)? as u64
+                    };
+                    total += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                Ok(total)
+            }
+            SYS_PREAD64 => {
+                let mut data = vec![0; a[2].min(MAX_IO) as usize];
+                let n = self.files.read_at(a[0], &mut data, a[3])?;
+                self.write_guest(a[1], &data[..n])?;
+                Ok(n as u64)
+            }
+            SYS_PWRITE64 => {
+                let data = self.read_guest(a[1], a[2].min(MAX_IO))?;
+                self.files.write_at(a[0], &data, a[3]).map(|n| n as u64)
+            }
+            SYS_READLINKAT => {
+                let name = self.read_string(a[1])?;
+                let target = if name == "/proc/self/exe" {
+                    fs::canonicalize(&self.exe).map_err(errno)?
+                } else {
+                    fs::read_link(self.resolve(a[0], name)?).map_err(errno)?
+                };
+                let target = target.into_os_string().into_encoded_bytes();
+                let n = target.len().min(a[3] as usize);
+                self.write_guest(a[2], &target[..n])?;
+                Ok(n as u64)
+            }
+            SYS_NEWFSTATAT => {
+                let name = self.read_string(a[1])?;
+                let metadata = if name.is_empty() && a[3] & AT_EMPTY_PATH != 0 {
+                    self.files.get_mut(a[0])?.metadata()?
+                } else {
+                    let path = self.resolve(a[0], name)?;
+                    if a[3] & AT_SYMLINK_NOFOLLOW != 0 {
+                        fs::symlink_metadata(path).map_err(errno)?
+                    } else {
+                        fs::metadata(path).map_err(errno)?
+                    }
+                };
+                self.write_guest(a[2], &stat_bytes(&metadata))?;
+                Ok(0)
+            }
+            SYS_FSTAT => {
+                let metadata = self.files.get_mut(a[0])?.metadata()?;
+                self.write_guest(a[1], &stat_bytes(&metadata))?;
+                Ok(0)
+            }
+            SYS_EXIT if !self.threads.is_empty() => {
+                self.exit_thread()?;
+                self.reschedule = Some(Reschedule::Exit);
+                Ok(0)
+            }
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_status = Some(a[0] as u8 as u32);
+                Ok(0)
+            }
+            SYS_SET_TID_ADDRESS => {
+                self.clear_child_tid = a[0];
+                Ok(self.tid)
+            }
+            SYS_GETPID => Ok(self.pid),
+            SYS_GETTID => Ok(self.tid),
+            SYS_CLONE => self.clone_thread(a),
+            SYS_FUTEX => self.futex(a),
+            SYS_SCHED_YIELD => {
+                self.reschedule = Some(Reschedule::Yield);
+                Ok(0)
+            }
+            SYS_GETPPID => Ok(std::os::unix::process::parent_id() as u64),
+            SYS_GETUID | SYS_GETEUID => {
+                Ok(fs::metadata("/proc/self").map_or(0, |m| m.uid()) as u64)
+            }
+            SYS_GETGID | SYS_GETEGID => {
+                Ok(fs::metadata("/proc/self").map_or(0, |m| m.gid()) as u64)
+            }
+            SYS_SET_ROBUST_LIST => Ok(0),
+            SYS_KILL => self.kill(Some(a[0]), None, a[1]),
+            SYS_TKILL => self.kill(None, Some(a[0]), a[1]),
+            SYS_TGKILL => self.kill(Some(a[0]), Some(a[1]), a[2]),
+            SYS_RT_SIGACTION => self.sigaction(a),
+            SYS_RT_SIGPROCMASK => self.sigprocmask(a),
+            SYS_RT_SIGRETURN => self.sigreturn(),
+            SYS_GETITIMER => self.getitimer(a),
+            SYS_SETITIMER => self.setitimer(a),
+            SYS_CLOCK_GETTIME => {
+                let (sec, nsec) = self.clock(a[0]);
+                self.write_guest(a[1], &[sec.to_le_bytes(), nsec.to_le_bytes()].concat())?;
+                Ok(0)
+            }
+            SYS_GETTIMEOFDAY => {
+                if a[0] != 0 {
+                    let (sec, nsec) = self.clock(CLOCK_REALTIME);
+                    let usec = nsec / 1000;
+                    self.write_guest(a[0], &[sec.to_le_bytes(), usec.to_le_bytes()].concat())?;
+                }
+                Ok(0)
+            }
+            SYS_UNAME => {
+                let fields = ["Linux", "riscv", "6.1.0", "#1", "riscv64", ""];
+                let data: Vec<u8> = fields.iter().flat_map(|f| uts_field(f)).collect();
+                self.write_guest(a[0], &data)?;
+                Ok(0)
+            }
+            SYS_BRK => Ok(self.brk(a[0])),
+            SYS_MUNMAP => {
+                if !a[0].is_multiple_of(PAGE_SIZE)
+                    || a[1] == 0
+                    || a[0] > USER_TOP
+                    || page_align_up(a[1]) > USER_TOP - a[0]
+                {
+                    return Err(EINVAL);
+                }
+                self.memory().unmap(a[0], a[1]);
+                Ok(0)
+            }
+            SYS_MMAP => self.mmap(a[0], a[1], a[3], a[4] as i64, a[5]),
+            // Page protections are not enforced.
+            SYS_MPROTECT | SYS_MADVISE => Ok(0),
+            SYS_PRLIMIT64 => {
+                if a[3] != 0 {
+                    let current = if a[1] == RLIMIT_STACK {
+                        STACK_SIZE
+                    } else {
+                        RLIM_INFINITY
+                    };
+                    self.write_guest(
+                        a[3],
+                        &[current.to_le_bytes(), RLIM_INFINITY.to_le_bytes()].concat(),
+                    )?;
+                }
+                Ok(0)
+            }
+            SYS_GETRANDOM => {
+                let mut data = vec![0; a[1].min(MAX_IO) as usize];
+                random_bytes(&mut data);
+                self.write_guest(a[0], &data)?;
+                Ok(data.len() as u64)
+            }
+            _ => {
+                eprintln!("linux: unsupported syscall {}", number);
+                Err(ENOSYS)
+            }
+        }
+    }
+
+    pub(super) fn read_guest(&mut self, addr: u64, len: u64) -> Result<Vec<u8>, u32> {
+        let mut data = vec![0; len as usize];
+        self.cpu
+            .bus
+            .read_bytes(addr, &mut data)
+            .map_err(|_| EFAULT)?;
+        Ok(data)
+    }
+
+    pub(super) fn write_guest(&mut self, addr: u64, data: &[u8]) -> Result<(), u32> {
+        self.cpu.bus.write_bytes(addr, data).map_err(|_| EFAULT)
+    }
+
+    pub(super) fn read_word(&mut self, addr: u64) -> Result<u64, u32> {
+        let data = self.read_guest(addr, 8)?;
+        Ok(u64::from_le_bytes(data.try_into().unwrap()))
+    }
+
+    /// NUL terminated string at `addr`.
+    fn read_string(&mut self, addr: u64) -> Result<String, u32> {
+        let mut data = Vec::new();
+        loop {
+            let next = addr.checked_add(data.len() as u64).ok_or(EFAULT)?;
+            let byte = self.read_guest(next, 1)?[0];
+            if byte == 0 {
+                break;
+            }
+            if data.len() == PATH_MAX {
+                return Err(ENAMETOOLONG);
+            }
+            data.push(byte);
+        }
+        String::from_utf8(data).map_err(|_| EINVAL)
+    }
+
+    /// Host path for `name` looked up relative to the directory descriptor `dirfd`.
+    fn resolve(&mut self, dirfd: u64, name: String) -> Result<PathBuf, u32> {
+        if name.starts_with('/') {
+            return Ok(sysroot_path(self.sysroot.as_deref(), &name));
+        }
+        if dirfd as i64 == AT_FDCWD {
+            return Ok(PathBuf::from(name));
+        }
+        match self.files.get_mut(dirfd)? {
+            HostFile::File(dir) => {
+                Ok(PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd())).join(name))
+            }
+            _ => Err(EBADF),
+        }
+    }
+
+    fn path(&mut self, dirfd: u64, addr: u64) -> Result<PathBuf, u32> {
+        let name = self.read_string(addr)?;
+        self.resolve(dirfd, name)
+    }
+
+    fn clock(&self, clock: u64) -> (u64, u64) {
+        let time = match clock {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            _ => self.start.elapsed(),
+        };
+        (time.as_secs(), time.subsec_nanos() as u64)
+    }
+
+    /// Move the program break to `addr` if possible and return the resulting break.
+    fn brk(&mut self, addr: u64) -> u64 {
+        if addr < self.brk_start || addr > USER_TOP {
+            return self.brk;
+        }
+        let old_end = page_align_up(self.brk);
+        let new_end = page_align_up(addr);
+        if new_end > old_end {
+            let len = new_end - old_end;
+            if self.memory().find_free(old_end, len) != old_end {
+                return self.brk;
+            }
+            self.memory().map(old_end, len);
+        } else {
+            self.memory().unmap(new_end, old_end - new_end);
+        }
+        self.brk = addr;
+        addr
+    }
+
+    fn mmap(&mut self, addr: u64, len: u64, flags: u64, fd: i64, offset: u64) -> SyscallResult {
+        if len == 0 || !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(EINVAL);
+        }
+        // Pages are tracked one by one, so a length past the address space is refused before
+        // anything is mapped.
+        if page_align_up(len) > USER_TOP {
+            return Err(ENOMEM);
+        }
+        let start = if flags & MAP_FIXED != 0 {
+            if !addr.is_multiple_of(PAGE_SIZE) {
+                return Err(EINVAL);
+            }
+            addr
+        } else {
+            let hint = if addr == 0 { MMAP_BASE } else { addr };
+            self.memory().find_free(hint, len)
+        };
+        if start > USER_TOP - page_align_up(len) {
+            return Err(ENOMEM);
+        }
+        self.memory().map(start, len);
+        if flags & MAP_ANONYMOUS == 0 {
+            if let Err(err) = self.map_file(start, len, fd as u64, offset) {
+                self.memory().unmap(start, len);
+                return Err(err);
+            }
+        }
+        Ok(start)
+    }
+
+    /// Copy the contents of `fd` from `offset` on into the mapping at `start`, until `len`
+    /// bytes or the end of the file, one `MAX_IO` chunk at a time.
+    fn map_file(&mut self, start: u64, len: u64, fd: u64, offset: u64) -> Result<(), u32> {
+        let mut data = vec![0; len.min(MAX_IO) as usize];
+        let mut filled = 0;
+        while filled < len {
+            let chunk = (len - filled).min(MAX_IO) as usize;
+            let n = self
+                .files
+                .read_at(fd, &mut data[..chunk], offset.saturating_add(filled))?;
+            if n == 0 {
+                break;
+            }
+            self.write_guest(start + filled, &data[..n])?;
+            filled += n as u64;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! Guest threads. All threads share the process' address space, so instead of one `Cpu` per
+//! thread the single hart is time-sliced: the running thread's registers live in the `Cpu`,
+//! the others are saved here and swapped in round-robin, either when the running thread
+//! blocks or yields, or after a fixed number of instructions. This keeps runs deterministic.
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{signal::SIGKILL, LinuxProcess};
+use crate::interpreter::host::{EAGAIN, EINVAL, ENOSYS, ETIMEDOUT};
+
+/// Instructions a thread runs before the next runnable thread gets the hart.
+pub const QUANTUM: u64 = 10_000;
+/// Exit status when every thread sleeps on a futex without a timeout. Nothing could ever wake
+/// them, so the process ends as if SIGKILL had killed it.
+pub const DEADLOCK_STATUS: u32 = 128 + SIGKILL as u32;
+
+pub const CLONE_VM: u64 = 0x100;
+pub const CLONE_SETTLS: u64 = 0x80000;
+pub const CLONE_PARENT_SETTID: u64 = 0x100000;
+pub const CLONE_CHILD_CLEARTID: u64 = 0x200000;
+pub const CLONE_THREAD: u64 = 0x10000;
+pub const CLONE_CHILD_SETTID: u64 = 0x1000000;
+
+pub const FUTEX_WAIT: u64 = 0;
+pub const FUTEX_WAKE: u64 = 1;
+pub const FUTEX_WAIT_BITSET: u64 = 9;
+pub const FUTEX_WAKE_BITSET: u64 = 10;
+const FUTEX_PRIVATE_FLAG: u64 = 128;
+const FUTEX_CLOCK_REALTIME: u64 = 256;
+
+/// What the running thread asked the scheduler for during a system call.
+pub(super) enum Reschedule {
+    Yield,
+    /// Sleep until woken through the futex at this address, or until the deadline.
+    Wait {
+        futex: u64,
+        deadline: Option<Instant>,
+    },
+    Exit,
+}
+
+pub(super) struct Thread {
+    pub tid: u64,
+    pub regs: [u64; 32],
+    pub pc: u64,
+    /// Cleared and woken as a futex when the thread exits, for `pthread_join`.
+    pub clear_child_tid: u64,
+    /// Futex the thread sleeps on, and when that sleep times out.
+    pub waiting: Option<(u64, Option<Instant>)>,
+    pub signal_mask: u64,
+}
+
+impl LinuxProcess {
+    /// clone(flags, stack, parent_tid, tls, child_tid). Only threads are supported, not new
+    /// processes.
+    pub(super) fn clone_thread(&mut self, a: [u64; 6]) -> Result<u64, u32> {
+        let (flags, stack, parent_tid, tls, child_tid) = (a[0], a[1], a[2], a[3], a[4]);
+        if flags & (CLONE_VM | CLONE_THREAD) != CLONE_VM | CLONE_THREAD {
+            return Err(ENOSYS);
+        }
+        let tid = self.next_tid;
+        self.next_tid += 1;
+
+        let mut regs = self.cpu.regs;
+        regs[10] = 0;
+        if stack != 0 {
+            regs[2] = stack;
+        }
+        if flags & CLONE_SETTLS != 0 {
+            regs[4] = tls;
+        }
+        if flags & CLONE_PARENT_SETTID != 0 {
+            self.write_guest(parent_tid, &(tid as u32).to_le_bytes())?;
+        }
+        if flags & CLONE_CHILD_SETTID != 0 {
+            self.write_guest(child_tid, &(tid as u32).to_le_bytes())?;
+        }
+        self.threads.push_back(Thread {
+            tid,
+            regs,
+            // pc already points past the ecall
+            pc: self.cpu.pc,
+            clear_child_tid: if flags & CLONE_CHILD_CLEARTID != 0 {
+                child_tid
+            } else {
+                0
+            },
+            waiting: None,
+            signal_mask: self.signals.mask,
+        });
+        Ok(tid)
+    }
+
+    /// futex(addr, op, val, timeout, addr2, val3)
+    pub(super) fn futex(&mut self, a: [u64; 6]) -> Result<u64, u32> {
+        let (addr, op, value, timeout) = (a[0], a[1], a[2], a[3]);
+        match op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME) {
+            // The bitset variants are treated as if every bit was set.
+            FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+                let current = u32::from_le_bytes(self.read_guest(addr, 4)?.try_into().unwrap());
+                if current != value as u32 {
+                    return Err(EAGAIN);
+                }
+                let deadline = match timeout {
+                    0 => None,
+                    _ => Some(self.futex_deadline(op, timeout)?),
+                };
+                self.reschedule = Some(Reschedule::Wait {
+                    futex: addr,
+                    deadline,
+                });
+                Ok(0)
+            }
+            FUTEX_WAKE | FUTEX_WAKE_BITSET => Ok(self.futex_wake(addr, value)),
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// When a wait with the timespec at `addr` times out. FUTEX_WAIT takes a relative
+    /// timeout, the bitset variant an absolute time on the monotonic or realtime clock.
+    fn futex_deadline(&mut self, op: u64, addr: u64) -> Result<Instant, u32> {
+        let words = self.read_guest(addr, 16)?;
+        let sec = u64::from_le_bytes(words[..8].try_into().unwrap());
+        let nsec = u64::from_le_bytes(words[8..].try_into().unwrap());
+        let time = Duration::new(sec, nsec.min(999_999_999) as u32);
+        let now = Instant::now();
+        Ok(
+            if op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME) == FUTEX_WAIT {
+                now + time
+            } else if op & FUTEX_CLOCK_REALTIME != 0 {
+                let since_epoch = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                now + time.saturating_sub(since_epoch)
+            } else {
+                self.start + time
+            },
+        )
+    }
+
+    /// Wake up to `count` threads sleeping on `addr` and return how many woke up.
+    pub(super) fn futex_wake(&mut self, addr: u64, count: u64) -> u64 {
+        let mut woken = 0;
+        for thread in &mut self.threads {
+            if woken == count {
+                break;
+            }
+            if thread.waiting.is_some_and(|(futex, _)| futex == addr) {
+                thread.waiting = None;
+                woken += 1;
+            }
+        }
+        woken
+    }
+
+    /// End the running thread, waking whoever joins it.
+    pub(super) fn exit_thread(&mut self) -> Result<(), u32> {
+        if self.clear_child_tid != 0 {
+            self.write_guest(self.clear_child_tid, &0u32.to_le_bytes())?;
+            self.futex_wake(self.clear_child_tid, 1);
+        }
+        Ok(())
+    }
+
+    /// Put the running thread back in the queue, unless it exited, and move the next
+    /// runnable thread onto the hart. If none can ever run again, the process exits with
+    /// `DEADLOCK_STATUS`.
+    pub(super) fn switch_thread(&mut self, exited: bool, waiting: Option<(u64, Option<Instant>)>) {
+        if !exited {
+            self.threads.push_back(Thread {
+                tid: self.tid,
+                regs: self.cpu.regs,
+                pc: self.cpu.pc,
+                clear_child_tid: self.clear_child_tid,
+                waiting,
+                signal_mask: self.signals.mask,
+            });
+        }
+        let now = Instant::now();
+        for thread in &mut self.threads {
+            if thread
+                .waiting
+                .is_some_and(|(_, deadline)| deadline.is_some_and(|deadline| deadline <= now))
+            {
+                thread.waiting = None;
+                thread.regs[10] = (ETIMEDOUT as i64).wrapping_neg() as u64;
+            }
+        }
+        let index = match self.threads.iter().position(|t| t.waiting.is_none()) {
+            Some(index) => index,
+            None => {
+                // Everybody sleeps. The sleep that times out first ends now, there is no
+                // real waiting in between as nothing else could run anyway.
+                let Some((index, _)) = self
+                    .threads
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, t)| Some((index, t.waiting?.1?)))
+                    .min_by_key(|(_, deadline)| *deadline)
+                else {
+                    eprintln!("linux: deadlock, every thread waits on a futex without timeout");
+                    self.exit_status = Some(DEADLOCK_STATUS);
+                    return;
+                };
+                let thread = &mut self.threads[index];
+                thread.waiting = None;
+                thread.regs[10] = (ETIMEDOUT as i64).wrapping_neg() as u64;
+                index
+            }
+        };
+        let thread = self.threads.remove(index).unwrap();
+        self.tid = thread.tid;
+        self.cpu.regs = thread.regs;
+        self.cpu.pc = thread.pc;
+        self.clear_child_tid = thread.clear_child_tid;
+        self.signals.mask = thread.signal_mask;
+    }
+}
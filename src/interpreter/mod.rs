@@ -1,8 +1,39 @@
+pub mod arch_test;
 pub mod bus;
+pub mod chardev;
+pub mod cosim;
 pub mod cpu;
 pub mod dram;
+pub mod elf;
 pub mod exception;
+pub mod framebuffer;
+pub mod gdbstub;
+pub mod host;
+pub mod htif;
+pub mod linux;
+pub mod plugin;
+pub mod replay;
+pub mod riscv_tests;
+pub mod rvvi;
+pub mod semihosting;
+pub mod snapshot;
+pub mod syscon;
+pub mod virtio;
 
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 pub const DRAM_BASE: u64 = 0x8000_0000;
 pub const DRAM_END: u64 = DRAM_BASE + DRAM_SIZE - 1;
+
+/// SiFive test finisher / syscon, at the same place as on QEMU's virt machine.
+pub const SYSCON_BASE: u64 = 0x0010_0000;
+pub const SYSCON_END: u64 = 0x0010_0fff;
+
+/// virtio-mmio register windows, one device per slot.
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRTIO_STRIDE: u64 = 0x1000;
+pub const VIRTIO_COUNT: u64 = 8;
+pub const VIRTIO_END: u64 = VIRTIO_BASE + VIRTIO_STRIDE * VIRTIO_COUNT - 1;
+
+/// Window for the linear framebuffer, which uses as much of it as its resolution needs.
+pub const FRAMEBUFFER_BASE: u64 = 0x5000_0000;
+pub const FRAMEBUFFER_END: u64 = 0x5fff_ffff;
@@ -0,0 +1,162 @@
+//! Analysis plugins: host shared libraries implementing the C interface declared in
+//! `include/riscv_plugin.h`, attached to a hart through the instrumentation hooks.
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    ptr,
+    sync::Arc,
+};
+
+use super::cpu::Cpu;
+
+/// Version of the plugin interface, `RISCV_PLUGIN_ABI_VERSION` in the header.
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+const RTLD_NOW: c_int = 2;
+
+#[link(name = "dl")]
+extern "C" {
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlclose(handle: *mut c_void) -> c_int;
+    fn dlerror() -> *const c_char;
+}
+
+type InitFn = unsafe extern "C" fn(
+    u32,
+    usize,
+    *const *const c_char,
+    *const *const c_char,
+    *mut *mut c_void,
+) -> i32;
+type InsnFn = unsafe extern "C" fn(*mut c_void, u64, u32);
+type MemFn = unsafe extern "C" fn(*mut c_void, u64, u64, u32, u64, u8);
+type ExitFn = unsafe extern "C" fn(*mut c_void, u32);
+
+fn last_error() -> String {
+    // SAFETY: dlerror returns null or a NUL terminated message owned by the loader.
+    let message = unsafe { dlerror() };
+    if message.is_null() {
+        "unknown error".to_string()
+    } else {
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+pub struct Plugin {
+    handle: *mut c_void,
+    user_data: *mut c_void,
+    insn: Option<InsnFn>,
+    mem: Option<MemFn>,
+    exit: Option<ExitFn>,
+    /// Keys and values passed to init, which the plugin may keep pointers to.
+    arguments: Vec<CString>,
+}
+
+// SAFETY: the plugin is only ever called from the thread running the hart it is attached
+// to, the header promises that much to plugin authors.
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    /// Exit code reported when a run ends at an unhandled exception.
+    pub const EXCEPTION: u32 = u32::MAX;
+
+    /// Load the library at `path` and run its init function with `args`.
+    pub fn load(path: &Path, args: &[(String, String)]) -> Result<Plugin, String> {
+        let name = CString::new(path.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+        // SAFETY: loading a library runs its constructors; that is what the user asked for.
+        let handle = unsafe { dlopen(name.as_ptr(), RTLD_NOW) };
+        if handle.is_null() {
+            return Err(last_error());
+        }
+        let mut plugin = Self {
+            handle,
+            user_data: ptr::null_mut(),
+            insn: None,
+            mem: None,
+            exit: None,
+            arguments: Vec::new(),
+        };
+        let symbol = |name: &CStr| {
+            // SAFETY: handle is a live library handle.
+            let address = unsafe { dlsym(handle, name.as_ptr()) };
+            (!address.is_null()).then_some(address)
+        };
+        let init = symbol(c"riscv_plugin_init").ok_or("no riscv_plugin_init symbol")?;
+        // SAFETY: the symbols have the signatures declared in the header.
+        unsafe {
+            plugin.insn = symbol(c"riscv_plugin_insn").map(|f| std::mem::transmute(f));
+            plugin.mem = symbol(c"riscv_plugin_mem").map(|f| std::mem::transmute(f));
+            plugin.exit = symbol(c"riscv_plugin_exit").map(|f| std::mem::transmute(f));
+        }
+
+        let strings = |pick: fn(&(String, String)) -> &String| {
+            args.iter()
+                .map(|arg| CString::new(pick(arg).as_str()).map_err(|e| e.to_string()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let keys = strings(|(key, _)| key)?;
+        let values = strings(|(_, value)| value)?;
+        let key_pointers: Vec<_> = keys.iter().map(|key| key.as_ptr()).collect();
+        let value_pointers: Vec<_> = values.iter().map(|value| value.as_ptr()).collect();
+        plugin.arguments = keys.into_iter().chain(values).collect();
+        // SAFETY: the pointer arrays outlive the call, the strings the plugin.
+        let status = unsafe {
+            let init: InitFn = std::mem::transmute(init);
+            init(
+                PLUGIN_ABI_VERSION,
+                args.len(),
+                key_pointers.as_ptr(),
+                value_pointers.as_ptr(),
+                &mut plugin.user_data,
+            )
+        };
+        if status != 0 {
+            return Err(format!("riscv_plugin_init failed with {}", status));
+        }
+        Ok(plugin)
+    }
+
+    /// Call the plugin from the hooks of `cpu`. Only the callbacks it exports are hooked up.
+    pub fn attach(self: &Arc<Self>, cpu: &mut Cpu) {
+        if let Some(insn) = self.insn {
+            let plugin = self.clone();
+            // SAFETY: the signature matches the header.
+            cpu.on_instruction(move |pc, decoded| unsafe {
+                insn(plugin.user_data, pc, decoded.raw)
+            });
+        }
+        if let Some(mem) = self.mem {
+            let plugin = self.clone();
+            cpu.on_memory(move |access| unsafe {
+                mem(
+                    plugin.user_data,
+                    access.pc,
+                    access.addr,
+                    access.size as u32,
+                    access.value,
+                    access.write as u8,
+                )
+            });
+        }
+    }
+
+    /// Report the end of the run.
+    pub fn exit(&self, exit_code: u32) {
+        if let Some(exit) = self.exit {
+            // SAFETY: the signature matches the header.
+            unsafe { exit(self.user_data, exit_code) };
+        }
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        // SAFETY: nothing refers to the library anymore, the hooks holding it are gone.
+        unsafe { dlclose(self.handle) };
+    }
+}
@@ -0,0 +1,268 @@
+//! Deterministic record and replay. Host inputs a guest can observe go through a `Channel`
+//! of a `Journal`: while recording, each input is logged with the instruction count at
+//! which it was consumed; while replaying, the logged inputs are handed out again at the
+//! same instruction counts instead of asking the host, so the run is identical.
+//!
+//! Covered are console and terminal input, host entropy, the semihosting clocks and what
+//! HTIF or semihosting guests read from stdin. Files opened through HTIF or semihosting are
+//! not recorded and must be the same when replaying. Host directories shared over 9p and
+//! vsock connections cannot be recorded at all.
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, ErrorKind, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::{chardev::CharBackend, cpu::Cpu};
+
+pub const JOURNAL_MAGIC: &[u8; 8] = b"RVREPLAY";
+pub const JOURNAL_VERSION: u32 = 1;
+
+/// Record introducing a channel: id, name length and name.
+const RECORD_CHANNEL: u8 = 0;
+/// Record of an input: instruction count, channel id, data length and data.
+const RECORD_INPUT: u8 = 1;
+
+enum State {
+    Record {
+        file: File,
+        channels: Vec<String>,
+    },
+    Replay {
+        inputs: HashMap<String, VecDeque<(u64, Vec<u8>)>>,
+        divergence: Option<String>,
+    },
+}
+
+pub struct Journal {
+    /// Instruction count of the machine, kept up to date by the bus.
+    instret: AtomicU64,
+    state: Mutex<State>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+impl Journal {
+    /// Start a journal logging to `path`.
+    pub fn record(path: &Path) -> io::Result<Arc<Journal>> {
+        let mut file = File::create(path)?;
+        file.write_all(JOURNAL_MAGIC)?;
+        file.write_all(&JOURNAL_VERSION.to_le_bytes())?;
+        Ok(Arc::new(Self {
+            instret: AtomicU64::new(0),
+            state: Mutex::new(State::Record {
+                file,
+                channels: Vec::new(),
+            }),
+        }))
+    }
+
+    /// Load a journal written by a recording run.
+    pub fn replay(path: &Path) -> io::Result<Arc<Journal>> {
+        let data = std::fs::read(path)?;
+        if data.len() < 12 || &data[..8] != JOURNAL_MAGIC {
+            return Err(invalid("not a replay journal"));
+        }
+        if data[8..12] != JOURNAL_VERSION.to_le_bytes() {
+            return Err(invalid("replay journal version is not supported"));
+        }
+        let mut rest = &data[12..];
+        let mut take = |len: usize| -> io::Result<&[u8]> {
+            if rest.len() < len {
+                return Err(invalid("replay journal is truncated"));
+            }
+            let (head, tail) = rest.split_at(len);
+            rest = tail;
+            Ok(head)
+        };
+        let mut names = Vec::new();
+        let mut inputs: HashMap<String, VecDeque<(u64, Vec<u8>)>> = HashMap::new();
+        while let Ok(&[kind]) = take(1) {
+            match kind {
+                RECORD_CHANNEL => {
+                    let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+                    names.push(String::from_utf8_lossy(take(len)?).into_owned());
+                }
+                RECORD_INPUT => {
+                    let instret = u64::from_le_bytes(take(8)?.try_into().unwrap());
+                    let id = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+                    let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+                    let name = names
+                        .get(id)
+                        .ok_or_else(|| invalid("replay journal uses an unknown channel"))?;
+                    let data = take(len)?.to_vec();
+                    inputs
+                        .entry(name.clone())
+                        .or_default()
+                        .push_back((instret, data));
+                }
+                _ => return Err(invalid("corrupt replay journal")),
+            }
+        }
+        Ok(Arc::new(Self {
+            instret: AtomicU64::new(0),
+            state: Mutex::new(State::Replay {
+                inputs,
+                divergence: None,
+            }),
+        }))
+    }
+
+    /// The channel for inputs of one source. Names must be unique and the same when
+    /// recording and replaying.
+    pub fn channel(self: &Arc<Self>, name: &str) -> Channel {
+        if let State::Record { file, channels } = &mut *self.state.lock().unwrap() {
+            let mut record = vec![RECORD_CHANNEL];
+            record.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            record.extend_from_slice(name.as_bytes());
+            let _ = file.write_all(&record);
+            channels.push(name.to_string());
+        }
+        Channel {
+            journal: self.clone(),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn set_instret(&self, instret: u64) {
+        self.instret.store(instret, Ordering::Relaxed);
+    }
+
+    /// Why the replayed run stopped following the recording, if it did. Inputs are taken
+    /// from the host again from then on.
+    pub fn divergence(&self) -> Option<String> {
+        match &*self.state.lock().unwrap() {
+            State::Replay { divergence, .. } => divergence.clone(),
+            State::Record { .. } => None,
+        }
+    }
+
+    /// Record or replay one input of `name`. `host` produces it when not replaying and
+    /// returns its length.
+    fn input(&self, name: &str, buf: &mut [u8], host: impl FnOnce(&mut [u8]) -> usize) -> usize {
+        let instret = self.instret.load(Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        match &mut *state {
+            State::Record { file, channels } => {
+                let n = host(buf);
+                if n > 0 {
+                    let id = channels.iter().position(|c| c == name).unwrap();
+                    let mut record = vec![RECORD_INPUT];
+                    record.extend_from_slice(&instret.to_le_bytes());
+                    record.extend_from_slice(&(id as u32).to_le_bytes());
+                    record.extend_from_slice(&(n as u32).to_le_bytes());
+                    record.extend_from_slice(&buf[..n]);
+                    // written right away so a crashing run still leaves a usable journal
+                    let _ = file.write_all(&record);
+                }
+                n
+            }
+            State::Replay {
+                divergence: Some(_),
+                ..
+            } => host(buf),
+            State::Replay { inputs, divergence } => {
+                let queue = inputs.entry(name.to_string()).or_default();
+                match queue.front() {
+                    Some((at, data)) if *at == instret && data.len() <= buf.len() => {
+                        let n = data.len();
+                        buf[..n].copy_from_slice(data);
+                        queue.pop_front();
+                        n
+                    }
+                    Some((at, _)) if *at <= instret => {
+                        *divergence = Some(format!(
+                            "{} input recorded at instruction {} was not consumed",
+                            name, at
+                        ));
+                        host(buf)
+                    }
+                    _ => 0,
+                }
+            }
+        }
+    }
+}
+
+/// Where inputs of one host source are recorded or replayed.
+#[derive(Clone)]
+pub struct Channel {
+    journal: Arc<Journal>,
+    name: String,
+}
+
+impl Channel {
+    /// Input of up to `buf.len()` bytes that may also be absent, like polled console input.
+    /// Returns the number of bytes produced.
+    pub fn input(&self, buf: &mut [u8], host: impl FnOnce(&mut [u8]) -> usize) -> usize {
+        self.journal.input(&self.name, buf, host)
+    }
+
+    /// Input the guest always gets in full, like host entropy.
+    pub fn fill(&self, buf: &mut [u8], host: impl FnOnce(&mut [u8])) {
+        let len = buf.len();
+        let mut host = Some(host);
+        let n = self.input(buf, |buf| {
+            host.take().unwrap()(buf);
+            len
+        });
+        if n != len {
+            let instret = self.journal.instret.load(Ordering::Relaxed);
+            if let State::Replay { divergence, .. } = &mut *self.journal.state.lock().unwrap() {
+                divergence.get_or_insert_with(|| {
+                    format!(
+                        "{} input at instruction {} was not recorded",
+                        self.name, instret
+                    )
+                });
+            }
+            host.take().unwrap()(buf);
+        }
+    }
+
+    /// A value the guest always gets, like the time of day.
+    pub fn value(&self, host: impl FnOnce() -> u64) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill(&mut bytes, |buf| buf.copy_from_slice(&host().to_le_bytes()));
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// A character backend whose input is recorded or replayed. Output always goes to the
+/// wrapped backend; input is only read from it when not replaying.
+pub struct JournaledBackend {
+    inner: Box<dyn CharBackend>,
+    channel: Channel,
+}
+
+impl JournaledBackend {
+    pub fn new(inner: Box<dyn CharBackend>, channel: Channel) -> JournaledBackend {
+        Self { inner, channel }
+    }
+}
+
+impl CharBackend for JournaledBackend {
+    fn write(&mut self, data: &[u8]) {
+        self.inner.write(data);
+    }
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let inner = &mut self.inner;
+        self.channel.input(buf, |buf| inner.read(buf))
+    }
+}
+
+impl Cpu {
+    /// Record or replay the inputs of this machine's devices through `journal`, counting
+    /// instructions from now.
+    pub fn set_journal(&mut self, journal: Arc<Journal>) {
+        journal.set_instret(self.instret);
+        self.bus.set_journal(journal);
+    }
+}
@@ -0,0 +1,130 @@
+//! Runner for prebuilt riscv-tests ISA tests. Each test is an ELF executable that ends by
+//! writing its result to `tohost`: 1 for a pass, `n << 1 | 1` when test case `n` failed,
+//! which HTIF turns into an exit code of 0 or `n`. The tests set up an M-mode trap handler
+//! and end with an `ecall` into it, so they run with trap delivery on.
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use super::{
+    cpu::{control::StopReason, Cpu},
+    elf::Elf,
+    htif::Htif,
+};
+
+/// The suites for user-level RV64 instructions.
+pub const SUITES: [&str; 6] = ["rv64ui", "rv64um", "rv64ua", "rv64uf", "rv64ud", "rv64uc"];
+
+/// Instructions run between checks of the time limit.
+const CHUNK: u64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// Test case number n failed.
+    Fail(u32),
+    /// Still running when the time was up.
+    Timeout,
+    /// The test could not be loaded or stopped at an exception.
+    Error(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "PASS"),
+            Outcome::Fail(n) => write!(f, "FAIL (test {})", n),
+            Outcome::Timeout => write!(f, "TIMEOUT"),
+            Outcome::Error(error) => write!(f, "ERROR ({})", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub instructions: u64,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<28} {} [{} instructions, {} ms]",
+            self.name,
+            self.outcome,
+            self.instructions,
+            self.elapsed.as_millis()
+        )
+    }
+}
+
+/// Whether `name` is a test of one of the suites in the physical (-p) or virtual memory (-v)
+/// environment, like `rv64ui-p-add`.
+fn is_test(name: &str) -> bool {
+    let mut parts = name.splitn(3, '-');
+    matches!(
+        (parts.next(), parts.next(), parts.next()),
+        (Some(suite), Some("p" | "v"), Some(test))
+            if SUITES.contains(&suite) && !test.is_empty() && !test.contains('.')
+    )
+}
+
+/// The tests in `dir`, sorted by name. Other files, like the `.dump` disassembly the build
+/// leaves next to them, are skipped.
+pub fn discover(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut tests = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if is_test(&name) && path.is_file() {
+            tests.push(path);
+        }
+    }
+    tests.sort();
+    Ok(tests)
+}
+
+fn load(path: &Path) -> Result<Cpu, String> {
+    let elf = Elf::parse(fs::read(path).map_err(|e| e.to_string())?)?;
+    let tohost = elf.symbol("tohost").ok_or("no tohost symbol")?;
+    let mut cpu = Cpu::new(vec![]);
+    cpu.load_elf(&elf).map_err(|e| format!("{:?}", e))?;
+    cpu.bus.set_htif(Htif::new(tohost, elf.symbol("fromhost")));
+    cpu.set_trap_delivery(true);
+    Ok(cpu)
+}
+
+/// Run the test at `path` until it reports its result, for at most `time_limit`.
+pub fn run_test(path: &Path, time_limit: Duration) -> TestResult {
+    let start = Instant::now();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut instructions = 0;
+    let outcome = match load(path) {
+        Err(error) => Outcome::Error(error),
+        Ok(mut cpu) => loop {
+            let reason = cpu.run(CHUNK);
+            instructions = cpu.instret;
+            match reason {
+                StopReason::Limit if start.elapsed() < time_limit => (),
+                StopReason::Limit => break Outcome::Timeout,
+                StopReason::Exited { code: 0 } => break Outcome::Pass,
+                StopReason::Exited { code } => break Outcome::Fail(code),
+                StopReason::Exception(exception) => {
+                    break Outcome::Error(format!("{:?} at 0x{:x}", exception, cpu.pc))
+                }
+                reason => break Outcome::Error(format!("{:?}", reason)),
+            }
+        },
+    };
+    TestResult {
+        name: name.into_owned(),
+        outcome,
+        instructions,
+        elapsed: start.elapsed(),
+    }
+}
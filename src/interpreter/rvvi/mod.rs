@@ -0,0 +1,319 @@
+//! C interface for RTL testbenches after the RISC-V Verification Interface (RVVI), declared in
+//! `include/riscv_rvvi.h`. The testbench steps the emulator as the reference next to its
+//! design, gets one retire event per step with the register and CSR change sets, and feeds
+//! back what the emulator cannot know: interrupt lines and values read from devices only
+//! the design has.
+use std::{
+    collections::HashMap,
+    ffi::{c_char, CStr},
+    ops::RangeInclusive,
+    ptr,
+};
+
+use super::{
+    cpu::{
+        control::StopReason,
+        csr::{MCAUSE, MIP, MTVAL},
+        trace::Tracer,
+        Cpu,
+    },
+    elf::{is_elf, Elf},
+    DRAM_SIZE,
+};
+
+/// Version of the interface, `RISCV_RVVI_ABI_VERSION` in the header.
+pub const RVVI_ABI_VERSION: u32 = 1;
+
+/// CSR writes a retire event has room for. Instructions write at most one.
+pub const RVVI_MAX_CSR_WRITES: usize = 4;
+
+/// Memory whose contents the design decides, such as device registers. Loads from it see
+/// what was last stored, by the guest or by the testbench.
+#[derive(Clone, Default)]
+pub struct VolatileMemory {
+    ranges: Vec<RangeInclusive<u64>>,
+    bytes: HashMap<u64, u8>,
+}
+
+impl VolatileMemory {
+    pub fn add_range(&mut self, range: RangeInclusive<u64>) {
+        self.ranges.push(range);
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        self.ranges.iter().any(|range| range.contains(&addr))
+    }
+
+    /// The value at `addr`, or `None` outside of the volatile ranges.
+    pub fn load(&self, addr: u64, size: u64) -> Option<u64> {
+        if !self.contains(addr) {
+            return None;
+        }
+        let mut value = 0;
+        for i in (0..(size / 8).min(8)).rev() {
+            let byte = self.bytes.get(&addr.wrapping_add(i)).copied().unwrap_or(0);
+            value = value << 8 | byte as u64;
+        }
+        Some(value)
+    }
+
+    /// Store `value` if `addr` is in a volatile range. Returns whether it was.
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> bool {
+        if !self.contains(addr) {
+            return false;
+        }
+        let bytes = value.to_le_bytes().into_iter().take((size / 8) as usize);
+        for (i, byte) in bytes.enumerate() {
+            self.bytes.insert(addr.wrapping_add(i as u64), byte);
+        }
+        true
+    }
+}
+
+/// What one step did, `riscv_rvvi_retire_t` in the header.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RvviRetire {
+    /// Number of instructions retired before this one.
+    pub order: u64,
+    pub pc: u64,
+    /// mcause and mtval of the exception or interrupt when `trap` is set.
+    pub cause: u64,
+    pub tval: u64,
+    /// All integer registers after the step.
+    pub x: [u64; 32],
+    pub csr_value: [u64; RVVI_MAX_CSR_WRITES],
+    pub insn: u32,
+    /// Exit code when `halt` is set.
+    pub exit_code: u32,
+    /// Bit n is set when xn was written.
+    pub x_written: u32,
+    /// There are no floating point registers, this is always 0.
+    pub f_written: u32,
+    pub csr_count: u32,
+    pub csr_addr: [u32; RVVI_MAX_CSR_WRITES],
+    /// The instruction raised an exception instead of retiring, or the hart took an
+    /// interrupt before it.
+    pub trap: u8,
+    /// The guest powered the machine off.
+    pub halt: u8,
+}
+
+impl RvviRetire {
+    fn new(cpu: &Cpu) -> RvviRetire {
+        Self {
+            order: cpu.instret,
+            pc: cpu.pc,
+            cause: 0,
+            tval: 0,
+            x: cpu.regs,
+            csr_value: [0; RVVI_MAX_CSR_WRITES],
+            insn: 0,
+            exit_code: 0,
+            x_written: 0,
+            f_written: 0,
+            csr_count: 0,
+            csr_addr: [0; RVVI_MAX_CSR_WRITES],
+            trap: 0,
+            halt: 0,
+        }
+    }
+}
+
+/// Execute one instruction of `cpu`, which must have a `Tracer::collect` tracer and trap
+/// delivery enabled.
+fn step(cpu: &mut Cpu) -> RvviRetire {
+    let pc = cpu.pc;
+    let insn = cpu.bus.load(pc, 32).unwrap_or(0) as u32;
+    let interrupt = cpu.pending_interrupt();
+    let reason = cpu.step();
+    let mut event = RvviRetire::new(cpu);
+    if let Some(cause) = interrupt {
+        event.pc = pc;
+        event.trap = 1;
+        event.cause = cause;
+        return event;
+    }
+    let commit = cpu.take_commits().pop();
+    let retired = commit.is_some();
+    match commit {
+        Some(commit) => {
+            event.order = commit.instret;
+            event.pc = commit.pc;
+            event.insn = commit.raw;
+            for (reg, _) in &commit.writes {
+                event.x_written |= 1 << reg;
+            }
+            let csr_writes = commit.csr_writes.iter().take(RVVI_MAX_CSR_WRITES);
+            for (i, (csr, value)) in csr_writes.enumerate() {
+                event.csr_addr[i] = *csr as u32;
+                event.csr_value[i] = *value;
+                event.csr_count += 1;
+            }
+        }
+        None => {
+            event.pc = pc;
+            event.insn = insn;
+        }
+    }
+    match reason {
+        // nothing retired, the instruction trapped into the handler
+        StopReason::Limit if !retired => {
+            event.trap = 1;
+            event.cause = cpu.csr.load(MCAUSE);
+            event.tval = cpu.csr.load(MTVAL);
+        }
+        StopReason::Exception(exception) => {
+            event.trap = 1;
+            event.cause = exception.code();
+            event.tval = exception.value();
+        }
+        StopReason::Exited { code } => {
+            event.halt = 1;
+            event.exit_code = code;
+        }
+        _ => (),
+    }
+    event
+}
+
+/// Load the ELF executable or raw binary at `path` into a new machine.
+fn open(path: &CStr) -> Result<Cpu, String> {
+    let path = path.to_str().map_err(|e| e.to_string())?;
+    let code = std::fs::read(path).map_err(|e| e.to_string())?;
+    let mut cpu = if is_elf(&code) {
+        let elf = Elf::parse(code)?;
+        let mut cpu = Cpu::new(vec![]);
+        cpu.load_elf(&elf).map_err(|e| format!("{:?}", e))?;
+        cpu
+    } else if code.len() as u64 <= DRAM_SIZE {
+        Cpu::new(code)
+    } else {
+        return Err("program larger than DRAM".to_string());
+    };
+    cpu.set_tracer(Tracer::collect());
+    cpu.set_trap_delivery(true);
+    Ok(cpu)
+}
+
+#[no_mangle]
+pub extern "C" fn riscv_rvvi_abi_version() -> u32 {
+    RVVI_ABI_VERSION
+}
+
+/// Create a machine running the ELF executable or raw binary at `path`. Returns null when
+/// it cannot be loaded.
+///
+/// # Safety
+/// `path` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_rvvi_open(path: *const c_char) -> *mut Cpu {
+    match open(CStr::from_ptr(path)) {
+        Ok(cpu) => Box::into_raw(Box::new(cpu)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// `cpu` must come from `riscv_rvvi_open` and is not valid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_rvvi_close(cpu: *mut Cpu) {
+    if !cpu.is_null() {
+        drop(Box::from_raw(cpu));
+    }
+}
+
+/// Execute one instruction and describe it in `event`. An instruction that raises an
+/// exception enters the handler at mtvec: the event has `trap` set with the pc and encoding
+/// of the instruction, and the hart is at the handler. A step that takes an interrupt
+/// executes nothing: the event has `trap` set and the pc of the interrupted instruction,
+/// and the hart is at the handler.
+///
+/// # Safety
+/// `cpu` must come from `riscv_rvvi_open`, `event` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_rvvi_step(cpu: *mut Cpu, event: *mut RvviRetire) {
+    *event = step(&mut *cpu);
+}
+
+/// # Safety
+/// `cpu` must come from `riscv_rvvi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_rvvi_pc(cpu: *const Cpu) -> u64 {
+    (*cpu).pc
+}
+
+/// # Safety
+/// `cpu` must come from `riscv_rvvi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_rvvi_gpr(cpu: *const Cpu, index: u32) -> u64 {
+    (*cpu).regs.get(index as usize).copied().unwrap_or(0)
+}
+
+/// Overwrite a register, for values only the design can produce. Writes to x0 are ignored.
+///
+/// # Safety
+/// `cpu` must come from `riscv_rvvi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_rvvi_set_gpr(cpu: *mut Cpu, index: u32, value: u64) {
+    if (1..32).contains(&index) {
+        (*cpu).regs[index as usize] = value;
+    }
+}
+
+/// # Safety
+/// `cpu` must come from `riscv_rvvi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_rvvi_csr(cpu: *const Cpu, csr: u32) -> u64 {
+    (*cpu).csr.load(csr as usize & 0xfff)
+}
+
+/// Drive interrupt line `irq`, bit `irq` of mip. The hart takes the interrupt at the next
+/// step once the guest enabled it in mie and mstatus.MIE.
+///
+/// # Safety
+/// `cpu` must come from `riscv_rvvi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_rvvi_set_interrupt(cpu: *mut Cpu, irq: u32, level: u8) {
+    if irq >= 64 {
+        return;
+    }
+    let csr = &mut (*cpu).csr;
+    let mip = csr.load(MIP);
+    let mip = match level {
+        0 => mip & !(1 << irq),
+        _ => mip | 1 << irq,
+    };
+    csr.store(MIP, mip);
+}
+
+/// Make `lo..=hi` volatile: the emulator no longer models it, loads from it return what the
+/// testbench stored with `riscv_rvvi_mem_write` or the guest stored last.
+///
+/// # Safety
+/// `cpu` must come from `riscv_rvvi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_rvvi_set_volatile(cpu: *mut Cpu, lo: u64, hi: u64) {
+    (*cpu).bus.add_volatile_range(lo..=hi);
+}
+
+/// Store `value` of `size` bits at `addr`, as the design saw it there. Returns 0, or -1 if
+/// nothing is at `addr` or `size` is not 8, 16, 32 or 64.
+///
+/// # Safety
+/// `cpu` must come from `riscv_rvvi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_rvvi_mem_write(
+    cpu: *mut Cpu,
+    addr: u64,
+    value: u64,
+    size: u32,
+) -> i32 {
+    if ![8, 16, 32, 64].contains(&size) {
+        return -1;
+    }
+    match (*cpu).bus.store(addr, size as u64, value) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
@@ -0,0 +1,300 @@
+//! RISC-V semihosting: an `ebreak` placed between `slli x0, x0, 0x1f` and `srai x0, x0, 7`
+//! asks the host to perform the operation in a0, with a1 pointing to its parameter block of
+//! XLEN sized words. The result goes back in a0. Operation numbers and semantics follow the
+//! Arm semihosting specification, which RISC-V adopted.
+use std::{
+    fs, io,
+    path::Path,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    dram::Dram,
+    host::{errno, FileTable, EFAULT, O_APPEND, O_CREAT, O_RDWR, O_TRUNC, O_WRONLY},
+    replay::{Channel, Journal},
+    snapshot::{SnapshotReader, SnapshotWriter},
+    syscon::{restore_event, save_event, PowerEvent},
+    DRAM_SIZE,
+};
+
+/// `slli x0, x0, 0x1f`, right before the `ebreak`.
+pub const SEMIHOSTING_ENTRY: u32 = 0x01f0_1013;
+/// `srai x0, x0, 7`, right after the `ebreak`.
+pub const SEMIHOSTING_EXIT: u32 = 0x4070_5013;
+
+pub const SYS_OPEN: u64 = 0x01;
+pub const SYS_CLOSE: u64 = 0x02;
+pub const SYS_WRITEC: u64 = 0x03;
+pub const SYS_WRITE0: u64 = 0x04;
+pub const SYS_WRITE: u64 = 0x05;
+pub const SYS_READ: u64 = 0x06;
+pub const SYS_READC: u64 = 0x07;
+pub const SYS_ISERROR: u64 = 0x08;
+pub const SYS_ISTTY: u64 = 0x09;
+pub const SYS_SEEK: u64 = 0x0a;
+pub const SYS_FLEN: u64 = 0x0c;
+pub const SYS_TMPNAM: u64 = 0x0d;
+pub const SYS_REMOVE: u64 = 0x0e;
+pub const SYS_RENAME: u64 = 0x0f;
+pub const SYS_CLOCK: u64 = 0x10;
+pub const SYS_TIME: u64 = 0x11;
+pub const SYS_SYSTEM: u64 = 0x12;
+pub const SYS_ERRNO: u64 = 0x13;
+pub const SYS_GET_CMDLINE: u64 = 0x15;
+pub const SYS_HEAPINFO: u64 = 0x16;
+pub const SYS_EXIT: u64 = 0x18;
+pub const SYS_EXIT_EXTENDED: u64 = 0x20;
+pub const SYS_ELAPSED: u64 = 0x30;
+pub const SYS_TICKFREQ: u64 = 0x31;
+
+/// Exit reason for a normal application exit, the subcode being the exit status.
+pub const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// Ticks reported by SYS_ELAPSED are retired instructions; this is their nominal frequency.
+const TICK_FREQUENCY: u64 = 1_000_000_000;
+
+const FAILURE: u64 = u64::MAX;
+
+pub struct Semihosting {
+    files: FileTable,
+    /// Command line returned by SYS_GET_CMDLINE, program name first.
+    args: Vec<String>,
+    start: Instant,
+    /// Host errno of the last failed operation, for SYS_ERRNO.
+    errno: u32,
+    event: Option<PowerEvent>,
+    /// Records or replays the clocks. Console reads are journaled by `files`.
+    journal: Option<Channel>,
+}
+
+impl Default for Semihosting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn word(dram: &Dram, addr: u64) -> Result<u64, u32> {
+    let mut bytes = [0; 8];
+    dram.read_bytes(addr, &mut bytes).map_err(|_| EFAULT)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn bytes(dram: &Dram, addr: u64, len: u64) -> Result<Vec<u8>, u32> {
+    let mut data = vec![0; len.min(DRAM_SIZE) as usize];
+    dram.read_bytes(addr, &mut data).map_err(|_| EFAULT)?;
+    Ok(data)
+}
+
+fn string(dram: &Dram, addr: u64, len: u64) -> Result<String, u32> {
+    String::from_utf8(bytes(dram, addr, len)?).map_err(|_| EFAULT)
+}
+
+impl Semihosting {
+    pub fn new() -> Semihosting {
+        Self {
+            files: FileTable::new(),
+            args: Vec::new(),
+            start: Instant::now(),
+            errno: 0,
+            event: None,
+            journal: None,
+        }
+    }
+
+    pub fn with_args(mut self, args: Vec<String>) -> Semihosting {
+        self.args = args;
+        self
+    }
+
+    pub fn with_journal(mut self, journal: &Arc<Journal>) -> Semihosting {
+        self.journal = Some(journal.channel("semihosting"));
+        self.files
+            .set_stdin_journal(journal.channel("semihosting-stdin"));
+        self
+    }
+
+    pub fn take_event(&mut self) -> Option<PowerEvent> {
+        self.event.take()
+    }
+
+    /// The guest visible state for a snapshot. Open files are host state and not saved.
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        w.u32(self.errno);
+        save_event(w, self.event);
+    }
+
+    pub(crate) fn restore_state(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.errno = r.u32()?;
+        self.event = restore_event(r)?;
+        Ok(())
+    }
+
+    /// A value from the host, through the journal if there is one.
+    fn host_value(&self, host: impl FnOnce() -> u64) -> u64 {
+        match &self.journal {
+            Some(journal) => journal.value(host),
+            None => host(),
+        }
+    }
+
+    /// Perform operation `op` and return the value for a0.
+    pub fn call(&mut self, op: u64, param: u64, dram: &mut Dram, instret: u64) -> u64 {
+        match self.dispatch(op, param, dram, instret) {
+            Ok(value) => value,
+            Err(errno) => {
+                self.errno = errno;
+                FAILURE
+            }
+        }
+    }
+
+    fn dispatch(&mut self, op: u64, param: u64, dram: &mut Dram, instret: u64) -> Result<u64, u32> {
+        let arg = |dram: &Dram, n: u64| word(dram, param + n * 8);
+        match op {
+            SYS_OPEN => {
+                let name = string(dram, arg(dram, 0)?, arg(dram, 2)?)?;
+                let mode = arg(dram, 1)?;
+                if name == ":tt" {
+                    // the console: stdin for reading, stdout for writing, stderr for appending
+                    return Ok(mode / 4);
+                }
+                let flags = match mode / 4 {
+                    0 => 0,
+                    1 => O_WRONLY | O_CREAT | O_TRUNC,
+                    _ => O_WRONLY | O_CREAT | O_APPEND,
+                };
+                // the "+" variants open for both reading and writing
+                let flags = if mode & 2 != 0 {
+                    (flags & !O_WRONLY) | O_RDWR
+                } else {
+                    flags
+                };
+                self.files.open(Path::new(&name), flags, 0o644)
+            }
+            SYS_CLOSE => self.files.close(arg(dram, 0)?).map(|_| 0),
+            SYS_WRITEC => {
+                let c = bytes(dram, param, 1)?;
+                self.files.write(1, &c).map(|_| 0)
+            }
+            SYS_WRITE0 => {
+                let mut text = Vec::new();
+                let mut addr = param;
+                loop {
+                    let c = bytes(dram, addr, 1)?[0];
+                    if c == 0 {
+                        break;
+                    }
+                    text.push(c);
+                    addr += 1;
+                }
+                self.files.write(1, &text).map(|_| 0)
+            }
+            SYS_WRITE => {
+                let len = arg(dram, 2)?;
+                let data = bytes(dram, arg(dram, 1)?, len)?;
+                let n = self.files.write(arg(dram, 0)?, &data)?;
+                // the number of bytes *not* written
+                Ok(len - n as u64)
+            }
+            SYS_READ => {
+                let len = arg(dram, 2)?;
+                let mut data = vec![0; len.min(DRAM_SIZE) as usize];
+                let n = self.files.read(arg(dram, 0)?, &mut data)?;
+                dram.write_bytes(arg(dram, 1)?, &data[..n])
+                    .map_err(|_| EFAULT)?;
+                Ok(len - n as u64)
+            }
+            SYS_READC => {
+                let mut c = [0];
+                self.files.read(0, &mut c)?;
+                Ok(c[0] as u64)
+            }
+            SYS_ISERROR => Ok(((arg(dram, 0)? as i64) < 0) as u64),
+            SYS_ISTTY => self.files.is_tty(arg(dram, 0)?).map(|tty| tty as u64),
+            SYS_SEEK => self
+                .files
+                .seek(arg(dram, 0)?, arg(dram, 1)? as i64, 0)
+                .map(|_| 0),
+            SYS_FLEN => self.files.file_len(arg(dram, 0)?),
+            SYS_TMPNAM => {
+                let name = format!(
+                    "{}/riscv-semihosting-{}-{}\0",
+                    std::env::temp_dir().display(),
+                    std::process::id(),
+                    arg(dram, 1)? as u8
+                );
+                if name.len() as u64 > arg(dram, 2)? {
+                    return Err(EFAULT);
+                }
+                dram.write_bytes(arg(dram, 0)?, name.as_bytes())
+                    .map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            SYS_REMOVE => {
+                let name = string(dram, arg(dram, 0)?, arg(dram, 1)?)?;
+                fs::remove_file(name).map(|_| 0).map_err(errno)
+            }
+            SYS_RENAME => {
+                let from = string(dram, arg(dram, 0)?, arg(dram, 1)?)?;
+                let to = string(dram, arg(dram, 2)?, arg(dram, 3)?)?;
+                fs::rename(from, to).map(|_| 0).map_err(errno)
+            }
+            SYS_CLOCK => {
+                let start = self.start;
+                Ok(self.host_value(|| start.elapsed().as_millis() as u64 / 10))
+            }
+            SYS_TIME => Ok(self.host_value(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs())
+            })),
+            // running host commands on behalf of the guest is not supported
+            SYS_SYSTEM => Ok(FAILURE),
+            SYS_ERRNO => Ok(self.errno as u64),
+            SYS_GET_CMDLINE => {
+                let line = format!("{}\0", self.args.join(" "));
+                if line.len() as u64 > arg(dram, 1)? {
+                    return Err(EFAULT);
+                }
+                dram.write_bytes(arg(dram, 0)?, line.as_bytes())
+                    .map_err(|_| EFAULT)?;
+                dram.write_bytes(param + 8, &(line.len() as u64 - 1).to_le_bytes())
+                    .map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            SYS_HEAPINFO => {
+                // zero tells the C library to use its own defaults
+                dram.write_bytes(arg(dram, 0)?, &[0; 32])
+                    .map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                // RV32 passes the reason itself, RV64 a block with reason and subcode. An
+                // unreadable block still ends the run, as a failure.
+                let (reason, subcode) = if param == ADP_STOPPED_APPLICATION_EXIT {
+                    (param, 0)
+                } else {
+                    (arg(dram, 0).unwrap_or(0), arg(dram, 1).unwrap_or(0))
+                };
+                let exit_code = if reason == ADP_STOPPED_APPLICATION_EXIT {
+                    subcode as u32
+                } else {
+                    1
+                };
+                self.event = Some(PowerEvent::PowerOff { exit_code });
+                Ok(0)
+            }
+            SYS_ELAPSED => {
+                dram.write_bytes(param, &instret.to_le_bytes())
+                    .map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            SYS_TICKFREQ => Ok(TICK_FREQUENCY),
+            _ => {
+                eprintln!("semihosting: unsupported operation {:#x}", op);
+                Ok(FAILURE)
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Machine snapshots: the hart, memory and device registers in a versioned file, so a run
+//! can be resumed later, e.g. right after an OS finished booting.
+//!
+//! The file starts with `SNAPSHOT_MAGIC` and `SNAPSHOT_VERSION`, followed by the hart, the
+//! bus and its devices, all little endian. Memory is stored as 4 KiB pages, leaving out the
+//! ones that are all zero and PackBits compressing the rest. Host side state of devices,
+//! such as open files of a 9p share or buffered console input, is not part of a snapshot;
+//! a snapshot is restored into a machine configured the same way as the one it was taken
+//! from.
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use super::{
+    cpu::{
+        csr::{Privilege, SIE, SIP, SSTATUS},
+        Cpu,
+    },
+    dram::{Dram, PAGE_SIZE},
+};
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"RVSNAPSH";
+/// Bumped whenever the layout changes, older files are rejected.
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+const NUM_CSRS: usize = 4096;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[derive(Default)]
+pub struct SnapshotWriter {
+    data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A length prefixed block of bytes, PackBits compressed.
+    pub fn packed(&mut self, data: &[u8]) {
+        let packed = pack(data);
+        self.u32(packed.len() as u32);
+        self.data.extend_from_slice(&packed);
+    }
+
+    /// Memory as the list of its non-zero pages.
+    pub fn pages(&mut self, dram: &Dram) {
+        self.u64(dram.size());
+        let pages: Vec<_> = (0..(dram.size() / PAGE_SIZE) as usize)
+            .filter(|&index| dram.page(index).iter().any(|&byte| byte != 0))
+            .collect();
+        self.u32(pages.len() as u32);
+        for index in pages {
+            self.u32(index as u32);
+            self.packed(dram.page(index));
+        }
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(invalid("snapshot is truncated"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Unpack a block written by `SnapshotWriter::packed` into `out`, which it must fill.
+    pub fn packed(&mut self, out: &mut [u8]) -> io::Result<()> {
+        let len = self.u32()? as usize;
+        unpack(self.take(len)?, out)
+    }
+
+    /// Read pages written by `SnapshotWriter::pages` into `dram`, zeroing the others.
+    pub fn pages(&mut self, dram: &mut Dram) -> io::Result<()> {
+        if self.u64()? != dram.size() {
+            return Err(invalid("snapshot memory size differs"));
+        }
+        dram.clear();
+        for _ in 0..self.u32()? {
+            let index = self.u32()? as usize;
+            if index as u64 >= dram.size() / PAGE_SIZE {
+                return Err(invalid("snapshot page out of range"));
+            }
+            self.packed(dram.page_mut(index))?;
+        }
+        Ok(())
+    }
+}
+
+/// PackBits: a control byte n < 128 is followed by n + 1 literal bytes, n > 128 by one byte
+/// repeated 257 - n times.
+fn pack(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..]
+            .iter()
+            .take(128)
+            .take_while(|&&b| b == data[i])
+            .count();
+        if run >= 2 {
+            out.push((257 - run) as u8);
+            out.push(data[i]);
+            i += run;
+            continue;
+        }
+        let start = i;
+        while i < data.len() && i - start < 128 {
+            if i + 1 < data.len() && data[i] == data[i + 1] {
+                break;
+            }
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&data[start..i]);
+    }
+    out
+}
+
+fn unpack(mut data: &[u8], out: &mut [u8]) -> io::Result<()> {
+    let mut written = 0;
+    while let Some((&control, rest)) = data.split_first() {
+        let (len, bytes) = match control {
+            0..=127 => (control as usize + 1, control as usize + 1),
+            128 => (0, 0),
+            _ => (257 - control as usize, 1),
+        };
+        if rest.len() < bytes || written + len > out.len() {
+            return Err(invalid("corrupt snapshot data"));
+        }
+        if bytes == 1 {
+            out[written..written + len].fill(rest[0]);
+        } else {
+            out[written..written + len].copy_from_slice(&rest[..len]);
+        }
+        written += len;
+        data = &rest[bytes..];
+    }
+    if written != out.len() {
+        return Err(invalid("corrupt snapshot data"));
+    }
+    Ok(())
+}
+
+/// The supervisor views of machine CSRs have no storage of their own.
+fn is_view(csr: usize) -> bool {
+    matches!(csr, SIE | SIP | SSTATUS)
+}
+
+impl Cpu {
+    /// Serialize the machine. Fails for user-mode processes, whose address space lives
+    /// outside the bus.
+    pub fn save_snapshot(&self) -> io::Result<Vec<u8>> {
+        if self.bus.user_memory().is_some() {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "snapshots of user-mode processes are not supported",
+            ));
+        }
+        let mut w = SnapshotWriter::default();
+        w.data.extend_from_slice(SNAPSHOT_MAGIC);
+        w.u32(SNAPSHOT_VERSION);
+        w.u64(self.pc);
+        w.u64(self.instret);
+        w.u8(self.privilege as u8);
+        for reg in self.regs {
+            w.u64(reg);
+        }
+        let csrs: Vec<_> = (0..NUM_CSRS)
+            .filter(|&csr| !is_view(csr) && self.csr.load(csr) != 0)
+            .collect();
+        w.u32(csrs.len() as u32);
+        for csr in csrs {
+            w.u16(csr as u16);
+            w.u64(self.csr.load(csr));
+        }
+        self.bus.save_state(&mut w);
+        Ok(w.data)
+    }
+
+    /// Resume from `snapshot`. The machine must have the same memory size and devices as
+    /// the one it was taken on; on error the machine is left in an unspecified state.
+    pub fn restore_snapshot(&mut self, snapshot: &[u8]) -> io::Result<()> {
+        let mut r = SnapshotReader { data: snapshot };
+        if r.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        let version = r.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(&format!(
+                "snapshot version {} is not supported",
+                version
+            )));
+        }
+        self.pc = r.u64()?;
+        self.instret = r.u64()?;
+        self.privilege = match r.u8()? {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            3 => Privilege::Machine,
+            _ => return Err(invalid("snapshot privilege level is invalid")),
+        };
+        for reg in self.regs.iter_mut() {
+            *reg = r.u64()?;
+        }
+        self.csr = Default::default();
+        for _ in 0..r.u32()? {
+            let csr = r.u16()? as usize;
+            if csr >= NUM_CSRS || is_view(csr) {
+                return Err(invalid("snapshot CSR out of range"));
+            }
+            self.csr.store(csr, r.u64()?);
+        }
+        self.bus.restore_state(&mut r)?;
+        if !r.data.is_empty() {
+            return Err(invalid("trailing data after snapshot"));
+        }
+        self.exit_code = None;
+        Ok(())
+    }
+
+    pub fn save_snapshot_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.save_snapshot()?)
+    }
+
+    pub fn restore_snapshot_file(&mut self, path: &Path) -> io::Result<()> {
+        self.restore_snapshot(&std::fs::read(path)?)
+    }
+}
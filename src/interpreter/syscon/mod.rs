@@ -0,0 +1,88 @@
+//! SiFive test finisher, also used as the target of `syscon-poweroff` and `syscon-reboot`
+//! device tree nodes. The guest stops or resets the machine by writing a magic value to it.
+use std::io;
+
+use super::{
+    exception::Exception,
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+/// Power off with the exit code in the upper 16 bits.
+pub const FINISHER_FAIL: u64 = 0x3333;
+/// Power off with exit code 0.
+pub const FINISHER_PASS: u64 = 0x5555;
+pub const FINISHER_RESET: u64 = 0x7777;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PowerEvent {
+    PowerOff { exit_code: u32 },
+    Reset,
+}
+
+/// Save a power event that was requested but not handled yet.
+pub(crate) fn save_event(w: &mut SnapshotWriter, event: Option<PowerEvent>) {
+    match event {
+        None => w.u8(0),
+        Some(PowerEvent::PowerOff { exit_code }) => {
+            w.u8(1);
+            w.u32(exit_code);
+        }
+        Some(PowerEvent::Reset) => w.u8(2),
+    }
+}
+
+pub(crate) fn restore_event(r: &mut SnapshotReader) -> io::Result<Option<PowerEvent>> {
+    Ok(match r.u8()? {
+        0 => None,
+        1 => Some(PowerEvent::PowerOff {
+            exit_code: r.u32()?,
+        }),
+        2 => Some(PowerEvent::Reset),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot power event is invalid",
+            ))
+        }
+    })
+}
+
+#[derive(Default)]
+pub struct Syscon {
+    event: Option<PowerEvent>,
+}
+
+impl Syscon {
+    pub fn new() -> Syscon {
+        Self { event: None }
+    }
+
+    pub fn load(&self, _addr: u64, _size: u64) -> Result<u64, Exception> {
+        Ok(0)
+    }
+
+    pub fn store(&mut self, _addr: u64, _size: u64, value: u64) -> Result<(), Exception> {
+        self.event = match value & 0xffff {
+            FINISHER_PASS => Some(PowerEvent::PowerOff { exit_code: 0 }),
+            FINISHER_FAIL => Some(PowerEvent::PowerOff {
+                exit_code: ((value >> 16) & 0xffff) as u32,
+            }),
+            FINISHER_RESET => Some(PowerEvent::Reset),
+            _ => self.event,
+        };
+        Ok(())
+    }
+
+    pub fn take_event(&mut self) -> Option<PowerEvent> {
+        self.event.take()
+    }
+
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        save_event(w, self.event);
+    }
+
+    pub(crate) fn restore_state(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.event = restore_event(r)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+//! virtio-console with the multiport feature. Port 0 is the console, every further port is a
+//! named channel (`/dev/vportNpM` in a Linux guest) connected to its own host backend.
+use super::{queue::Virtqueue, VirtioDevice};
+use crate::interpreter::{chardev::CharBackend, dram::Dram, exception::Exception};
+
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+/// Device has support for multiple ports.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+// virtio_console_control events
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+struct Port {
+    name: String,
+    backend: Box<dyn CharBackend>,
+    /// The guest has this port open.
+    open: bool,
+}
+
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    /// Control messages waiting for a buffer on the control receive queue.
+    control: Vec<Vec<u8>>,
+}
+
+fn rx_queue(port: usize) -> usize {
+    if port == 0 {
+        0
+    } else {
+        2 + port * 2
+    }
+}
+
+fn control_message(id: u32, event: u16, value: u16) -> Vec<u8> {
+    let mut message = id.to_le_bytes().to_vec();
+    message.extend_from_slice(&event.to_le_bytes());
+    message.extend_from_slice(&value.to_le_bytes());
+    message
+}
+
+impl VirtioConsole {
+    /// A console whose port 0 is connected to `backend`.
+    pub fn new(backend: Box<dyn CharBackend>) -> VirtioConsole {
+        Self {
+            ports: vec![Port {
+                name: String::new(),
+                backend,
+                open: false,
+            }],
+            control: Vec::new(),
+        }
+    }
+
+    /// Add a named port. Returns its port number. Ports have to be added before the device is
+    /// plugged into the bus, since every port brings its own pair of queues.
+    pub fn add_port(&mut self, name: &str, backend: Box<dyn CharBackend>) -> usize {
+        self.ports.push(Port {
+            name: name.to_string(),
+            backend,
+            open: false,
+        });
+        self.ports.len() - 1
+    }
+
+    pub fn is_port_open(&self, port: usize) -> bool {
+        self.ports.get(port).is_some_and(|port| port.open)
+    }
+
+    fn port_of_queue(&self, queue: usize) -> Option<usize> {
+        match queue {
+            0 | 1 => Some(0),
+            CONTROL_RX | CONTROL_TX => None,
+            _ => Some(queue / 2 - 1),
+        }
+    }
+
+    fn handle_control(&mut self, message: &[u8]) {
+        if message.len() < 8 {
+            return;
+        }
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for port in 0..self.ports.len() {
+                    self.control
+                        .push(control_message(port as u32, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 => {
+                let Some(port) = self.ports.get(id as usize) else {
+                    return;
+                };
+                if id == 0 {
+                    self.control
+                        .push(control_message(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                } else {
+                    let mut message = control_message(id, VIRTIO_CONSOLE_PORT_NAME, 0);
+                    message.extend_from_slice(port.name.as_bytes());
+                    self.control.push(message);
+                }
+                // host side channels are always connected
+                self.control
+                    .push(control_message(id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(id as usize) {
+                    port.open = value == 1;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn deliver_control(
+        &mut self,
+        queues: &mut [Virtqueue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        while !self.control.is_empty() {
+            let Some(chain) = queues[CONTROL_RX].pop(dram)? else {
+                break;
+            };
+            let message = self.control.remove(0);
+            let len = chain.write_all(dram, &message)?;
+            queues[CONTROL_RX].push_used(dram, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn deliver_input(
+        &mut self,
+        queues: &mut [Virtqueue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        for (index, port) in self.ports.iter_mut().enumerate() {
+            let queue = &mut queues[rx_queue(index)];
+            while let Some(chain) = queue.pop(dram)? {
+                let mut buf = vec![0; chain.fill_len()];
+                let n = port.backend.read(&mut buf);
+                if n == 0 {
+                    queue.unpop();
+                    break;
+                }
+                let len = chain.write_all(dram, &buf[..n])?;
+                queue.push_used(dram, chain.head, len)?;
+                used = true;
+            }
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    fn num_queues(&self) -> usize {
+        2 + 2 * self.ports.len()
+    }
+
+    fn config(&self) -> Vec<u8> {
+        // cols, rows, max_nr_ports, emerg_wr
+        let mut config = vec![0; 4];
+        config.extend_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config.extend_from_slice(&[0; 4]);
+        config
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // emergency write, usable before the queues are set up
+        if offset == 8 {
+            self.ports[0].backend.write(&data[..1]);
+        }
+    }
+
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        if queue == CONTROL_TX {
+            while let Some(chain) = queues[queue].pop(dram)? {
+                let message = chain.read_all(dram)?;
+                self.handle_control(&message);
+                queues[queue].push_used(dram, chain.head, 0)?;
+                used = true;
+            }
+        } else if queue % 2 == 1 {
+            if let Some(port) = self.port_of_queue(queue) {
+                while let Some(chain) = queues[queue].pop(dram)? {
+                    let data = chain.read_all(dram)?;
+                    self.ports[port].backend.write(&data);
+                    queues[queue].push_used(dram, chain.head, 0)?;
+                    used = true;
+                }
+            }
+        }
+        // new receive buffers, or control requests that produced replies
+        used |= self.deliver_control(queues, dram)?;
+        used |= self.deliver_input(queues, dram)?;
+        Ok(used)
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], dram: &mut Dram) -> Result<bool, Exception> {
+        self.deliver_input(queues, dram)
+    }
+
+    fn reset(&mut self) {
+        self.control.clear();
+        for port in &mut self.ports {
+            port.open = false;
+        }
+    }
+}
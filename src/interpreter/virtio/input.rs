@@ -0,0 +1,425 @@
+//! virtio-input keyboard and mouse. Events come from a script replayed at given instruction
+//! counts, which keeps runs deterministic, or from a host terminal.
+//!
+//! Script lines have the form `<instret> <type> <code> <value>`, for example
+//! `5000 key KEY_A 1`. Types and codes are either numbers or the usual evdev names. Events
+//! sharing an instruction count form one batch, which gets a SYN_REPORT appended unless the
+//! script ends it with one. `#` starts a comment.
+use std::collections::VecDeque;
+
+use super::{queue::Virtqueue, VirtioDevice};
+use crate::interpreter::{chardev::CharBackend, dram::Dram, exception::Exception};
+
+pub const VIRTIO_ID_INPUT: u32 = 18;
+
+const EVENTQ: usize = 0;
+const STATUSQ: usize = 1;
+
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const SYN_REPORT: u16 = 0;
+
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+pub const KEY_ESC: u16 = 1;
+pub const KEY_BACKSPACE: u16 = 14;
+pub const KEY_TAB: u16 = 15;
+pub const KEY_ENTER: u16 = 28;
+pub const KEY_LEFTSHIFT: u16 = 42;
+pub const KEY_SPACE: u16 = 57;
+
+/// Key codes of the rows of a US keyboard, as (character, shifted character, code).
+const KEYMAP: &[(char, char, u16)] = &[
+    ('1', '!', 2),
+    ('2', '@', 3),
+    ('3', '#', 4),
+    ('4', '$', 5),
+    ('5', '%', 6),
+    ('6', '^', 7),
+    ('7', '&', 8),
+    ('8', '*', 9),
+    ('9', '(', 10),
+    ('0', ')', 11),
+    ('-', '_', 12),
+    ('=', '+', 13),
+    ('q', 'Q', 16),
+    ('w', 'W', 17),
+    ('e', 'E', 18),
+    ('r', 'R', 19),
+    ('t', 'T', 20),
+    ('y', 'Y', 21),
+    ('u', 'U', 22),
+    ('i', 'I', 23),
+    ('o', 'O', 24),
+    ('p', 'P', 25),
+    ('[', '{', 26),
+    (']', '}', 27),
+    ('a', 'A', 30),
+    ('s', 'S', 31),
+    ('d', 'D', 32),
+    ('f', 'F', 33),
+    ('g', 'G', 34),
+    ('h', 'H', 35),
+    ('j', 'J', 36),
+    ('k', 'K', 37),
+    ('l', 'L', 38),
+    (';', ':', 39),
+    ('\'', '"', 40),
+    ('`', '~', 41),
+    ('\\', '|', 43),
+    ('z', 'Z', 44),
+    ('x', 'X', 45),
+    ('c', 'C', 46),
+    ('v', 'V', 47),
+    ('b', 'B', 48),
+    ('n', 'N', 49),
+    ('m', 'M', 50),
+    (',', '<', 51),
+    ('.', '>', 52),
+    ('/', '?', 53),
+    (' ', ' ', KEY_SPACE),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputEvent {
+    pub kind: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+impl InputEvent {
+    pub fn new(kind: u16, code: u16, value: u32) -> InputEvent {
+        Self { kind, code, value }
+    }
+
+    fn syn() -> InputEvent {
+        Self::new(EV_SYN, SYN_REPORT, 0)
+    }
+
+    fn encode(&self) -> [u8; 8] {
+        let mut data = [0; 8];
+        data[0..2].copy_from_slice(&self.kind.to_le_bytes());
+        data[2..4].copy_from_slice(&self.code.to_le_bytes());
+        data[4..8].copy_from_slice(&self.value.to_le_bytes());
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScriptedEvent {
+    pub at: u64,
+    pub event: InputEvent,
+}
+
+fn parse_kind(name: &str) -> Option<u16> {
+    match name {
+        "syn" | "EV_SYN" => Some(EV_SYN),
+        "key" | "EV_KEY" => Some(EV_KEY),
+        "rel" | "EV_REL" => Some(EV_REL),
+        _ => name.parse().ok(),
+    }
+}
+
+fn parse_code(name: &str) -> Option<u16> {
+    let named = match name {
+        "SYN_REPORT" => SYN_REPORT,
+        "REL_X" => REL_X,
+        "REL_Y" => REL_Y,
+        "REL_WHEEL" => REL_WHEEL,
+        "BTN_LEFT" => BTN_LEFT,
+        "BTN_RIGHT" => BTN_RIGHT,
+        "BTN_MIDDLE" => BTN_MIDDLE,
+        "KEY_ESC" => KEY_ESC,
+        "KEY_BACKSPACE" => KEY_BACKSPACE,
+        "KEY_TAB" => KEY_TAB,
+        "KEY_ENTER" => KEY_ENTER,
+        "KEY_LEFTSHIFT" => KEY_LEFTSHIFT,
+        "KEY_SPACE" => KEY_SPACE,
+        _ => {
+            let mut chars = name.strip_prefix("KEY_").unwrap_or("").chars();
+            return match (chars.next(), chars.next()) {
+                (Some(c), None) => key_of(c.to_ascii_lowercase()).map(|(code, _)| code),
+                _ => name.parse().ok(),
+            };
+        }
+    };
+    Some(named)
+}
+
+/// Key code of `c`, and whether shift has to be held.
+fn key_of(c: char) -> Option<(u16, bool)> {
+    match c {
+        '\n' | '\r' => return Some((KEY_ENTER, false)),
+        '\t' => return Some((KEY_TAB, false)),
+        '\x08' | '\x7f' => return Some((KEY_BACKSPACE, false)),
+        '\x1b' => return Some((KEY_ESC, false)),
+        _ => (),
+    }
+    KEYMAP.iter().find_map(|(plain, shifted, code)| {
+        if c == *plain {
+            Some((*code, false))
+        } else if c == *shifted {
+            Some((*code, true))
+        } else {
+            None
+        }
+    })
+}
+
+/// Parse an event script, see the module documentation for the format.
+pub fn parse_script(text: &str) -> Result<Vec<ScriptedEvent>, String> {
+    let mut events: Vec<ScriptedEvent> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = || format!("line {}: cannot parse '{}'", number + 1, line);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [at, kind, code, value] = fields[..] else {
+            return Err(error());
+        };
+        let at: u64 = at.parse().map_err(|_| error())?;
+        let event = InputEvent::new(
+            parse_kind(kind).ok_or_else(error)?,
+            parse_code(code).ok_or_else(error)?,
+            value.parse::<i32>().map_err(|_| error())? as u32,
+        );
+        if events.last().is_some_and(|last| last.at > at) {
+            return Err(format!(
+                "line {}: instruction counts must not decrease",
+                number + 1
+            ));
+        }
+        if events
+            .last()
+            .is_some_and(|last| last.at != at && last.event != InputEvent::syn())
+        {
+            let previous = events.last().unwrap().at;
+            events.push(ScriptedEvent {
+                at: previous,
+                event: InputEvent::syn(),
+            });
+        }
+        events.push(ScriptedEvent { at, event });
+    }
+    if let Some(last) = events.last().copied() {
+        if last.event != InputEvent::syn() {
+            events.push(ScriptedEvent {
+                at: last.at,
+                event: InputEvent::syn(),
+            });
+        }
+    }
+    Ok(events)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum InputKind {
+    Keyboard,
+    Mouse,
+}
+
+pub struct VirtioInput {
+    kind: InputKind,
+    select: u8,
+    subsel: u8,
+    script: VecDeque<ScriptedEvent>,
+    terminal: Option<Box<dyn CharBackend>>,
+    /// Events waiting for a buffer on the event queue.
+    pending: VecDeque<InputEvent>,
+}
+
+impl VirtioInput {
+    pub fn new(kind: InputKind) -> VirtioInput {
+        Self {
+            kind,
+            select: 0,
+            subsel: 0,
+            script: VecDeque::new(),
+            terminal: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Replay `events`, each once its instruction count is reached.
+    pub fn with_script(mut self, events: Vec<ScriptedEvent>) -> VirtioInput {
+        self.script = events.into();
+        self
+    }
+
+    /// Type the characters read from `terminal`. Only makes sense for a keyboard.
+    pub fn with_terminal(mut self, terminal: Box<dyn CharBackend>) -> VirtioInput {
+        self.terminal = Some(terminal);
+        self
+    }
+
+    /// Queue an event for the guest right away.
+    pub fn push_event(&mut self, event: InputEvent) {
+        self.pending.push_back(event);
+    }
+
+    fn supported(&self, kind: u16) -> Vec<u16> {
+        match (self.kind, kind) {
+            (InputKind::Keyboard, EV_KEY) => (1..256).collect(),
+            (InputKind::Mouse, EV_KEY) => vec![BTN_LEFT, BTN_RIGHT, BTN_MIDDLE],
+            (InputKind::Mouse, EV_REL) => vec![REL_X, REL_Y, REL_WHEEL],
+            _ => Vec::new(),
+        }
+    }
+
+    fn type_char(&mut self, c: char) {
+        let Some((code, shift)) = key_of(c) else {
+            return;
+        };
+        if shift {
+            self.pending
+                .push_back(InputEvent::new(EV_KEY, KEY_LEFTSHIFT, 1));
+        }
+        self.pending.push_back(InputEvent::new(EV_KEY, code, 1));
+        self.pending.push_back(InputEvent::syn());
+        self.pending.push_back(InputEvent::new(EV_KEY, code, 0));
+        if shift {
+            self.pending
+                .push_back(InputEvent::new(EV_KEY, KEY_LEFTSHIFT, 0));
+        }
+        self.pending.push_back(InputEvent::syn());
+    }
+
+    fn deliver(&mut self, queues: &mut [Virtqueue], dram: &mut Dram) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(event) = self.pending.front() {
+            let Some(chain) = queues[EVENTQ].pop(dram)? else {
+                break;
+            };
+            let len = chain.write_all(dram, &event.encode())?;
+            queues[EVENTQ].push_used(dram, chain.head, len)?;
+            self.pending.pop_front();
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioInput {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_INPUT
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let payload: Vec<u8> = match self.select {
+            VIRTIO_INPUT_CFG_ID_NAME => match self.kind {
+                InputKind::Keyboard => b"riscv virtio keyboard".to_vec(),
+                InputKind::Mouse => b"riscv virtio mouse".to_vec(),
+            },
+            VIRTIO_INPUT_CFG_ID_SERIAL => b"0".to_vec(),
+            VIRTIO_INPUT_CFG_ID_DEVIDS => {
+                // bustype BUS_VIRTUAL, vendor, product, version
+                let product: u16 = if self.kind == InputKind::Keyboard {
+                    1
+                } else {
+                    2
+                };
+                [0x06u16, 0x0627, product, 1]
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect()
+            }
+            VIRTIO_INPUT_CFG_EV_BITS => {
+                let mut bits = vec![0u8; 128];
+                for code in self.supported(self.subsel as u16) {
+                    bits[code as usize / 8] |= 1 << (code % 8);
+                }
+                let len = bits.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+                bits.truncate(len);
+                bits
+            }
+            _ => Vec::new(),
+        };
+        // select, subsel, size, reserved[5], u[128]
+        let mut config = vec![self.select, self.subsel, payload.len() as u8, 0, 0, 0, 0, 0];
+        config.extend_from_slice(&payload);
+        config.resize(8 + 128, 0);
+        config
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            match offset + i as u64 {
+                0 => self.select = *byte,
+                1 => self.subsel = *byte,
+                _ => (),
+            }
+        }
+    }
+
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        if queue == STATUSQ {
+            // LED updates from the guest, nothing to show them on
+            while let Some(chain) = queues[STATUSQ].pop(dram)? {
+                queues[STATUSQ].push_used(dram, chain.head, 0)?;
+                used = true;
+            }
+        }
+        used |= self.deliver(queues, dram)?;
+        Ok(used)
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], dram: &mut Dram) -> Result<bool, Exception> {
+        if let Some(terminal) = &mut self.terminal {
+            let mut buf = [0; 64];
+            let n = terminal.read(&mut buf);
+            for byte in buf[..n].iter().copied() {
+                self.type_char(byte as char);
+            }
+        }
+        self.deliver(queues, dram)
+    }
+
+    fn deadline(&self) -> Option<u64> {
+        self.script.front().map(|event| event.at)
+    }
+
+    fn tick(
+        &mut self,
+        instret: u64,
+        queues: &mut [Virtqueue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        while self.script.front().is_some_and(|event| event.at <= instret) {
+            let event = self.script.pop_front().unwrap().event;
+            self.pending.push_back(event);
+        }
+        self.deliver(queues, dram)
+    }
+
+    fn reset(&mut self) {
+        self.select = 0;
+        self.subsel = 0;
+    }
+}
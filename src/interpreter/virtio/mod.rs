@@ -0,0 +1,354 @@
+//! virtio-mmio transport (version 2) shared by all paravirtual devices.
+pub mod console;
+pub mod input;
+pub mod p9;
+pub mod queue;
+pub mod rng;
+pub mod vsock;
+
+use std::io;
+
+use self::queue::Virtqueue;
+use super::{
+    dram::Dram,
+    exception::Exception,
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+/// "virt" in little endian.
+const MAGIC: u64 = 0x7472_6976;
+/// "RISV" in little endian.
+const VENDOR: u64 = 0x5653_4952;
+
+pub const QUEUE_SIZE_MAX: u32 = 256;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Status bit telling the driver the device hit an unrecoverable error.
+const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+/// InterruptStatus bit for "used buffer notification".
+const INTERRUPT_USED_BUFFER: u32 = 1;
+
+/// Device specific half of a virtio device. The transport owns the queues and hands them to the
+/// device whenever the driver kicks one, or when the bus polls for host side input.
+pub trait VirtioDevice: Send {
+    fn device_id(&self) -> u32;
+    /// Device specific feature bits. `VIRTIO_F_VERSION_1` is added by the transport.
+    fn features(&self) -> u64;
+    fn num_queues(&self) -> usize;
+    /// Device specific configuration space, starting at offset 0x100 of the register window.
+    fn config(&self) -> Vec<u8>;
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+    /// The driver made new buffers available on `queue`. Returns whether used buffers were
+    /// returned and the driver should be interrupted.
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception>;
+    /// Give the device a chance to deliver host side input. Same return value as `notify`.
+    fn poll(&mut self, _queues: &mut [Virtqueue], _dram: &mut Dram) -> Result<bool, Exception> {
+        Ok(false)
+    }
+    /// Instruction count at which the device wants `tick` to be called next, if any.
+    fn deadline(&self) -> Option<u64> {
+        None
+    }
+    /// The instruction count returned by `deadline` was reached. Same return value as `notify`.
+    fn tick(
+        &mut self,
+        _instret: u64,
+        _queues: &mut [Virtqueue],
+        _dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        Ok(false)
+    }
+    /// The driver wrote 0 to the status register.
+    fn reset(&mut self) {}
+}
+
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    queues: Vec<Virtqueue>,
+    queue_sel: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    status: u32,
+    interrupt_status: u32,
+    config_generation: u32,
+}
+
+fn set_low(target: &mut u64, value: u64) {
+    *target = (*target & !0xffff_ffff) | (value & 0xffff_ffff);
+}
+fn set_high(target: &mut u64, value: u64) {
+    *target = (*target & 0xffff_ffff) | (value << 32);
+}
+
+impl VirtioMmio {
+    pub fn new(device: Box<dyn VirtioDevice>) -> VirtioMmio {
+        let queues = vec![Virtqueue::default(); device.num_queues()];
+        Self {
+            device,
+            queues,
+            queue_sel: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            status: 0,
+            interrupt_status: 0,
+            config_generation: 0,
+        }
+    }
+
+    pub fn device(&self) -> &dyn VirtioDevice {
+        self.device.as_ref()
+    }
+
+    pub fn device_mut(&mut self) -> &mut dyn VirtioDevice {
+        self.device.as_mut()
+    }
+
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        self.queues = vec![Virtqueue::default(); self.device.num_queues()];
+        self.queue_sel = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.interrupt_status = 0;
+        self.device.reset();
+    }
+
+    fn handle_result(&mut self, result: Result<bool, Exception>) {
+        match result {
+            Ok(true) => self.interrupt_status |= INTERRUPT_USED_BUFFER,
+            Ok(false) => (),
+            Err(_) => self.status |= STATUS_DEVICE_NEEDS_RESET,
+        }
+    }
+
+    /// `offset` is relative to the start of the register window, `size` is in bits.
+    pub fn load(&self, offset: u64, size: u64) -> u64 {
+        if offset >= CONFIG {
+            let config = self.device.config();
+            let start = (offset - CONFIG) as usize;
+            let mut value = 0;
+            for i in 0..(size / 8) as usize {
+                let byte = config.get(start + i).copied().unwrap_or(0);
+                value |= (byte as u64) << (i * 8);
+            }
+            return value;
+        }
+        let queue = self.queues.get(self.queue_sel as usize);
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => self.device.device_id() as u64,
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() & 0xffff_ffff,
+                1 => self.features() >> 32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_SIZE_MAX as u64),
+            QUEUE_READY => queue.map_or(0, |q| q.ready as u64),
+            INTERRUPT_STATUS => self.interrupt_status as u64,
+            STATUS => self.status as u64,
+            CONFIG_GENERATION => self.config_generation as u64,
+            _ => 0,
+        }
+    }
+
+    pub fn store(&mut self, offset: u64, size: u64, value: u64, dram: &mut Dram) {
+        if offset >= CONFIG {
+            let bytes = value.to_le_bytes();
+            self.device
+                .write_config(offset - CONFIG, &bytes[..(size / 8) as usize]);
+            self.config_generation = self.config_generation.wrapping_add(1);
+            return;
+        }
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value as u32,
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value as u32,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features, value),
+                1 => set_high(&mut self.driver_features, value),
+                _ => (),
+            },
+            QUEUE_SEL => self.queue_sel = value as u32,
+            QUEUE_NUM => {
+                if let Some(q) = self.selected_queue() {
+                    q.num = (value as u32).min(QUEUE_SIZE_MAX)
+                }
+            }
+            QUEUE_READY => {
+                if let Some(q) = self.selected_queue() {
+                    q.ready = value & 1 == 1
+                }
+            }
+            QUEUE_DESC_LOW => {
+                if let Some(q) = self.selected_queue() {
+                    set_low(&mut q.desc, value)
+                }
+            }
+            QUEUE_DESC_HIGH => {
+                if let Some(q) = self.selected_queue() {
+                    set_high(&mut q.desc, value)
+                }
+            }
+            QUEUE_DRIVER_LOW => {
+                if let Some(q) = self.selected_queue() {
+                    set_low(&mut q.driver, value)
+                }
+            }
+            QUEUE_DRIVER_HIGH => {
+                if let Some(q) = self.selected_queue() {
+                    set_high(&mut q.driver, value)
+                }
+            }
+            QUEUE_DEVICE_LOW => {
+                if let Some(q) = self.selected_queue() {
+                    set_low(&mut q.device, value)
+                }
+            }
+            QUEUE_DEVICE_HIGH => {
+                if let Some(q) = self.selected_queue() {
+                    set_high(&mut q.device, value)
+                }
+            }
+            QUEUE_NOTIFY => {
+                let queue = value as usize;
+                if queue < self.queues.len() && self.queues[queue].is_usable() {
+                    let result = self.device.notify(queue, &mut self.queues, dram);
+                    self.handle_result(result);
+                }
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !(value as u32),
+            STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else {
+                    self.status = value as u32;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Transport registers and queue setup for a snapshot. The device itself only keeps host
+    /// side state, which is not saved.
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        w.u32(self.device.device_id());
+        w.u32(self.queue_sel);
+        w.u32(self.device_features_sel);
+        w.u32(self.driver_features_sel);
+        w.u64(self.driver_features);
+        w.u32(self.status);
+        w.u32(self.interrupt_status);
+        w.u32(self.config_generation);
+        w.u32(self.queues.len() as u32);
+        for queue in &self.queues {
+            w.u32(queue.num);
+            w.u8(queue.ready as u8);
+            w.u64(queue.desc);
+            w.u64(queue.driver);
+            w.u64(queue.device);
+            w.u16(queue.last_avail_idx);
+        }
+    }
+
+    pub(crate) fn restore_state(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        if r.u32()? != self.device.device_id() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot virtio devices differ",
+            ));
+        }
+        self.queue_sel = r.u32()?;
+        self.device_features_sel = r.u32()?;
+        self.driver_features_sel = r.u32()?;
+        self.driver_features = r.u64()?;
+        self.status = r.u32()?;
+        self.interrupt_status = r.u32()?;
+        self.config_generation = r.u32()?;
+        if r.u32()? as usize != self.queues.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot virtqueues differ",
+            ));
+        }
+        for queue in &mut self.queues {
+            queue.num = r.u32()?;
+            queue.ready = r.u8()? != 0;
+            queue.desc = r.u64()?;
+            queue.driver = r.u64()?;
+            queue.device = r.u64()?;
+            queue.last_avail_idx = r.u16()?;
+        }
+        Ok(())
+    }
+
+    pub fn deadline(&self) -> Option<u64> {
+        self.device.deadline()
+    }
+
+    pub fn tick(&mut self, instret: u64, dram: &mut Dram) {
+        if self
+            .device
+            .deadline()
+            .is_some_and(|deadline| deadline <= instret)
+        {
+            let result = self.device.tick(instret, &mut self.queues, dram);
+            self.handle_result(result);
+        }
+    }
+
+    pub fn poll(&mut self, dram: &mut Dram) {
+        if self.queues.iter().any(|q| q.is_usable()) {
+            let result = self.device.poll(&mut self.queues, dram);
+            self.handle_result(result);
+        }
+    }
+}
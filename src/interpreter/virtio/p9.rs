@@ -0,0 +1,624 @@
+//! virtio-9p device speaking 9P2000.L, exporting one host directory to the guest.
+//!
+//! Every fid remembers its path relative to the shared root, never containing `..`. Before the
+//! host file system is touched the path is resolved and checked to still be inside the root, so
+//! neither `..` nor symlinks pointing outside let the guest escape the share.
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use super::{queue::Virtqueue, VirtioDevice};
+use crate::interpreter::{
+    dram::Dram,
+    exception::Exception,
+    host::{errno, open_options, EBADF, EINVAL, O_ACCMODE, O_CREAT, O_EXCL, O_TRUNC},
+};
+
+pub const VIRTIO_ID_9P: u32 = 9;
+/// The mount tag is available in the configuration space.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const QID_DIR: u8 = 0x80;
+const QID_SYMLINK: u8 = 0x02;
+const QID_FILE: u8 = 0x00;
+
+/// Header of every message: size[4] type[1] tag[2].
+const HEADER_SIZE: usize = 7;
+const DEFAULT_MSIZE: u32 = 8192;
+/// Mask for all fields of Rgetattr up to and including `blocks`.
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+// Linux errno values, which are what 9P2000.L puts on the wire.
+const EPERM: u32 = 1;
+const EACCES: u32 = 13;
+const EROFS: u32 = 30;
+const ENOTSUP: u32 = 95;
+
+// Tsetattr valid bits.
+const P9_SETATTR_MODE: u32 = 1 << 0;
+const P9_SETATTR_SIZE: u32 = 1 << 3;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+type P9Result<T> = Result<T, u32>;
+
+struct Fid {
+    /// Relative to the share root, normalized so it never contains `..`.
+    path: PathBuf,
+    file: Option<File>,
+    /// Snapshot of the directory listing taken on the first Treaddir.
+    entries: Option<Vec<(String, fs::Metadata)>>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Fid {
+        Self {
+            path,
+            file: None,
+            entries: None,
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> P9Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + n).ok_or(EINVAL)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> P9Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> P9Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    fn u32(&mut self) -> P9Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn u64(&mut self) -> P9Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    fn string(&mut self) -> P9Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| EINVAL)
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.data.push(v);
+        self
+    }
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn string(&mut self, s: &str) -> &mut Self {
+        self.u16(s.len() as u16);
+        self.data.extend_from_slice(s.as_bytes());
+        self
+    }
+    fn qid(&mut self, metadata: &fs::Metadata) -> &mut Self {
+        let kind = if metadata.is_dir() {
+            QID_DIR
+        } else if metadata.file_type().is_symlink() {
+            QID_SYMLINK
+        } else {
+            QID_FILE
+        };
+        self.u8(kind)
+            .u32(metadata.mtime() as u32)
+            .u64(metadata.ino())
+    }
+}
+
+pub struct Virtio9p {
+    tag: String,
+    root: PathBuf,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Virtio9p {
+    /// Export `root` under the mount tag `tag`.
+    pub fn new(tag: &str, root: &Path, read_only: bool) -> io::Result<Virtio9p> {
+        let root = root.canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}' is not a directory", root.display()),
+            ));
+        }
+        Ok(Self {
+            tag: tag.to_string(),
+            root,
+            read_only,
+            msize: DEFAULT_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    fn check_inside(&self, path: &Path) -> P9Result<()> {
+        if path.starts_with(&self.root) {
+            Ok(())
+        } else {
+            Err(EACCES)
+        }
+    }
+
+    /// Host path of `relative`. With `follow` the final component may be a symlink and is
+    /// resolved, otherwise only the parent directory is resolved.
+    fn resolve(&self, relative: &Path, follow: bool) -> P9Result<PathBuf> {
+        let full = self.root.join(relative);
+        if follow || relative.as_os_str().is_empty() {
+            let path = full.canonicalize().map_err(errno)?;
+            self.check_inside(&path)?;
+            return Ok(path);
+        }
+        let parent = full.parent().ok_or(EINVAL)?.canonicalize().map_err(errno)?;
+        self.check_inside(&parent)?;
+        Ok(parent.join(full.file_name().ok_or(EINVAL)?))
+    }
+
+    fn fid(&self, fid: u32) -> P9Result<&Fid> {
+        self.fids.get(&fid).ok_or(EBADF)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> P9Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    fn writable(&self) -> P9Result<()> {
+        if self.read_only {
+            Err(EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Path of `name` inside the directory `dfid`, for operations creating or removing entries.
+    fn child(&self, dfid: u32, name: &str) -> P9Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(EINVAL);
+        }
+        Ok(self.fid(dfid)?.path.join(name))
+    }
+
+    /// Handle one T-message and produce the matching R-message.
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reader = Reader {
+            data: request,
+            pos: 0,
+        };
+        let header = (|| -> P9Result<(u8, u16)> {
+            reader.u32()?;
+            Ok((reader.u8()?, reader.u16()?))
+        })();
+        let (kind, tag) = header.unwrap_or((0, 0xffff));
+        let mut body = Writer::default();
+        let kind = match self.dispatch(kind, &mut reader, &mut body) {
+            Ok(()) => kind + 1,
+            Err(ecode) => {
+                body = Writer::default();
+                body.u32(ecode);
+                RLERROR
+            }
+        };
+        let mut response = Writer::default();
+        response
+            .u32((HEADER_SIZE + body.data.len()) as u32)
+            .u8(kind)
+            .u16(tag);
+        response.data.extend_from_slice(&body.data);
+        response.data
+    }
+
+    fn dispatch(&mut self, kind: u8, r: &mut Reader, w: &mut Writer) -> P9Result<()> {
+        match kind {
+            TVERSION => {
+                let msize = r.u32()?;
+                let version = r.string()?;
+                self.msize = msize.clamp(HEADER_SIZE as u32 + 4096, 1024 * 1024);
+                self.fids.clear();
+                let version = if version == "9P2000.L" {
+                    "9P2000.L"
+                } else {
+                    "unknown"
+                };
+                w.u32(self.msize).string(version);
+            }
+            TATTACH => {
+                let fid = r.u32()?;
+                let _afid = r.u32()?;
+                let _uname = r.string()?;
+                let _aname = r.string()?;
+                let metadata = fs::metadata(&self.root).map_err(errno)?;
+                self.fids.insert(fid, Fid::new(PathBuf::new()));
+                w.qid(&metadata);
+            }
+            TFLUSH => {
+                // requests are handled synchronously, so there is never anything to flush
+                r.u16()?;
+            }
+            TWALK => {
+                let fid = r.u32()?;
+                let newfid = r.u32()?;
+                let nwname = r.u16()?;
+                let mut path = self.fid(fid)?.path.clone();
+                let mut qids = Vec::new();
+                for i in 0..nwname {
+                    let name = r.string()?;
+                    let next = match name.as_str() {
+                        "." => path.clone(),
+                        ".." => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                        _ if name.contains('/') || name.is_empty() => return Err(EINVAL),
+                        _ => path.join(&name),
+                    };
+                    let metadata = self
+                        .resolve(&next, false)
+                        .and_then(|host| fs::symlink_metadata(host).map_err(errno));
+                    match metadata {
+                        Ok(metadata) => qids.push(metadata),
+                        // only the first element failing is an error, otherwise the partial
+                        // walk is reported and newfid stays unused
+                        Err(e) if i == 0 => return Err(e),
+                        Err(_) => break,
+                    }
+                    path = next;
+                }
+                if qids.len() == nwname as usize {
+                    self.fids.insert(newfid, Fid::new(path));
+                }
+                w.u16(qids.len() as u16);
+                for metadata in &qids {
+                    w.qid(metadata);
+                }
+            }
+            TLOPEN => {
+                let fid = r.u32()?;
+                let flags = r.u32()?;
+                let host = self.resolve(&self.fid(fid)?.path, true)?;
+                let metadata = fs::metadata(&host).map_err(errno)?;
+                if flags & O_ACCMODE != 0 || flags & O_TRUNC != 0 {
+                    self.writable()?;
+                }
+                let file = if metadata.is_dir() {
+                    None
+                } else {
+                    Some(open_options(flags).open(&host).map_err(errno)?)
+                };
+                let iounit = self.msize - HEADER_SIZE as u32 - 4;
+                let entry = self.fid_mut(fid)?;
+                entry.file = file;
+                entry.entries = None;
+                w.qid(&metadata).u32(iounit);
+            }
+            TLCREATE => {
+                let fid = r.u32()?;
+                let name = r.string()?;
+                let flags = r.u32()?;
+                let mode = r.u32()?;
+                let _gid = r.u32()?;
+                self.writable()?;
+                let path = self.child(fid, &name)?;
+                let host = self.resolve(&path, false)?;
+                let file = open_options(flags | O_CREAT | O_EXCL)
+                    .mode(mode & 0o7777)
+                    .open(&host)
+                    .map_err(errno)?;
+                let metadata = file.metadata().map_err(errno)?;
+                let iounit = self.msize - HEADER_SIZE as u32 - 4;
+                let entry = self.fid_mut(fid)?;
+                entry.path = path;
+                entry.file = Some(file);
+                w.qid(&metadata).u32(iounit);
+            }
+            TSYMLINK => {
+                let fid = r.u32()?;
+                let name = r.string()?;
+                let target = r.string()?;
+                let _gid = r.u32()?;
+                self.writable()?;
+                let host = self.resolve(&self.child(fid, &name)?, false)?;
+                std::os::unix::fs::symlink(&target, &host).map_err(errno)?;
+                w.qid(&fs::symlink_metadata(&host).map_err(errno)?);
+            }
+            TREADLINK => {
+                let fid = r.u32()?;
+                let host = self.resolve(&self.fid(fid)?.path, false)?;
+                let target = fs::read_link(host).map_err(errno)?;
+                w.string(&target.to_string_lossy());
+            }
+            TGETATTR => {
+                let fid = r.u32()?;
+                let _request_mask = r.u64()?;
+                let host = self.resolve(&self.fid(fid)?.path, false)?;
+                let m = fs::symlink_metadata(host).map_err(errno)?;
+                w.u64(P9_GETATTR_BASIC)
+                    .qid(&m)
+                    .u32(m.mode())
+                    .u32(m.uid())
+                    .u32(m.gid())
+                    .u64(m.nlink())
+                    .u64(m.rdev())
+                    .u64(m.size())
+                    .u64(m.blksize())
+                    .u64(m.blocks())
+                    .u64(m.atime() as u64)
+                    .u64(m.atime_nsec() as u64)
+                    .u64(m.mtime() as u64)
+                    .u64(m.mtime_nsec() as u64)
+                    .u64(m.ctime() as u64)
+                    .u64(m.ctime_nsec() as u64)
+                    // btime, gen and data_version are not reported
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0);
+            }
+            TSETATTR => {
+                let fid = r.u32()?;
+                let valid = r.u32()?;
+                let mode = r.u32()?;
+                let _uid = r.u32()?;
+                let _gid = r.u32()?;
+                let size = r.u64()?;
+                self.writable()?;
+                let host = self.resolve(&self.fid(fid)?.path, true)?;
+                if valid & P9_SETATTR_MODE != 0 {
+                    fs::set_permissions(&host, fs::Permissions::from_mode(mode & 0o7777))
+                        .map_err(errno)?;
+                }
+                if valid & P9_SETATTR_SIZE != 0 {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&host)
+                        .and_then(|file| file.set_len(size))
+                        .map_err(errno)?;
+                }
+                // ownership and timestamps are left to the host
+            }
+            TXATTRWALK => return Err(ENOTSUP),
+            TREADDIR => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?;
+                let host = self.resolve(&self.fid(fid)?.path, true)?;
+                let entry = self.fid_mut(fid)?;
+                if entry.entries.is_none() || offset == 0 {
+                    entry.entries = Some(read_dir(&host)?);
+                }
+                let entries = entry.entries.as_ref().unwrap();
+                let mut data = Writer::default();
+                for (index, (name, metadata)) in entries.iter().enumerate().skip(offset as usize) {
+                    // qid[13] offset[8] type[1] name[s]
+                    if data.data.len() + 24 + name.len() > count as usize {
+                        break;
+                    }
+                    data.qid(metadata)
+                        .u64(index as u64 + 1)
+                        .u8(dirent_type(metadata))
+                        .string(name);
+                }
+                w.u32(data.data.len() as u32);
+                w.data.extend_from_slice(&data.data);
+            }
+            TFSYNC => {
+                let fid = r.u32()?;
+                if let Some(file) = &self.fid(fid)?.file {
+                    file.sync_all().map_err(errno)?;
+                }
+            }
+            TMKDIR => {
+                let dfid = r.u32()?;
+                let name = r.string()?;
+                let mode = r.u32()?;
+                let _gid = r.u32()?;
+                self.writable()?;
+                let host = self.resolve(&self.child(dfid, &name)?, false)?;
+                fs::create_dir(&host).map_err(errno)?;
+                fs::set_permissions(&host, fs::Permissions::from_mode(mode & 0o7777))
+                    .map_err(errno)?;
+                w.qid(&fs::metadata(&host).map_err(errno)?);
+            }
+            TRENAMEAT => {
+                let olddirfid = r.u32()?;
+                let oldname = r.string()?;
+                let newdirfid = r.u32()?;
+                let newname = r.string()?;
+                self.writable()?;
+                let from = self.resolve(&self.child(olddirfid, &oldname)?, false)?;
+                let to = self.resolve(&self.child(newdirfid, &newname)?, false)?;
+                fs::rename(from, to).map_err(errno)?;
+            }
+            TUNLINKAT => {
+                let dirfid = r.u32()?;
+                let name = r.string()?;
+                let flags = r.u32()?;
+                self.writable()?;
+                let host = self.resolve(&self.child(dirfid, &name)?, false)?;
+                remove(&host, flags & AT_REMOVEDIR != 0)?;
+            }
+            TREMOVE => {
+                let fid = r.u32()?;
+                let entry = self.fids.remove(&fid).ok_or(EBADF)?;
+                self.writable()?;
+                if entry.path.as_os_str().is_empty() {
+                    return Err(EPERM);
+                }
+                let host = self.resolve(&entry.path, false)?;
+                let is_dir = fs::symlink_metadata(&host).map_err(errno)?.is_dir();
+                remove(&host, is_dir)?;
+            }
+            TREAD => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?.min(self.msize - HEADER_SIZE as u32 - 4);
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                let mut data = vec![0; count as usize];
+                let n = file.read_at(&mut data, offset).map_err(errno)?;
+                w.u32(n as u32);
+                w.data.extend_from_slice(&data[..n]);
+            }
+            TWRITE => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?;
+                let data = r.take(count as usize)?;
+                self.writable()?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                let n = file.write_at(data, offset).map_err(errno)?;
+                w.u32(n as u32);
+            }
+            TCLUNK => {
+                let fid = r.u32()?;
+                self.fids.remove(&fid).ok_or(EBADF)?;
+            }
+            TSTATFS => {
+                let fid = r.u32()?;
+                self.fid(fid)?;
+                // the host statfs needs libc, report a plausible fixed geometry instead
+                w.u32(0x0102_1997) // V9FS_MAGIC
+                    .u32(4096)
+                    .u64(1 << 20)
+                    .u64(1 << 19)
+                    .u64(1 << 19)
+                    .u64(1 << 16)
+                    .u64(1 << 15)
+                    .u64(0)
+                    .u32(255);
+            }
+            _ => return Err(ENOTSUP),
+        }
+        Ok(())
+    }
+}
+
+fn remove(host: &Path, is_dir: bool) -> P9Result<()> {
+    if is_dir {
+        fs::remove_dir(host).map_err(errno)
+    } else {
+        fs::remove_file(host).map_err(errno)
+    }
+}
+
+fn read_dir(host: &Path) -> P9Result<Vec<(String, fs::Metadata)>> {
+    let mut entries = vec![
+        (".".to_string(), fs::metadata(host).map_err(errno)?),
+        // the parent of the share root is presented as the root itself
+        ("..".to_string(), fs::metadata(host).map_err(errno)?),
+    ];
+    let mut children = Vec::new();
+    for entry in fs::read_dir(host).map_err(errno)? {
+        let entry = entry.map_err(errno)?;
+        let metadata = fs::symlink_metadata(entry.path()).map_err(errno)?;
+        children.push((entry.file_name().to_string_lossy().into_owned(), metadata));
+    }
+    children.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.extend(children);
+    Ok(entries)
+}
+
+/// `d_type` values of struct dirent.
+fn dirent_type(metadata: &fs::Metadata) -> u8 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        4
+    } else if file_type.is_symlink() {
+        10
+    } else {
+        8
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        config
+    }
+
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(dram)? {
+            let request = chain.read_all(dram)?;
+            let response = self.handle(&request);
+            let len = chain.write_all(dram, &response)?;
+            queues[queue].push_used(dram, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn reset(&mut self) {
+        self.fids.clear();
+        self.msize = DEFAULT_MSIZE;
+    }
+}
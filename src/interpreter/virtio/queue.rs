@@ -0,0 +1,182 @@
+use crate::interpreter::{dram::Dram, exception::Exception};
+
+/// This marks a buffer as continuing via the next field.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// This marks a buffer as device write-only (otherwise device read-only).
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// This means the buffer contains a list of buffer descriptors.
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+const DESC_SIZE: u64 = 16;
+/// Descriptors followed for one chain, indirect ones included, before it is rejected.
+const MAX_CHAIN_LEN: usize = 4096;
+/// Largest request gathered from the device-readable buffers of a chain.
+const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+/// Most bytes a device produces for one chain when it fills as much as the driver offers.
+const MAX_FILL: usize = 64 * 1024;
+
+/// Split virtqueue as described in the virtio 1.1 spec, section 2.6.
+#[derive(Default, Clone)]
+pub struct Virtqueue {
+    pub num: u32,
+    pub ready: bool,
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    pub last_avail_idx: u16,
+}
+
+/// One buffer of a descriptor chain: guest physical address and length.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+}
+
+/// A descriptor chain popped from the available ring, split into the device-readable and the
+/// device-writable part.
+#[derive(Debug, Default)]
+pub struct DescriptorChain {
+    pub head: u16,
+    pub readable: Vec<Buffer>,
+    pub writable: Vec<Buffer>,
+}
+
+impl DescriptorChain {
+    /// Gather all device-readable buffers into one contiguous request, of at most
+    /// `MAX_REQUEST_SIZE` bytes.
+    pub fn read_all(&self, dram: &Dram) -> Result<Vec<u8>, Exception> {
+        let mut data = Vec::new();
+        for buffer in &self.readable {
+            let start = data.len();
+            if start + buffer.len as usize > MAX_REQUEST_SIZE
+                || !dram.contains(buffer.addr, buffer.len as usize)
+            {
+                return Err(Exception::LoadAccessFault {
+                    address: buffer.addr,
+                });
+            }
+            data.resize(start + buffer.len as usize, 0);
+            dram.read_bytes(buffer.addr, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Scatter `data` into the device-writable buffers. Returns the number of bytes written,
+    /// which is less than `data.len()` when the driver did not provide enough room.
+    pub fn write_all(&self, dram: &mut Dram, data: &[u8]) -> Result<u32, Exception> {
+        let mut written = 0usize;
+        for buffer in &self.writable {
+            if written == data.len() {
+                break;
+            }
+            let n = (buffer.len as usize).min(data.len() - written);
+            dram.write_bytes(buffer.addr, &data[written..written + n])?;
+            written += n;
+        }
+        Ok(written as u32)
+    }
+
+    pub fn writable_len(&self) -> u64 {
+        self.writable
+            .iter()
+            .fold(0u64, |len, buffer| len.saturating_add(buffer.len as u64))
+    }
+
+    /// Room in the device-writable buffers, up to `MAX_FILL`.
+    pub fn fill_len(&self) -> usize {
+        self.writable_len().min(MAX_FILL as u64) as usize
+    }
+}
+
+fn load_u16(dram: &Dram, addr: u64) -> Result<u16, Exception> {
+    let mut buf = [0; 2];
+    dram.read_bytes(addr, &mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+impl Virtqueue {
+    pub fn is_usable(&self) -> bool {
+        self.ready && self.num != 0
+    }
+
+    /// Put the most recently popped chain back, for devices that found nothing to fill it with.
+    pub fn unpop(&mut self) {
+        self.last_avail_idx = self.last_avail_idx.wrapping_sub(1);
+    }
+
+    /// Take the next chain the driver made available, if any.
+    pub fn pop(&mut self, dram: &Dram) -> Result<Option<DescriptorChain>, Exception> {
+        if !self.is_usable() {
+            return Ok(None);
+        }
+        let avail_idx = load_u16(dram, self.driver + 2)?;
+        if avail_idx == self.last_avail_idx {
+            return Ok(None);
+        }
+        let slot = (self.last_avail_idx as u64) % self.num as u64;
+        let head = load_u16(dram, self.driver + 4 + slot * 2)?;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+
+        let mut chain = DescriptorChain {
+            head,
+            ..Default::default()
+        };
+        self.walk(dram, self.desc, self.num, head, false, &mut chain)?;
+        Ok(Some(chain))
+    }
+
+    fn walk(
+        &self,
+        dram: &Dram,
+        table: u64,
+        size: u32,
+        head: u16,
+        indirect: bool,
+        chain: &mut DescriptorChain,
+    ) -> Result<(), Exception> {
+        let mut index = head;
+        // a malformed ring could loop forever, so never follow more links than the table has
+        for _ in 0..size {
+            let desc = table.wrapping_add(index as u64 * DESC_SIZE);
+            let fault = Exception::LoadAccessFault { address: desc };
+            if index as u32 >= size || chain.readable.len() + chain.writable.len() >= MAX_CHAIN_LEN
+            {
+                return Err(fault);
+            }
+            let mut raw = [0; DESC_SIZE as usize];
+            dram.read_bytes(desc, &mut raw)?;
+            let addr = u64::from_le_bytes(raw[0..8].try_into().unwrap());
+            let len = u32::from_le_bytes(raw[8..12].try_into().unwrap());
+            let flags = u16::from_le_bytes(raw[12..14].try_into().unwrap());
+            let next = u16::from_le_bytes(raw[14..16].try_into().unwrap());
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                // an indirect table must not refer to another one
+                if indirect {
+                    return Err(fault);
+                }
+                self.walk(dram, addr, len / DESC_SIZE as u32, 0, true, chain)?;
+            } else if flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable.push(Buffer { addr, len });
+            } else {
+                chain.readable.push(Buffer { addr, len });
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+        Ok(())
+    }
+
+    /// Return a chain to the driver through the used ring.
+    pub fn push_used(&mut self, dram: &mut Dram, head: u16, len: u32) -> Result<(), Exception> {
+        let used_idx = load_u16(dram, self.device + 2)?;
+        let slot = (used_idx as u64) % self.num as u64;
+        let mut elem = [0; 8];
+        elem[0..4].copy_from_slice(&(head as u32).to_le_bytes());
+        elem[4..8].copy_from_slice(&len.to_le_bytes());
+        dram.write_bytes(self.device + 4 + slot * 8, &elem)?;
+        dram.write_bytes(self.device + 2, &used_idx.wrapping_add(1).to_le_bytes())
+    }
+}
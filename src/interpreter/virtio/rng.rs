@@ -0,0 +1,92 @@
+//! virtio-rng entropy device.
+use std::{fs::File, io::Read};
+
+use super::{queue::Virtqueue, VirtioDevice};
+use crate::interpreter::{dram::Dram, exception::Exception, replay::Channel};
+
+pub const VIRTIO_ID_RNG: u32 = 4;
+
+pub enum EntropySource {
+    /// splitmix64 stream, so runs are reproducible.
+    Seeded(u64),
+    /// The host's `/dev/urandom`.
+    Host(File),
+    /// Another source, recorded or replayed.
+    Journaled(Box<EntropySource>, Channel),
+}
+
+impl EntropySource {
+    pub fn host() -> std::io::Result<EntropySource> {
+        Ok(EntropySource::Host(File::open("/dev/urandom")?))
+    }
+
+    pub fn journaled(self, channel: Channel) -> EntropySource {
+        EntropySource::Journaled(Box::new(self), channel)
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        match self {
+            EntropySource::Seeded(state) => {
+                for chunk in buf.chunks_mut(8) {
+                    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+            }
+            EntropySource::Host(file) => {
+                if file.read_exact(buf).is_err() {
+                    buf.fill(0);
+                }
+            }
+            EntropySource::Journaled(source, channel) => channel.fill(buf, |buf| source.fill(buf)),
+        }
+    }
+}
+
+pub struct VirtioRng {
+    source: EntropySource,
+}
+
+impl VirtioRng {
+    pub fn new(source: EntropySource) -> VirtioRng {
+        Self { source }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(dram)? {
+            let mut data = vec![0; chain.fill_len()];
+            self.source.fill(&mut data);
+            let len = chain.write_all(dram, &data)?;
+            queues[queue].push_used(dram, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
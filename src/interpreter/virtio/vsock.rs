@@ -0,0 +1,493 @@
+//! virtio-vsock bridged to host UNIX sockets.
+//!
+//! The host side follows the hybrid scheme used by Firecracker:
+//! - A guest connecting to CID 2 (the host) on port `P` is connected to the UNIX socket
+//!   `<path>_<P>`, which some host process has to listen on.
+//! - A host process connects to the UNIX socket `<path>` and sends `CONNECT <P>\n`. Once a guest
+//!   process listening on port `P` accepts, the device answers `OK <host port>\n` and the stream
+//!   carries the connection from then on.
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+};
+
+use super::{queue::Virtqueue, VirtioDevice};
+use crate::interpreter::{dram::Dram, exception::Exception};
+
+pub const VIRTIO_ID_VSOCK: u32 = 19;
+pub const VSOCK_HOST_CID: u64 = 2;
+
+const RX: usize = 0;
+const TX: usize = 1;
+
+const HEADER_SIZE: usize = 44;
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// Both directions of a SHUTDOWN.
+const VIRTIO_VSOCK_SHUTDOWN_BOTH: u32 = 3;
+
+/// Bytes the device is willing to buffer per connection on its way to the host socket.
+const BUF_ALLOC: u32 = 256 * 1024;
+/// Host side ports for host initiated connections are handed out from here.
+const FIRST_HOST_PORT: u32 = 1 << 30;
+/// Stop reading host sockets while this many packets wait for guest receive buffers.
+const MAX_PENDING_PACKETS: usize = 64;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Header {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    kind: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Option<Header> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes(data[i..i + 2].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        Some(Header {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            kind: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE);
+        data.extend_from_slice(&self.src_cid.to_le_bytes());
+        data.extend_from_slice(&self.dst_cid.to_le_bytes());
+        data.extend_from_slice(&self.src_port.to_le_bytes());
+        data.extend_from_slice(&self.dst_port.to_le_bytes());
+        data.extend_from_slice(&self.len.to_le_bytes());
+        data.extend_from_slice(&self.kind.to_le_bytes());
+        data.extend_from_slice(&self.op.to_le_bytes());
+        data.extend_from_slice(&self.flags.to_le_bytes());
+        data.extend_from_slice(&self.buf_alloc.to_le_bytes());
+        data.extend_from_slice(&self.fwd_cnt.to_le_bytes());
+        data
+    }
+}
+
+#[derive(PartialEq)]
+enum State {
+    /// Host initiated, waiting for the guest to answer the REQUEST.
+    Connecting,
+    Established,
+    /// The host socket hit EOF and the guest was told to shut down.
+    Closing,
+}
+
+struct Connection {
+    stream: UnixStream,
+    state: State,
+    /// Guest to host bytes not yet accepted by the host socket.
+    outgoing: Vec<u8>,
+    /// Bytes handed to the host socket, advertised to the guest as fwd_cnt.
+    fwd_cnt: u32,
+    /// fwd_cnt in the last packet sent to the guest.
+    last_fwd_cnt: u32,
+    /// Bytes sent to the guest, and the guest's own buffer space and fwd_cnt.
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, state: State) -> Connection {
+        Self {
+            stream,
+            state,
+            outgoing: Vec::new(),
+            fwd_cnt: 0,
+            last_fwd_cnt: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    /// Bytes the guest can still take.
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add(n as u32);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A host stream that connected to the listener and has not sent its CONNECT line yet.
+struct Handshake {
+    stream: UnixStream,
+    line: Vec<u8>,
+}
+
+pub struct VirtioVsock {
+    guest_cid: u64,
+    path: PathBuf,
+    listener: UnixListener,
+    handshakes: Vec<Handshake>,
+    /// Keyed by (host port, guest port).
+    connections: HashMap<(u32, u32), Connection>,
+    /// Packets waiting for a receive buffer.
+    pending: VecDeque<Vec<u8>>,
+    next_host_port: u32,
+}
+
+impl VirtioVsock {
+    /// A device with context id `guest_cid`, listening for host connections on `path`.
+    pub fn new(guest_cid: u64, path: &Path) -> io::Result<VirtioVsock> {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            guest_cid,
+            path: path.to_path_buf(),
+            listener,
+            handshakes: Vec::new(),
+            connections: HashMap::new(),
+            pending: VecDeque::new(),
+            next_host_port: FIRST_HOST_PORT,
+        })
+    }
+
+    /// The host socket a guest connection to `port` is forwarded to.
+    pub fn host_socket(&self, port: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!("_{}", port));
+        path.into()
+    }
+
+    fn send(&mut self, key: (u32, u32), op: u16, flags: u32, payload: &[u8]) {
+        let mut header = Header {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: key.0,
+            dst_port: key.1,
+            len: payload.len() as u32,
+            kind: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: BUF_ALLOC,
+            fwd_cnt: 0,
+        };
+        if let Some(connection) = self.connections.get_mut(&key) {
+            header.fwd_cnt = connection.fwd_cnt;
+            connection.last_fwd_cnt = connection.fwd_cnt;
+            connection.tx_cnt = connection.tx_cnt.wrapping_add(payload.len() as u32);
+        }
+        let mut packet = header.encode();
+        packet.extend_from_slice(payload);
+        self.pending.push_back(packet);
+    }
+
+    fn reset(&mut self, key: (u32, u32)) {
+        self.connections.remove(&key);
+        self.send(key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+    }
+
+    /// Handle one packet the guest transmitted.
+    fn handle_packet(&mut self, header: Header, payload: &[u8]) {
+        if header.kind != VIRTIO_VSOCK_TYPE_STREAM || header.dst_cid != VSOCK_HOST_CID {
+            if header.op != VIRTIO_VSOCK_OP_RST {
+                self.send(
+                    (header.dst_port, header.src_port),
+                    VIRTIO_VSOCK_OP_RST,
+                    0,
+                    &[],
+                );
+            }
+            return;
+        }
+        let key = (header.dst_port, header.src_port);
+        if let Some(connection) = self.connections.get_mut(&key) {
+            connection.peer_buf_alloc = header.buf_alloc;
+            connection.peer_fwd_cnt = header.fwd_cnt;
+        }
+        match header.op {
+            VIRTIO_VSOCK_OP_REQUEST => {
+                if self.connections.contains_key(&key) {
+                    return self.reset(key);
+                }
+                match UnixStream::connect(self.host_socket(header.dst_port)) {
+                    Ok(stream) if stream.set_nonblocking(true).is_ok() => {
+                        let mut connection = Connection::new(stream, State::Established);
+                        connection.peer_buf_alloc = header.buf_alloc;
+                        connection.peer_fwd_cnt = header.fwd_cnt;
+                        self.connections.insert(key, connection);
+                        self.send(key, VIRTIO_VSOCK_OP_RESPONSE, 0, &[]);
+                    }
+                    _ => self.send(key, VIRTIO_VSOCK_OP_RST, 0, &[]),
+                }
+            }
+            VIRTIO_VSOCK_OP_RESPONSE => {
+                let Some(connection) = self.connections.get_mut(&key) else {
+                    return self.send(key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+                };
+                if connection.state != State::Connecting {
+                    return self.reset(key);
+                }
+                connection.state = State::Established;
+                let reply = format!("OK {}\n", key.0);
+                connection.outgoing.extend_from_slice(reply.as_bytes());
+                // the reply is not guest data, keep it out of fwd_cnt
+                connection.fwd_cnt = connection.fwd_cnt.wrapping_sub(reply.len() as u32);
+                if connection.flush().is_err() {
+                    self.reset(key);
+                }
+            }
+            VIRTIO_VSOCK_OP_RW => {
+                let Some(connection) = self.connections.get_mut(&key) else {
+                    return self.send(key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+                };
+                connection.outgoing.extend_from_slice(payload);
+                if connection.flush().is_err() {
+                    return self.reset(key);
+                }
+                if connection.fwd_cnt.wrapping_sub(connection.last_fwd_cnt) > BUF_ALLOC / 2 {
+                    self.send(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[]);
+                }
+            }
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST if self.connections.contains_key(&key) => {
+                self.send(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[]);
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                if let Some(mut connection) = self.connections.remove(&key) {
+                    let _ = connection.flush();
+                    let _ = connection.stream.shutdown(std::net::Shutdown::Both);
+                }
+                self.send(key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+            }
+            VIRTIO_VSOCK_OP_RST => {
+                self.connections.remove(&key);
+            }
+            // CREDIT_UPDATE only carries the buffer fields handled above
+            _ => (),
+        }
+    }
+
+    /// Accept host streams and turn complete `CONNECT` lines into guest connection requests.
+    fn poll_listener(&mut self) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.handshakes.push(Handshake {
+                    stream,
+                    line: Vec::new(),
+                });
+            }
+        }
+        let mut index = 0;
+        while index < self.handshakes.len() {
+            let handshake = &mut self.handshakes[index];
+            let mut byte = [0];
+            let mut done = false;
+            let mut failed = false;
+            loop {
+                match handshake.stream.read(&mut byte) {
+                    Ok(1) if byte[0] == b'\n' => {
+                        done = true;
+                        break;
+                    }
+                    Ok(1) if handshake.line.len() < 64 => handshake.line.push(byte[0]),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    // EOF, error or overlong line
+                    _ => {
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            if failed {
+                // the last handshake moved to `index` and is looked at next
+                self.handshakes.swap_remove(index);
+                continue;
+            }
+            if !done {
+                index += 1;
+                continue;
+            }
+            let Handshake { mut stream, line } = self.handshakes.swap_remove(index);
+            let port = std::str::from_utf8(&line)
+                .ok()
+                .and_then(|line| line.trim().strip_prefix("CONNECT "))
+                .and_then(|port| port.trim().parse::<u32>().ok());
+            let Some(port) = port else {
+                let _ = stream.write_all(b"ERROR\n");
+                continue;
+            };
+            let key = (self.next_host_port, port);
+            self.next_host_port = self.next_host_port.wrapping_add(1).max(FIRST_HOST_PORT);
+            self.connections
+                .insert(key, Connection::new(stream, State::Connecting));
+            self.send(key, VIRTIO_VSOCK_OP_REQUEST, 0, &[]);
+        }
+    }
+
+    /// Move data from host sockets towards the guest, within the guest's credit.
+    fn poll_connections(&mut self) {
+        let mut packets = Vec::new();
+        let mut closed = Vec::new();
+        for (key, connection) in &mut self.connections {
+            if connection.flush().is_err() {
+                closed.push(*key);
+                continue;
+            }
+            if connection.fwd_cnt != connection.last_fwd_cnt {
+                packets.push((*key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, Vec::new()));
+            }
+            if connection.state != State::Established {
+                continue;
+            }
+            let credit = connection.peer_credit().min(64 * 1024) as usize;
+            if credit == 0 || self.pending.len() + packets.len() >= MAX_PENDING_PACKETS {
+                continue;
+            }
+            let mut buf = vec![0; credit];
+            match connection.stream.read(&mut buf) {
+                Ok(0) => {
+                    connection.state = State::Closing;
+                    packets.push((
+                        *key,
+                        VIRTIO_VSOCK_OP_SHUTDOWN,
+                        VIRTIO_VSOCK_SHUTDOWN_BOTH,
+                        Vec::new(),
+                    ));
+                }
+                Ok(n) => {
+                    buf.truncate(n);
+                    packets.push((*key, VIRTIO_VSOCK_OP_RW, 0, buf));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(_) => closed.push(*key),
+            }
+        }
+        for key in closed {
+            self.reset(key);
+        }
+        for (key, op, flags, payload) in packets {
+            self.send(key, op, flags, &payload);
+        }
+    }
+
+    fn deliver(&mut self, queues: &mut [Virtqueue], dram: &mut Dram) -> Result<bool, Exception> {
+        let mut used = false;
+        while !self.pending.is_empty() {
+            let Some(chain) = queues[RX].pop(dram)? else {
+                break;
+            };
+            let mut packet = self.pending.pop_front().unwrap();
+            let room = chain.writable_len() as usize;
+            if packet.len() > room && room > HEADER_SIZE {
+                // split the payload over several receive buffers
+                let mut header = Header::parse(&packet).unwrap();
+                let rest = packet.split_off(room);
+                header.len = (room - HEADER_SIZE) as u32;
+                packet[..HEADER_SIZE].copy_from_slice(&header.encode());
+                header.len = rest.len() as u32;
+                self.pending.push_front([header.encode(), rest].concat());
+            }
+            let len = chain.write_all(dram, &packet)?;
+            queues[RX].push_used(dram, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl Drop for VirtioVsock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl VirtioDevice for VirtioVsock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_VSOCK
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        // rx, tx and event
+        3
+    }
+
+    fn config(&self) -> Vec<u8> {
+        self.guest_cid.to_le_bytes().to_vec()
+    }
+
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        dram: &mut Dram,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        if queue == TX {
+            while let Some(chain) = queues[TX].pop(dram)? {
+                let packet = chain.read_all(dram)?;
+                if let Some(header) = Header::parse(&packet) {
+                    let end = (HEADER_SIZE + header.len as usize).min(packet.len());
+                    self.handle_packet(header, &packet[HEADER_SIZE..end]);
+                }
+                queues[TX].push_used(dram, chain.head, 0)?;
+                used = true;
+            }
+        }
+        used |= self.deliver(queues, dram)?;
+        Ok(used)
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], dram: &mut Dram) -> Result<bool, Exception> {
+        self.poll_listener();
+        self.poll_connections();
+        self.deliver(queues, dram)
+    }
+
+    fn reset(&mut self) {
+        self.connections.clear();
+        self.handshakes.clear();
+        self.pending.clear();
+    }
+}
@@ -0,0 +1,86 @@
+mod utils;
+use riscv::interpreter::{
+    arch_test::{isa_yaml, run_test, write_riscof_config, ISA, MODEL_TEST_H},
+    DRAM_BASE,
+};
+use utils::{
+    elf::{build_elf, code},
+    temp::TempPath,
+};
+
+const TOHOST: u64 = DRAM_BASE + 0x1000;
+const SIGNATURE: u64 = DRAM_BASE + 0x2000;
+
+/// A test built with model_test.h: it stores its results in the signature and halts by
+/// writing 1 to tohost.
+fn fake_test() -> Vec<u8> {
+    let program = code(&[
+        0x0000_2297, // auipc t0, 2
+        0x1230_0313, // li t1, 0x123
+        0x0062_a023, // sw t1, 0(t0)
+        0xfff0_0313, // li t1, -1
+        0x0062_a223, // sw t1, 4(t0)
+        0x0010_0393, // li t2, 1
+        0x0000_1e17, // auipc t3, 1
+        0xfe7e_2423, // sw t2, -0x18(t3)
+        0x0000_006f, // j .
+    ]);
+    build_elf(
+        DRAM_BASE,
+        &[
+            (DRAM_BASE, &program),
+            (TOHOST, &[0; 16]),
+            (SIGNATURE, &[0; 16]),
+        ],
+        &[
+            ("tohost", TOHOST),
+            ("fromhost", TOHOST + 8),
+            ("begin_signature", SIGNATURE),
+            ("end_signature", SIGNATURE + 16),
+        ],
+    )
+}
+
+#[test]
+fn test_signature() {
+    let test = TempPath::file("arch-test-signature", &fake_test());
+    assert_eq!(
+        run_test(&test, 4, 1000).unwrap(),
+        "00000123\nffffffff\n00000000\n00000000\n"
+    );
+    assert_eq!(
+        run_test(&test, 8, 1000).unwrap(),
+        "ffffffff00000123\n0000000000000000\n"
+    );
+    assert!(run_test(&test, 3, 1000).is_err());
+    assert!(run_test(&test, 4, 3).is_err());
+}
+
+#[test]
+fn test_missing_signature() {
+    let elf = build_elf(
+        DRAM_BASE,
+        &[(DRAM_BASE, &code(&[0x0000_006f]))],
+        &[("tohost", TOHOST)],
+    );
+    let test = TempPath::file("arch-test-missing", &elf);
+    assert!(run_test(&test, 4, 10)
+        .unwrap_err()
+        .contains("begin_signature"));
+}
+
+#[test]
+fn test_riscof_config() {
+    let yaml = isa_yaml();
+    assert!(yaml.contains(&format!("ISA: {}", ISA)));
+    assert!(yaml.contains("reset-val: 0x8000000000000100"));
+    let dir = TempPath::new("arch-test-config");
+    write_riscof_config(&dir).unwrap();
+    for name in ["riscv_isa.yaml", "riscv_platform.yaml"] {
+        assert!(dir.join(name).is_file());
+    }
+    assert_eq!(
+        std::fs::read_to_string(dir.join("model_test.h")).unwrap(),
+        MODEL_TEST_H
+    );
+}
@@ -0,0 +1,171 @@
+mod utils;
+use std::{thread, time::Duration};
+
+use riscv::interpreter::{
+    cpu::{
+        control::{StopReason, WatchKind, Watchpoint},
+        Cpu,
+    },
+    exception::Exception,
+    DRAM_BASE,
+};
+use utils::elf::code;
+
+const DATA: u64 = DRAM_BASE + 0x1008;
+
+fn program() -> Cpu {
+    Cpu::new(code(&[
+        0x0010_0513, // li a0, 1
+        0x0015_0513, // addi a0, a0, 1
+        0x0000_1597, // auipc a1, 1
+        0x00a5_b023, // sd a0, 0(a1)
+        0x0005_b603, // ld a2, 0(a1)
+        0x0010_02b7, // lui t0, 0x100
+        0x0000_5337, // lui t1, 5
+        0x5553_0313, // addi t1, t1, 0x555
+        0x0062_a023, // sw t1, 0(t0) (syscon power off)
+    ]))
+}
+
+#[test]
+fn test_step_and_limit() {
+    let mut cpu = program();
+    assert_eq!(cpu.step(), StopReason::Limit);
+    assert_eq!(cpu.pc, DRAM_BASE + 4);
+    assert_eq!(cpu.regs[10], 1);
+    assert_eq!(cpu.run(2), StopReason::Limit);
+    assert_eq!(cpu.instret, 3);
+    assert_eq!(cpu.run(100), StopReason::Exited { code: 0 });
+    assert_eq!(cpu.exit_code, Some(0));
+}
+
+#[test]
+fn test_breakpoints_and_run_until() {
+    let mut cpu = program();
+    cpu.add_breakpoint(DRAM_BASE + 4);
+    cpu.add_breakpoint(DRAM_BASE + 12);
+    assert_eq!(cpu.run(100), StopReason::Breakpoint { pc: DRAM_BASE + 4 });
+    assert_eq!(cpu.instret, 1);
+    // resuming executes the instruction under the breakpoint
+    assert_eq!(cpu.run(100), StopReason::Breakpoint { pc: DRAM_BASE + 12 });
+    assert!(cpu.remove_breakpoint(DRAM_BASE + 12));
+    assert!(!cpu.remove_breakpoint(DRAM_BASE + 12));
+    assert_eq!(
+        cpu.run_until(DRAM_BASE + 20, 100),
+        StopReason::Breakpoint { pc: DRAM_BASE + 20 }
+    );
+    assert_eq!(cpu.regs[12], 2);
+}
+
+#[test]
+fn test_watchpoints() {
+    let mut cpu = program();
+    let write = Watchpoint {
+        addr: DATA,
+        len: 4,
+        kind: WatchKind::Write,
+    };
+    cpu.add_watchpoint(write);
+    cpu.add_watchpoint(Watchpoint {
+        addr: DATA - 8,
+        len: 16,
+        kind: WatchKind::Access,
+    });
+    // the store hits both watchpoints but is reported once
+    assert_eq!(
+        cpu.run(100),
+        StopReason::Watchpoint {
+            addr: DATA,
+            kind: WatchKind::Write
+        }
+    );
+    assert_eq!(cpu.pc, DRAM_BASE + 16);
+    assert_eq!(
+        cpu.run(100),
+        StopReason::Watchpoint {
+            addr: DATA,
+            kind: WatchKind::Read
+        }
+    );
+    assert!(cpu.remove_watchpoint(&write));
+    assert_eq!(cpu.run(100), StopReason::Exited { code: 0 });
+}
+
+#[test]
+fn test_interrupt_from_another_thread() {
+    let mut cpu = Cpu::new(code(&[0x0000_006f])); // j .
+    let handle = cpu.interrupt_handle();
+    handle.interrupt();
+    assert_eq!(cpu.run(u64::MAX), StopReason::Interrupted);
+    assert_eq!(cpu.instret, 0);
+
+    let interrupter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        handle.interrupt();
+    });
+    assert_eq!(cpu.run(u64::MAX), StopReason::Interrupted);
+    assert!(cpu.instret > 0);
+    interrupter.join().unwrap();
+}
+
+#[test]
+fn test_exception_stops_run() {
+    let mut cpu = Cpu::new(code(&[0x0010_0513, 0x0000_0000]));
+    assert_eq!(
+        cpu.run(100),
+        StopReason::Exception(Exception::InvalidInstruction)
+    );
+    assert_eq!(cpu.pc, DRAM_BASE + 4);
+}
+
+#[test]
+fn test_unimplemented_instruction_traps() {
+    let program = code(&[
+        0x0000_0297, // auipc t0, 0
+        0x0102_8293, // addi t0, t0, 16
+        0x3052_9073, // csrw mtvec, t0
+        0x02a5_0533, // mul a0, a0, a0
+        0x3420_2573, // handler: csrr a0, mcause
+        0x3410_25f3, // csrr a1, mepc
+    ]);
+    let mut cpu = Cpu::new(program.clone());
+    assert_eq!(
+        cpu.run(100),
+        StopReason::Exception(Exception::InvalidInstruction)
+    );
+
+    let mut cpu = Cpu::new(program);
+    cpu.set_trap_delivery(true);
+    assert_eq!(cpu.run(6), StopReason::Limit);
+    assert_eq!(cpu.regs[10], 2);
+    assert_eq!(cpu.regs[11], DRAM_BASE + 12);
+}
+
+#[test]
+fn test_faulting_handler_stops_at_limit() {
+    // mtvec is 0, where there is no memory
+    let mut cpu = Cpu::new(code(&[0x0000_0000]));
+    cpu.set_trap_delivery(true);
+    assert_eq!(cpu.run(1000), StopReason::Limit);
+    assert_eq!(cpu.pc, 0);
+    assert_eq!(cpu.instret, 0);
+}
+
+#[test]
+fn test_faulting_access_does_not_hit_watchpoint() {
+    let mut cpu = Cpu::new(code(&[
+        0x0000_3503, // ld a0, 0(zero)
+        0x0010_0593, // li a1, 1
+    ]));
+    cpu.add_watchpoint(Watchpoint {
+        addr: 0,
+        len: 8,
+        kind: WatchKind::Access,
+    });
+    assert_eq!(
+        cpu.run(100),
+        StopReason::Exception(Exception::LoadAccessFault { address: 0 })
+    );
+    cpu.pc += 4;
+    assert_eq!(cpu.run(1), StopReason::Limit);
+}
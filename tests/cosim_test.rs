@@ -0,0 +1,144 @@
+mod utils;
+use std::{io::Cursor, process::Command};
+
+use riscv::interpreter::{
+    cosim::{parse_commit, Lockstep, ProcessModel, SpikeLog},
+    cpu::{
+        control::StopReason,
+        trace::{TraceFormat, Tracer},
+        Cpu,
+    },
+    exception::Exception,
+    DRAM_BASE,
+};
+use utils::{buffer::Buffer, elf::code, programs::STORE_LOOP, temp::TempPath};
+
+fn program() -> Cpu {
+    Cpu::new(code(STORE_LOOP))
+}
+
+/// The commit log of the first `limit` instructions of `cpu`, as Spike would write it.
+fn reference_log(mut cpu: Cpu, limit: u64) -> Vec<String> {
+    let buffer = Buffer::default();
+    cpu.set_tracer(Tracer::new(TraceFormat::Spike, Box::new(buffer.clone())));
+    cpu.run(limit);
+    buffer.lines()
+}
+
+fn spike_log(lines: &[String]) -> SpikeLog {
+    // the boot ROM and lines that are not commits come first
+    let mut log = String::from(
+        "core   0: 3 0x0000000000001000 (0x00000297) x5  0x0000000000001000\n\
+         core   0: 0x0000000000001004 (0x02028593) addi    a1, t0, 32\n",
+    );
+    for line in lines {
+        log += line;
+        log += "\n";
+    }
+    SpikeLog::new(Box::new(Cursor::new(log))).starting_at(DRAM_BASE)
+}
+
+#[test]
+fn test_lockstep_with_spike_log() {
+    let lines = reference_log(program(), 100);
+    assert_eq!(lines.len(), 13);
+    assert_eq!(
+        lines[1],
+        "core   0: 3 0x0000000080000004 (0x34059073) c832_mscratch 0x0000000000000003"
+    );
+    let mut lockstep = Lockstep::new(Box::new(spike_log(&lines)));
+    let mut cpu = program();
+    assert_eq!(
+        lockstep.run(&mut cpu, 100),
+        Ok(StopReason::Exception(Exception::Breakpoint))
+    );
+    assert_eq!(cpu.read_reg(10), 1);
+}
+
+#[test]
+fn test_lockstep_reports_divergence() {
+    let mut lines = reference_log(program(), 100);
+    lines[7] = lines[7].replace("x11 0x0000000000000001", "x11 0x0000000000000005");
+    let mut lockstep = Lockstep::new(Box::new(spike_log(&lines))).with_context(2);
+    let mut cpu = program();
+    let divergence = lockstep.run(&mut cpu, 100).unwrap_err();
+    assert_eq!(divergence.instret, 7);
+    assert_eq!(divergence.reason, "register writes differ");
+    assert_eq!(divergence.expected.unwrap().writes, [(11, 5)]);
+    assert_eq!(divergence.actual.writes, [(11, 1)]);
+    assert_eq!(divergence.context.len(), 2);
+    assert_eq!(divergence.context[1].instret, 6);
+
+    // the reference ends early
+    let mut lockstep = Lockstep::new(Box::new(spike_log(&lines[..4])));
+    let divergence = lockstep.run(&mut program(), 100).unwrap_err();
+    assert_eq!(divergence.instret, 4);
+    assert_eq!(divergence.reason, "the reference stopped");
+}
+
+#[test]
+fn test_lockstep_through_trap_handler() {
+    let program = || {
+        let mut cpu = Cpu::new(code(&[
+            0x0000_0297, // auipc t0, 0
+            0x0102_8293, // addi t0, t0, 16
+            0x3052_9073, // csrw mtvec, t0
+            0x0000_0073, // ecall
+            0x3420_2573, // handler: csrr a0, mcause
+            0x3410_25f3, // csrr a1, mepc
+            0x0000_006f, // j .
+        ]));
+        cpu.set_trap_delivery(true);
+        cpu
+    };
+    // the ecall commits nothing
+    let lines = reference_log(program(), 10);
+    assert_eq!(lines.len(), 9);
+    let mut lockstep = Lockstep::new(Box::new(spike_log(&lines)));
+    let mut cpu = program();
+    assert_eq!(lockstep.run(&mut cpu, 10), Ok(StopReason::Limit));
+    assert_eq!(cpu.read_reg(10), 11);
+    assert_eq!(cpu.read_reg(11), DRAM_BASE + 12);
+    assert_eq!(cpu.instret, 9);
+}
+
+#[test]
+fn test_parse_commit() {
+    let commit = parse_commit(
+        "core   0: 3 0x0000000080000010 (0x00b63423) x0  0x0000000000000000 \
+         c768_mstatus 0x0000000000000008 mem 0x000000008000100c 0x00000003 mem 0x0000000080001000",
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(commit.pc, 0x8000_0010);
+    assert_eq!(commit.raw, 0x00b6_3423);
+    assert!(commit.writes.is_empty());
+    assert_eq!(commit.csr_writes, [(0x300, 8)]);
+    assert_eq!(commit.accesses.len(), 2);
+    assert_eq!((commit.accesses[0].size, commit.accesses[0].value), (32, 3));
+    assert!(commit.accesses[0].write);
+    assert!(!commit.accesses[1].write);
+
+    assert_eq!(
+        parse_commit("core   0: exception trap_illegal_instruction"),
+        Ok(None)
+    );
+    assert!(parse_commit("core   0: 3 0x0000000080000010 (0x00000053) f1  0x0").is_err());
+}
+
+#[test]
+fn test_lockstep_with_process() {
+    let dir = TempPath::dir("cosim-test");
+    let path = dir.join("commits.log");
+    std::fs::write(&path, reference_log(program(), 100).join("\n") + "\n").unwrap();
+
+    // answers each step with the next line of the log
+    let script =
+        "while read step; do IFS= read -r line <&3 || line=halt; echo \"$line\"; done 3<\"$0\"";
+    let model = ProcessModel::spawn(Command::new("sh").args(["-c", script]).arg(&path)).unwrap();
+    let mut lockstep = Lockstep::new(Box::new(model));
+    assert_eq!(
+        lockstep.run(&mut program(), 100),
+        Ok(StopReason::Exception(Exception::Breakpoint))
+    );
+}
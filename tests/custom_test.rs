@@ -0,0 +1,83 @@
+mod utils;
+use riscv::interpreter::{
+    cpu::{
+        control::StopReason,
+        custom::{CustomInstruction, CUSTOM_0, CUSTOM_1},
+        decode::Format,
+        disasm::disassemble,
+        Cpu,
+    },
+    exception::Exception,
+    DRAM_BASE,
+};
+use utils::elf::code;
+
+fn with_extensions(program: &[u32]) -> Cpu {
+    let mut cpu = Cpu::new(code(program));
+    // mac rd, rs1, rs2: rd += rs1 * rs2
+    cpu.add_custom_instruction(CustomInstruction::new(
+        "mac",
+        CUSTOM_0,
+        Format::R,
+        |cpu, d| {
+            let product = cpu.read_reg(d.rs1).wrapping_mul(cpu.read_reg(d.rs2));
+            cpu.write_reg(d.rd, cpu.read_reg(d.rd).wrapping_add(product));
+            Ok(())
+        },
+    ));
+    // addx2 rd, rs1, imm: rd = rs1 + 2 * imm, only funct3 = 1 of custom-1
+    cpu.add_custom_instruction(CustomInstruction::with_pattern(
+        "addx2",
+        0x707f,
+        CUSTOM_1 | 1 << 12,
+        Format::I,
+        |cpu, d| {
+            let value = cpu.read_reg(d.rs1).wrapping_add(d.imm.wrapping_mul(2));
+            cpu.write_reg(d.rd, value);
+            Ok(())
+        },
+    ));
+    cpu
+}
+
+#[test]
+fn test_custom_instructions_execute() {
+    let mut cpu = with_extensions(&[
+        0x0030_0513, // li a0, 3
+        0x0040_0593, // li a1, 4
+        0x0050_0613, // li a2, 5
+        0x00b5_060b, // mac a2, a0, a1
+        0x0075_16ab, // addx2 a3, a0, 7
+        0x0010_0073, // ebreak
+    ]);
+    assert_eq!(cpu.run(100), StopReason::Exception(Exception::Breakpoint));
+    assert_eq!(cpu.read_reg(12), 17);
+    assert_eq!(cpu.read_reg(13), 17);
+    assert_eq!(cpu.pc, DRAM_BASE + 20);
+}
+
+#[test]
+fn test_unregistered_custom_opcode() {
+    let mut cpu = with_extensions(&[
+        0x0005_002b, // custom-1 with funct3 = 0, not registered
+    ]);
+    assert_eq!(
+        cpu.run(100),
+        StopReason::Exception(Exception::InvalidInstruction)
+    );
+    assert_eq!(cpu.pc, DRAM_BASE);
+}
+
+#[test]
+fn test_disassembler() {
+    let cpu = with_extensions(&[]);
+    assert_eq!(cpu.disassemble(0x00b5_060b), "mac a2, a0, a1");
+    assert_eq!(cpu.disassemble(0x0075_16ab), "addx2 a3, a0, 7");
+    assert_eq!(cpu.disassemble(0x0005_002b), ".word 0x0005002b");
+    assert_eq!(disassemble(0x0030_0513), "addi a0, zero, 3");
+    assert_eq!(disassemble(0x00b6_3023), "sd a1, 0(a2)");
+    assert_eq!(disassemble(0x3400_25f3), "csrrs a1, mscratch, zero");
+    assert_eq!(disassemble(0xfeb5_1ce3), "bne a0, a1, -8");
+    assert_eq!(disassemble(0x02b5_0533), "mul a0, a0, a1");
+    assert_eq!(disassemble(0x0010_0073), "ebreak");
+}
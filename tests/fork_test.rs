@@ -0,0 +1,93 @@
+mod utils;
+use std::thread;
+
+use riscv::interpreter::{
+    cpu::{control::StopReason, Cpu},
+    exception::Exception,
+    htif::Htif,
+    semihosting::Semihosting,
+    syscon::PowerEvent,
+    DRAM_BASE,
+};
+use utils::{
+    elf::code,
+    programs::{SUM_DATA, SUM_LOOP},
+};
+
+const TOHOST: u64 = DRAM_BASE + 0x2000;
+
+fn program() -> Cpu {
+    Cpu::new(code(SUM_LOOP))
+}
+
+#[test]
+fn test_forked_children_diverge() {
+    let mut parent = program();
+    parent.bus.store(SUM_DATA, 64, 7).unwrap();
+    // stop in the loop right after the first add
+    assert_eq!(parent.run(3), StopReason::Limit);
+    assert_eq!(parent.read_reg(11), 100);
+
+    let children: Vec<_> = (1..=8u64)
+        .map(|n| {
+            let mut child = parent.fork().unwrap();
+            // fault injection: change the remaining loop count
+            child.write_reg(11, n);
+            thread::spawn(move || {
+                let stop = child.run(1000);
+                (
+                    stop,
+                    child.read_reg(10),
+                    child.bus.load(SUM_DATA, 64).unwrap(),
+                )
+            })
+        })
+        .collect();
+    for (n, child) in (1..=8u64).zip(children) {
+        let expected = 100 + n * (n - 1) / 2;
+        assert_eq!(
+            child.join().unwrap(),
+            (
+                StopReason::Exception(Exception::Breakpoint),
+                expected,
+                expected
+            )
+        );
+    }
+
+    // the children's writes stayed private
+    assert_eq!(parent.bus.load(SUM_DATA, 64).unwrap(), 7);
+    assert_eq!(
+        parent.run(1000),
+        StopReason::Exception(Exception::Breakpoint)
+    );
+    assert_eq!(parent.bus.load(SUM_DATA, 64).unwrap(), 5050);
+}
+
+#[test]
+fn test_fork_refuses_host_devices() {
+    let mut cpu = program();
+    cpu.bus.set_semihosting(Semihosting::new());
+    assert!(cpu.fork().is_err());
+}
+
+#[test]
+fn test_fork_copies_htif() {
+    let mut parent = program();
+    parent.bus.set_htif(Htif::new(TOHOST, None));
+    let mut child = parent.fork().unwrap();
+
+    // exit code 7 from the child only
+    child.bus.store(TOHOST, 64, 15).unwrap();
+    child.bus.tick(1);
+    assert_eq!(
+        child.bus.take_power_event(),
+        Some(PowerEvent::PowerOff { exit_code: 7 })
+    );
+    parent.bus.tick(1);
+    assert_eq!(parent.bus.take_power_event(), None);
+    assert_eq!(
+        parent.run(1000),
+        StopReason::Exception(Exception::Breakpoint)
+    );
+}
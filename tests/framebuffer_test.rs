@@ -0,0 +1,71 @@
+mod utils;
+use riscv::interpreter::{cpu::Cpu, framebuffer::Framebuffer, FRAMEBUFFER_BASE};
+use utils::temp::TempPath;
+
+fn draw(cpu: &mut Cpu) {
+    // x8r8g8b8: red at (0, 0), green at (1, 0), blue at (0, 1)
+    cpu.bus.store(FRAMEBUFFER_BASE, 32, 0x00ff_0000).unwrap();
+    cpu.bus
+        .store(FRAMEBUFFER_BASE + 4, 32, 0x0000_ff00)
+        .unwrap();
+    cpu.bus
+        .store(FRAMEBUFFER_BASE + 8, 32, 0x0000_00ff)
+        .unwrap();
+}
+
+#[test]
+fn test_framebuffer_ppm() {
+    let mut cpu = Cpu::new(vec![]);
+    cpu.bus.set_framebuffer(Framebuffer::new(2, 2));
+    draw(&mut cpu);
+    assert_eq!(cpu.bus.load(FRAMEBUFFER_BASE + 4, 32).unwrap(), 0x0000_ff00);
+    assert!(cpu.bus.load(FRAMEBUFFER_BASE + 16, 32).is_err());
+
+    let ppm = cpu.bus.framebuffer().unwrap().to_ppm();
+    let mut expected = b"P6\n2 2\n255\n".to_vec();
+    expected.extend_from_slice(&[255, 0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0]);
+    assert_eq!(ppm, expected);
+}
+
+#[test]
+fn test_framebuffer_png() {
+    let mut cpu = Cpu::new(vec![]);
+    cpu.bus.set_framebuffer(Framebuffer::new(2, 2));
+    draw(&mut cpu);
+
+    let png = cpu.bus.framebuffer().unwrap().to_png();
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    // IHDR: 2x2, 8 bit truecolor
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+    assert_eq!(&png[29..33], &[0xfd, 0xd4, 0x9a, 0x73]);
+    assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+}
+
+#[test]
+fn test_framebuffer_scheduled_screenshot() {
+    let path = TempPath::new("riscv-screenshot");
+    let mut framebuffer = Framebuffer::new(2, 2);
+    framebuffer.schedule_screenshot(100, path.to_str().unwrap());
+    let mut cpu = Cpu::new(vec![]);
+    cpu.bus.set_framebuffer(framebuffer);
+    draw(&mut cpu);
+
+    cpu.bus.tick(99);
+    assert!(!path.exists());
+    cpu.bus.tick(100);
+    assert_eq!(
+        std::fs::read(&path).unwrap(),
+        cpu.bus.framebuffer().unwrap().to_ppm()
+    );
+}
+
+#[test]
+fn test_framebuffer_size_must_fit_window() {
+    assert!(Framebuffer::fits(1920, 1080));
+    // 256 MiB, the whole window
+    assert!(Framebuffer::fits(8192, 8192));
+    assert!(!Framebuffer::fits(8192, 8193));
+    assert!(!Framebuffer::fits(u32::MAX, u32::MAX));
+    assert!(!Framebuffer::fits(0, 10));
+}
@@ -0,0 +1,155 @@
+mod utils;
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    thread::{self, JoinHandle},
+};
+
+use riscv::interpreter::{
+    cpu::Cpu,
+    gdbstub::{serve, SessionEnd},
+    DRAM_BASE,
+};
+use utils::elf::code;
+
+const DATA: u64 = DRAM_BASE + 8 + 0x1000;
+const STORE: u64 = DRAM_BASE + 16;
+const EBREAK: u64 = DRAM_BASE + 28;
+
+/// Stores 1 to 10 at DATA, one after the other.
+const PROGRAM: &[u32] = &[
+    0x0000_0513, // li a0, 0
+    0x00a0_0593, // li a1, 10
+    0x0000_1617, // auipc a2, 1
+    0x0015_0513, // loop: addi a0, a0, 1
+    0x00a6_3023, // sd a0, 0(a2)
+    0xfff5_8593, // addi a1, a1, -1
+    0xfe05_9ae3, // bnez a1, loop
+    0x0010_0073, // ebreak
+];
+
+/// A stub serving a machine running `program` on its own thread, and a client connected to it.
+fn connect(program: &'static [u32], reverse: bool) -> (Gdb, JoinHandle<SessionEnd>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let stub = thread::spawn(move || {
+        let mut cpu = Cpu::new(code(program));
+        if reverse {
+            cpu.enable_reverse_execution(4, 100).unwrap();
+        }
+        let (stream, _) = listener.accept().unwrap();
+        serve(&mut cpu, stream).unwrap()
+    });
+    let stream = TcpStream::connect(address).unwrap();
+    (Gdb { stream }, stub)
+}
+
+struct Gdb {
+    stream: TcpStream,
+}
+
+impl Gdb {
+    fn byte(&mut self) -> u8 {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    fn send(&mut self, payload: &str) {
+        let checksum = payload.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", payload, checksum).unwrap();
+        assert_eq!(self.byte(), b'+');
+    }
+
+    fn receive(&mut self) -> String {
+        assert_eq!(self.byte(), b'$');
+        let mut payload = Vec::new();
+        loop {
+            match self.byte() {
+                b'#' => break,
+                byte => payload.push(byte),
+            }
+        }
+        let checksum = [self.byte(), self.byte()];
+        let sum = payload.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        assert_eq!(
+            std::str::from_utf8(&checksum).unwrap(),
+            format!("{:02x}", sum)
+        );
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(payload).unwrap()
+    }
+
+    fn request(&mut self, payload: &str) -> String {
+        self.send(payload);
+        self.receive()
+    }
+
+    /// Register `n` as sent by `p`.
+    fn register(&mut self, n: usize) -> u64 {
+        let reply = self.request(&format!("p{:x}", n));
+        let bytes: Vec<u8> = (0..8)
+            .map(|i| u8::from_str_radix(&reply[i * 2..i * 2 + 2], 16).unwrap())
+            .collect();
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+#[test]
+fn test_gdb_session() {
+    let (mut gdb, stub) = connect(PROGRAM, true);
+    let features = gdb.request("qSupported:multiprocess+;swbreak+");
+    assert!(features.contains("QStartNoAckMode+"));
+    assert!(features.contains("ReverseStep+;ReverseContinue+"));
+    assert!(gdb
+        .request("qXfer:features:read:target.xml:0,4000")
+        .starts_with("l<?xml"));
+    assert_eq!(gdb.request("?"), "S05");
+    assert_eq!(gdb.request("g").len(), 33 * 16);
+
+    assert_eq!(gdb.request("s"), "S05");
+    assert_eq!(gdb.register(32), DRAM_BASE + 4);
+    assert_eq!(gdb.request("P1=0100000000000000"), "OK");
+    assert_eq!(gdb.register(1), 1);
+
+    // a write watchpoint stops after each store
+    assert_eq!(gdb.request(&format!("Z2,{:x},8", DATA)), "OK");
+    assert_eq!(gdb.request("c"), format!("T05watch:{:x};", DATA));
+    assert_eq!(gdb.request("c"), format!("T05watch:{:x};", DATA));
+    assert_eq!(gdb.request(&format!("m{:x},8", DATA)), "0200000000000000");
+
+    // back to right before the store of 2, then one more instruction
+    assert_eq!(gdb.request("bc"), format!("T05watch:{:x};", DATA));
+    assert_eq!(gdb.register(32), STORE);
+    assert_eq!(gdb.request(&format!("m{:x},8", DATA)), "0100000000000000");
+    assert_eq!(gdb.request("bs"), "S05");
+    assert_eq!(gdb.register(32), STORE - 4);
+    assert_eq!(gdb.register(10), 1);
+
+    assert_eq!(gdb.request(&format!("z2,{:x},8", DATA)), "OK");
+    assert_eq!(gdb.request(&format!("Z0,{:x},4", EBREAK)), "OK");
+    assert_eq!(gdb.request("c"), "S05");
+    assert_eq!(gdb.register(32), EBREAK);
+    assert_eq!(gdb.request(&format!("m{:x},8", DATA)), "0a00000000000000");
+    assert_eq!(gdb.request(&format!("M{:x},2:3412", DATA)), "OK");
+    assert_eq!(gdb.request(&format!("m{:x},2", DATA)), "3412");
+    assert_eq!(gdb.request("m0,4"), "E0e");
+
+    assert_eq!(gdb.request("D"), "OK");
+    assert_eq!(stub.join().unwrap(), SessionEnd::Detached);
+}
+
+#[test]
+fn test_gdb_interrupt_and_kill() {
+    // j .
+    let (mut gdb, stub) = connect(&[0x0000_006f], false);
+    // no history to go back in
+    assert!(!gdb.request("qSupported").contains("Reverse"));
+    assert_eq!(gdb.request("bs"), "E01");
+    assert_eq!(gdb.request("QStartNoAckMode"), "OK");
+    write!(gdb.stream, "$c#63").unwrap();
+    gdb.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(gdb.receive(), "S02");
+    write!(gdb.stream, "$k#6b").unwrap();
+    assert_eq!(stub.join().unwrap(), SessionEnd::Killed);
+}
@@ -0,0 +1,164 @@
+mod utils;
+use std::sync::{Arc, Mutex};
+
+use riscv::interpreter::{
+    cpu::{
+        control::StopReason,
+        csr::{Privilege, MIP, MSCRATCH},
+        hooks::{CsrAccess, MemoryAccess, PrivilegeChange, Trap, TrapReturn},
+        Cpu,
+    },
+    exception::Exception,
+    DRAM_BASE,
+};
+use utils::elf::code;
+
+fn program() -> Cpu {
+    Cpu::new(code(&[
+        0x0050_0513, // li a0, 5
+        0x3405_1073, // csrw mscratch, a0
+        0x3400_25f3, // csrr a1, mscratch
+        0x0000_1617, // auipc a2, 1
+        0x00b6_3023, // sd a1, 0(a2)
+        0x0006_3683, // ld a3, 0(a2)
+        0x0010_0073, // ebreak
+    ]))
+}
+
+/// A hook that appends what it sees to the returned list.
+fn recorder<T: Send + 'static>() -> (Arc<Mutex<Vec<T>>>, impl FnMut(T) + Send + 'static) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    (events, move |event| sink.lock().unwrap().push(event))
+}
+
+#[test]
+fn test_instruction_and_trap_hooks() {
+    let mut cpu = program();
+    let (instructions, mut record) = recorder();
+    cpu.on_instruction(move |pc, decoded| record((pc, decoded.raw, decoded.rd, decoded.imm)));
+    let (traps, mut record) = recorder();
+    cpu.on_trap(move |pc, trap: &Trap| record((pc, format!("{:?}", trap))));
+    assert_eq!(cpu.run(100), StopReason::Exception(Exception::Breakpoint));
+
+    let instructions = instructions.lock().unwrap();
+    assert_eq!(instructions.len(), 7);
+    assert_eq!(instructions[0], (DRAM_BASE, 0x0050_0513, 10, 5));
+    assert_eq!(instructions[3], (DRAM_BASE + 12, 0x0000_1617, 12, 0x1000));
+    assert_eq!(
+        *traps.lock().unwrap(),
+        vec![(DRAM_BASE + 24, "Exception(Breakpoint)".to_string())]
+    );
+}
+
+#[test]
+fn test_memory_and_csr_hooks() {
+    let mut cpu = program();
+    let (memory, mut record) = recorder();
+    cpu.on_memory(move |access: &MemoryAccess| record(*access));
+    let (csrs, mut record) = recorder();
+    cpu.on_csr(move |access: &CsrAccess| record(*access));
+    cpu.run(100);
+
+    let data = DRAM_BASE + 12 + 0x1000;
+    let access = |pc, write| MemoryAccess {
+        pc,
+        addr: data,
+        size: 64,
+        value: 5,
+        write,
+    };
+    assert_eq!(
+        *memory.lock().unwrap(),
+        vec![access(DRAM_BASE + 16, true), access(DRAM_BASE + 20, false)]
+    );
+    // csrw does not read, csrr does not write
+    let access = |pc, write| CsrAccess {
+        pc,
+        csr: MSCRATCH,
+        value: 5,
+        write,
+    };
+    assert_eq!(
+        *csrs.lock().unwrap(),
+        vec![access(DRAM_BASE + 4, true), access(DRAM_BASE + 8, false)]
+    );
+
+    cpu.clear_hooks();
+    cpu.pc = DRAM_BASE;
+    cpu.run(100);
+    assert_eq!(memory.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn test_trap_return_and_privilege_hooks() {
+    let mut cpu = Cpu::new(code(&[
+        0x0000_0297, // auipc t0, 0
+        0x0202_8293, // addi t0, t0, 0x20
+        0x3052_9073, // csrw mtvec, t0
+        0x0000_0317, // auipc t1, 0
+        0x0103_0313, // addi t1, t1, 0x10
+        0x3413_1073, // csrw mepc, t1
+        0x3020_0073, // mret (mstatus.MPP is U)
+        0x0000_0073, // ecall
+        0x3000_2573, // handler: csrr a0, mstatus
+    ]));
+    cpu.set_trap_delivery(true);
+    let (returns, mut record) = recorder();
+    cpu.on_trap_return(move |pc, trap_return| record((pc, *trap_return)));
+    let (changes, mut record) = recorder();
+    cpu.on_privilege_change(move |pc, change| record((pc, *change)));
+
+    assert_eq!(cpu.run(9), StopReason::Limit);
+    assert_eq!(
+        *returns.lock().unwrap(),
+        [(
+            DRAM_BASE + 0x18,
+            TrapReturn {
+                target: DRAM_BASE + 0x1c,
+                privilege: Privilege::User,
+            }
+        )]
+    );
+    let change = |from, to| PrivilegeChange { from, to };
+    assert_eq!(
+        *changes.lock().unwrap(),
+        [
+            (
+                DRAM_BASE + 0x18,
+                change(Privilege::Machine, Privilege::User)
+            ),
+            (
+                DRAM_BASE + 0x1c,
+                change(Privilege::User, Privilege::Machine)
+            ),
+        ]
+    );
+    assert_eq!(cpu.privilege(), Privilege::Machine);
+    // the trap came from U
+    assert_eq!(cpu.regs[10] & (0b11 << 11), 0);
+}
+
+#[test]
+fn test_trap_hook_sees_interrupts() {
+    let mut cpu = Cpu::new(code(&[
+        0x0000_0297, // auipc t0, 0
+        0x0182_8293, // addi t0, t0, 0x18
+        0x3052_9073, // csrw mtvec, t0
+        0x0800_0313, // li t1, 0x80
+        0x3043_1073, // csrw mie, t1
+        0x3004_6073, // csrsi mstatus, 8
+        0x0000_006f, // handler: j .
+    ]));
+    cpu.set_trap_delivery(true);
+    // machine timer interrupt
+    cpu.csr.store(MIP, 1 << 7);
+    let (traps, mut record) = recorder();
+    cpu.on_trap(move |pc, trap: &Trap| record((pc, format!("{:?}", trap))));
+
+    assert_eq!(cpu.run(8), StopReason::Limit);
+    assert_eq!(
+        *traps.lock().unwrap(),
+        [(DRAM_BASE + 0x18, format!("Interrupt({})", 1u64 << 63 | 7))]
+    );
+}
@@ -0,0 +1,166 @@
+mod utils;
+use riscv::interpreter::{
+    chardev::BufferBackend,
+    cpu::Cpu,
+    elf::Elf,
+    htif::{Htif, SYS_CLOSE, SYS_OPENAT, SYS_READ, SYS_WRITE},
+    DRAM_BASE,
+};
+use utils::{
+    elf::{build_elf, code},
+    temp::TempPath,
+    virtio::{read_bytes, write_bytes},
+};
+
+const TOHOST: u64 = DRAM_BASE + 0x1000;
+const FROMHOST: u64 = DRAM_BASE + 0x1008;
+const MAGIC_MEM: u64 = DRAM_BASE + 0x2000;
+const BUFFER: u64 = DRAM_BASE + 0x3000;
+
+fn htif_cpu() -> Cpu {
+    let mut cpu = Cpu::new(vec![]);
+    cpu.bus.set_htif(Htif::new(TOHOST, Some(FROMHOST)));
+    cpu
+}
+
+/// Issue a proxied syscall and return its result.
+fn syscall(cpu: &mut Cpu, number: u64, args: &[u64]) -> i64 {
+    let mut block = vec![number];
+    block.extend_from_slice(args);
+    block.resize(8, 0);
+    for (i, word) in block.iter().enumerate() {
+        cpu.bus.store(MAGIC_MEM + i as u64 * 8, 64, *word).unwrap();
+    }
+    cpu.bus.store(TOHOST, 64, MAGIC_MEM).unwrap();
+    cpu.bus.tick(1);
+    assert_eq!(cpu.bus.load(TOHOST, 64).unwrap(), 0);
+    assert_eq!(cpu.bus.load(FROMHOST, 64).unwrap(), 1);
+    cpu.bus.store(FROMHOST, 64, 0).unwrap();
+    cpu.bus.load(MAGIC_MEM, 64).unwrap() as i64
+}
+
+#[test]
+fn test_elf_load_and_symbols() {
+    let program = code(&[0x0000_006f]);
+    let image = build_elf(
+        DRAM_BASE + 0x100,
+        &[(DRAM_BASE + 0x100, &program)],
+        &[("tohost", TOHOST), ("fromhost", FROMHOST)],
+    );
+    let elf = Elf::parse(image).unwrap();
+    assert_eq!(elf.symbol("tohost"), Some(TOHOST));
+    assert_eq!(elf.symbol("fromhost"), Some(FROMHOST));
+    assert_eq!(elf.symbol("missing"), None);
+
+    let mut cpu = Cpu::new(vec![]);
+    cpu.load_elf(&elf).unwrap();
+    assert_eq!(cpu.pc, DRAM_BASE + 0x100);
+    assert_eq!(cpu.bus.load(DRAM_BASE + 0x100, 32).unwrap(), 0x0000_006f);
+
+    assert!(Elf::parse(b"not an elf".to_vec()).is_err());
+}
+
+#[test]
+fn test_elf_rejects_malformed_files() {
+    let program = code(&[0x0000_006f]);
+    let image = build_elf(DRAM_BASE, &[(DRAM_BASE, &program)], &[("tohost", TOHOST)]);
+    let patched = |offset: usize, value: u64| {
+        let mut image = image.clone();
+        image[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        image
+    };
+    // program header fields: offset at 72, filesz at 96, memsz at 104
+    assert!(Elf::parse(patched(72, u64::MAX)).is_err());
+    assert!(Elf::parse(patched(96, u64::MAX - 64)).is_err());
+    assert!(Elf::parse(patched(104, 0)).is_err());
+    // the name of the first symbol, right after the program and the null symbol
+    let name = 64 + 56 + program.len() + 24;
+    assert!(Elf::parse(patched(name, u32::MAX as u64)).is_err());
+    assert!(Elf::parse(image[..64 + 56].to_vec()).is_err());
+
+    // a segment larger than memory is refused instead of allocated
+    let elf = Elf::parse(patched(104, u64::MAX)).unwrap();
+    let mut cpu = Cpu::new(vec![]);
+    assert!(cpu.load_elf(&elf).is_err());
+}
+
+#[test]
+fn test_htif_exit_code() {
+    let program = code(&[
+        0x0000_1517, // auipc a0, 1
+        0x00f0_0593, // li a1, 15
+        0x00b5_3023, // sd a1, 0(a0)
+        0x0000_006f, // j .
+    ]);
+    let elf = Elf::parse(build_elf(
+        DRAM_BASE,
+        &[(DRAM_BASE, &program)],
+        &[("tohost", TOHOST)],
+    ))
+    .unwrap();
+    let mut cpu = Cpu::new(vec![]);
+    cpu.load_elf(&elf).unwrap();
+    cpu.bus
+        .set_htif(Htif::new(elf.symbol("tohost").unwrap(), None));
+
+    assert_eq!(cpu.execute(), None);
+    assert_eq!(cpu.exit_code, Some(7));
+}
+
+#[test]
+fn test_htif_console() {
+    let console = BufferBackend::default();
+    let mut cpu = Cpu::new(vec![]);
+    cpu.bus
+        .set_htif(Htif::new(TOHOST, Some(FROMHOST)).with_console(Box::new(console.clone())));
+
+    cpu.bus
+        .store(TOHOST, 64, (1 << 56) | (1 << 48) | b'h' as u64)
+        .unwrap();
+    cpu.bus.tick(1);
+    assert_eq!(console.take_output(), b"h");
+    assert_eq!(cpu.bus.load(FROMHOST, 64).unwrap(), (1 << 56) | (1 << 48));
+    cpu.bus.store(FROMHOST, 64, 0).unwrap();
+
+    // getchar is answered once input shows up
+    cpu.bus.store(TOHOST, 64, 1 << 56).unwrap();
+    cpu.bus.tick(2);
+    assert_eq!(cpu.bus.load(FROMHOST, 64).unwrap(), 0);
+    console.push_input(b"x");
+    cpu.bus.tick(3);
+    assert_eq!(cpu.bus.load(FROMHOST, 64).unwrap(), (1 << 56) | b'x' as u64);
+}
+
+#[test]
+fn test_htif_syscall_proxy() {
+    let path = TempPath::new("riscv-htif");
+    let name = format!("{}\0", path.display());
+    let mut cpu = htif_cpu();
+    write_bytes(&mut cpu.bus, BUFFER, name.as_bytes());
+    write_bytes(&mut cpu.bus, BUFFER + 0x100, b"proxied");
+
+    // O_WRONLY | O_CREAT | O_TRUNC
+    let flags = 0o1 | 0o100 | 0o1000;
+    let at_fdcwd = -100i64 as u64;
+    let fd = syscall(
+        &mut cpu,
+        SYS_OPENAT,
+        &[at_fdcwd, BUFFER, name.len() as u64, flags, 0o644],
+    );
+    assert_eq!(fd, 3);
+    assert_eq!(syscall(&mut cpu, SYS_WRITE, &[3, BUFFER + 0x100, 7]), 7);
+    assert_eq!(syscall(&mut cpu, SYS_CLOSE, &[3]), 0);
+    assert_eq!(std::fs::read(&path).unwrap(), b"proxied");
+
+    let fd = syscall(
+        &mut cpu,
+        SYS_OPENAT,
+        &[at_fdcwd, BUFFER, name.len() as u64, 0, 0],
+    );
+    assert_eq!(fd, 3);
+    assert_eq!(syscall(&mut cpu, SYS_READ, &[3, BUFFER + 0x200, 64]), 7);
+    assert_eq!(read_bytes(&cpu.bus, BUFFER + 0x200, 7), b"proxied");
+    assert_eq!(syscall(&mut cpu, SYS_CLOSE, &[3]), 0);
+    // EBADF
+    assert_eq!(syscall(&mut cpu, SYS_CLOSE, &[3]), -9);
+}
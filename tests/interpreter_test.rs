@@ -347,6 +347,42 @@ fn test_bltu_blt_instruction() {
     }
 }
 
+#[test]
+fn test_srai_instruction() {
+    let code = compile_assembly(function_name!(), "srai x31, x30, 36");
+    let mut cpu = Cpu::new(code);
+    cpu.write_reg(30, (-1i64 << 40) as u64);
+
+    let err = cpu.execute().unwrap();
+    assert_eq!(err, exception::Exception::InvalidInstruction);
+
+    assert_eq!(cpu.read_reg(31), -16i64 as u64);
+}
+
+#[test]
+fn test_shift_instructions() {
+    // only the low six bits of rs2 count
+    let code = compile_assembly(
+        function_name!(),
+        "
+            sll x31, x30, x29
+            srl x28, x30, x29
+            sra x27, x26, x29
+        ",
+    );
+    let mut cpu = Cpu::new(code);
+    cpu.write_reg(30, 0x10);
+    cpu.write_reg(29, 64 + 4);
+    cpu.write_reg(26, -32i64 as u64);
+
+    let err = cpu.execute().unwrap();
+    assert_eq!(err, exception::Exception::InvalidInstruction);
+
+    assert_eq!(cpu.read_reg(31), 0x100);
+    assert_eq!(cpu.read_reg(28), 0x1);
+    assert_eq!(cpu.read_reg(27), -2i64 as u64);
+}
+
 #[test]
 fn test_load_instruction() {
     {
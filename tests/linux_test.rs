@@ -0,0 +1,446 @@
+mod utils;
+use riscv::interpreter::{
+    elf::{Elf, ET_DYN, PT_INTERP},
+    exception::Exception,
+    linux::{
+        check_extensions, load_interpreter,
+        signal::{SA_SIGINFO, SIGTERM},
+        thread::DEADLOCK_STATUS,
+        LinuxProcess, AT_BASE, AT_ENTRY, AT_HWCAP, AT_PAGESZ, AT_RANDOM, HWCAP, INTERPRETER_BASE,
+        STACK_TOP,
+    },
+};
+use utils::{
+    elf::{build_elf, code, with_arch},
+    temp::TempPath,
+};
+
+const TEXT: u64 = 0x10000;
+const DATA: u64 = 0x11000;
+
+fn process(text: &[u8], data: &[u8], args: &[&str]) -> LinuxProcess {
+    let elf = Elf::parse(build_elf(TEXT, &[(TEXT, text), (DATA, data)], &[])).unwrap();
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    LinuxProcess::new(&elf, &args, &["HOME=/root".to_string()]).unwrap()
+}
+
+fn read_string(process: &LinuxProcess, addr: u64) -> String {
+    let mut data = Vec::new();
+    loop {
+        let byte = process.cpu.bus.load(addr + data.len() as u64, 8).unwrap() as u8;
+        if byte == 0 {
+            return String::from_utf8(data).unwrap();
+        }
+        data.push(byte);
+    }
+}
+
+#[test]
+fn test_initial_stack() {
+    let process = process(&code(&[0x0000_0073]), &[], &["prog", "-x"]);
+    let bus = &process.cpu.bus;
+    let sp = process.cpu.regs[2];
+    assert_eq!(sp % 16, 0);
+    assert!(sp < STACK_TOP);
+    assert_eq!(process.cpu.pc, TEXT);
+
+    let word = |i: u64| bus.load(sp + i * 8, 64).unwrap();
+    assert_eq!(word(0), 2);
+    assert_eq!(read_string(&process, word(1)), "prog");
+    assert_eq!(read_string(&process, word(2)), "-x");
+    assert_eq!(word(3), 0);
+    assert_eq!(read_string(&process, word(4)), "HOME=/root");
+    assert_eq!(word(5), 0);
+
+    let mut auxv = Vec::new();
+    let mut i = 6;
+    while word(i) != 0 {
+        auxv.push((word(i), word(i + 1)));
+        i += 2;
+    }
+    assert!(auxv.contains(&(AT_PAGESZ, 4096)));
+    let random = auxv.iter().find(|(key, _)| *key == AT_RANDOM).unwrap().1;
+    assert!(bus.load(random + 8, 64).is_ok());
+}
+
+#[test]
+fn test_exit_status_from_argc() {
+    let text = code(&[
+        0x0001_3503, // ld a0, 0(sp)
+        0x05e0_0893, // li a7, 94
+        0x0000_0073, // ecall
+    ]);
+    let mut process = process(&text, &[], &["prog", "a", "b"]);
+    assert_eq!(process.run(), Ok(3));
+}
+
+#[test]
+fn test_file_and_brk_syscalls() {
+    let path = TempPath::new("riscv-linux");
+    let mut data = format!("{}\0", path.display()).into_bytes();
+    data.resize(0x100, 0);
+    data.extend_from_slice(b"hello");
+    let text = code(&[
+        0x0001_1437, // lui s0, 17
+        0xf9c0_0513, // li a0, -100
+        0x0004_0593, // mv a1, s0
+        0x2410_0613, // li a2, O_WRONLY | O_CREAT | O_TRUNC
+        0x1a40_0693, // li a3, 0644
+        0x0380_0893, // li a7, openat
+        0x0000_0073, // ecall
+        0x0005_0493, // mv s1, a0
+        0x0004_8513, // mv a0, s1
+        0x1004_0593, // addi a1, s0, 256
+        0x0050_0613, // li a2, 5
+        0x0400_0893, // li a7, write
+        0x0000_0073, // ecall
+        0x0004_8513, // mv a0, s1
+        0x0390_0893, // li a7, close
+        0x0000_0073, // ecall
+        0x0000_0513, // li a0, 0
+        0x0d60_0893, // li a7, brk
+        0x0000_0073, // ecall
+        0x0005_0913, // mv s2, a0
+        0x0000_12b7, // lui t0, 1
+        0x0059_0533, // add a0, s2, t0
+        0x0d60_0893, // li a7, brk
+        0x0000_0073, // ecall
+        0x00a9_3023, // sd a0, 0(s2)
+        0x0009_3503, // ld a0, 0(s2)
+        0x4125_0533, // sub a0, a0, s2
+        0x0085_5513, // srli a0, a0, 8
+        0x05e0_0893, // li a7, exit_group
+        0x0000_0073, // ecall
+    ]);
+    let mut process = process(&text, &data, &["prog"]);
+
+    assert_eq!(process.run(), Ok(16));
+    assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+}
+
+#[test]
+fn test_large_file_mapping_and_bad_pointers() {
+    let mut contents = vec![0; 0x100_0000];
+    contents.extend_from_slice(&100u64.to_le_bytes());
+    let path = TempPath::file("riscv-linux-mmap", &contents);
+    let data = format!("{}\0", path.display()).into_bytes();
+    let text = code(&[
+        0x0001_1437, // lui s0, 17
+        0xf9c0_0513, // li a0, -100
+        0x0004_0593, // mv a1, s0
+        0x0000_0613, // li a2, O_RDONLY
+        0x0000_0693, // li a3, 0
+        0x0380_0893, // li a7, openat
+        0x0000_0073, // ecall
+        0x0005_0493, // mv s1, a0
+        0x0000_0513, // li a0, 0
+        0x0100_15b7, // lui a1, 0x1001
+        0x0010_0613, // li a2, PROT_READ
+        0x0020_0693, // li a3, MAP_PRIVATE
+        0x0004_8713, // mv a4, s1
+        0x0000_0793, // li a5, 0
+        0x0de0_0893, // li a7, mmap
+        0x0000_0073, // ecall
+        0x0100_02b7, // lui t0, 0x1000
+        0x0055_02b3, // add t0, a0, t0
+        0x0002_b903, // ld s2, 0(t0)
+        0xf9c0_0513, // li a0, -100
+        0xfff0_0593, // li a1, -1
+        0x0000_0613, // li a2, O_RDONLY
+        0x0000_0693, // li a3, 0
+        0x0380_0893, // li a7, openat
+        0x0000_0073, // ecall
+        0x00a9_0933, // add s2, s2, a0
+        0x0000_0513, // li a0, 0
+        0xff80_0593, // li a1, -8
+        0x0010_0613, // li a2, 1
+        0x0410_0893, // li a7, readv
+        0x0000_0073, // ecall
+        0x00a9_0533, // add a0, s2, a0
+        0x05e0_0893, // li a7, exit_group
+        0x0000_0073, // ecall
+    ]);
+    let mut process = process(&text, &data, &["prog"]);
+
+    // the word past the first 16 MiB of the file, less EFAULT from openat and readv
+    assert_eq!(process.run(), Ok(100 - 14 - 14));
+}
+
+#[test]
+fn test_mapping_lengths_past_address_space() {
+    let text = code(&[
+        0x0001_1437, // lui s0, 17
+        0x0004_0513, // mv a0, s0
+        0xfff0_0593, // li a1, -1
+        0x0d70_0893, // li a7, munmap
+        0x0000_0073, // ecall
+        0x0005_0493, // mv s1, a0
+        0x0004_2283, // lw t0, 0(s0)
+        0x0000_0513, // li a0, 0
+        0x0010_0593, // li a1, 1
+        0x02e5_9593, // slli a1, a1, 46
+        0x0010_0613, // li a2, PROT_READ
+        0x0220_0693, // li a3, MAP_PRIVATE | MAP_ANONYMOUS
+        0xfff0_0713, // li a4, -1
+        0x0000_0793, // li a5, 0
+        0x0de0_0893, // li a7, mmap
+        0x0000_0073, // ecall
+        0x0095_0533, // add a0, a0, s1
+        0x40a0_0533, // neg a0, a0
+        0x05e0_0893, // li a7, exit_group
+        0x0000_0073, // ecall
+    ]);
+    let mut process = process(&text, &[0; 8], &["prog"]);
+
+    // EINVAL from munmap, which leaves the data mapped, and ENOMEM from mmap
+    assert_eq!(process.run(), Ok(22 + 12));
+}
+
+#[test]
+fn test_unmapped_access_faults() {
+    let text = code(&[
+        0x0000_3503, // ld a0, 0(zero)
+    ]);
+    let mut process = process(&text, &[], &["prog"]);
+    assert_eq!(
+        process.run(),
+        Err(Exception::LoadAccessFault { address: 0 })
+    );
+}
+
+#[test]
+fn test_clone_and_futex_join() {
+    // The parent clones a thread with tls = 42 and joins it through the child tid futex,
+    // the child busy-loops past a few time slices, then stores tp for the parent to exit with.
+    let text = code(&[
+        0x0001_1437, // lui s0, 17
+        0x0039_0537, // lui a0, 912
+        0x1005_051b, // addiw a0, a0, 256 (CLONE_VM|THREAD|SETTLS|PARENT_SETTID|CHILD_CLEARTID)
+        0x7f04_0593, // addi a1, s0, 2032
+        0x0004_0613, // mv a2, s0
+        0x02a0_0693, // li a3, 42
+        0x0004_0713, // mv a4, s0
+        0x0dc0_0893, // li a7, 220 (clone)
+        0x0000_0073, // ecall
+        0x0205_0863, // beqz a0, child
+        0x0004_2603, // parent_loop: lw a2, 0(s0)
+        0x0006_0e63, // beqz a2, joined
+        0x0004_0513, // mv a0, s0
+        0x0000_0593, // li a1, 0 (FUTEX_WAIT)
+        0x0000_0693, // li a3, 0
+        0x0620_0893, // li a7, 98 (futex)
+        0x0000_0073, // ecall
+        0xfe5f_f06f, // j parent_loop
+        0x0084_3503, // joined: ld a0, 8(s0)
+        0x05e0_0893, // li a7, 94 (exit_group)
+        0x0000_0073, // ecall
+        0x0000_52b7, // child: lui t0, 5
+        0xfff2_8293, // spin: addi t0, t0, -1
+        0xfe02_9ee3, // bnez t0, spin
+        0x0044_3423, // sd tp, 8(s0)
+        0x0000_0513, // li a0, 0
+        0x05d0_0893, // li a7, 93 (exit)
+        0x0000_0073, // ecall
+    ]);
+    let mut process = process(&text, &[0; 16], &["prog"]);
+    assert_eq!(process.run(), Ok(42));
+    assert_eq!(process.thread_count(), 1);
+}
+
+#[test]
+fn test_futex_timeout_while_another_thread_runs() {
+    // The parent waits 1ms on a futex nobody wakes while its child spins forever, then exits
+    // with the negated result.
+    let text = code(&[
+        0x0001_1437, // lui s0, 17
+        0x0001_0537, // lui a0, 16
+        0x1005_0513, // addi a0, a0, 256 (CLONE_VM|CLONE_THREAD)
+        0x7f04_0593, // addi a1, s0, 2032
+        0x0dc0_0893, // li a7, 220 (clone)
+        0x0000_0073, // ecall
+        0x0205_0463, // beqz a0, child
+        0x0004_0513, // mv a0, s0
+        0x0000_0593, // li a1, 0 (FUTEX_WAIT)
+        0x0000_0613, // li a2, 0
+        0x0084_0693, // addi a3, s0, 8
+        0x0620_0893, // li a7, 98 (futex)
+        0x0000_0073, // ecall
+        0x40a0_0533, // neg a0, a0
+        0x05e0_0893, // li a7, 94 (exit_group)
+        0x0000_0073, // ecall
+        0x0000_006f, // child: j .
+    ]);
+    let mut process = process(&text, &words(&[0, 0, 1_000_000]), &["prog"]);
+    // ETIMEDOUT
+    assert_eq!(process.run(), Ok(110));
+}
+
+#[test]
+fn test_deadlock_ends_process() {
+    let text = code(&[
+        0x0001_1537, // lui a0, 17
+        0x0000_0593, // li a1, FUTEX_WAIT
+        0x0000_0613, // li a2, 0
+        0x0000_0693, // li a3, 0
+        0x0620_0893, // li a7, futex
+        0x0000_0073, // ecall
+        0x0000_006f, // j .
+    ]);
+    let mut process = process(&text, &[0; 8], &["prog"]);
+    assert_eq!(process.run(), Ok(DEADLOCK_STATUS));
+}
+
+#[test]
+fn test_extensions_beyond_rv64i_are_refused() {
+    let image = build_elf(TEXT, &[(TEXT, &code(&[0x0000_006f]))], &[]);
+    let elf = |arch| Elf::parse(with_arch(image.clone(), arch)).unwrap();
+
+    assert_eq!(Elf::parse(image.clone()).unwrap().arch, None);
+    assert_eq!(
+        check_extensions(&Elf::parse(image.clone()).unwrap()),
+        Ok(())
+    );
+    let base = elf("rv64i2p1_zicsr2p0_zifencei2p0");
+    assert_eq!(base.arch.as_deref(), Some("rv64i2p1_zicsr2p0_zifencei2p0"));
+    assert_eq!(check_extensions(&base), Ok(()));
+
+    let error = check_extensions(&elf("rv64i2p1_m2p0_a2p1_c2p0_zicsr2p0")).unwrap_err();
+    assert!(error.ends_with("M, A, C is missing"), "{}", error);
+    let error = check_extensions(&elf("rv64gc")).unwrap_err();
+    assert!(error.ends_with("M, A, F, D, C is missing"), "{}", error);
+}
+
+#[test]
+fn test_interpreter_from_sysroot() {
+    let sysroot = TempPath::new("riscv-sysroot");
+    std::fs::create_dir_all(sysroot.join("lib")).unwrap();
+    std::fs::write(sysroot.join("lib/libtest-data.so"), [7]).unwrap();
+
+    // The "loader" opens a library by its absolute path and exits with its first byte.
+    let mut loader = code(&[
+        0xf9c0_0513, // li a0, -100 (AT_FDCWD)
+        0x0000_0597, // auipc a1, 0
+        0x03c5_8593, // addi a1, a1, 60 (the path below)
+        0x0000_0613, // li a2, 0
+        0x0380_0893, // li a7, 56 (openat)
+        0x0000_0073, // ecall
+        0xff01_0593, // addi a1, sp, -16
+        0x0010_0613, // li a2, 1
+        0x03f0_0893, // li a7, 63 (read)
+        0x0000_0073, // ecall
+        0xff01_4503, // lbu a0, -16(sp)
+        0x05e0_0893, // li a7, 94 (exit_group)
+        0x0000_0073, // ecall
+    ]);
+    loader.resize(0x40, 0);
+    loader.extend_from_slice(b"/lib/libtest-data.so\0");
+    let mut loader = build_elf(0, &[(0, &loader)], &[]);
+    loader[16] = ET_DYN as u8;
+    std::fs::write(sysroot.join("lib/ld-test.so"), loader).unwrap();
+
+    // Turn the second segment of the executable into its PT_INTERP.
+    let mut exe = build_elf(
+        TEXT,
+        &[(TEXT, &code(&[0x0000_0073])), (DATA, b"/lib/ld-test.so\0")],
+        &[],
+    );
+    exe[64 + 56] = PT_INTERP as u8;
+    let elf = Elf::parse(exe).unwrap();
+
+    let interpreter = load_interpreter(&elf, Some(&sysroot)).unwrap();
+    let args = ["prog".to_string()];
+    let mut process =
+        LinuxProcess::with_interpreter(&elf, interpreter.as_ref(), &args, &[]).unwrap();
+    process.set_sysroot(sysroot.to_path_buf());
+    assert_eq!(process.cpu.pc, INTERPRETER_BASE);
+
+    let sp = process.cpu.regs[2];
+    let word = |i: u64| process.cpu.bus.load(sp + i * 8, 64).unwrap();
+    // argc, argv[0], NULL, NULL and then the auxiliary vector
+    let auxv: Vec<_> = (4..)
+        .step_by(2)
+        .map(|i| (word(i), word(i + 1)))
+        .take_while(|e| e.0 != 0)
+        .collect();
+    assert!(auxv.contains(&(AT_BASE, INTERPRETER_BASE)));
+    assert!(auxv.contains(&(AT_ENTRY, TEXT)));
+    assert!(auxv.contains(&(AT_HWCAP, HWCAP)));
+
+    assert_eq!(process.run(), Ok(7));
+}
+
+fn words(words: &[u64]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+#[test]
+fn test_segv_handler_resumes() {
+    // The handler derives a0 from si_addr and skips the faulting load.
+    let text = code(&[
+        0x00b0_0513, // li a0, 11 (SIGSEGV)
+        0x0001_15b7, // lui a1, 17
+        0x0000_0613, // li a2, 0
+        0x0080_0693, // li a3, 8
+        0x0860_0893, // li a7, 134 (rt_sigaction)
+        0x0000_0073, // ecall
+        0x0000_0513, // li a0, 0
+        0x0080_3283, // ld t0, 8(zero)
+        0x05e0_0893, // li a7, 94 (exit_group)
+        0x0000_0073, // ecall
+        0x0105_b303, // handler: ld t1, 16(a1) (si_addr)
+        0x0193_0313, // addi t1, t1, 25
+        0x1066_3023, // sd t1, 256(a2) (saved a0)
+        0x0b06_3283, // ld t0, 176(a2) (saved pc)
+        0x0042_8293, // addi t0, t0, 4
+        0x0a56_3823, // sd t0, 176(a2)
+        0x0000_8067, // ret
+    ]);
+    let action = words(&[TEXT + 40, SA_SIGINFO, 0]);
+    let mut process = process(&text, &action, &["prog"]);
+    assert_eq!(process.run(), Ok(33));
+}
+
+#[test]
+fn test_kill_default_action() {
+    let text = code(&[
+        0x0ac0_0893, // li a7, 172 (getpid)
+        0x0000_0073, // ecall
+        0x00f0_0593, // li a1, 15 (SIGTERM)
+        0x0810_0893, // li a7, 129 (kill)
+        0x0000_0073, // ecall
+        0x0000_0513, // li a0, 0
+        0x05e0_0893, // li a7, 94 (exit_group)
+        0x0000_0073, // ecall
+    ]);
+    let mut process = process(&text, &[], &["prog"]);
+    assert_eq!(process.run(), Ok(128 + SIGTERM as u32));
+}
+
+#[test]
+fn test_alarm_interrupts_loop() {
+    // A one-shot 1ms ITIMER_REAL whose SIGALRM handler sets the flag the program spins on.
+    let text = code(&[
+        0x0001_1437, // lui s0, 17
+        0x00e0_0513, // li a0, 14 (SIGALRM)
+        0x0004_0593, // mv a1, s0
+        0x0000_0613, // li a2, 0
+        0x0080_0693, // li a3, 8
+        0x0860_0893, // li a7, 134 (rt_sigaction)
+        0x0000_0073, // ecall
+        0x0000_0513, // li a0, 0 (ITIMER_REAL)
+        0x0204_0593, // addi a1, s0, 32
+        0x0000_0613, // li a2, 0
+        0x0670_0893, // li a7, 103 (setitimer)
+        0x0000_0073, // ecall
+        0x0404_3503, // spin: ld a0, 64(s0)
+        0xfe05_0ee3, // beqz a0, spin
+        0x05e0_0893, // li a7, 94 (exit_group)
+        0x0000_0073, // ecall
+        0x0050_0293, // handler: li t0, 5
+        0x0454_3023, // sd t0, 64(s0)
+        0x0000_8067, // ret
+    ]);
+    let data = words(&[TEXT + 64, 0, 0, 0, 0, 0, 0, 1000, 0]);
+    let mut process = process(&text, &data, &["prog"]);
+    assert_eq!(process.run(), Ok(5));
+}
@@ -0,0 +1,86 @@
+mod utils;
+use std::{path::Path, process::Command, sync::Arc};
+
+use riscv::interpreter::{
+    cpu::{control::StopReason, Cpu},
+    plugin::Plugin,
+};
+use utils::{elf::code, temp::TempPath};
+
+const PLUGIN: &str = r#"
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include "riscv_plugin.h"
+
+struct counts { const char *out; unsigned long insns, loads, stores; };
+
+int32_t riscv_plugin_init(uint32_t abi_version, size_t argc, const char *const *keys,
+                          const char *const *values, void **user_data) {
+    struct counts *counts = calloc(1, sizeof(*counts));
+    for (size_t i = 0; i < argc; i++)
+        if (strcmp(keys[i], "out") == 0)
+            counts->out = values[i];
+    *user_data = counts;
+    return abi_version == RISCV_PLUGIN_ABI_VERSION && counts->out ? 0 : 1;
+}
+
+void riscv_plugin_insn(void *user_data, uint64_t pc, uint32_t insn) {
+    ((struct counts *)user_data)->insns++;
+}
+
+void riscv_plugin_mem(void *user_data, uint64_t pc, uint64_t addr, uint32_t size,
+                      uint64_t value, uint8_t is_store) {
+    struct counts *counts = user_data;
+    if (is_store) counts->stores++; else counts->loads++;
+}
+
+void riscv_plugin_exit(void *user_data, uint32_t exit_code) {
+    struct counts *counts = user_data;
+    FILE *file = fopen(counts->out, "w");
+    fprintf(file, "%lu %lu %lu %u", counts->insns, counts->loads, counts->stores, exit_code);
+    fclose(file);
+}
+"#;
+
+/// Compile the C plugin above with the host C compiler.
+fn build_plugin(dir: &Path) -> std::path::PathBuf {
+    let source = dir.join("plugin.c");
+    let library = dir.join("libplugin.so");
+    std::fs::write(&source, PLUGIN).unwrap();
+    let status = Command::new(std::env::var("CC").unwrap_or("cc".to_string()))
+        .args(["-shared", "-fPIC", "-I"])
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("include"))
+        .arg("-o")
+        .arg(&library)
+        .arg(&source)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    library
+}
+
+#[test]
+fn test_plugin_callbacks() {
+    let dir = TempPath::dir("riscv-plugin");
+    let library = build_plugin(&dir);
+    let out = dir.join("counts");
+
+    assert!(Plugin::load(&library, &[]).is_err());
+    let arguments = [("out".to_string(), out.display().to_string())];
+    let plugin = Arc::new(Plugin::load(&library, &arguments).unwrap());
+
+    let mut cpu = Cpu::new(code(&[
+        0x0000_1597, // auipc a1, 1
+        0x00b5_b023, // sd a1, 0(a1)
+        0x0005_b603, // ld a2, 0(a1)
+        0x0010_02b7, // lui t0, 0x100
+        0x0000_5337, // lui t1, 5
+        0x5553_0313, // addi t1, t1, 0x555
+        0x0062_a023, // sw t1, 0(t0) (syscon power off)
+    ]));
+    plugin.attach(&mut cpu);
+    assert_eq!(cpu.run(100), StopReason::Exited { code: 0 });
+    plugin.exit(0);
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), "7 1 2 0");
+}
@@ -0,0 +1,161 @@
+mod utils;
+use std::{thread, time::Duration};
+
+use riscv::interpreter::{
+    chardev::{BufferBackend, CharBackend},
+    cpu::{control::StopReason, Cpu},
+    exception::Exception,
+    htif::{self, Htif},
+    replay::{Journal, JournaledBackend},
+    semihosting::{
+        self, Semihosting, SEMIHOSTING_ENTRY, SEMIHOSTING_EXIT, SYS_CLOCK, SYS_ERRNO, SYS_READC,
+    },
+    DRAM_BASE,
+};
+use utils::{elf::code, temp::TempPath, virtio::read_bytes};
+
+#[test]
+fn test_replay_console_input() {
+    let path = TempPath::new("riscv-replay-console");
+    let journal = Journal::record(&path).unwrap();
+    let host = BufferBackend::default();
+    let mut console = JournaledBackend::new(Box::new(host.clone()), journal.channel("console0"));
+    let mut buf = [0; 16];
+    journal.set_instret(10);
+    assert_eq!(console.read(&mut buf), 0);
+    host.push_input(b"ls\n");
+    journal.set_instret(20);
+    assert_eq!(console.read(&mut buf), 3);
+    drop((console, journal));
+
+    // different host input is ignored, the recorded one arrives at the same count again
+    let journal = Journal::replay(&path).unwrap();
+    let host = BufferBackend::default();
+    host.push_input(b"rm -rf /\n");
+    let mut console = JournaledBackend::new(Box::new(host.clone()), journal.channel("console0"));
+    journal.set_instret(10);
+    assert_eq!(console.read(&mut buf), 0);
+    journal.set_instret(20);
+    assert_eq!(console.read(&mut buf), 3);
+    assert_eq!(&buf[..3], b"ls\n");
+    journal.set_instret(30);
+    assert_eq!(console.read(&mut buf), 0);
+    assert_eq!(journal.divergence(), None);
+    // output still reaches the host
+    console.write(b"ok");
+    assert_eq!(host.take_output(), b"ok");
+}
+
+#[test]
+fn test_replay_divergence() {
+    let path = TempPath::new("riscv-replay-divergence");
+    let journal = Journal::record(&path).unwrap();
+    let host = BufferBackend::default();
+    let mut console = JournaledBackend::new(Box::new(host.clone()), journal.channel("console0"));
+    host.push_input(b"a");
+    journal.set_instret(5);
+    assert_eq!(console.read(&mut [0; 4]), 1);
+    drop((console, journal));
+
+    // the replayed run does not read at instruction 5
+    let journal = Journal::replay(&path).unwrap();
+    let mut console = JournaledBackend::new(
+        Box::new(BufferBackend::default()),
+        journal.channel("console0"),
+    );
+    journal.set_instret(7);
+    assert_eq!(console.read(&mut [0; 4]), 0);
+    assert!(journal.divergence().unwrap().contains("console0"));
+}
+
+#[test]
+fn test_replay_semihosting_clock() {
+    let program = code(&[
+        0x0100_0513, // li a0, SYS_CLOCK
+        SEMIHOSTING_ENTRY,
+        0x0010_0073, // ebreak
+        SEMIHOSTING_EXIT,
+        0x0005_0413, // mv s0, a0
+        0x0010_0073, // ebreak
+    ]);
+    assert_eq!(SYS_CLOCK, 0x10);
+    let run = |journal| {
+        let mut cpu = Cpu::new(program.clone());
+        cpu.bus
+            .set_semihosting(Semihosting::new().with_journal(&journal));
+        cpu.set_journal(journal);
+        // the host clock moves on, the guest does not notice when replaying
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(cpu.run(100), StopReason::Exception(Exception::Breakpoint));
+        cpu.read_reg(8)
+    };
+
+    let path = TempPath::new("riscv-replay-clock");
+    let recorded = run(Journal::record(&path).unwrap());
+    let journal = Journal::replay(&path).unwrap();
+    assert_eq!(run(journal.clone()), recorded);
+    assert_eq!(journal.divergence(), None);
+}
+
+#[test]
+fn test_replay_stdin_reads() {
+    const TOHOST: u64 = DRAM_BASE + 0x1000;
+    const FROMHOST: u64 = DRAM_BASE + 0x1008;
+    const PARAM: u64 = DRAM_BASE + 0x2000;
+    const BUFFER: u64 = DRAM_BASE + 0x3000;
+
+    // "hi" read at instruction 3 and a read failing with EIO at 4, each logged with a leading
+    // status byte as the file tables do
+    let path = TempPath::new("riscv-replay-stdin");
+    let journal = Journal::record(&path).unwrap();
+    for name in ["htif-stdin", "semihosting-stdin"] {
+        let stdin = journal.channel(name);
+        journal.set_instret(3);
+        stdin.input(&mut [0; 8], |buf| {
+            buf[..3].copy_from_slice(b"\0hi");
+            3
+        });
+        journal.set_instret(4);
+        stdin.input(&mut [0; 8], |buf| {
+            buf[..5].copy_from_slice(&[1, 5, 0, 0, 0]);
+            5
+        });
+    }
+    drop(journal);
+    let journal = Journal::replay(&path).unwrap();
+
+    let mut cpu = Cpu::new(vec![]);
+    cpu.bus
+        .set_htif(Htif::new(TOHOST, Some(FROMHOST)).with_journal(&journal));
+    cpu.set_journal(journal.clone());
+    let mut htif_read = |instret| {
+        for (i, word) in [htif::SYS_READ, 0, BUFFER, 16].iter().enumerate() {
+            cpu.bus.store(PARAM + i as u64 * 8, 64, *word).unwrap();
+        }
+        cpu.bus.store(TOHOST, 64, PARAM).unwrap();
+        cpu.bus.tick(instret);
+        cpu.bus.store(FROMHOST, 64, 0).unwrap();
+        cpu.bus.load(PARAM, 64).unwrap() as i64
+    };
+    assert_eq!(htif_read(3), 2);
+    assert_eq!(htif_read(4), -5);
+    assert_eq!(read_bytes(&cpu.bus, BUFFER, 2), b"hi");
+
+    let mut cpu = Cpu::new(vec![]);
+    cpu.bus
+        .set_semihosting(Semihosting::new().with_journal(&journal));
+    for (i, word) in [0, BUFFER, 16].iter().enumerate() {
+        cpu.bus.store(PARAM + i as u64 * 8, 64, *word).unwrap();
+    }
+    journal.set_instret(3);
+    // the number of bytes not read
+    assert_eq!(
+        cpu.bus.semihosting_call(semihosting::SYS_READ, PARAM, 3),
+        Some(14)
+    );
+    assert_eq!(read_bytes(&cpu.bus, BUFFER, 2), b"hi");
+    journal.set_instret(4);
+    assert_eq!(cpu.bus.semihosting_call(SYS_READC, 0, 4), Some(u64::MAX));
+    assert_eq!(cpu.bus.semihosting_call(SYS_ERRNO, 0, 4), Some(5));
+    assert_eq!(journal.divergence(), None);
+}
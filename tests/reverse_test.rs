@@ -0,0 +1,118 @@
+mod utils;
+use riscv::interpreter::{
+    cpu::{
+        control::{StopReason, WatchKind, Watchpoint},
+        Cpu,
+    },
+    exception::Exception,
+    htif::Htif,
+    replay::Journal,
+    DRAM_BASE,
+};
+use utils::{elf::code, temp::TempPath};
+
+const DATA: u64 = DRAM_BASE + 8 + 0x1000;
+const STORE: u64 = DRAM_BASE + 16;
+
+/// Stores 1 to 10 at DATA, one after the other.
+fn program() -> Cpu {
+    Cpu::new(code(&[
+        0x0000_0513, // li a0, 0
+        0x00a0_0593, // li a1, 10
+        0x0000_1617, // auipc a2, 1
+        0x0015_0513, // loop: addi a0, a0, 1
+        0x00a6_3023, // sd a0, 0(a2)
+        0xfff5_8593, // addi a1, a1, -1
+        0xfe05_9ae3, // bnez a1, loop
+        0x0010_0073, // ebreak
+    ]))
+}
+
+#[test]
+fn test_reverse_step() {
+    let mut cpu = program();
+    cpu.enable_reverse_execution(4, 100).unwrap();
+    let mut states = vec![(cpu.pc, cpu.regs)];
+    while cpu.step() == StopReason::Limit {
+        states.push((cpu.pc, cpu.regs));
+    }
+    assert_eq!(cpu.bus.load(DATA, 64).unwrap(), 10);
+
+    // the ebreak did not retire, so the last state is the current one
+    states.pop();
+    while let Some((pc, regs)) = states.pop() {
+        assert_eq!(cpu.reverse_step(), Ok(StopReason::Limit));
+        assert_eq!((cpu.pc, cpu.regs), (pc, regs));
+        assert_eq!(cpu.instret, states.len() as u64);
+    }
+    assert_eq!(cpu.reverse_step(), Ok(StopReason::HistoryStart));
+    assert_eq!(cpu.bus.load(DATA, 64).unwrap(), 0);
+
+    // and forward again
+    assert_eq!(cpu.run(100), StopReason::Exception(Exception::Breakpoint));
+    assert_eq!(cpu.bus.load(DATA, 64).unwrap(), 10);
+}
+
+#[test]
+fn test_reverse_continue_to_watchpoint() {
+    let mut cpu = program();
+    cpu.enable_reverse_execution(8, 100).unwrap();
+    assert_eq!(cpu.run(100), StopReason::Exception(Exception::Breakpoint));
+    cpu.add_watchpoint(Watchpoint {
+        addr: DATA,
+        len: 8,
+        kind: WatchKind::Write,
+    });
+
+    // who wrote the value?
+    for value in (1..=10).rev() {
+        let stop = StopReason::Watchpoint {
+            addr: DATA,
+            kind: WatchKind::Write,
+        };
+        assert_eq!(cpu.reverse_continue(), Ok(stop));
+        assert_eq!(cpu.pc, STORE);
+        assert_eq!(cpu.read_reg(10), value);
+        assert_eq!(cpu.bus.load(DATA, 64).unwrap(), value - 1);
+    }
+    assert_eq!(cpu.reverse_continue(), Ok(StopReason::HistoryStart));
+    assert_eq!(cpu.instret, 0);
+}
+
+#[test]
+fn test_reverse_continue_to_breakpoint() {
+    let mut cpu = program();
+    cpu.enable_reverse_execution(5, 100).unwrap();
+    cpu.add_breakpoint(STORE);
+    assert_eq!(cpu.run(100), StopReason::Breakpoint { pc: STORE });
+    assert_eq!(cpu.run(100), StopReason::Breakpoint { pc: STORE });
+    assert_eq!(cpu.read_reg(10), 2);
+
+    // the breakpoint the run stopped at does not count
+    assert_eq!(
+        cpu.reverse_continue(),
+        Ok(StopReason::Breakpoint { pc: STORE })
+    );
+    assert_eq!(cpu.read_reg(10), 1);
+    assert_eq!(cpu.instret, 4);
+}
+
+#[test]
+fn test_reverse_step_keeps_recording() {
+    let path = TempPath::new("riscv-reverse-journal");
+    let mut cpu = program();
+    cpu.set_journal(Journal::record(&path).unwrap());
+    cpu.enable_reverse_execution(4, 100).unwrap();
+    cpu.run(10);
+    assert_eq!(cpu.reverse_step(), Ok(StopReason::Limit));
+    assert!(cpu.bus.take_journal().is_some());
+}
+
+#[test]
+fn test_reverse_execution_needs_deterministic_machine() {
+    let mut cpu = program();
+    assert!(cpu.reverse_step().is_err());
+    cpu.bus.set_htif(Htif::new(DATA, None));
+    assert!(cpu.enable_reverse_execution(10, 10).is_err());
+    assert!(cpu.reverse_continue().is_err());
+}
@@ -0,0 +1,124 @@
+mod utils;
+use std::{path::Path, time::Duration};
+
+use riscv::interpreter::{
+    riscv_tests::{discover, run_test, Outcome},
+    DRAM_BASE,
+};
+use utils::{
+    elf::{build_elf, code},
+    temp::TempPath,
+};
+
+const TOHOST: u64 = DRAM_BASE + 0x1000;
+
+/// A test built like riscv-tests in the -p environment: it installs a trap handler, drops
+/// into the test with mret and ends with an ecall, with the result in gp.
+fn fake_test(result: u32) -> Vec<u8> {
+    let li_gp = result << 20 | 0x0000_0193;
+    let program = code(&[
+        0x0000_0297, // auipc t0, 0
+        0x0402_8293, // addi t0, t0, 0x40
+        0x3052_9073, // csrw mtvec, t0
+        0x0000_0297, // auipc t0, 0
+        0x0142_8293, // addi t0, t0, 0x14
+        0x3412_9073, // csrw mepc, t0
+        0x3020_0073, // mret
+        0x0000_0013, // nop
+        li_gp,       // li gp, result
+        0x05d0_0893, // li a7, 93
+        0x0000_0073, // ecall
+        0x0000_0013, // nop
+        0x0000_0013, // nop
+        0x0000_0013, // nop
+        0x0000_0013, // nop
+        0x0000_0013, // nop
+        0x3420_2f73, // trap_vector: csrr t5, mcause
+        0x00b0_0f93, // li t6, 11
+        0x01ff_0663, // beq t5, t6, write_tohost
+        0x5391_e193, // ori gp, gp, 1337
+        0x0000_0013, // nop
+        0x0000_1f17, // write_tohost: auipc t5, 1
+        0xfa3f_2623, // sw gp, -0x54(t5)
+        0xfa0f_2823, // sw zero, -0x50(t5)
+        0xff5f_f06f, // j write_tohost
+    ]);
+    build_elf(
+        DRAM_BASE,
+        &[(DRAM_BASE, &program), (TOHOST, &[0; 16])],
+        &[("tohost", TOHOST), ("fromhost", TOHOST + 8)],
+    )
+}
+
+#[test]
+fn test_discover() {
+    let dir = TempPath::dir("riscv-tests-discover");
+    for name in [
+        "rv64ui-p-add",
+        "rv64ui-p-add.dump",
+        "rv64uc-v-rvc",
+        "rv64mi-p-csr",
+        "rv32ui-p-add",
+        "Makefile",
+    ] {
+        dir.write(name, b"");
+    }
+    let names: Vec<_> = discover(&dir)
+        .unwrap()
+        .iter()
+        .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(names, ["rv64uc-v-rvc", "rv64ui-p-add"]);
+}
+
+#[test]
+fn test_run_test_outcomes() {
+    let dir = TempPath::dir("riscv-tests-run");
+    let limit = Duration::from_secs(10);
+
+    let result = run_test(&dir.write("rv64ui-p-pass", &fake_test(1)), limit);
+    assert_eq!(result.outcome, Outcome::Pass);
+    assert_eq!(result.name, "rv64ui-p-pass");
+    assert!(result.instructions > 10);
+
+    // test case 3 failed
+    let result = run_test(&dir.write("rv64ui-p-fail", &fake_test(3 << 1 | 1)), limit);
+    assert_eq!(result.outcome, Outcome::Fail(3));
+
+    let spin = build_elf(
+        DRAM_BASE,
+        &[(DRAM_BASE, &code(&[0x0000_006f]))], // j .
+        &[("tohost", TOHOST)],
+    );
+    let result = run_test(&dir.write("rv64ui-p-spin", &spin), Duration::ZERO);
+    assert_eq!(result.outcome, Outcome::Timeout);
+
+    let raw = dir.write("rv64ui-p-raw", &code(&[0x0000_006f]));
+    assert!(matches!(run_test(&raw, limit).outcome, Outcome::Error(_)));
+}
+
+/// Runs the real suite when RISCV_TESTS_DIR points at the built `isa` directory of
+/// riscv-tests. Tests whose names start with one of the comma separated prefixes in
+/// RISCV_TESTS_REQUIRE, like `rv64ui-p`, must pass; the others are only reported.
+#[test]
+fn test_riscv_tests_suite() {
+    let Some(dir) = std::env::var_os("RISCV_TESTS_DIR") else {
+        println!("RISCV_TESTS_DIR is not set, skipping");
+        return;
+    };
+    let require = std::env::var("RISCV_TESTS_REQUIRE").unwrap_or_default();
+    let required = |name: &str| {
+        require
+            .split(',')
+            .any(|prefix| !prefix.is_empty() && name.starts_with(prefix))
+    };
+    let mut failures = Vec::new();
+    for path in discover(Path::new(&dir)).unwrap() {
+        let result = run_test(&path, Duration::from_secs(10));
+        println!("{}", result);
+        if result.outcome != Outcome::Pass && required(&result.name) {
+            failures.push(result.name);
+        }
+    }
+    assert!(failures.is_empty(), "failed: {}", failures.join(", "));
+}
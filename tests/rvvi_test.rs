@@ -0,0 +1,139 @@
+mod utils;
+use std::ffi::CString;
+
+use riscv::interpreter::rvvi::*;
+use utils::{elf::code, temp::TempPath};
+
+#[test]
+fn test_rvvi_retire_events() {
+    let dir = TempPath::dir("rvvi-test");
+    let path = dir.join("program.bin");
+    std::fs::write(
+        &path,
+        code(&[
+            0x0030_0593, // li a1, 3
+            0x3405_9073, // csrw mscratch, a1
+            0x2000_0637, // lui a2, 0x20000
+            0x0006_3503, // ld a0, 0(a2)
+            0x3440_26f3, // csrr a3, mip
+            0x0010_0073, // ebreak
+        ]),
+    )
+    .unwrap();
+    let path = CString::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(riscv_rvvi_abi_version(), RVVI_ABI_VERSION);
+
+    unsafe {
+        let missing = CString::new("/nonexistent").unwrap();
+        assert!(riscv_rvvi_open(missing.as_ptr()).is_null());
+
+        let rvvi = riscv_rvvi_open(path.as_ptr());
+        assert!(!rvvi.is_null());
+        let mut event = std::mem::zeroed();
+
+        riscv_rvvi_step(rvvi, &mut event);
+        assert_eq!(
+            (event.order, event.pc, event.insn),
+            (0, 0x8000_0000, 0x0030_0593)
+        );
+        assert_eq!(event.x_written, 1 << 11);
+        assert_eq!(event.x[11], 3);
+        assert_eq!((event.trap, event.halt, event.csr_count), (0, 0, 0));
+
+        riscv_rvvi_step(rvvi, &mut event);
+        assert_eq!(event.x_written, 0);
+        assert_eq!(event.csr_count, 1);
+        assert_eq!((event.csr_addr[0], event.csr_value[0]), (0x340, 3));
+
+        // a device only the design has
+        riscv_rvvi_step(rvvi, &mut event);
+        riscv_rvvi_set_volatile(rvvi, 0x2000_0000, 0x2000_0fff);
+        assert_eq!(riscv_rvvi_mem_write(rvvi, 0x2000_0000, 0x1234, 32), 0);
+        assert_eq!(riscv_rvvi_mem_write(rvvi, 0x4000_0000, 0, 32), -1);
+        riscv_rvvi_step(rvvi, &mut event);
+        assert_eq!(event.x[10], 0x1234);
+        assert_eq!(riscv_rvvi_gpr(rvvi, 10), 0x1234);
+
+        // machine timer interrupt
+        riscv_rvvi_set_interrupt(rvvi, 7, 1);
+        riscv_rvvi_step(rvvi, &mut event);
+        assert_eq!(event.x[13], 1 << 7);
+        assert_eq!(riscv_rvvi_csr(rvvi, 0x344), 1 << 7);
+
+        riscv_rvvi_step(rvvi, &mut event);
+        assert_eq!((event.trap, event.cause), (1, 3));
+        assert_eq!(
+            (event.order, event.pc, event.insn),
+            (5, 0x8000_0014, 0x0010_0073)
+        );
+        // mtvec is 0
+        assert_eq!(riscv_rvvi_pc(rvvi), 0);
+        assert_eq!(riscv_rvvi_csr(rvvi, 0x341), 0x8000_0014);
+
+        riscv_rvvi_set_gpr(rvvi, 0, 5);
+        riscv_rvvi_set_gpr(rvvi, 5, 5);
+        assert_eq!(riscv_rvvi_gpr(rvvi, 0), 0);
+        assert_eq!(riscv_rvvi_gpr(rvvi, 5), 5);
+        riscv_rvvi_close(rvvi);
+    }
+}
+
+#[test]
+fn test_rvvi_interrupts_and_faults() {
+    let dir = TempPath::dir("rvvi-interrupt-test");
+    let path = dir.join("program.bin");
+    std::fs::write(
+        &path,
+        code(&[
+            0x0000_0297, // auipc t0, 0
+            0x0202_8293, // addi t0, t0, 0x20
+            0x3052_9073, // csrw mtvec, t0
+            0x0800_0313, // li t1, 0x80
+            0x3043_1073, // csrw mie, t1
+            0x3004_6073, // csrsi mstatus, 8
+            0x0000_0013, // nop
+            0x02a5_0533, // mul a0, a0, a0
+            0x3420_26f3, // handler: csrr a3, mcause
+            0x3020_0073, // mret
+        ]),
+    )
+    .unwrap();
+    let path = CString::new(path.to_str().unwrap()).unwrap();
+
+    unsafe {
+        let rvvi = riscv_rvvi_open(path.as_ptr());
+        let mut event = std::mem::zeroed();
+        for _ in 0..6 {
+            riscv_rvvi_step(rvvi, &mut event);
+        }
+        assert_eq!(riscv_rvvi_mem_write(rvvi, 0x8000_1000, 0, 128), -1);
+        assert_eq!(riscv_rvvi_mem_write(rvvi, 0x8000_1000, 0, 12), -1);
+
+        riscv_rvvi_step(rvvi, &mut event);
+
+        // there is no M extension
+        riscv_rvvi_step(rvvi, &mut event);
+        assert_eq!((event.trap, event.cause, event.pc), (1, 2, 0x8000_001c));
+        assert_eq!(event.insn, 0x02a5_0533);
+        assert_eq!(riscv_rvvi_pc(rvvi), 0x8000_0020);
+
+        // the handler runs with interrupts off, mret turns them back on
+        riscv_rvvi_set_interrupt(rvvi, 7, 1);
+        riscv_rvvi_step(rvvi, &mut event);
+        assert_eq!((event.trap, event.pc, event.x[13]), (0, 0x8000_0020, 2));
+        riscv_rvvi_step(rvvi, &mut event);
+        assert_eq!(riscv_rvvi_pc(rvvi), 0x8000_001c);
+
+        riscv_rvvi_step(rvvi, &mut event);
+        assert_eq!(event.trap, 1);
+        assert_eq!(event.cause, 1 << 63 | 7);
+        assert_eq!(event.pc, 0x8000_001c);
+        assert_eq!(riscv_rvvi_pc(rvvi), 0x8000_0020);
+        assert_eq!(riscv_rvvi_csr(rvvi, 0x341), 0x8000_001c);
+
+        riscv_rvvi_step(rvvi, &mut event);
+        assert_eq!(event.trap, 0);
+        assert_eq!(event.x[13], 1 << 63 | 7);
+        riscv_rvvi_close(rvvi);
+    }
+}
@@ -0,0 +1,109 @@
+mod utils;
+use riscv::interpreter::{
+    cpu::Cpu,
+    exception::Exception,
+    semihosting::{
+        Semihosting, ADP_STOPPED_APPLICATION_EXIT, SEMIHOSTING_ENTRY, SEMIHOSTING_EXIT, SYS_CLOSE,
+        SYS_EXIT, SYS_FLEN, SYS_GET_CMDLINE, SYS_OPEN, SYS_READ, SYS_SEEK, SYS_WRITE,
+    },
+    syscon::PowerEvent,
+    DRAM_BASE,
+};
+use utils::{
+    elf::code,
+    temp::TempPath,
+    virtio::{read_bytes, write_bytes},
+};
+
+const EBREAK: u32 = 0x0010_0073;
+const PARAM: u64 = DRAM_BASE + 0x1000;
+const BUFFER: u64 = DRAM_BASE + 0x2000;
+
+fn call(cpu: &mut Cpu, op: u64, args: &[u64]) -> u64 {
+    for (i, arg) in args.iter().enumerate() {
+        cpu.bus.store(PARAM + i as u64 * 8, 64, *arg).unwrap();
+    }
+    cpu.bus.semihosting_call(op, PARAM, 0).unwrap()
+}
+
+#[test]
+fn test_semihosting_exit() {
+    let program = code(&[
+        0x0000_1597, // auipc a1, 1
+        0x0180_0513, // li a0, SYS_EXIT
+        SEMIHOSTING_ENTRY,
+        EBREAK,
+        SEMIHOSTING_EXIT,
+        0x0000_006f, // j .
+    ]);
+    let mut cpu = Cpu::new(program);
+    cpu.bus.set_semihosting(Semihosting::new());
+    cpu.bus
+        .store(PARAM, 64, ADP_STOPPED_APPLICATION_EXIT)
+        .unwrap();
+    cpu.bus.store(PARAM + 8, 64, 5).unwrap();
+
+    assert_eq!(cpu.execute(), None);
+    assert_eq!(cpu.exit_code, Some(5));
+
+    // a parameter block outside of memory still exits
+    let mut cpu = Cpu::new(vec![]);
+    cpu.bus.set_semihosting(Semihosting::new());
+    assert_eq!(cpu.bus.semihosting_call(SYS_EXIT, 0x10, 0), Some(0));
+    assert_eq!(
+        cpu.bus.take_power_event(),
+        Some(PowerEvent::PowerOff { exit_code: 1 })
+    );
+}
+
+#[test]
+fn test_plain_ebreak_still_traps() {
+    // the markers are there, but semihosting is off
+    let program = code(&[SEMIHOSTING_ENTRY, EBREAK, SEMIHOSTING_EXIT]);
+    let mut cpu = Cpu::new(program);
+    assert_eq!(cpu.execute(), Some(Exception::Breakpoint));
+
+    // semihosting is on, but the markers are missing
+    let mut cpu = Cpu::new(code(&[EBREAK]));
+    cpu.bus.set_semihosting(Semihosting::new());
+    assert_eq!(cpu.execute(), Some(Exception::Breakpoint));
+    assert_eq!(cpu.pc, DRAM_BASE);
+}
+
+#[test]
+fn test_semihosting_files() {
+    let path = TempPath::new("riscv-semihosting");
+    let name = path.display().to_string();
+    let mut cpu = Cpu::new(vec![]);
+    cpu.bus.set_semihosting(Semihosting::new());
+    write_bytes(&mut cpu.bus, BUFFER, name.as_bytes());
+    write_bytes(&mut cpu.bus, BUFFER + 0x100, b"semihosted");
+
+    // "w+b"
+    let fd = call(&mut cpu, SYS_OPEN, &[BUFFER, 7, name.len() as u64]);
+    assert_eq!(fd, 3);
+    assert_eq!(call(&mut cpu, SYS_WRITE, &[fd, BUFFER + 0x100, 10]), 0);
+    assert_eq!(call(&mut cpu, SYS_FLEN, &[fd]), 10);
+    assert_eq!(call(&mut cpu, SYS_SEEK, &[fd, 4]), 0);
+    // asking for more than is left reports the bytes not read
+    assert_eq!(call(&mut cpu, SYS_READ, &[fd, BUFFER + 0x200, 8]), 2);
+    assert_eq!(read_bytes(&cpu.bus, BUFFER + 0x200, 6), b"hosted");
+    assert_eq!(call(&mut cpu, SYS_CLOSE, &[fd]), 0);
+    assert_eq!(call(&mut cpu, SYS_CLOSE, &[fd]), u64::MAX);
+    assert_eq!(std::fs::read(&path).unwrap(), b"semihosted");
+
+    // ":tt" opened for writing is stdout
+    write_bytes(&mut cpu.bus, BUFFER, b":tt");
+    assert_eq!(call(&mut cpu, SYS_OPEN, &[BUFFER, 4, 3]), 1);
+}
+
+#[test]
+fn test_semihosting_cmdline() {
+    let mut cpu = Cpu::new(vec![]);
+    cpu.bus
+        .set_semihosting(Semihosting::new().with_args(vec!["prog".to_string(), "-v".to_string()]));
+    assert_eq!(call(&mut cpu, SYS_GET_CMDLINE, &[BUFFER, 4]), u64::MAX);
+    assert_eq!(call(&mut cpu, SYS_GET_CMDLINE, &[BUFFER, 64]), 0);
+    assert_eq!(read_bytes(&cpu.bus, BUFFER, 8), b"prog -v\0");
+    assert_eq!(cpu.bus.load(PARAM + 8, 64).unwrap(), 7);
+}
@@ -0,0 +1,104 @@
+mod utils;
+use std::io::ErrorKind;
+
+use riscv::interpreter::{
+    chardev::BufferBackend,
+    cpu::{control::StopReason, csr::MSCRATCH, Cpu},
+    exception::Exception,
+    htif::Htif,
+    snapshot::SNAPSHOT_VERSION,
+    DRAM_BASE,
+};
+use utils::{
+    elf::code,
+    programs::{SUM_DATA, SUM_LOOP},
+    temp::TempPath,
+};
+
+#[test]
+fn test_snapshot_resumes_run() {
+    let mut cpu = Cpu::new(code(SUM_LOOP));
+    assert_eq!(cpu.run(50), StopReason::Limit);
+    let snapshot = cpu.save_snapshot().unwrap();
+    // almost all of memory is zero and left out
+    assert!(snapshot.len() < 4096);
+    assert_eq!(cpu.run(1000), StopReason::Exception(Exception::Breakpoint));
+
+    let mut resumed = Cpu::new(vec![]);
+    resumed.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(resumed.instret, 50);
+    assert_eq!(
+        resumed.run(1000),
+        StopReason::Exception(Exception::Breakpoint)
+    );
+    assert_eq!(resumed.regs, cpu.regs);
+    assert_eq!(resumed.pc, cpu.pc);
+    assert_eq!(resumed.instret, cpu.instret);
+    assert_eq!(resumed.bus.load(SUM_DATA, 64).unwrap(), 5050);
+    assert_eq!(resumed.csr.load(MSCRATCH), 5050);
+}
+
+#[test]
+fn test_snapshot_file_restores_memory() {
+    let path = TempPath::new("snapshot-test");
+    let mut cpu = Cpu::new(code(SUM_LOOP));
+    cpu.run(1000);
+    cpu.save_snapshot_file(&path).unwrap();
+
+    // memory written after the snapshot is rolled back
+    cpu.bus.store(SUM_DATA, 64, 1).unwrap();
+    cpu.bus.store(SUM_DATA + 0x10_0000, 8, 1).unwrap();
+    cpu.restore_snapshot_file(&path).unwrap();
+    assert_eq!(cpu.bus.load(SUM_DATA, 64).unwrap(), 5050);
+    assert_eq!(cpu.bus.load(SUM_DATA + 0x10_0000, 8).unwrap(), 0);
+}
+
+#[test]
+fn test_snapshot_rejects_bad_files() {
+    let cpu = Cpu::new(code(SUM_LOOP));
+    let snapshot = cpu.save_snapshot().unwrap();
+    let mut other = Cpu::new(vec![]);
+
+    let mut newer = snapshot.clone();
+    newer[8..12].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+    let error = other.restore_snapshot(&newer).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(error.to_string().contains("version"));
+
+    let error = other
+        .restore_snapshot(&snapshot[..snapshot.len() - 1])
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(other.restore_snapshot(b"not a snapshot").is_err());
+}
+
+#[test]
+fn test_snapshot_keeps_htif_answers() {
+    const TOHOST: u64 = DRAM_BASE + 0x1000;
+    const FROMHOST: u64 = DRAM_BASE + 0x1008;
+    const PUTCHAR: u64 = (1 << 56) | (1 << 48);
+    let htif_cpu = || {
+        let mut cpu = Cpu::new(vec![]);
+        cpu.bus.set_htif(
+            Htif::new(TOHOST, Some(FROMHOST)).with_console(Box::new(BufferBackend::default())),
+        );
+        cpu
+    };
+    // the second answer waits for the guest to take the first
+    let mut cpu = htif_cpu();
+    for c in b"ab" {
+        cpu.bus.store(TOHOST, 64, PUTCHAR | *c as u64).unwrap();
+        cpu.bus.tick(1);
+    }
+    let snapshot = cpu.save_snapshot().unwrap();
+
+    let mut resumed = htif_cpu();
+    resumed.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(resumed.bus.load(FROMHOST, 64).unwrap(), PUTCHAR);
+    resumed.bus.store(FROMHOST, 64, 0).unwrap();
+    resumed.bus.tick(2);
+    assert_eq!(resumed.bus.load(FROMHOST, 64).unwrap(), PUTCHAR);
+
+    let error = Cpu::new(vec![]).restore_snapshot(&snapshot).unwrap_err();
+    assert!(error.to_string().contains("HTIF"));
+}
@@ -0,0 +1,45 @@
+mod utils;
+use riscv::interpreter::{
+    cpu::Cpu,
+    syscon::{PowerEvent, FINISHER_FAIL, FINISHER_PASS, FINISHER_RESET},
+    SYSCON_BASE,
+};
+use utils::compile_assembly::compile_assembly;
+
+#[test]
+fn test_syscon_power_events() {
+    let mut cpu = Cpu::new(vec![]);
+    assert_eq!(cpu.bus.take_power_event(), None);
+    cpu.bus.store(SYSCON_BASE, 32, FINISHER_PASS).unwrap();
+    assert_eq!(
+        cpu.bus.take_power_event(),
+        Some(PowerEvent::PowerOff { exit_code: 0 })
+    );
+    assert_eq!(cpu.bus.take_power_event(), None);
+    cpu.bus
+        .store(SYSCON_BASE, 32, (3 << 16) | FINISHER_FAIL)
+        .unwrap();
+    assert_eq!(
+        cpu.bus.take_power_event(),
+        Some(PowerEvent::PowerOff { exit_code: 3 })
+    );
+    cpu.bus.store(SYSCON_BASE, 32, FINISHER_RESET).unwrap();
+    assert_eq!(cpu.bus.take_power_event(), Some(PowerEvent::Reset));
+}
+
+#[test]
+fn test_syscon_power_off() {
+    let code = compile_assembly(
+        function_name!(),
+        "lui a0, 0x100
+        li a1, 0x2a3333
+        sw a1, 0(a0)
+        addi a2, x0, 1",
+    );
+    let mut cpu = Cpu::new(code);
+
+    assert_eq!(cpu.execute(), None);
+
+    assert_eq!(cpu.exit_code, Some(0x2a));
+    assert_eq!(cpu.read_reg(12), 0);
+}
@@ -0,0 +1,96 @@
+mod utils;
+use std::io::Write;
+
+use riscv::interpreter::{
+    cpu::{
+        control::StopReason,
+        trace::{TraceFormat, Tracer},
+        Cpu,
+    },
+    exception::Exception,
+    DRAM_BASE,
+};
+use utils::{buffer::Buffer, elf::code, programs::STORE_LOOP};
+
+fn program() -> Cpu {
+    Cpu::new(code(STORE_LOOP))
+}
+
+fn trace(tracer: impl FnOnce(Box<dyn Write + Send>) -> Tracer) -> Vec<String> {
+    let buffer = Buffer::default();
+    let mut cpu = program();
+    cpu.set_tracer(tracer(Box::new(buffer.clone())));
+    assert_eq!(cpu.run(100), StopReason::Exception(Exception::Breakpoint));
+    drop(cpu.take_tracer());
+    buffer.lines()
+}
+
+#[test]
+fn test_spike_trace() {
+    let lines = trace(|output| Tracer::new(TraceFormat::Spike, output));
+    // the ebreak does not retire
+    assert_eq!(lines.len(), 13);
+    assert_eq!(
+        lines[0],
+        "core   0: 3 0x0000000080000000 (0x00300593) x11 0x0000000000000003"
+    );
+    assert_eq!(
+        lines[1],
+        "core   0: 3 0x0000000080000004 (0x34059073) c832_mscratch 0x0000000000000003"
+    );
+    assert_eq!(
+        lines[2],
+        "core   0: 3 0x0000000080000008 (0x00001617) x12 0x0000000080001008"
+    );
+    assert_eq!(
+        lines[3],
+        "core   0: 3 0x000000008000000c (0x00b63423) mem 0x0000000080001010 0x0000000000000003"
+    );
+    assert_eq!(lines[5], "core   0: 3 0x0000000080000014 (0xfe059ce3)");
+    assert_eq!(
+        lines[12],
+        "core   0: 3 0x0000000080000018 (0x00863503) x10 0x0000000000000001 mem 0x0000000080001010"
+    );
+}
+
+#[test]
+fn test_trace_filters() {
+    let lines = trace(|output| {
+        Tracer::new(TraceFormat::Spike, output).with_pc_range(DRAM_BASE + 12..DRAM_BASE + 16)
+    });
+    assert_eq!(lines.len(), 3);
+    assert!(lines.iter().all(|line| line.contains("0x000000008000000c")));
+
+    // the third and fourth instruction
+    let lines = trace(|output| Tracer::new(TraceFormat::Spike, output).with_instret_range(2..4));
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("(0x00001617)"));
+    assert!(lines[1].contains("(0x00b63423)"));
+}
+
+#[test]
+fn test_json_trace() {
+    let lines = trace(|output| Tracer::new(TraceFormat::Json, output).with_instret_range(3..5));
+    assert_eq!(
+        lines,
+        [
+            "{\"instret\":3,\"pc\":\"0x8000000c\",\"raw\":\"0x00b63423\",\"disasm\":\"sd a1, 8(a2)\",\
+             \"writes\":[],\"mem\":[{\"addr\":\"0x80001010\",\"size\":8,\"value\":\"0x3\",\"write\":true}]}",
+            "{\"instret\":4,\"pc\":\"0x80000010\",\"raw\":\"0xfff58593\",\"disasm\":\"addi a1, a1, -1\",\
+             \"writes\":[{\"reg\":\"a1\",\"value\":\"0x2\"}],\"mem\":[]}",
+        ]
+    );
+}
+
+#[test]
+fn test_qemu_trace() {
+    let lines = trace(|output| Tracer::new(TraceFormat::Qemu, output).with_instret_range(0..1));
+    assert_eq!(
+        lines[2],
+        "0x0000000080000000:  00300593          addi a1, zero, 3"
+    );
+    assert_eq!(lines[4], " pc       0000000080000004");
+    assert!(lines[5].starts_with(" x0/zero  0000000000000000 x1/ra    0000000000000000"));
+    assert!(lines[7].contains(" x11/a1   0000000000000003"));
+    assert_eq!(lines.len(), 13);
+}
@@ -28,8 +28,8 @@ pub fn compile_assembly(file: &str, assembly: &str) -> Vec<u8> {
             "-march=rv64g",
             "-mno-relax",
             "-o",
-            &object_file.to_str().unwrap(),
-            &assembly_file.to_str().unwrap(),
+            object_file.to_str().unwrap(),
+            assembly_file.to_str().unwrap(),
         ])
         .output()
         .expect("clang error");
@@ -39,8 +39,8 @@ pub fn compile_assembly(file: &str, assembly: &str) -> Vec<u8> {
         .args([
             "-O",
             "binary",
-            &object_file.to_str().unwrap(),
-            &binary_file.to_str().unwrap(),
+            object_file.to_str().unwrap(),
+            binary_file.to_str().unwrap(),
         ])
         .output()
         .expect("llvm-binary error");
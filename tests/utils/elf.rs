@@ -0,0 +1,115 @@
+/// Build a little endian ELF64 RISC-V executable with one PT_LOAD segment per entry of
+/// `segments` (loaded at the same virtual and physical address) and a symbol table.
+pub fn build_elf(entry: u64, segments: &[(u64, &[u8])], symbols: &[(&str, u64)]) -> Vec<u8> {
+    const EHDR_SIZE: usize = 64;
+    const PHDR_SIZE: usize = 56;
+    const SHDR_SIZE: usize = 64;
+    const SYM_SIZE: usize = 24;
+
+    let phoff = EHDR_SIZE;
+    let mut data_offset = phoff + PHDR_SIZE * segments.len();
+    let mut body = Vec::new();
+    let mut phdrs = Vec::new();
+    for (addr, contents) in segments {
+        phdrs.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+        phdrs.extend_from_slice(&7u32.to_le_bytes()); // RWX
+        phdrs.extend_from_slice(&(data_offset as u64).to_le_bytes());
+        phdrs.extend_from_slice(&addr.to_le_bytes());
+        phdrs.extend_from_slice(&addr.to_le_bytes());
+        phdrs.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+        phdrs.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+        phdrs.extend_from_slice(&0x1000u64.to_le_bytes());
+        body.extend_from_slice(contents);
+        data_offset += contents.len();
+    }
+
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; SYM_SIZE];
+    for (name, value) in symbols {
+        symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+        symtab.extend_from_slice(&[0x11, 0]); // global object
+        symtab.extend_from_slice(&1u16.to_le_bytes());
+        symtab.extend_from_slice(&value.to_le_bytes());
+        symtab.extend_from_slice(&8u64.to_le_bytes());
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+    }
+    let symtab_offset = data_offset;
+    let strtab_offset = symtab_offset + symtab.len();
+    let shoff = strtab_offset + strtab.len();
+
+    let mut elf = b"\x7fELF".to_vec();
+    elf.extend_from_slice(&[2, 1, 1, 0]);
+    elf.resize(16, 0);
+    elf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    elf.extend_from_slice(&243u16.to_le_bytes()); // EM_RISCV
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&entry.to_le_bytes());
+    elf.extend_from_slice(&(phoff as u64).to_le_bytes());
+    elf.extend_from_slice(&(shoff as u64).to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    elf.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+    elf.extend_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&3u16.to_le_bytes());
+    elf.extend_from_slice(&0u16.to_le_bytes());
+    elf.extend_from_slice(&phdrs);
+    elf.extend_from_slice(&body);
+    elf.extend_from_slice(&symtab);
+    elf.extend_from_slice(&strtab);
+
+    let section = |kind: u32, offset: usize, size: usize, link: u32, entsize: u64| {
+        let mut header = Vec::new();
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&kind.to_le_bytes());
+        header.extend_from_slice(&[0; 16]);
+        header.extend_from_slice(&(offset as u64).to_le_bytes());
+        header.extend_from_slice(&(size as u64).to_le_bytes());
+        header.extend_from_slice(&link.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&8u64.to_le_bytes());
+        header.extend_from_slice(&entsize.to_le_bytes());
+        header
+    };
+    elf.extend_from_slice(&[0; SHDR_SIZE]);
+    elf.extend_from_slice(&section(2, symtab_offset, symtab.len(), 2, SYM_SIZE as u64));
+    elf.extend_from_slice(&section(3, strtab_offset, strtab.len(), 0, 0));
+    elf
+}
+
+/// Add a `.riscv.attributes` section naming the ISA `arch` to a file made by `build_elf`.
+pub fn with_arch(mut elf: Vec<u8>, arch: &str) -> Vec<u8> {
+    let shoff = u64::from_le_bytes(elf[40..48].try_into().unwrap()) as usize;
+    let headers = elf.split_off(shoff);
+
+    let mut attributes = vec![5]; // Tag_RISCV_arch
+    attributes.extend_from_slice(arch.as_bytes());
+    attributes.push(0);
+    let mut vendor = (4 + 6 + 5 + attributes.len() as u32).to_le_bytes().to_vec();
+    vendor.extend_from_slice(b"riscv\0");
+    vendor.push(1); // Tag_File
+    vendor.extend_from_slice(&(5 + attributes.len() as u32).to_le_bytes());
+    vendor.extend_from_slice(&attributes);
+    let offset = elf.len();
+    elf.push(b'A');
+    elf.extend_from_slice(&vendor);
+    let size = elf.len() - offset;
+
+    let shoff = elf.len();
+    elf.extend_from_slice(&headers);
+    let mut header = vec![0; 64];
+    header[4..8].copy_from_slice(&0x7000_0003u32.to_le_bytes()); // SHT_RISCV_ATTRIBUTES
+    header[24..32].copy_from_slice(&(offset as u64).to_le_bytes());
+    header[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+    elf.extend_from_slice(&header);
+    elf[40..48].copy_from_slice(&(shoff as u64).to_le_bytes());
+    let shnum = u16::from_le_bytes(elf[60..62].try_into().unwrap()) + 1;
+    elf[60..62].copy_from_slice(&shnum.to_le_bytes());
+    elf
+}
+
+/// Encode a list of instructions as little endian bytes.
+pub fn code(instructions: &[u32]) -> Vec<u8> {
+    instructions.iter().flat_map(|i| i.to_le_bytes()).collect()
+}
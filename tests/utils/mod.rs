@@ -1,2 +1,8 @@
+#![allow(dead_code)]
+pub mod buffer;
 pub mod compile_assembly;
+pub mod elf;
 pub mod function_name;
+pub mod programs;
+pub mod temp;
+pub mod virtio;
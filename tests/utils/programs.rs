@@ -0,0 +1,30 @@
+use riscv::interpreter::DRAM_BASE;
+
+/// Sums 100 + 99 + ... + 1 into a0, stores the sum at `SUM_DATA` and in mscratch, then stops
+/// at an ebreak.
+pub const SUM_LOOP: &[u32] = &[
+    0x0000_0513, // li a0, 0
+    0x0640_0593, // li a1, 100
+    0x00b5_0533, // loop: add a0, a0, a1
+    0xfff5_8593, // addi a1, a1, -1
+    0xfe05_9ce3, // bnez a1, loop
+    0x0000_1617, // auipc a2, 1
+    0x00a6_3023, // sd a0, 0(a2)
+    0x3405_1073, // csrw mscratch, a0
+    0x0010_0073, // ebreak
+];
+/// Where `SUM_LOOP` stores the sum.
+pub const SUM_DATA: u64 = DRAM_BASE + 20 + 0x1000;
+
+/// Copies 3 to mscratch, stores 3, 2 and 1 at DRAM_BASE + 0x1010 one after the other, loads
+/// the last one back into a0, then stops at an ebreak.
+pub const STORE_LOOP: &[u32] = &[
+    0x0030_0593, // li a1, 3
+    0x3405_9073, // csrw mscratch, a1
+    0x0000_1617, // auipc a2, 1
+    0x00b6_3423, // loop: sd a1, 8(a2)
+    0xfff5_8593, // addi a1, a1, -1
+    0xfe05_9ce3, // bnez a1, loop
+    0x0086_3503, // ld a0, 8(a2)
+    0x0010_0073, // ebreak
+];
@@ -0,0 +1,62 @@
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// `<temp dir>/<name>-<pid>`, removed together with whatever is there when dropped.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// Nothing is there yet; leftovers of an earlier run are removed.
+    pub fn new(name: &str) -> TempPath {
+        let path = TempPath(std::env::temp_dir().join(format!("{}-{}", name, std::process::id())));
+        path.remove();
+        path
+    }
+
+    /// An empty directory.
+    pub fn dir(name: &str) -> TempPath {
+        let dir = TempPath::new(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A file holding `data`.
+    pub fn file(name: &str, data: &[u8]) -> TempPath {
+        let file = TempPath::new(name);
+        std::fs::write(&file, data).unwrap();
+        file
+    }
+
+    /// Write `data` to `name` in this directory.
+    pub fn write(&self, name: &str, data: &[u8]) -> PathBuf {
+        let path = self.0.join(name);
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn remove(&self) {
+        let _ = std::fs::remove_file(&self.0);
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
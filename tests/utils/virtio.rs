@@ -0,0 +1,110 @@
+use riscv::interpreter::bus::Bus;
+
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+
+const QUEUE_SIZE: u16 = 16;
+
+pub fn write_bytes(bus: &mut Bus, addr: u64, data: &[u8]) {
+    for (i, byte) in data.iter().enumerate() {
+        bus.store(addr + i as u64, 8, *byte as u64).unwrap();
+    }
+}
+
+pub fn read_bytes(bus: &Bus, addr: u64, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| bus.load(addr + i as u64, 8).unwrap() as u8)
+        .collect()
+}
+
+/// Driver side of one split virtqueue, laid out in guest memory starting at `area`.
+pub struct TestQueue {
+    mmio: u64,
+    queue: u32,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    next_desc: u16,
+    next_avail: u16,
+    last_used: u16,
+}
+
+impl TestQueue {
+    pub fn new(bus: &mut Bus, mmio: u64, queue: u32, area: u64) -> TestQueue {
+        let desc = area;
+        let avail = desc + 16 * QUEUE_SIZE as u64;
+        let used = avail + 0x100;
+        bus.store(mmio + STATUS, 32, 0xf).unwrap();
+        bus.store(mmio + QUEUE_SEL, 32, queue as u64).unwrap();
+        bus.store(mmio + QUEUE_NUM, 32, QUEUE_SIZE as u64).unwrap();
+        bus.store(mmio + QUEUE_DESC_LOW, 32, desc).unwrap();
+        bus.store(mmio + QUEUE_DRIVER_LOW, 32, avail).unwrap();
+        bus.store(mmio + QUEUE_DEVICE_LOW, 32, used).unwrap();
+        bus.store(mmio + QUEUE_READY, 32, 1).unwrap();
+        Self {
+            mmio,
+            queue,
+            desc,
+            avail,
+            used,
+            next_desc: 0,
+            next_avail: 0,
+            last_used: 0,
+        }
+    }
+
+    /// Make a chain of (addr, len, device writable) buffers available without notifying.
+    pub fn add(&mut self, bus: &mut Bus, buffers: &[(u64, u32, bool)]) {
+        let head = self.next_desc;
+        for (i, (addr, len, writable)) in buffers.iter().enumerate() {
+            let index = (self.next_desc % QUEUE_SIZE) as u64;
+            let next = (self.next_desc + 1) % QUEUE_SIZE;
+            let mut flags = if *writable { 2 } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= 1;
+            }
+            let entry = self.desc + index * 16;
+            bus.store(entry, 64, *addr).unwrap();
+            bus.store(entry + 8, 32, *len as u64).unwrap();
+            bus.store(entry + 12, 16, flags).unwrap();
+            bus.store(entry + 14, 16, next as u64).unwrap();
+            self.next_desc = next;
+        }
+        let slot = (self.next_avail % QUEUE_SIZE) as u64;
+        bus.store(self.avail + 4 + slot * 2, 16, (head % QUEUE_SIZE) as u64)
+            .unwrap();
+        self.next_avail = self.next_avail.wrapping_add(1);
+        bus.store(self.avail + 2, 16, self.next_avail as u64)
+            .unwrap();
+    }
+
+    pub fn notify(&mut self, bus: &mut Bus) {
+        bus.store(self.mmio + QUEUE_NOTIFY, 32, self.queue as u64)
+            .unwrap();
+    }
+
+    /// Add a chain and notify the device.
+    pub fn submit(&mut self, bus: &mut Bus, buffers: &[(u64, u32, bool)]) {
+        self.add(bus, buffers);
+        self.notify(bus);
+    }
+
+    /// Next entry the device put into the used ring, as (descriptor head, written length).
+    pub fn pop_used(&mut self, bus: &Bus) -> Option<(u16, u32)> {
+        let used_idx = bus.load(self.used + 2, 16).unwrap() as u16;
+        if used_idx == self.last_used {
+            return None;
+        }
+        let slot = (self.last_used % QUEUE_SIZE) as u64;
+        let id = bus.load(self.used + 4 + slot * 8, 32).unwrap() as u16;
+        let len = bus.load(self.used + 8 + slot * 8, 32).unwrap() as u32;
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((id, len))
+    }
+}
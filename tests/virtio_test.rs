@@ -0,0 +1,507 @@
+mod utils;
+use riscv::interpreter::{
+    chardev::BufferBackend,
+    cpu::Cpu,
+    dram::Dram,
+    virtio::{
+        console::VirtioConsole,
+        input::{parse_script, InputKind, VirtioInput},
+        p9::{Virtio9p, VIRTIO_ID_9P},
+        queue::Virtqueue,
+        rng::{EntropySource, VirtioRng},
+        vsock::VirtioVsock,
+    },
+    DRAM_BASE, VIRTIO_BASE,
+};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+};
+use utils::{
+    temp::TempPath,
+    virtio::{read_bytes, write_bytes, TestQueue},
+};
+
+const RLERROR: u8 = 7;
+const EACCES: u32 = 13;
+const EROFS: u32 = 30;
+
+fn share_dir(name: &str) -> TempPath {
+    let dir = TempPath::new(&format!("riscv-{}", name));
+    std::fs::create_dir_all(dir.join("share")).unwrap();
+    std::fs::write(dir.join("share").join("hello.txt"), b"hello from host").unwrap();
+    std::fs::write(dir.join("secret.txt"), b"outside").unwrap();
+    dir
+}
+
+fn message(kind: u8, tag: u16, body: &[u8]) -> Vec<u8> {
+    let mut data = ((7 + body.len()) as u32).to_le_bytes().to_vec();
+    data.push(kind);
+    data.extend_from_slice(&tag.to_le_bytes());
+    data.extend_from_slice(body);
+    data
+}
+
+fn string(s: &str) -> Vec<u8> {
+    let mut data = (s.len() as u16).to_le_bytes().to_vec();
+    data.extend_from_slice(s.as_bytes());
+    data
+}
+
+fn attach(p9: &mut Virtio9p) {
+    let version = [8192u32.to_le_bytes().to_vec(), string("9P2000.L")].concat();
+    assert_eq!(p9.handle(&message(100, 0xffff, &version))[4], 101);
+    let attach = [
+        0u32.to_le_bytes().to_vec(),
+        u32::MAX.to_le_bytes().to_vec(),
+        string("root"),
+        string(""),
+        0u32.to_le_bytes().to_vec(),
+    ]
+    .concat();
+    assert_eq!(p9.handle(&message(104, 1, &attach))[4], 105);
+}
+
+fn walk(p9: &mut Virtio9p, fid: u32, newfid: u32, names: &[&str]) -> Vec<u8> {
+    let mut body = [fid.to_le_bytes(), newfid.to_le_bytes()].concat();
+    body.extend_from_slice(&(names.len() as u16).to_le_bytes());
+    for name in names {
+        body.extend_from_slice(&string(name));
+    }
+    p9.handle(&message(110, 2, &body))
+}
+
+fn error_code(response: &[u8]) -> u32 {
+    assert_eq!(response[4], RLERROR);
+    u32::from_le_bytes(response[7..11].try_into().unwrap())
+}
+
+#[test]
+fn test_virtio_9p_read_file_through_virtqueue() {
+    let dir = share_dir("9p-read");
+    let device = Virtio9p::new("host", &dir.join("share"), true).unwrap();
+    let mut cpu = Cpu::new(vec![]);
+    let mmio = cpu.bus.add_virtio_device(Box::new(device)).unwrap();
+    assert_eq!(mmio, VIRTIO_BASE);
+    assert_eq!(cpu.bus.load(mmio, 32).unwrap(), 0x7472_6976);
+    assert_eq!(cpu.bus.load(mmio + 0x8, 32).unwrap(), VIRTIO_ID_9P as u64);
+    // tag_len followed by the tag
+    assert_eq!(cpu.bus.load(mmio + 0x100, 16).unwrap(), 4);
+    assert_eq!(read_bytes(&cpu.bus, mmio + 0x102, 4), b"host");
+
+    let mut queue = TestQueue::new(&mut cpu.bus, mmio, 0, DRAM_BASE + 0x1000);
+    let request = DRAM_BASE + 0x2000;
+    let response = DRAM_BASE + 0x3000;
+    let mut transact = |cpu: &mut Cpu, msg: Vec<u8>| {
+        write_bytes(&mut cpu.bus, request, &msg);
+        queue.submit(
+            &mut cpu.bus,
+            &[(request, msg.len() as u32, false), (response, 0x1000, true)],
+        );
+        let (_, len) = queue.pop_used(&cpu.bus).unwrap();
+        read_bytes(&cpu.bus, response, len as usize)
+    };
+
+    let version = [8192u32.to_le_bytes().to_vec(), string("9P2000.L")].concat();
+    assert_eq!(transact(&mut cpu, message(100, 0xffff, &version))[4], 101);
+    let attach = [
+        0u32.to_le_bytes().to_vec(),
+        u32::MAX.to_le_bytes().to_vec(),
+        string("root"),
+        string(""),
+        0u32.to_le_bytes().to_vec(),
+    ]
+    .concat();
+    assert_eq!(transact(&mut cpu, message(104, 1, &attach))[4], 105);
+    let walk = [
+        0u32.to_le_bytes().to_vec(),
+        1u32.to_le_bytes().to_vec(),
+        1u16.to_le_bytes().to_vec(),
+        string("hello.txt"),
+    ]
+    .concat();
+    assert_eq!(transact(&mut cpu, message(110, 2, &walk))[4], 111);
+    let open = [1u32.to_le_bytes(), 0u32.to_le_bytes()].concat();
+    assert_eq!(transact(&mut cpu, message(12, 3, &open))[4], 13);
+    let read = [
+        1u32.to_le_bytes().to_vec(),
+        0u64.to_le_bytes().to_vec(),
+        100u32.to_le_bytes().to_vec(),
+    ]
+    .concat();
+    let response = transact(&mut cpu, message(116, 4, &read));
+    assert_eq!(response[4], 117);
+    assert_eq!(&response[11..], b"hello from host");
+}
+
+#[test]
+fn test_virtio_9p_sandbox() {
+    let dir = share_dir("9p-sandbox");
+    std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("share").join("escape")).unwrap();
+    let mut p9 = Virtio9p::new("host", &dir.join("share"), false).unwrap();
+    attach(&mut p9);
+
+    // ".." at the root stays at the root
+    let response = walk(&mut p9, 0, 1, &["..", "..", "hello.txt"]);
+    assert_eq!(response[4], 111);
+    // only ".." can be walked, so the walk is partial and fid 2 is not created
+    let response = walk(&mut p9, 0, 2, &["..", "secret.txt"]);
+    assert_eq!(response[4], 111);
+    assert_eq!(u16::from_le_bytes([response[7], response[8]]), 1);
+    assert_eq!(error_code(&walk(&mut p9, 2, 4, &[])), 9);
+
+    // a symlink pointing outside can be walked to, but not opened
+    assert_eq!(walk(&mut p9, 0, 3, &["escape"])[4], 111);
+    let open = [3u32.to_le_bytes(), 0u32.to_le_bytes()].concat();
+    assert_eq!(error_code(&p9.handle(&message(12, 3, &open))), EACCES);
+}
+
+#[test]
+fn test_virtio_9p_read_only() {
+    let dir = share_dir("9p-readonly");
+    let mut p9 = Virtio9p::new("host", &dir.join("share"), true).unwrap();
+    attach(&mut p9);
+
+    let create = [
+        0u32.to_le_bytes().to_vec(),
+        string("new.txt"),
+        0o102u32.to_le_bytes().to_vec(),
+        0o644u32.to_le_bytes().to_vec(),
+        0u32.to_le_bytes().to_vec(),
+    ]
+    .concat();
+    assert_eq!(error_code(&p9.handle(&message(14, 5, &create))), EROFS);
+    assert!(!dir.join("share").join("new.txt").exists());
+}
+
+fn control(id: u32, event: u16, value: u16) -> Vec<u8> {
+    [
+        id.to_le_bytes().to_vec(),
+        event.to_le_bytes().to_vec(),
+        value.to_le_bytes().to_vec(),
+    ]
+    .concat()
+}
+
+#[test]
+fn test_virtio_console_multiport() {
+    let console_backend = BufferBackend::default();
+    let channel_backend = BufferBackend::default();
+    let mut console = VirtioConsole::new(Box::new(console_backend.clone()));
+    assert_eq!(
+        console.add_port("org.test.0", Box::new(channel_backend.clone())),
+        1
+    );
+    let mut cpu = Cpu::new(vec![]);
+    let mmio = cpu.bus.add_virtio_device(Box::new(console)).unwrap();
+    // max_nr_ports
+    assert_eq!(cpu.bus.load(mmio + 0x104, 32).unwrap(), 2);
+
+    let mut control_rx = TestQueue::new(&mut cpu.bus, mmio, 2, DRAM_BASE + 0x1000);
+    let mut control_tx = TestQueue::new(&mut cpu.bus, mmio, 3, DRAM_BASE + 0x2000);
+    let mut port1_rx = TestQueue::new(&mut cpu.bus, mmio, 4, DRAM_BASE + 0x3000);
+    let mut port0_tx = TestQueue::new(&mut cpu.bus, mmio, 1, DRAM_BASE + 0x4000);
+    for i in 0..4 {
+        let buffer = DRAM_BASE + 0x10000 + i * 0x100;
+        control_rx.add(&mut cpu.bus, &[(buffer, 0x100, true)]);
+    }
+
+    // DEVICE_READY makes the device announce both ports
+    write_bytes(&mut cpu.bus, DRAM_BASE + 0x20000, &control(0, 0, 1));
+    control_tx.submit(&mut cpu.bus, &[(DRAM_BASE + 0x20000, 8, false)]);
+    let added: Vec<Vec<u8>> = std::iter::from_fn(|| control_rx.pop_used(&cpu.bus))
+        .map(|(head, len)| {
+            read_bytes(
+                &cpu.bus,
+                DRAM_BASE + 0x10000 + head as u64 * 0x100,
+                len as usize,
+            )
+        })
+        .collect();
+    assert_eq!(added, vec![control(0, 1, 0), control(1, 1, 0)]);
+
+    // PORT_READY for port 1 is answered with its name and PORT_OPEN
+    write_bytes(&mut cpu.bus, DRAM_BASE + 0x20000, &control(1, 3, 1));
+    control_tx.submit(&mut cpu.bus, &[(DRAM_BASE + 0x20000, 8, false)]);
+    let (head, len) = control_rx.pop_used(&cpu.bus).unwrap();
+    let name = read_bytes(
+        &cpu.bus,
+        DRAM_BASE + 0x10000 + head as u64 * 0x100,
+        len as usize,
+    );
+    assert_eq!(name, [control(1, 7, 0), b"org.test.0".to_vec()].concat());
+    let (head, len) = control_rx.pop_used(&cpu.bus).unwrap();
+    let open = read_bytes(
+        &cpu.bus,
+        DRAM_BASE + 0x10000 + head as u64 * 0x100,
+        len as usize,
+    );
+    assert_eq!(open, control(1, 6, 1));
+
+    // output on port 0 goes to the console backend
+    write_bytes(&mut cpu.bus, DRAM_BASE + 0x21000, b"boot ok");
+    port0_tx.submit(&mut cpu.bus, &[(DRAM_BASE + 0x21000, 7, false)]);
+    assert_eq!(console_backend.take_output(), b"boot ok");
+
+    // host input on port 1 is delivered when the bus polls
+    port1_rx.submit(&mut cpu.bus, &[(DRAM_BASE + 0x22000, 0x100, true)]);
+    assert!(port1_rx.pop_used(&cpu.bus).is_none());
+    channel_backend.push_input(b"ping");
+    cpu.bus.poll_devices();
+    let (_, len) = port1_rx.pop_used(&cpu.bus).unwrap();
+    assert_eq!(
+        read_bytes(&cpu.bus, DRAM_BASE + 0x22000, len as usize),
+        b"ping"
+    );
+}
+
+fn read_entropy(source: EntropySource) -> Vec<u8> {
+    let mut cpu = Cpu::new(vec![]);
+    let mmio = cpu
+        .bus
+        .add_virtio_device(Box::new(VirtioRng::new(source)))
+        .unwrap();
+    assert_eq!(cpu.bus.load(mmio + 0x8, 32).unwrap(), 4);
+    let mut queue = TestQueue::new(&mut cpu.bus, mmio, 0, DRAM_BASE + 0x1000);
+    queue.submit(&mut cpu.bus, &[(DRAM_BASE + 0x2000, 32, true)]);
+    assert_eq!(queue.pop_used(&cpu.bus), Some((0, 32)));
+    read_bytes(&cpu.bus, DRAM_BASE + 0x2000, 32)
+}
+
+#[test]
+fn test_virtio_rng() {
+    let seeded = read_entropy(EntropySource::Seeded(42));
+    assert_eq!(seeded, read_entropy(EntropySource::Seeded(42)));
+    assert_ne!(seeded, read_entropy(EntropySource::Seeded(43)));
+    assert_ne!(seeded, vec![0; 32]);
+    assert_ne!(read_entropy(EntropySource::host().unwrap()), vec![0; 32]);
+}
+
+#[test]
+fn test_virtio_rng_fills_huge_buffers_partly() {
+    let mut cpu = Cpu::new(vec![]);
+    let mmio = cpu
+        .bus
+        .add_virtio_device(Box::new(VirtioRng::new(EntropySource::Seeded(1))))
+        .unwrap();
+    let mut queue = TestQueue::new(&mut cpu.bus, mmio, 0, DRAM_BASE + 0x1000);
+    let buffer = (DRAM_BASE + 0x2000, u32::MAX, true);
+    queue.submit(&mut cpu.bus, &[buffer, buffer]);
+    assert_eq!(queue.pop_used(&cpu.bus), Some((0, 64 * 1024)));
+}
+
+const GUEST_CID: u64 = 3;
+
+fn vsock_packet(src_port: u32, dst_port: u32, op: u16, payload: &[u8]) -> Vec<u8> {
+    [
+        GUEST_CID.to_le_bytes().to_vec(),
+        2u64.to_le_bytes().to_vec(),
+        src_port.to_le_bytes().to_vec(),
+        dst_port.to_le_bytes().to_vec(),
+        (payload.len() as u32).to_le_bytes().to_vec(),
+        1u16.to_le_bytes().to_vec(),
+        op.to_le_bytes().to_vec(),
+        0u32.to_le_bytes().to_vec(),
+        65536u32.to_le_bytes().to_vec(),
+        0u32.to_le_bytes().to_vec(),
+        payload.to_vec(),
+    ]
+    .concat()
+}
+
+/// (src_port, dst_port, op, payload) of a packet the device sent to the guest.
+fn vsock_received(data: &[u8]) -> (u32, u32, u16, Vec<u8>) {
+    let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+    let op = u16::from_le_bytes(data[30..32].try_into().unwrap());
+    (u32_at(16), u32_at(20), op, data[44..].to_vec())
+}
+
+#[test]
+fn test_virtio_vsock() {
+    let dir = TempPath::dir("riscv-vsock");
+    let path = dir.join("vsock");
+    let vsock = VirtioVsock::new(GUEST_CID, &path).unwrap();
+    let host_listener = UnixListener::bind(vsock.host_socket(1234)).unwrap();
+    let mut cpu = Cpu::new(vec![]);
+    let mmio = cpu.bus.add_virtio_device(Box::new(vsock)).unwrap();
+    assert_eq!(cpu.bus.load(mmio + 0x100, 64).unwrap(), GUEST_CID);
+
+    let mut rx = TestQueue::new(&mut cpu.bus, mmio, 0, DRAM_BASE + 0x1000);
+    let mut tx = TestQueue::new(&mut cpu.bus, mmio, 1, DRAM_BASE + 0x2000);
+    let rx_buffer = |head: u16| DRAM_BASE + 0x10000 + head as u64 * 0x1000;
+    for i in 0..8 {
+        rx.add(&mut cpu.bus, &[(rx_buffer(i), 0x1000, true)]);
+    }
+    let mut transmit = |cpu: &mut Cpu, packet: Vec<u8>| {
+        write_bytes(&mut cpu.bus, DRAM_BASE + 0x20000, &packet);
+        tx.submit(
+            &mut cpu.bus,
+            &[(DRAM_BASE + 0x20000, packet.len() as u32, false)],
+        );
+    };
+    // next packet for the guest, skipping credit updates
+    let mut receive = |cpu: &Cpu| loop {
+        let (head, len) = rx.pop_used(&cpu.bus).unwrap();
+        let packet = vsock_received(&read_bytes(&cpu.bus, rx_buffer(head), len as usize));
+        if packet.2 != 6 {
+            break packet;
+        }
+    };
+
+    // guest connects to the host on port 1234
+    transmit(&mut cpu, vsock_packet(5000, 1234, 1, &[]));
+    assert_eq!(receive(&cpu), (1234, 5000, 2, vec![]));
+    let (mut host, _) = host_listener.accept().unwrap();
+    transmit(&mut cpu, vsock_packet(5000, 1234, 5, b"hello host"));
+    let mut buf = [0; 10];
+    host.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello host");
+    host.write_all(b"hello guest").unwrap();
+    cpu.bus.poll_devices();
+    assert_eq!(receive(&cpu), (1234, 5000, 5, b"hello guest".to_vec()));
+
+    // a host process connects to a guest listening on port 52, right after another one
+    // that gave up before its handshake
+    drop(UnixStream::connect(&path).unwrap());
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.write_all(b"CONNECT 52\n").unwrap();
+    cpu.bus.poll_devices();
+    let (host_port, guest_port, op, _) = receive(&cpu);
+    assert_eq!((guest_port, op), (52, 1));
+    transmit(&mut cpu, vsock_packet(52, host_port, 2, &[]));
+    let mut line = String::new();
+    BufReader::new(&client).read_line(&mut line).unwrap();
+    assert_eq!(line, format!("OK {}\n", host_port));
+    transmit(&mut cpu, vsock_packet(52, host_port, 5, b"from guest"));
+    let mut buf = [0; 10];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"from guest");
+
+    // nobody listens on the host side of port 99
+    transmit(&mut cpu, vsock_packet(5001, 99, 1, &[]));
+    assert_eq!(receive(&cpu), (99, 5001, 3, vec![]));
+}
+
+fn input_events(cpu: &Cpu, queue: &mut TestQueue) -> Vec<(u16, u16, u32)> {
+    std::iter::from_fn(|| queue.pop_used(&cpu.bus))
+        .map(|(head, _)| {
+            let data = read_bytes(&cpu.bus, DRAM_BASE + 0x10000 + head as u64 * 8, 8);
+            (
+                u16::from_le_bytes([data[0], data[1]]),
+                u16::from_le_bytes([data[2], data[3]]),
+                u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_virtio_input_script() {
+    let script = "# press and release A, then move the mouse\n\
+        100 key KEY_A 1\n\
+        150 key KEY_A 0\n\
+        200 rel REL_X -5\n\
+        200 rel REL_Y 7\n";
+    let events = parse_script(script).unwrap();
+    let device = VirtioInput::new(InputKind::Keyboard).with_script(events);
+    let mut cpu = Cpu::new(vec![]);
+    let mmio = cpu.bus.add_virtio_device(Box::new(device)).unwrap();
+    assert_eq!(cpu.bus.load(mmio + 0x8, 32).unwrap(), 18);
+    // select ID_NAME
+    cpu.bus.store(mmio + 0x100, 8, 0x01).unwrap();
+    let size = cpu.bus.load(mmio + 0x102, 8).unwrap() as usize;
+    assert_eq!(
+        read_bytes(&cpu.bus, mmio + 0x108, size),
+        b"riscv virtio keyboard"
+    );
+
+    let mut queue = TestQueue::new(&mut cpu.bus, mmio, 0, DRAM_BASE + 0x1000);
+    for i in 0..16 {
+        queue.add(&mut cpu.bus, &[(DRAM_BASE + 0x10000 + i * 8, 8, true)]);
+    }
+    cpu.bus.tick(99);
+    assert_eq!(input_events(&cpu, &mut queue), vec![]);
+    cpu.bus.tick(100);
+    assert_eq!(input_events(&cpu, &mut queue), vec![(1, 30, 1), (0, 0, 0)]);
+    cpu.bus.tick(200);
+    assert_eq!(
+        input_events(&cpu, &mut queue),
+        vec![
+            (1, 30, 0),
+            (0, 0, 0),
+            (2, 0, -5i32 as u32),
+            (2, 1, 7),
+            (0, 0, 0)
+        ]
+    );
+}
+
+#[test]
+fn test_virtio_input_terminal() {
+    let terminal = BufferBackend::default();
+    let device = VirtioInput::new(InputKind::Keyboard).with_terminal(Box::new(terminal.clone()));
+    let mut cpu = Cpu::new(vec![]);
+    let mmio = cpu.bus.add_virtio_device(Box::new(device)).unwrap();
+    let mut queue = TestQueue::new(&mut cpu.bus, mmio, 0, DRAM_BASE + 0x1000);
+    for i in 0..16 {
+        queue.add(&mut cpu.bus, &[(DRAM_BASE + 0x10000 + i * 8, 8, true)]);
+    }
+    terminal.push_input(b"H");
+    cpu.bus.poll_devices();
+    assert_eq!(
+        input_events(&cpu, &mut queue),
+        vec![
+            (1, 42, 1),
+            (1, 35, 1),
+            (0, 0, 0),
+            (1, 35, 0),
+            (1, 42, 0),
+            (0, 0, 0)
+        ]
+    );
+}
+
+fn descriptor(addr: u64, len: u32, flags: u16, next: u16) -> Vec<u8> {
+    [
+        &addr.to_le_bytes()[..],
+        &len.to_le_bytes(),
+        &flags.to_le_bytes(),
+        &next.to_le_bytes(),
+    ]
+    .concat()
+}
+
+#[test]
+fn test_virtqueue_rejects_malformed_chains() {
+    const INDIRECT: u16 = 4;
+    let mut dram = Dram::new(vec![]);
+    let desc = DRAM_BASE + 0x1000;
+    let driver = DRAM_BASE + 0x2000;
+    let mut queue = Virtqueue {
+        num: 4,
+        ready: true,
+        desc,
+        driver,
+        device: DRAM_BASE + 0x3000,
+        last_avail_idx: 0,
+    };
+    let make_available = |dram: &mut Dram, slot: u16, head: u16| {
+        dram.write_bytes(driver + 4 + slot as u64 * 2, &head.to_le_bytes())
+            .unwrap();
+        dram.write_bytes(driver + 2, &(slot + 1).to_le_bytes())
+            .unwrap();
+    };
+
+    // an indirect table whose only entry is itself
+    dram.write_bytes(desc, &descriptor(desc, 16, INDIRECT, 0))
+        .unwrap();
+    make_available(&mut dram, 0, 0);
+    assert!(queue.pop(&dram).is_err());
+
+    // a 4 GiB request
+    dram.write_bytes(desc + 16, &descriptor(DRAM_BASE, u32::MAX, 0, 0))
+        .unwrap();
+    make_available(&mut dram, 1, 1);
+    let chain = queue.pop(&dram).unwrap().unwrap();
+    assert!(chain.read_all(&dram).is_err());
+}